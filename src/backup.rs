@@ -0,0 +1,194 @@
+//! Crash-safe writes of the save files, and rotating backups of them.
+//!
+//! A backup of `<dir>/<name>` is kept as `<backup dir>/<name>.<timestamp>.bak`, so sorting the
+//! backups by name sorts them by time.
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::Utc;
+
+pub const BACKUP_DIR: &str = "backups";
+pub const DEFAULT_BACKUP_COUNT: usize = 5;
+pub const DEFAULT_BACKUP_INTERVAL_MIN: u64 = 60;
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A path next to `path` which no other write uses
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+/// The rename of a file is durable only after its directory is synced
+fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Write `contents` to a temporary file, sync it and rename it over `path`. After a crash,
+/// `path` holds either the previous or the new contents.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    create_file_atomic(path, |temp| {
+        let mut file = fs::File::create(temp)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    })
+}
+
+/// Create the file at `path` through `write`, which must create and sync the given temporary
+/// file, then rename it to `path`
+fn create_file_atomic(path: &Path, write: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+    let temp = temp_path(path);
+    let result = write(&temp)
+        .and_then(|_| fs::rename(&temp, path))
+        .and_then(|_| sync_dir(path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Copy `from` to the temporary file `to` and sync it. Usable as the `write` of [`rotate`].
+pub fn copy_synced(from: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(from, to)?;
+    fs::File::open(to)?.sync_all()
+}
+
+/// The backups of `path` in `backup_dir`, the newest first
+pub fn list(path: &Path, backup_dir: &Path) -> Vec<PathBuf> {
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    let mut backups = match fs::read_dir(backup_dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                let name = p.file_name().unwrap_or_default().to_string_lossy();
+                name.starts_with(&prefix) && name.ends_with(".bak")
+            })
+            .collect::<Vec<PathBuf>>(),
+        Err(_) => vec![],
+    };
+    backups.sort_by(|a, b| b.cmp(a));
+    backups
+}
+
+/// Create a new backup of `path` through `write`, which must create and sync the given
+/// temporary file. Only the newest `keep` backups are kept.
+pub fn rotate(
+    path: &Path,
+    backup_dir: &Path,
+    keep: usize,
+    write: impl FnOnce(&Path) -> io::Result<()>,
+) -> io::Result<PathBuf> {
+    fs::create_dir_all(backup_dir)?;
+    let backup = backup_dir.join(format!(
+        "{}.{}.bak",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        Utc::now().format(TIMESTAMP_FORMAT)
+    ));
+    create_file_atomic(&backup, write)?;
+    for old in list(path, backup_dir).into_iter().skip(keep) {
+        if let Err(e) = fs::remove_file(&old) {
+            log::error!("Remove backup {} failed: {e}", old.display());
+        }
+    }
+    Ok(backup)
+}
+
+/// Read and parse `path`. When it can't be parsed, the newest backup that can is used instead.
+pub fn read_with_fallback<T>(
+    path: &Path,
+    backup_dir: &Path,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> io::Result<T> {
+    let error = match parse(&fs::read_to_string(path)?) {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+    log::error!("Parse {} failed: {error}", path.display());
+    for backup in list(path, backup_dir) {
+        match fs::read_to_string(&backup)
+            .map_err(|e| e.to_string())
+            .and_then(|s| parse(&s))
+        {
+            Ok(value) => {
+                log::warn!("Restored {} from {}", path.display(), backup.display());
+                return Ok(value);
+            }
+            Err(e) => log::error!("Parse backup {} failed: {e}", backup.display()),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "yt-watcher-test-backup-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = test_dir("write");
+        let path = dir.join("save.txt");
+        write_atomic(&path, b"a long first version\n").unwrap();
+        write_atomic(&path, b"short\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "short\n");
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_and_fallback() {
+        let dir = test_dir("rotate");
+        let path = dir.join("save.json");
+        let backup_dir = dir.join(BACKUP_DIR);
+        for n in 0..4 {
+            write_atomic(&path, format!("{n}").as_bytes()).unwrap();
+            rotate(&path, &backup_dir, 2, |temp| copy_synced(&path, temp)).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        let backups = list(&path, &backup_dir);
+        assert_eq!(backups.len(), 2);
+        assert_eq!(fs::read_to_string(&backups[0]).unwrap(), "3");
+
+        let parse = |s: &str| s.parse::<u32>().map_err(|e| e.to_string());
+        write_atomic(&path, b"corrupted").unwrap();
+        assert_eq!(read_with_fallback(&path, &backup_dir, parse).unwrap(), 3);
+        fs::write(&backups[0], "corrupted").unwrap();
+        assert_eq!(read_with_fallback(&path, &backup_dir, parse).unwrap(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+//! The command line. Without a subcommand, the server is started.
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, str::FromStr};
+
+use clap::{Parser, Subcommand};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{paths::DataPaths, server::admin::AdminCommand, Config};
+
+#[derive(Debug, Parser, PartialEq)]
+#[command(version, about)]
+pub struct Cli {
+    /// The config file. Defaults to config.toml in the working directory if it exists, otherwise
+    /// in the config directory of the platform, like $XDG_CONFIG_HOME/yt-watcher
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// The directory of the database, the backups, the quota save and the log. Overrides the
+    /// data_dir of the config
+    #[arg(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand, PartialEq)]
+pub enum Command {
+    /// Run the server
+    Serve,
+    /// Start tracking a channel
+    Track {
+        #[command(subcommand)]
+        channel: Channel,
+    },
+    /// Stop tracking a channel and drop its events
+    Untrack {
+        #[command(subcommand)]
+        channel: Channel,
+    },
+    /// Print the tracked channels
+    ListChannels,
+    /// Refresh the events of every channel through the youtube api. Needs a running server.
+    RefreshNow,
+    /// Manage the sync keys
+    SyncKeys {
+        #[command(subcommand)]
+        command: SyncKeysCommand,
+    },
+    /// Print the youtube quota used today
+    Quota,
+    /// Check the config file and exit
+    CheckConfig,
+}
+
+#[derive(Debug, Subcommand, PartialEq)]
+pub enum Channel {
+    /// A youtube channel, by id, handle or url
+    Yt { channel: String },
+    /// A twitch channel, by login
+    Tw { login: String },
+}
+
+#[derive(Debug, Subcommand, PartialEq)]
+pub enum SyncKeysCommand {
+    /// Print every key with the number of its channels and webhooks
+    List,
+    /// Delete a key and its webhooks
+    Revoke { key: Uuid },
+    /// Print every key with its channels and webhooks
+    Export,
+}
+
+impl Command {
+    /// The admin command run by this subcommand, if it is one
+    pub fn admin_command(&self) -> Option<AdminCommand> {
+        Some(match self {
+            Command::Serve | Command::CheckConfig => return None,
+            Command::Track {
+                channel: Channel::Yt { channel },
+            } => AdminCommand::TrackYt {
+                channel: channel.clone(),
+            },
+            Command::Track {
+                channel: Channel::Tw { login },
+            } => AdminCommand::TrackTw {
+                login: login.clone(),
+            },
+            Command::Untrack {
+                channel: Channel::Yt { channel },
+            } => AdminCommand::UntrackYt {
+                channel: channel.clone(),
+            },
+            Command::Untrack {
+                channel: Channel::Tw { login },
+            } => AdminCommand::UntrackTw {
+                login: login.clone(),
+            },
+            Command::ListChannels => AdminCommand::ListChannels,
+            Command::RefreshNow => AdminCommand::RefreshNow,
+            Command::SyncKeys {
+                command: SyncKeysCommand::List,
+            } => AdminCommand::ListSyncKeys,
+            Command::SyncKeys {
+                command: SyncKeysCommand::Revoke { key },
+            } => AdminCommand::RevokeSyncKey { key: *key },
+            Command::SyncKeys {
+                command: SyncKeysCommand::Export,
+            } => AdminCommand::ExportSyncKeys,
+            Command::Quota => AdminCommand::Quota,
+        })
+    }
+}
+
+/// Run `command` on the server listening on the socket of `config`. When no server is
+/// listening, it is run on the stores in `paths` instead.
+pub async fn run_admin(
+    config: &Config,
+    paths: DataPaths,
+    command: AdminCommand,
+) -> Result<Value, String> {
+    if let Some(data) = request_server(config, &command).await? {
+        return Ok(data);
+    }
+    log::info!(
+        "No server is listening on {}, using the stores",
+        config.socket
+    );
+    let context = crate::open_context(config, paths)?;
+    crate::server::admin::execute_locally(config, context, command).await
+}
+
+/// Send `command` to the `/admin` endpoint of the server. Returns `None` if no server is
+/// listening.
+async fn request_server(config: &Config, command: &AdminCommand) -> Result<Option<Value>, String> {
+    let mut address = SocketAddr::from_str(&config.socket)
+        .map_err(|e| format!("Invalid socket {}: {e}", config.socket))?;
+    if address.ip().is_unspecified() {
+        address.set_ip(match address {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let mut request = reqwest::Client::new()
+        .post(format!("http://{address}/admin"))
+        .json(command);
+    if let Some(token) = &config.admin_token {
+        request = request.bearer_auth(token);
+    }
+    let response = match request.send().await {
+        Ok(r) => r,
+        Err(e) if e.is_connect() => return Ok(None),
+        Err(e) => return Err(format!("Request the server on {address} failed: {e}")),
+    };
+    match response.status().as_u16() {
+        404 => Err(format!(
+            "The server on {address} has no admin api. Set admin_token in the config and restart it"
+        )),
+        401 => Err(format!("The server on {address} rejected the admin_token")),
+        _ => {
+            let mut body = response
+                .json::<HashMap<String, Value>>()
+                .await
+                .map_err(|e| format!("Read the response of the server failed: {e}"))?;
+            match body.remove("data") {
+                Some(data) => Ok(Some(data)),
+                None => Err(body
+                    .remove("error")
+                    .and_then(|e| e.as_str().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "The server returned no data".to_string())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cli = Cli::try_parse_from(["yt-watcher"]).unwrap();
+        assert_eq!(cli.command, None);
+        assert_eq!(cli.config, None);
+        assert_eq!(cli.data_dir, None);
+
+        let cli = Cli::try_parse_from([
+            "yt-watcher",
+            "serve",
+            "--config",
+            "/etc/yt-watcher.toml",
+            "--data-dir",
+            "/var/lib/yt-watcher",
+        ])
+        .unwrap();
+        assert_eq!(cli.command, Some(Command::Serve));
+        assert_eq!(cli.config, Some(PathBuf::from("/etc/yt-watcher.toml")));
+        assert_eq!(cli.data_dir, Some(PathBuf::from("/var/lib/yt-watcher")));
+
+        let command = |args: &[&str]| {
+            Cli::try_parse_from(["yt-watcher"].iter().chain(args))
+                .unwrap()
+                .command
+                .unwrap()
+                .admin_command()
+        };
+        assert_eq!(
+            command(&["track", "yt", "@handle"]),
+            Some(AdminCommand::TrackYt {
+                channel: "@handle".to_string()
+            })
+        );
+        assert_eq!(
+            command(&["untrack", "tw", "login"]),
+            Some(AdminCommand::UntrackTw {
+                login: "login".to_string()
+            })
+        );
+        let key = Uuid::new_v4();
+        assert_eq!(
+            command(&["sync-keys", "revoke", &key.to_string()]),
+            Some(AdminCommand::RevokeSyncKey { key })
+        );
+        assert_eq!(command(&["check-config"]), None);
+        assert!(Cli::try_parse_from(["yt-watcher", "sync-keys", "revoke", "not-a-key"]).is_err());
+        assert!(Cli::try_parse_from(["yt-watcher", "track", "yt"]).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+//! The values of the config come from the config file, then `YT_WATCHER_*` environment variables
+//! which override it, and secret files.
+//!
+//! `YT_WATCHER_VIDEO_REFRESH_INTERVAL=5` sets `video_refresh_interval`, and a double underscore
+//! separates the tables: `YT_WATCHER_TWITCH_KEY__CLIENT_ID` sets `client_id` of `[twitch_key]`.
+//! A value is read as toml if it can be, like `5` or `true`, otherwise as a string. The secrets,
+//! the urls and the paths are always strings, like a value which is a string in the config file.
+//!
+//! Each secret can instead be read from the file at `<name>_file`, in the config file or in the
+//! environment, like `YT_WATCHER_API_KEY_FILE=/run/secrets/api_key`. The file of `api_keys` has
+//! a key on each line.
+use std::{collections::HashMap, fmt::Display, path::PathBuf};
+
+use toml::{Table, Value};
+
+pub const ENV_PREFIX: &str = "YT_WATCHER_";
+const SECRET_FILE_SUFFIX: &str = "_file";
+/// The secrets which are lists, read from a file with an item on each line
+const LIST_SECRETS: &[&str] = &["api_keys"];
+/// The values which are never reported, by their dotted key
+const SECRETS: &[&str] = &[
+    "api_key",
+    "api_keys",
+    "admin_token",
+    "twitch_key.client_id",
+    "twitch_key.client_secret",
+    "twitch_eventsub.secret",
+    "websub.secret",
+];
+/// The values which are strings besides the secrets, by their dotted key
+const STRINGS: &[&str] = &[
+    "socket",
+    "log_level",
+    "data_dir",
+    "public_url",
+    "paths.database",
+    "paths.backups",
+    "paths.quota",
+    "paths.log",
+    "twitch_eventsub.callback_url",
+    "websub.callback_url",
+    "upstream.youtube_api",
+    "upstream.youtube",
+    "upstream.twitch_helix",
+    "upstream.twitch_oauth",
+    "upstream.websub_hub",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    File,
+    Env(String),
+    SecretFile(PathBuf),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::File => write!(f, "config file"),
+            Source::Env(name) => write!(f, "environment variable {name}"),
+            Source::SecretFile(path) => write!(f, "secret file {}", path.display()),
+        }
+    }
+}
+
+/// Every value of the config with its source, secrets redacted
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    values: Vec<(String, String, Source)>,
+}
+
+impl Sources {
+    #[cfg(test)]
+    pub fn source(&self, key: &str) -> Option<&Source> {
+        self.values
+            .iter()
+            .find(|(k, _, _)| k == key)
+            .map(|(_, _, s)| s)
+    }
+}
+
+impl Display for Sources {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, value, source) in self.values.iter() {
+            writeln!(f, "{key} = {value} ({source})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether any variable of `env` configures the server
+pub fn has_env_overrides(env: &[(String, String)]) -> bool {
+    env.iter().any(|(name, _)| name.starts_with(ENV_PREFIX))
+}
+
+/// Apply the overrides of `env` and the secret files to the table of the config file
+pub fn merge(
+    file: Table,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> Result<(Table, Sources), String> {
+    let mut table = file;
+    let mut sources = HashMap::new();
+    read_secret_files(&mut table, &mut sources)?;
+
+    let mut overrides = Table::new();
+    let mut env_sources = HashMap::new();
+    for (name, value) in env {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let path = key
+            .to_lowercase()
+            .split("__")
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+        let value = match get(&table, &path) {
+            Some(Value::String(_)) => Value::String(value),
+            _ if is_string(&path.join(".")) => Value::String(value),
+            _ => parse_value(&value),
+        };
+        set(&mut overrides, &path, value)
+            .map_err(|e| format!("Invalid environment variable {name}: {e}"))?;
+        env_sources.insert(path.join("."), Source::Env(name));
+    }
+    read_secret_files(&mut overrides, &mut env_sources)?;
+    merge_tables(&mut table, overrides);
+    sources.extend(env_sources);
+
+    let mut values = vec![];
+    flatten(&table, "", &mut |key, value| {
+        let shown = if SECRETS.contains(&key) {
+            "<redacted>".to_string()
+        } else {
+            value.to_string()
+        };
+        let source = sources.get(key).cloned().unwrap_or(Source::File);
+        values.push((key.to_string(), shown, source));
+    });
+    Ok((table, Sources { values }))
+}
+
+fn is_string(key: &str) -> bool {
+    STRINGS.contains(&key) || (SECRETS.contains(&key) && !LIST_SECRETS.contains(&key))
+}
+
+fn parse_value(s: &str) -> Value {
+    match format!("value = {s}").parse::<Table>() {
+        Ok(mut t) => t.remove("value").unwrap_or(Value::String(s.to_string())),
+        Err(_) => Value::String(s.to_string()),
+    }
+}
+
+fn secret_file_path(path: &[String]) -> Vec<String> {
+    let mut path = path.to_vec();
+    if let Some(last) = path.last_mut() {
+        last.push_str(SECRET_FILE_SUFFIX);
+    }
+    path
+}
+
+/// Replace each `<secret>_file` of `table` by the contents of the file
+fn read_secret_files(
+    table: &mut Table,
+    sources: &mut HashMap<String, Source>,
+) -> Result<(), String> {
+    for secret in SECRETS {
+        let path = secret
+            .split('.')
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+        let Some(file) = remove(table, &secret_file_path(&path)) else {
+            continue;
+        };
+        let Value::String(file) = file else {
+            return Err(format!("{secret}{SECRET_FILE_SUFFIX} must be a path"));
+        };
+        if get(table, &path).is_some() {
+            return Err(format!(
+                "Both {secret} and {secret}{SECRET_FILE_SUFFIX} are set"
+            ));
+        }
+        let value = std::fs::read_to_string(&file)
+            .map_err(|e| format!("Read the secret file {file} of {secret} failed: {e}"))?;
+        let value = if LIST_SECRETS.contains(secret) {
+            Value::Array(
+                value
+                    .lines()
+                    .map(|l| l.trim())
+                    .filter(|l| !l.is_empty())
+                    .map(|l| Value::String(l.to_string()))
+                    .collect(),
+            )
+        } else {
+            Value::String(value.trim_end_matches(['\r', '\n']).to_string())
+        };
+        set(table, &path, value)?;
+        sources.insert(secret.to_string(), Source::SecretFile(PathBuf::from(file)));
+    }
+    Ok(())
+}
+
+fn get<'a>(table: &'a Table, path: &[String]) -> Option<&'a Value> {
+    let (last, tables) = path.split_last()?;
+    let mut table = table;
+    for name in tables {
+        table = table.get(name)?.as_table()?;
+    }
+    table.get(last)
+}
+
+fn remove(table: &mut Table, path: &[String]) -> Option<Value> {
+    let (last, tables) = path.split_last()?;
+    let mut table = table;
+    for name in tables {
+        table = table.get_mut(name)?.as_table_mut()?;
+    }
+    table.remove(last)
+}
+
+fn set(table: &mut Table, path: &[String], value: Value) -> Result<(), String> {
+    let Some((last, tables)) = path.split_last() else {
+        return Err("Empty key".to_string());
+    };
+    let mut table = table;
+    for name in tables {
+        table = table
+            .entry(name.clone())
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("{name} is not a table"))?;
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+fn merge_tables(base: &mut Table, overrides: Table) {
+    for (name, value) in overrides {
+        match (base.get_mut(&name), value) {
+            (Some(Value::Table(base)), Value::Table(overrides)) => merge_tables(base, overrides),
+            (_, value) => {
+                base.insert(name, value);
+            }
+        }
+    }
+}
+
+/// Call `f` with the dotted key of every value which isn't a table
+fn flatten(table: &Table, prefix: &str, f: &mut impl FnMut(&str, &Value)) {
+    for (name, value) in table {
+        let key = format!("{prefix}{name}");
+        match value {
+            Value::Table(t) => flatten(t, &format!("{key}."), f),
+            _ => f(&key, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = std::env::temp_dir().join(format!(
+            "yt-watcher-test-config-sources-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let secret = dir.join("client_secret");
+        std::fs::write(&secret, "from-file\n").unwrap();
+        let keys = dir.join("api_keys");
+        std::fs::write(&keys, "key-1\n\nkey-2\n").unwrap();
+        let file = r#"
+            api_key = "file-key"
+            video_refresh_interval = 10
+            admin_token = "12345"
+
+            [twitch_key]
+            client_id = "file-id"
+            client_secret = "file-secret"
+            "#
+        .parse::<Table>()
+        .unwrap();
+
+        let (table, sources) = merge(
+            file.clone(),
+            env(&[
+                ("YT_WATCHER_VIDEO_REFRESH_INTERVAL", "5"),
+                ("YT_WATCHER_ADMIN_TOKEN", "67890"),
+                ("YT_WATCHER_LOG_LEVEL", "Debug"),
+                (
+                    "YT_WATCHER_TWITCH_KEY__CLIENT_SECRET_FILE",
+                    secret.to_str().unwrap(),
+                ),
+                ("YT_WATCHER_API_KEYS_FILE", keys.to_str().unwrap()),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            table["api_keys"],
+            Value::Array(vec!["key-1".into(), "key-2".into()])
+        );
+        assert_eq!(table["api_key"].as_str(), Some("file-key"));
+        assert_eq!(table["video_refresh_interval"].as_integer(), Some(5));
+        // stays a string like in the file
+        assert_eq!(table["admin_token"].as_str(), Some("67890"));
+        assert_eq!(table["log_level"].as_str(), Some("Debug"));
+        assert_eq!(
+            table["twitch_key"]["client_secret"].as_str(),
+            Some("from-file")
+        );
+        assert_eq!(sources.source("api_key"), Some(&Source::File));
+        assert_eq!(
+            sources.source("video_refresh_interval"),
+            Some(&Source::Env(
+                "YT_WATCHER_VIDEO_REFRESH_INTERVAL".to_string()
+            ))
+        );
+        assert_eq!(
+            sources.source("twitch_key.client_secret"),
+            Some(&Source::SecretFile(secret.clone()))
+        );
+        let report = sources.to_string();
+        assert!(report.contains("api_key = <redacted> (config file)"));
+        assert!(!report.contains("file-key"));
+        assert!(!report.contains("from-file"));
+        assert!(!report.contains("key-1"));
+        assert!(report.contains("video_refresh_interval = 5"));
+
+        // a secret can't be given twice at the same level
+        let mut twice = file;
+        twice.insert(
+            "api_key_file".to_string(),
+            Value::String(secret.to_str().unwrap().to_string()),
+        );
+        assert!(merge(twice, vec![]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_from_env_only() {
+        let missing = std::path::Path::new("/nonexistent/yt-watcher/config.toml");
+        assert!(crate::load_config(missing, vec![]).is_err());
+        let (config, _) = crate::load_config(
+            missing,
+            env(&[
+                ("YT_WATCHER_API_KEY", "env-key"),
+                ("YT_WATCHER_SOCKET", "0.0.0.0:8080"),
+                ("YT_WATCHER_VIDEO_REFRESH_INTERVAL", "10"),
+                ("YT_WATCHER_CHANNEL_REFRESH_INTERVAL", "1440"),
+                ("YT_WATCHER_CHANNEL_EXPIRE_MIN", "10080"),
+                ("YT_WATCHER_LOG_LEVEL", "Info"),
+                ("YT_WATCHER_USE_YOUTUBE_API_PER_HOUR", "2"),
+                ("YT_WATCHER_UPSTREAM__YOUTUBE", "http://127.0.0.1:1"),
+                // read as strings, though they look like numbers
+                ("YT_WATCHER_ADMIN_TOKEN", "12345"),
+                ("YT_WATCHER_TWITCH_KEY__CLIENT_ID", "67890"),
+                ("YT_WATCHER_TWITCH_KEY__CLIENT_SECRET", "1e5"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.api_keys(), ["env-key"]);
+        assert_eq!(config.admin_token.as_deref(), Some("12345"));
+        let twitch_key = config.twitch_key.as_ref().unwrap();
+        assert_eq!(twitch_key.client_id, "67890");
+        assert_eq!(twitch_key.client_secret, "1e5");
+        assert_eq!(config.socket, "0.0.0.0:8080");
+        assert_eq!(config.upstream.youtube, "http://127.0.0.1:1");
+
+        // an empty token would let any request in
+        let config = crate::Config {
+            admin_token: Some(" ".to_string()),
+            ..config
+        };
+        assert!(config.validate().is_err());
+    }
+}
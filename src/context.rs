@@ -0,0 +1,117 @@
+//! The state shared by the whole server, built once in `main` and passed to whoever needs it.
+use std::{future::Future, sync::Arc};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    paths::DataPaths,
+    storage::Storage,
+    sync::SyncStore,
+    yt_api::{quota::Quota, ChannelIdCache},
+    Upstream,
+};
+
+pub struct AppContext {
+    pub http: reqwest::Client,
+    /// The base urls the apis request
+    pub upstream: Upstream,
+    pub storage: Arc<dyn Storage>,
+    pub quota: Arc<Quota>,
+    pub channel_ids: Arc<ChannelIdCache>,
+    pub sync_store: Arc<SyncStore>,
+    pub paths: DataPaths,
+    /// The background tasks, by name, stopped on shutdown
+    tasks: std::sync::Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+}
+
+/// The client of every outgoing http request
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .local_address(local_ip_address::local_ip().unwrap())
+        .build()
+        .unwrap()
+}
+
+impl AppContext {
+    pub fn new(
+        http: reqwest::Client,
+        upstream: Upstream,
+        storage: Arc<dyn Storage>,
+        quota: Quota,
+        paths: DataPaths,
+    ) -> Self {
+        Self {
+            http,
+            upstream,
+            channel_ids: Arc::new(ChannelIdCache::new(storage.clone())),
+            sync_store: Arc::new(SyncStore::new(storage.clone())),
+            storage,
+            quota: Arc::new(quota),
+            paths,
+            tasks: std::sync::Mutex::new(vec![]),
+        }
+    }
+
+    /// Start the tasks which save the stores periodically
+    pub fn spawn_savers(&self) {
+        self.track("sync key saver", self.sync_store.spawn_saver());
+        self.track("channel id cache saver", self.channel_ids.spawn_saver());
+        self.track("quota saver", self.quota.spawn_saver());
+    }
+
+    /// Run `task` in the background until the shutdown
+    pub fn spawn(&self, name: &'static str, task: impl Future<Output = ()> + Send + 'static) {
+        self.track(name, tokio::spawn(task));
+    }
+
+    fn track(&self, name: &'static str, handle: JoinHandle<()>) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|(_, h)| !h.is_finished());
+        tasks.push((name, handle));
+    }
+
+    /// Stop the background tasks and save every store
+    pub async fn shutdown(&self) {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for (name, handle) in tasks {
+            handle.abort();
+            match handle.await {
+                Err(e) if e.is_panic() => log::error!("Task {name} panicked"),
+                _ => log::debug!("Task {name} stopped"),
+            }
+        }
+        self.sync_store.save().await;
+        self.channel_ids.save().await;
+        let quota = self.quota.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || quota.save()).await {
+            log::error!("Save quota failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::test::TOKIO_RUNTIME;
+
+    #[test]
+    fn test_shutdown() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let context = AppContext::new(
+            reqwest::Client::new(),
+            Upstream::default(),
+            storage.clone(),
+            Quota::default(),
+            DataPaths::in_dir(&std::env::temp_dir()),
+        );
+        TOKIO_RUNTIME.block_on(async {
+            context.spawn_savers();
+            context.spawn("endless", std::future::pending());
+            let key = context.sync_store.new_key().await;
+            context.shutdown().await;
+            assert!(context.tasks.lock().unwrap().is_empty());
+            assert_eq!(*storage.load_sync_keys().unwrap()[0].key(), key);
+        });
+    }
+}
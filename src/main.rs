@@ -1,59 +1,255 @@
+mod backup;
+mod cli;
+mod config_sources;
+mod context;
+#[cfg(test)]
+mod mock_upstream;
+mod paths;
+mod reload;
 mod server;
+mod storage;
 mod sync;
 mod tw_api;
 mod yt_api;
 
+use clap::Parser;
+use context::AppContext;
 use futures::Future;
-use once_cell::sync::Lazy;
 use reqwest::IntoUrl;
 use serde::Deserialize;
+use std::{str::FromStr, sync::Arc};
 
-const CONFIG_PATH: &str = "config.toml";
-static mut REQWEST_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
-    reqwest::Client::builder()
-        .local_address(local_ip_address::local_ip().unwrap())
-        .build()
-        .unwrap()
-});
-
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Config {
-    api_key: String,
+    api_key: Option<String>,
+    /// More keys, used in order when the quota of the previous ones is used up
+    #[serde(default)]
+    api_keys: Vec<String>,
     socket: String,
     video_refresh_interval: u64,
     channel_refresh_interval: u64,
     channel_expire_min: i64,
+    ended_event_retention_min: Option<i64>,
     log_level: String,
     twitch_key: Option<TwAppKey>,
     video_refresh_delay: Option<u64>,
     use_youtube_api_per_hour: u32,
+    youtube_quota_budget: Option<u32>,
+    backup_count: Option<usize>,
+    backup_interval_min: Option<u64>,
+    adaptive_polling: Option<AdaptivePolling>,
+    websub: Option<WebSub>,
+    twitch_eventsub: Option<TwEventSub>,
+    /// Enables the `/admin` endpoint, which requires it as a bearer token
+    admin_token: Option<String>,
+    /// Hosts the webhooks may target over http or on the local network
+    #[serde(default)]
+    webhook_allowed_hosts: Vec<String>,
+    /// Base url of this server as the clients reach it, for the links of the feeds
+    public_url: Option<String>,
+    /// Relative to the directory of the config file
+    data_dir: Option<std::path::PathBuf>,
+    #[serde(default)]
+    paths: paths::FilePaths,
+    #[serde(default)]
+    upstream: Upstream,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct TwAppKey {
     client_id: String,
     client_secret: String,
 }
 
-#[tokio::main]
-async fn main() {
-    // and log using log crate macros!
-    let config = match tokio::fs::read_to_string(CONFIG_PATH).await {
-        Ok(config_file) => {
-            println!("Found config file: {}", CONFIG_PATH);
-            match toml::from_str::<Config>(&config_file) {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Parse config file failed: {}", e);
-                    std::process::exit(1)
-                }
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TwEventSub {
+    /// Public url of the `/tw-eventsub` endpoint of this server
+    callback_url: String,
+    /// Signs the notifications, 10 to 100 ascii characters
+    secret: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct AdaptivePolling {
+    quota_budget: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct WebSub {
+    /// Public url of the `/websub` endpoint of this server
+    callback_url: String,
+    /// The secret of each subscription is derived from it, so the notifications can be verified
+    secret: String,
+    lease_seconds: Option<u32>,
+}
+
+/// Base urls of the upstream services. Every field can be overridden in the config file,
+/// so the server can be pointed at a local stand-in instead of the real services.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Upstream {
+    pub youtube_api: String,
+    pub youtube: String,
+    pub twitch_helix: String,
+    pub twitch_oauth: String,
+    pub websub_hub: String,
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            youtube_api: "https://www.googleapis.com/youtube/v3".to_string(),
+            youtube: "https://www.youtube.com".to_string(),
+            twitch_helix: "https://api.twitch.tv/helix".to_string(),
+            twitch_oauth: "https://id.twitch.tv/oauth2".to_string(),
+            websub_hub: "https://pubsubhubbub.appspot.com/subscribe".to_string(),
+        }
+    }
+}
+
+impl Config {
+    fn log_level_filter(&self) -> Result<log::LevelFilter, String> {
+        match self.log_level.to_lowercase().as_str() {
+            "off" => Ok(log::LevelFilter::Off),
+            "error" => Ok(log::LevelFilter::Error),
+            "warn" => Ok(log::LevelFilter::Warn),
+            "info" => Ok(log::LevelFilter::Info),
+            "debug" => Ok(log::LevelFilter::Debug),
+            "trace" => Ok(log::LevelFilter::Trace),
+            _ => Err(format!("Invalid log level: {}", self.log_level)),
+        }
+    }
+
+    /// Every youtube api key, `api_key` first, without duplicates
+    fn api_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = vec![];
+        for key in self.api_key.iter().chain(self.api_keys.iter()) {
+            if !keys.contains(key) {
+                keys.push(key.clone());
             }
         }
+        keys
+    }
+
+    /// Reject the values the server can't run with
+    fn validate(&self) -> Result<(), String> {
+        self.log_level_filter()?;
+        if self.api_keys().is_empty() {
+            return Err("Set api_key or api_keys".to_string());
+        }
+        std::net::SocketAddr::from_str(&self.socket)
+            .map_err(|e| format!("Invalid socket {}: {e}", self.socket))?;
+        if self.video_refresh_interval == 0 || self.channel_refresh_interval == 0 {
+            return Err("The refresh intervals must be at least 1 minute".to_string());
+        }
+        if self.use_youtube_api_per_hour > 60 {
+            return Err("use_youtube_api_per_hour must be at most 60".to_string());
+        }
+        if self
+            .admin_token
+            .as_ref()
+            .is_some_and(|t| t.trim().is_empty())
+        {
+            return Err(
+                "admin_token must not be empty, remove it to disable the admin endpoint"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Read and validate the config file, with the overrides of the environment
+fn read_config(path: &std::path::Path) -> Result<Config, String> {
+    load_config(path, std::env::vars().collect()).map(|(config, _)| config)
+}
+
+/// Read the config like [`read_config`], with the source of each value. The config file may be
+/// missing if the environment configures the server.
+fn load_config(
+    path: &std::path::Path,
+    env: Vec<(String, String)>,
+) -> Result<(Config, config_sources::Sources), String> {
+    let file = match std::fs::read_to_string(path) {
+        Ok(s) => s
+            .parse::<toml::Table>()
+            .map_err(|e| format!("Parse config file {} failed: {e}", path.display()))?,
+        Err(e)
+            if e.kind() == std::io::ErrorKind::NotFound
+                && config_sources::has_env_overrides(&env) =>
+        {
+            toml::Table::new()
+        }
+        Err(e) => return Err(format!("Open config file {} failed: {e}", path.display())),
+    };
+    let (table, sources) = config_sources::merge(file, env)?;
+    let config = toml::Value::Table(table)
+        .try_into::<Config>()
+        .map_err(|e| format!("Parse config {} failed: {e}", path.display()))?;
+    config.validate()?;
+    Ok((config, sources))
+}
+
+impl Upstream {
+    fn trimmed(mut self) -> Self {
+        for url in [
+            &mut self.youtube_api,
+            &mut self.youtube,
+            &mut self.twitch_helix,
+            &mut self.twitch_oauth,
+            &mut self.websub_hub,
+        ] {
+            *url = url.trim_end_matches('/').to_string();
+        }
+        self
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = cli::Cli::parse();
+    let config_path = cli.config.unwrap_or_else(paths::default_config_path);
+    let (config, sources) = match load_config(&config_path, std::env::vars().collect()) {
+        Ok(c) => c,
         Err(e) => {
-            eprintln!("Open config file failed: {}", e);
+            eprintln!("{e}");
             std::process::exit(1)
         }
     };
+    let paths = paths::DataPaths::resolve(cli.data_dir.as_deref(), &config, &config_path);
+    let command = cli.command.unwrap_or(cli::Command::Serve);
+    if command == cli::Command::CheckConfig {
+        println!("Config file {} is valid", config_path.display());
+        print!("{sources}");
+        println!("{paths:#?}");
+        return;
+    }
+    if let Err(e) = paths.create_dirs() {
+        eprintln!("Create the data directories failed: {e}");
+        std::process::exit(1)
+    }
+    if let Some(admin_command) = command.admin_command() {
+        fern::Dispatch::new()
+            .format(|out, message, record| {
+                out.finish(format_args!("[{}] {}", record.level(), message))
+            })
+            .chain(std::io::stderr())
+            .level(log::LevelFilter::Warn)
+            .apply()
+            .unwrap();
+        match cli::run_admin(&config, paths, admin_command).await {
+            Ok(data) => println!(
+                "{}",
+                serde_json::to_string_pretty(&data).unwrap_or_default()
+            ),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
+    println!("Found config file: {}", config_path.display());
 
     fern::Dispatch::new()
         // Perform allocation-free log formatting
@@ -67,32 +263,98 @@ async fn main() {
             ))
         })
         .chain(std::io::stdout())
-        .chain(fern::log_file("output.log").unwrap())
-        .level(match config.log_level.to_lowercase().as_str() {
-            "off" => log::LevelFilter::Off,
-            "error" => log::LevelFilter::Error,
-            "warn" => log::LevelFilter::Warn,
-            "info" => log::LevelFilter::Info,
-            "debug" => log::LevelFilter::Debug,
-            "trace" => log::LevelFilter::Trace,
-            _ => panic!("Invalid log level"),
-        })
+        .chain(fern::log_file(&paths.log).unwrap())
+        // filtered by the max level instead, which can be changed on reload
+        .level(log::LevelFilter::Trace)
         .apply()
         .unwrap();
+    log::set_max_level(config.log_level_filter().unwrap_or(log::LevelFilter::Info));
 
+    log::info!("Upstream urls: {:?}", config.upstream.clone().trimmed());
+    log::info!("Data files: {paths:?}");
+    log::info!("Config values:\n{sources}");
+    let context = match open_context(&config, paths) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1)
+        }
+    };
+    let (config_sender, config_receiver) = tokio::sync::watch::channel(config);
+    context.spawn(
+        "config watcher",
+        reload::watch_config(config_path, config_sender),
+    );
     log::info!("starting");
-    server::server_start(&config).await;
+    server::server_start(config_receiver, context, shutdown_signal()).await;
+    log::info!("Stopped, every store is saved");
+}
+
+/// Open the stores in `paths`, importing the save files of older versions
+fn open_context(config: &Config, paths: paths::DataPaths) -> Result<Arc<AppContext>, String> {
+    let storage = storage::SqliteStorage::open_with_fallback(&paths.database, &paths.backups)
+        .map_err(|e| format!("Open database {} failed: {e}", paths.database.display()))?;
+    storage::import_legacy_files(&storage, &paths.legacy);
+    let quota = yt_api::quota::Quota::load(
+        config.api_keys(),
+        config
+            .youtube_quota_budget
+            .unwrap_or(yt_api::quota::DEFAULT_DAILY_BUDGET),
+        &paths.quota,
+        &paths.backups,
+    );
+    Ok(Arc::new(AppContext::new(
+        context::http_client(),
+        config.upstream.clone().trimmed(),
+        Arc::new(storage),
+        quota,
+        paths,
+    )))
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM on unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("Listen for Ctrl-C failed: {e}");
+            std::future::pending::<()>().await
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                log::error!("Listen for SIGTERM failed: {e}");
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => log::info!("Received Ctrl-C"),
+        _ = terminate => log::info!("Received SIGTERM"),
+    }
 }
 
 fn make_http_get(
+    client: &reqwest::Client,
     url: impl IntoUrl,
 ) -> impl Future<Output = Result<reqwest::Response, reqwest::Error>> {
-    unsafe { REQWEST_CLIENT.execute(REQWEST_CLIENT.get(url).build().unwrap()) }
+    client.execute(client.get(url).build().unwrap())
 }
 
 #[cfg(test)]
 pub mod test {
+    use crate::context::AppContext;
+    use crate::mock_upstream::{self, MockUpstream};
+    use crate::storage::MemoryStorage;
+    use crate::yt_api::quota::Quota;
     use once_cell::sync::Lazy;
+    use std::sync::Arc;
     use tokio::runtime::Runtime;
 
     pub static TOKIO_RUNTIME: Lazy<Runtime> = Lazy::new(|| {
@@ -101,8 +363,42 @@ pub mod test {
             .build()
             .unwrap()
     });
+    /// The mock upstream shared by all tests. The contexts of the tests point the apis at it.
+    pub static MOCK: Lazy<MockUpstream> = Lazy::new(MockUpstream::start);
     pub static CONFIG: Lazy<crate::Config> = Lazy::new(|| {
-        toml::from_str::<crate::Config>(&std::fs::read_to_string(crate::CONFIG_PATH).unwrap())
-            .unwrap()
+        toml::from_str::<crate::Config>(&format!(
+            r#"
+            api_key = "{}"
+            socket = "127.0.0.1:0"
+            video_refresh_interval = 10
+            channel_refresh_interval = 1440
+            channel_expire_min = 10080
+            log_level = "Info"
+            use_youtube_api_per_hour = 2
+            backup_count = 0
+
+            [twitch_key]
+            client_id = "{}"
+            client_secret = "{}"
+            "#,
+            mock_upstream::API_KEY,
+            mock_upstream::TWITCH_CLIENT_ID,
+            mock_upstream::TWITCH_CLIENT_SECRET,
+        ))
+        .unwrap()
     });
+
+    /// A context with an empty in-memory storage and the default quota budget
+    pub fn new_context() -> Arc<AppContext> {
+        Arc::new(AppContext::new(
+            crate::context::http_client(),
+            MOCK.upstream(),
+            Arc::new(MemoryStorage::default()),
+            Quota::new(
+                vec![mock_upstream::API_KEY.to_string()],
+                crate::yt_api::quota::DEFAULT_DAILY_BUDGET,
+            ),
+            crate::paths::DataPaths::in_dir(&std::env::temp_dir()),
+        ))
+    }
 }
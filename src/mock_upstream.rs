@@ -0,0 +1,772 @@
+//! A fixture driven stand-in of the youtube and twitch services.
+//!
+//! The server binds to a random local port and serves the endpoints used by `yt_api` and
+//! `tw_api`. Videos and streams are scripted: every video owns a list of states and
+//! [`MockUpstream::advance_video`] moves it to the next one, so a test can walk a stream
+//! through upcoming, live and ended deterministically.
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use warp::{http::StatusCode, Filter};
+
+use crate::Upstream;
+
+pub const API_KEY: &str = "mock-api-key";
+/// An api key whose quota is exceeded
+pub const EXHAUSTED_API_KEY: &str = "mock-exhausted-api-key";
+pub const TWITCH_CLIENT_ID: &str = "mock-client-id";
+pub const TWITCH_CLIENT_SECRET: &str = "mock-client-secret";
+const TWITCH_ACCESS_TOKEN: &str = "mock-access-token";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoState {
+    /// A normal upload without live streaming details
+    Upload,
+    Upcoming(DateTime<Utc>),
+    Live(DateTime<Utc>),
+    Ended(DateTime<Utc>, DateTime<Utc>),
+}
+
+#[derive(Debug, Clone)]
+struct MockVideo {
+    channel_id: String,
+    title: String,
+    script: Vec<VideoState>,
+    step: usize,
+}
+
+impl MockVideo {
+    fn state(&self) -> &VideoState {
+        &self.script[self.step]
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MockChannel {
+    handle: String,
+    title: String,
+    // newest video first, the same order youtube uses in feeds and playlists
+    videos: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockStream {
+    pub title: String,
+    pub game_name: String,
+    pub started_at: DateTime<Utc>,
+    pub viewer_count: usize,
+}
+
+#[derive(Debug, Clone)]
+struct MockTwUser {
+    id: String,
+    login: String,
+    display_name: String,
+    stream: Option<MockStream>,
+}
+
+#[derive(Debug, Default)]
+struct Fixtures {
+    channels: HashMap<String, MockChannel>,
+    videos: HashMap<String, MockVideo>,
+    tw_users: Vec<MockTwUser>,
+    requests: Vec<String>,
+    hub_requests: Vec<HashMap<String, String>>,
+    eventsub_subscriptions: Vec<Value>,
+    next_subscription_id: usize,
+    webhook_requests: Vec<Value>,
+    webhook_failures: usize,
+    /// Videos whose next `videos` request is answered with 503
+    failing_videos: HashSet<String>,
+}
+
+pub struct MockUpstream {
+    addr: SocketAddr,
+    fixtures: Arc<Mutex<Fixtures>>,
+}
+
+impl MockUpstream {
+    /// Start the server on its own thread and runtime, so it keeps serving no matter which
+    /// runtime the test is blocked on.
+    pub fn start() -> Self {
+        let fixtures = Arc::new(Mutex::new(Fixtures::default()));
+        let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+        let routes = routes(fixtures.clone());
+        std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+                    addr_tx.send(addr).unwrap();
+                    server.await;
+                })
+        });
+        Self {
+            addr: addr_rx.recv().expect("Start mock upstream failed"),
+            fixtures,
+        }
+    }
+
+    pub fn upstream(&self) -> Upstream {
+        let base = format!("http://{}", self.addr);
+        Upstream {
+            youtube_api: format!("{base}/youtube/v3"),
+            youtube: base.clone(),
+            twitch_helix: format!("{base}/helix"),
+            twitch_oauth: format!("{base}/oauth2"),
+            websub_hub: format!("{base}/hub"),
+        }
+    }
+
+    pub fn add_yt_channel(&self, id: &str, handle: &str, title: &str) {
+        self.fixtures.lock().unwrap().channels.insert(
+            id.to_string(),
+            MockChannel {
+                handle: handle.to_string(),
+                title: title.to_string(),
+                videos: vec![],
+            },
+        );
+    }
+
+    /// Publish a new video on the channel. The video starts at the first state of the script.
+    pub fn add_video(
+        &self,
+        channel_id: &str,
+        video_id: &str,
+        title: &str,
+        script: Vec<VideoState>,
+    ) {
+        assert!(!script.is_empty(), "Video script can not be empty");
+        let mut fixtures = self.fixtures.lock().unwrap();
+        fixtures
+            .channels
+            .get_mut(channel_id)
+            .expect("Add video to an unknown channel")
+            .videos
+            .insert(0, video_id.to_string());
+        fixtures.videos.insert(
+            video_id.to_string(),
+            MockVideo {
+                channel_id: channel_id.to_string(),
+                title: title.to_string(),
+                script,
+                step: 0,
+            },
+        );
+    }
+
+    /// Move the video to the next state of its script. The last state is kept forever.
+    pub fn advance_video(&self, video_id: &str) {
+        let mut fixtures = self.fixtures.lock().unwrap();
+        let video = fixtures
+            .videos
+            .get_mut(video_id)
+            .expect("Advance an unknown video");
+        if video.step + 1 < video.script.len() {
+            video.step += 1;
+        }
+    }
+
+    pub fn add_tw_user(&self, id: &str, login: &str, display_name: &str) {
+        self.fixtures.lock().unwrap().tw_users.push(MockTwUser {
+            id: id.to_string(),
+            login: login.to_string(),
+            display_name: display_name.to_string(),
+            stream: None,
+        });
+    }
+
+    /// Start a stream on the twitch channel, or end it with `None`
+    pub fn set_tw_stream(&self, login: &str, stream: Option<MockStream>) {
+        let mut fixtures = self.fixtures.lock().unwrap();
+        fixtures
+            .tw_users
+            .iter_mut()
+            .find(|u| u.login == login)
+            .expect("Set stream of an unknown twitch user")
+            .stream = stream;
+    }
+
+    /// Paths of every request the server received, in order
+    pub fn requests(&self) -> Vec<String> {
+        self.fixtures.lock().unwrap().requests.clone()
+    }
+
+    /// Answer the next `count` requests to `/webhook` with 503
+    pub fn fail_next_webhooks(&self, count: usize) {
+        self.fixtures.lock().unwrap().webhook_failures = count;
+    }
+
+    /// Answer the next `videos` request which asks for `video_id` with 503
+    pub fn fail_next_video_request(&self, video_id: &str) {
+        self.fixtures
+            .lock()
+            .unwrap()
+            .failing_videos
+            .insert(video_id.to_string());
+    }
+
+    /// Bodies of every request `/webhook` received, in order
+    pub fn webhook_requests(&self) -> Vec<Value> {
+        self.fixtures.lock().unwrap().webhook_requests.clone()
+    }
+
+    /// `(type, broadcaster_user_id)` of the active EventSub subscriptions
+    pub fn eventsub_subscriptions(&self) -> Vec<(String, String)> {
+        self.fixtures
+            .lock()
+            .unwrap()
+            .eventsub_subscriptions
+            .iter()
+            .map(|s| {
+                (
+                    s["type"].as_str().unwrap_or_default().to_string(),
+                    s["condition"]["broadcaster_user_id"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// `(hub.mode, hub.topic)` of every websub request the hub received, in order
+    pub fn hub_requests(&self) -> Vec<(String, String)> {
+        self.fixtures
+            .lock()
+            .unwrap()
+            .hub_requests
+            .iter()
+            .map(|form| {
+                (
+                    form.get("hub.mode").cloned().unwrap_or_default(),
+                    form.get("hub.topic").cloned().unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+}
+
+fn upload_playlist_id(channel_id: &str) -> String {
+    format!("UU{}", channel_id.trim_start_matches("UC"))
+}
+
+fn list_response(kind: &str, items: Vec<Value>) -> Value {
+    json!({
+        "kind": kind,
+        "etag": "mock",
+        "pageInfo": { "totalResults": items.len(), "resultsPerPage": 50 },
+        "items": items,
+    })
+}
+
+fn query_values<'a>(query: &'a [(String, String)], key: &str) -> Vec<&'a str> {
+    query
+        .iter()
+        .filter(|(k, _)| k == key)
+        .flat_map(|(_, v)| v.split(','))
+        .collect()
+}
+
+fn reply(status: StatusCode, body: Value) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+fn error_reply(status: StatusCode, message: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    reply(
+        status,
+        json!({ "error": { "code": status.as_u16(), "message": message } }),
+    )
+}
+
+fn channel_json(id: &str, channel: &MockChannel) -> Value {
+    json!({
+        "kind": "youtube#channel",
+        "etag": "mock",
+        "id": id,
+        "snippet": {
+            "title": channel.title,
+            "description": "",
+            "customUrl": format!("@{}", channel.handle.to_lowercase()),
+            "publishedAt": "2020-01-01T00:00:00Z",
+            "thumbnails": {
+                "default": { "url": format!("https://yt3.example/{id}/88"), "width": 88, "height": 88 },
+                "medium": { "url": format!("https://yt3.example/{id}/240"), "width": 240, "height": 240 },
+            },
+            "localized": { "title": channel.title, "description": "" },
+        },
+        "contentDetails": {
+            "relatedPlaylists": { "likes": "", "uploads": upload_playlist_id(id) }
+        },
+    })
+}
+
+fn video_json(id: &str, video: &MockVideo, channel_title: &str) -> Value {
+    let (broadcast, details) = match video.state() {
+        VideoState::Upload => ("none", Value::Null),
+        VideoState::Upcoming(start) => (
+            "upcoming",
+            json!({ "scheduledStartTime": start.to_rfc3339() }),
+        ),
+        VideoState::Live(start) => (
+            "live",
+            json!({
+                "scheduledStartTime": start.to_rfc3339(),
+                "actualStartTime": start.to_rfc3339(),
+                "concurrentViewers": "1000",
+            }),
+        ),
+        VideoState::Ended(start, end) => (
+            "none",
+            json!({
+                "scheduledStartTime": start.to_rfc3339(),
+                "actualStartTime": start.to_rfc3339(),
+                "actualEndTime": end.to_rfc3339(),
+            }),
+        ),
+    };
+    let mut resource = json!({
+        "kind": "youtube#video",
+        "etag": "mock",
+        "id": id,
+        "snippet": {
+            "publishedAt": "2020-01-01T00:00:00Z",
+            "channelId": video.channel_id,
+            "title": video.title,
+            "description": "",
+            "thumbnails": {
+                "maxres": { "url": format!("https://i.ytimg.example/vi/{id}/maxresdefault.jpg"), "width": 1280, "height": 720 },
+            },
+            "channelTitle": channel_title,
+            "categoryId": "20",
+            "liveBroadcastContent": broadcast,
+            "localized": { "title": video.title, "description": "" },
+        },
+    });
+    if !details.is_null() {
+        resource["liveStreamingDetails"] = details;
+    }
+    resource
+}
+
+fn channel_page(id: &str) -> String {
+    format!(
+        concat!(
+            r#"<html><head>"#,
+            r#"<link rel="canonical" href="https://www.youtube.com/channel/{id}">"#,
+            r#"<meta property="og:url" content="https://www.youtube.com/channel/{id}">"#,
+            r#"<meta itemprop="identifier" content="{id}">"#,
+            r#"<link rel="alternate" type="application/rss+xml" title="RSS" href="https://www.youtube.com/feeds/videos.xml?channel_id={id}">"#,
+            r#"</head></html>"#
+        ),
+        id = id
+    )
+}
+
+fn feed(channel_id: &str, channel: &MockChannel) -> String {
+    let mut body = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><feed xmlns:yt="http://www.youtube.com/xml/schemas/2015" xmlns="http://www.w3.org/2005/Atom"><yt:channelId>{channel_id}</yt:channelId><title>{}</title>"#,
+        channel.title
+    );
+    for video_id in channel.videos.iter() {
+        body += &format!("<entry><id>yt:video:{video_id}</id><yt:videoId>{video_id}</yt:videoId><yt:channelId>{channel_id}</yt:channelId></entry>");
+    }
+    body += "</feed>";
+    body
+}
+
+fn tw_user_json(user: &MockTwUser) -> Value {
+    json!({
+        "id": user.id,
+        "login": user.login,
+        "display_name": user.display_name,
+        "type": "",
+        "broadcaster_type": "partner",
+        "description": "",
+        "profile_image_url": format!("https://static-cdn.example/{}-profile.png", user.login),
+        "offline_image_url": "",
+        "view_count": 0,
+        "created_at": "2020-01-01T00:00:00Z",
+    })
+}
+
+fn tw_channel_json(user: &MockTwUser) -> Value {
+    json!({
+        "broadcaster_id": user.id,
+        "broadcaster_login": user.login,
+        "broadcaster_name": user.display_name,
+        "broadcaster_language": "en",
+        "game_id": "0",
+        "game_name": user.stream.as_ref().map(|s| s.game_name.as_str()).unwrap_or_default(),
+        "title": user.stream.as_ref().map(|s| s.title.as_str()).unwrap_or_default(),
+        "delay": 0,
+        "tags": [],
+        "content_classification_labels": [],
+        "is_branded_content": false,
+    })
+}
+
+fn tw_stream_json(user: &MockTwUser, stream: &MockStream) -> Value {
+    json!({
+        "id": format!("{}-stream", user.id),
+        "user_id": user.id,
+        "user_login": user.login,
+        "user_name": user.display_name,
+        "game_id": "0",
+        "game_name": stream.game_name,
+        "type": "live",
+        "title": stream.title,
+        "tags": [],
+        "viewer_count": stream.viewer_count,
+        "started_at": stream.started_at.to_rfc3339(),
+        "language": "en",
+        "thumbnail_url": format!("https://static-cdn.example/previews-ttv/live_user_{}-{{width}}x{{height}}.jpg", user.login),
+        "tag_ids": [],
+        "is_mature": false,
+    })
+}
+
+fn routes(
+    fixtures: Arc<Mutex<Fixtures>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + Send + Sync + 'static
+{
+    let with_fixtures = {
+        let fixtures = fixtures.clone();
+        warp::any().map(move || fixtures.clone())
+    };
+    let log = {
+        let fixtures = fixtures.clone();
+        warp::path::full().map(move |path: warp::path::FullPath| {
+            fixtures
+                .lock()
+                .unwrap()
+                .requests
+                .push(path.as_str().to_string());
+        })
+    };
+
+    let yt_api = warp::path!("youtube" / "v3" / String)
+        .and(warp::query::<Vec<(String, String)>>())
+        .and(with_fixtures.clone())
+        .map(
+            |endpoint: String, query: Vec<(String, String)>, fixtures: Arc<Mutex<Fixtures>>| {
+                if query_values(&query, "key") == [EXHAUSTED_API_KEY] {
+                    return reply(
+                        StatusCode::FORBIDDEN,
+                        json!({ "error": {
+                            "code": 403,
+                            "message": "The request cannot be completed because you have exceeded your quota.",
+                            "errors": [{ "reason": "quotaExceeded", "domain": "youtube.quota" }],
+                        } }),
+                    );
+                }
+                if query_values(&query, "key") != [API_KEY] {
+                    return error_reply(StatusCode::BAD_REQUEST, "API key not valid");
+                }
+                let mut fixtures = fixtures.lock().unwrap();
+                if endpoint == "videos"
+                    && query_values(&query, "id")
+                        .into_iter()
+                        .any(|id| fixtures.failing_videos.remove(id))
+                {
+                    return error_reply(StatusCode::SERVICE_UNAVAILABLE, "backendError");
+                }
+                match endpoint.as_str() {
+                    "channels" => reply(
+                        StatusCode::OK,
+                        list_response(
+                            "youtube#channelListResponse",
+                            query_values(&query, "id")
+                                .into_iter()
+                                .filter_map(|id| {
+                                    fixtures.channels.get(id).map(|c| channel_json(id, c))
+                                })
+                                .collect(),
+                        ),
+                    ),
+                    "videos" => reply(
+                        StatusCode::OK,
+                        list_response(
+                            "youtube#videoListResponse",
+                            query_values(&query, "id")
+                                .into_iter()
+                                .filter_map(|id| {
+                                    fixtures.videos.get(id).map(|v| {
+                                        let channel_title = fixtures
+                                            .channels
+                                            .get(&v.channel_id)
+                                            .map(|c| c.title.as_str())
+                                            .unwrap_or_default();
+                                        video_json(id, v, channel_title)
+                                    })
+                                })
+                                .collect(),
+                        ),
+                    ),
+                    "playlistItems" => {
+                        let playlist_id = query_values(&query, "playlistId").concat();
+                        match fixtures
+                            .channels
+                            .iter()
+                            .find(|(id, _)| upload_playlist_id(id) == playlist_id)
+                        {
+                            None => error_reply(StatusCode::NOT_FOUND, "playlistNotFound"),
+                            Some((_, channel)) => reply(
+                                StatusCode::OK,
+                                list_response(
+                                    "youtube#playlistItemListResponse",
+                                    channel
+                                        .videos
+                                        .iter()
+                                        .map(|video_id| {
+                                            json!({
+                                                "kind": "youtube#playlistItem",
+                                                "etag": "mock",
+                                                "id": format!("{playlist_id}-{video_id}"),
+                                                "contentDetails": { "videoId": video_id },
+                                            })
+                                        })
+                                        .collect(),
+                                ),
+                            ),
+                        }
+                    }
+                    _ => error_reply(StatusCode::NOT_FOUND, "Unknown endpoint"),
+                }
+            },
+        );
+
+    let feed = warp::path!("feeds" / "videos.xml")
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_fixtures.clone())
+        .map(
+            |query: HashMap<String, String>, fixtures: Arc<Mutex<Fixtures>>| {
+                let fixtures = fixtures.lock().unwrap();
+                let channel_id = query.get("channel_id").cloned().unwrap_or_default();
+                match fixtures.channels.get(&channel_id) {
+                    None => warp::reply::with_status(String::new(), StatusCode::NOT_FOUND),
+                    Some(c) => warp::reply::with_status(feed(&channel_id, c), StatusCode::OK),
+                }
+            },
+        );
+
+    let channel_page_by_id = warp::path!("channel" / String)
+        .and(with_fixtures.clone())
+        .map(|id: String, fixtures: Arc<Mutex<Fixtures>>| {
+            if fixtures.lock().unwrap().channels.contains_key(&id) {
+                warp::reply::with_status(channel_page(&id), StatusCode::OK)
+            } else {
+                warp::reply::with_status(String::new(), StatusCode::NOT_FOUND)
+            }
+        });
+
+    let channel_page_by_handle = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(with_fixtures.clone())
+        .map(|handle: String, fixtures: Arc<Mutex<Fixtures>>| {
+            let handle = handle.trim_start_matches('@').to_lowercase();
+            match fixtures
+                .lock()
+                .unwrap()
+                .channels
+                .iter()
+                .find(|(_, c)| c.handle.to_lowercase() == handle)
+            {
+                Some((id, _)) => warp::reply::with_status(channel_page(id), StatusCode::OK),
+                None => warp::reply::with_status(String::new(), StatusCode::NOT_FOUND),
+            }
+        });
+
+    let oauth = warp::post()
+        .and(warp::path!("oauth2" / "token"))
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|query: HashMap<String, String>| {
+            if query.get("client_id").map(|s| s.as_str()) == Some(TWITCH_CLIENT_ID)
+                && query.get("client_secret").map(|s| s.as_str()) == Some(TWITCH_CLIENT_SECRET)
+            {
+                reply(
+                    StatusCode::OK,
+                    json!({
+                        "access_token": TWITCH_ACCESS_TOKEN,
+                        "expires_in": 3600,
+                        "token_type": "bearer",
+                    }),
+                )
+            } else {
+                error_reply(StatusCode::FORBIDDEN, "invalid client secret")
+            }
+        });
+
+    let hub = warp::post()
+        .and(warp::path!("hub"))
+        .and(warp::body::form::<HashMap<String, String>>())
+        .and(with_fixtures.clone())
+        .map(
+            |form: HashMap<String, String>, fixtures: Arc<Mutex<Fixtures>>| {
+                let valid = matches!(
+                    form.get("hub.mode").map(|s| s.as_str()),
+                    Some("subscribe" | "unsubscribe")
+                ) && form.contains_key("hub.topic")
+                    && form.contains_key("hub.callback");
+                fixtures.lock().unwrap().hub_requests.push(form);
+                if valid {
+                    StatusCode::ACCEPTED
+                } else {
+                    StatusCode::BAD_REQUEST
+                }
+            },
+        );
+
+    let webhook = warp::post()
+        .and(warp::path!("webhook"))
+        .and(warp::body::json::<Value>())
+        .and(with_fixtures.clone())
+        .map(|body: Value, fixtures: Arc<Mutex<Fixtures>>| {
+            let mut fixtures = fixtures.lock().unwrap();
+            fixtures.webhook_requests.push(body);
+            if fixtures.webhook_failures > 0 {
+                fixtures.webhook_failures -= 1;
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::NO_CONTENT
+            }
+        });
+
+    let eventsub = warp::path!("helix" / "eventsub" / "subscriptions")
+        .and(warp::method())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::bytes())
+        .and(with_fixtures.clone())
+        .map(
+            |method: warp::http::Method,
+             auth: Option<String>,
+             query: HashMap<String, String>,
+             body: warp::hyper::body::Bytes,
+             fixtures: Arc<Mutex<Fixtures>>| {
+                if auth.as_deref() != Some(&format!("Bearer {TWITCH_ACCESS_TOKEN}")) {
+                    return error_reply(StatusCode::UNAUTHORIZED, "Invalid OAuth token");
+                }
+                let mut fixtures = fixtures.lock().unwrap();
+                match method {
+                    warp::http::Method::POST => {
+                        let Ok(request) = serde_json::from_slice::<Value>(&body) else {
+                            return error_reply(StatusCode::BAD_REQUEST, "Invalid body");
+                        };
+                        let duplicated = fixtures.eventsub_subscriptions.iter().any(|s| {
+                            s["type"] == request["type"] && s["condition"] == request["condition"]
+                        });
+                        if duplicated {
+                            return error_reply(StatusCode::CONFLICT, "subscription already exists");
+                        }
+                        fixtures.next_subscription_id += 1;
+                        let subscription = json!({
+                            "id": format!("mock-subscription-{}", fixtures.next_subscription_id),
+                            "status": "webhook_callback_verification_pending",
+                            "type": request["type"],
+                            "version": request["version"],
+                            "condition": request["condition"],
+                            "created_at": Utc::now(),
+                            "transport": {
+                                "method": "webhook",
+                                "callback": request["transport"]["callback"],
+                            },
+                            "cost": 0,
+                        });
+                        fixtures.eventsub_subscriptions.push(subscription.clone());
+                        reply(
+                            StatusCode::ACCEPTED,
+                            json!({ "data": [subscription], "total": 1, "total_cost": 0, "max_total_cost": 10000 }),
+                        )
+                    }
+                    warp::http::Method::DELETE => {
+                        let id = query.get("id").cloned().unwrap_or_default();
+                        let before = fixtures.eventsub_subscriptions.len();
+                        fixtures.eventsub_subscriptions.retain(|s| s["id"] != id);
+                        if fixtures.eventsub_subscriptions.len() == before {
+                            error_reply(StatusCode::NOT_FOUND, "subscription not found")
+                        } else {
+                            reply(StatusCode::NO_CONTENT, Value::Null)
+                        }
+                    }
+                    _ => reply(
+                        StatusCode::OK,
+                        json!({
+                            "data": fixtures.eventsub_subscriptions,
+                            "total": fixtures.eventsub_subscriptions.len(),
+                            "pagination": {},
+                        }),
+                    ),
+                }
+            },
+        );
+
+    let helix = warp::path!("helix" / String)
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<Vec<(String, String)>>())
+        .and(with_fixtures)
+        .map(
+            |endpoint: String,
+             auth: Option<String>,
+             query: Vec<(String, String)>,
+             fixtures: Arc<Mutex<Fixtures>>| {
+                if auth.as_deref() != Some(&format!("Bearer {TWITCH_ACCESS_TOKEN}")) {
+                    return error_reply(StatusCode::UNAUTHORIZED, "Invalid OAuth token");
+                }
+                let fixtures = fixtures.lock().unwrap();
+                let (id_key, login_key) = match endpoint.as_str() {
+                    "users" => ("id", "login"),
+                    "streams" => ("user_id", "user_login"),
+                    "channels" => ("broadcaster_id", "broadcaster_login"),
+                    _ => return error_reply(StatusCode::NOT_FOUND, "Unknown endpoint"),
+                };
+                let ids = query_values(&query, id_key);
+                let logins = query_values(&query, login_key);
+                let users = fixtures
+                    .tw_users
+                    .iter()
+                    .filter(|u| ids.contains(&u.id.as_str()) || logins.contains(&u.login.as_str()));
+                if endpoint == "users" {
+                    reply(
+                        StatusCode::OK,
+                        json!({ "data": users.map(tw_user_json).collect::<Vec<Value>>() }),
+                    )
+                } else if endpoint == "channels" {
+                    reply(
+                        StatusCode::OK,
+                        json!({ "data": users.map(tw_channel_json).collect::<Vec<Value>>() }),
+                    )
+                } else {
+                    reply(
+                        StatusCode::OK,
+                        json!({
+                            "data": users
+                                .filter_map(|u| u.stream.as_ref().map(|s| tw_stream_json(u, s)))
+                                .collect::<Vec<Value>>(),
+                            "pagination": {},
+                        }),
+                    )
+                }
+            },
+        );
+
+    log.untuple_one().and(
+        yt_api
+            .or(feed)
+            .or(channel_page_by_id)
+            .or(oauth)
+            .or(eventsub)
+            .or(helix)
+            .or(hub)
+            .or(webhook)
+            .or(channel_page_by_handle),
+    )
+}
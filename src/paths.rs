@@ -0,0 +1,145 @@
+//! Where the server keeps its files.
+//!
+//! The data directory is, in order of precedence, the `--data-dir` argument, the `data_dir` of
+//! the config, the working directory if it already holds a database, and the data directory of
+//! the platform, like `$XDG_DATA_HOME/yt-watcher`. Each file can be moved by the `[paths]` of
+//! the config.
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{backup, storage, yt_api::quota};
+
+pub const APP_NAME: &str = "yt-watcher";
+pub const CONFIG_FILE: &str = "config.toml";
+pub const LOG_FILE: &str = "output.log";
+
+/// The path of each file, overriding its default place. Relative paths are relative to the data
+/// directory.
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub struct FilePaths {
+    database: Option<PathBuf>,
+    backups: Option<PathBuf>,
+    quota: Option<PathBuf>,
+    log: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataPaths {
+    pub database: PathBuf,
+    pub backups: PathBuf,
+    pub quota: PathBuf,
+    pub log: PathBuf,
+    /// Where the save files of older versions are imported from
+    pub legacy: PathBuf,
+}
+
+impl DataPaths {
+    /// Every file under its default name in `dir`
+    pub fn in_dir(dir: &Path) -> Self {
+        Self {
+            database: dir.join(storage::DATABASE_FILE),
+            backups: dir.join(backup::BACKUP_DIR),
+            quota: dir.join(quota::QUOTA_SAVE_FILE),
+            log: dir.join(LOG_FILE),
+            legacy: dir.to_path_buf(),
+        }
+    }
+
+    /// The paths given by the `--data-dir` argument and the config at `config_path`
+    pub fn resolve(data_dir: Option<&Path>, config: &crate::Config, config_path: &Path) -> Self {
+        let dir = match (data_dir, &config.data_dir) {
+            (Some(dir), _) => dir.to_path_buf(),
+            // relative to the config file, wherever the process is started
+            (None, Some(dir)) => config_path.parent().unwrap_or(Path::new(".")).join(dir),
+            (None, None) => default_data_dir(),
+        };
+        let mut paths = Self::in_dir(&dir);
+        for (path, custom) in [
+            (&mut paths.database, &config.paths.database),
+            (&mut paths.backups, &config.paths.backups),
+            (&mut paths.quota, &config.paths.quota),
+            (&mut paths.log, &config.paths.log),
+        ] {
+            if let Some(custom) = custom {
+                *path = dir.join(custom);
+            }
+        }
+        paths
+    }
+
+    /// Create the directories of every file
+    pub fn create_dirs(&self) -> std::io::Result<()> {
+        for dir in [
+            self.database.parent(),
+            self.quota.parent(),
+            self.log.parent(),
+            Some(self.backups.as_path()),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|d| !d.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// The working directory if it holds the save files of an older version, which didn't have a
+/// data directory. Otherwise the data directory of the platform.
+fn default_data_dir() -> PathBuf {
+    if [storage::DATABASE_FILE, storage::LEGACY_CHANNELS_FILE]
+        .iter()
+        .any(|f| Path::new(f).exists())
+    {
+        return PathBuf::from(".");
+    }
+    match dirs::data_dir() {
+        Some(dir) => dir.join(APP_NAME),
+        None => PathBuf::from("."),
+    }
+}
+
+/// `config.toml` in the working directory if it exists, otherwise in the config directory of the
+/// platform, like `$XDG_CONFIG_HOME/yt-watcher`
+pub fn default_config_path() -> PathBuf {
+    let local = PathBuf::from(CONFIG_FILE);
+    if local.exists() {
+        return local;
+    }
+    match dirs::config_dir() {
+        Some(dir) => dir.join(APP_NAME).join(CONFIG_FILE),
+        None => local,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::CONFIG;
+
+    #[test]
+    fn test_resolve() {
+        let mut config = CONFIG.clone();
+        let config_path = Path::new("/etc/yt-watcher/config.toml");
+        config.data_dir = Some(PathBuf::from("data"));
+        config.paths.database = Some(PathBuf::from("db/watcher.db"));
+        config.paths.log = Some(PathBuf::from("/var/log/yt-watcher.log"));
+
+        let paths = DataPaths::resolve(None, &config, config_path);
+        assert_eq!(
+            paths.database,
+            Path::new("/etc/yt-watcher/data/db/watcher.db")
+        );
+        assert_eq!(paths.backups, Path::new("/etc/yt-watcher/data/backups"));
+        assert_eq!(paths.quota, Path::new("/etc/yt-watcher/data/quota.json"));
+        assert_eq!(paths.log, Path::new("/var/log/yt-watcher.log"));
+        assert_eq!(paths.legacy, Path::new("/etc/yt-watcher/data"));
+
+        // the argument wins over the config
+        let paths = DataPaths::resolve(Some(Path::new("/srv/a")), &config, config_path);
+        assert_eq!(paths.database, Path::new("/srv/a/db/watcher.db"));
+        assert_eq!(paths.quota, Path::new("/srv/a/quota.json"));
+    }
+}
@@ -0,0 +1,176 @@
+//! Reload the config file when it is modified, or on SIGHUP.
+//!
+//! A valid config is sent to the server, which applies it live. An invalid one is logged and the
+//! current config stays in effect.
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::watch;
+
+use crate::{read_config, Config};
+
+#[cfg(not(test))]
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Read the config at `path` again whenever it is modified or SIGHUP is received, and send it to
+/// `updates` if it is valid and changed
+pub async fn watch_config(path: PathBuf, updates: watch::Sender<Config>) {
+    let mut modified = modified_time(&path);
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(s) => Some(s),
+        Err(e) => {
+            log::error!("Listen for SIGHUP failed: {e}");
+            None
+        }
+    };
+    loop {
+        let hangup_received = async {
+            #[cfg(unix)]
+            if let Some(hangup) = &mut hangup {
+                hangup.recv().await;
+                return;
+            }
+            std::future::pending::<()>().await
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {
+                let new_modified = modified_time(&path);
+                if new_modified == modified {
+                    continue;
+                }
+                modified = new_modified;
+                log::info!("Config file {} is modified, reloading", path.display());
+            }
+            _ = hangup_received => log::info!("Received SIGHUP, reloading the config"),
+        }
+        reload(&path, &updates);
+    }
+}
+
+fn reload(path: &Path, updates: &watch::Sender<Config>) {
+    let config = match read_config(path) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("Reload config failed, the current config stays in effect: {e}");
+            return;
+        }
+    };
+    if let Ok(level) = config.log_level_filter() {
+        log::set_max_level(level);
+    }
+    updates.send_if_modified(|current| {
+        if *current == config {
+            log::info!("Config is unchanged");
+            return false;
+        }
+        let restart_required = restart_required(current, &config);
+        if !restart_required.is_empty() {
+            log::warn!(
+                "Changes of {} take effect after a restart",
+                restart_required.join(", ")
+            );
+        }
+        *current = config;
+        true
+    });
+}
+
+/// The changed settings which are only read at startup
+fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut names = vec![];
+    if old.socket != new.socket {
+        names.push("socket");
+    }
+    if old.websub != new.websub {
+        names.push("websub");
+    }
+    if old.twitch_eventsub != new.twitch_eventsub {
+        names.push("twitch_eventsub");
+    }
+    if old.data_dir != new.data_dir || old.paths != new.paths {
+        names.push("data paths");
+    }
+    if old.admin_token != new.admin_token {
+        names.push("admin_token");
+    }
+    if old.webhook_allowed_hosts != new.webhook_allowed_hosts {
+        names.push("webhook_allowed_hosts");
+    }
+    if old.public_url != new.public_url {
+        names.push("public_url");
+    }
+    if old.upstream != new.upstream {
+        names.push("upstream");
+    }
+    if old.backup_count != new.backup_count || old.backup_interval_min != new.backup_interval_min {
+        names.push("backup");
+    }
+    names
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TOKIO_RUNTIME;
+
+    fn config_file(api_key: &str, log_level: &str) -> String {
+        format!(
+            r#"
+            api_key = "{api_key}"
+            socket = "127.0.0.1:0"
+            video_refresh_interval = 10
+            channel_refresh_interval = 1440
+            channel_expire_min = 10080
+            log_level = "{log_level}"
+            use_youtube_api_per_hour = 2
+            "#
+        )
+    }
+
+    #[test]
+    fn test_watch_config() {
+        let dir =
+            std::env::temp_dir().join(format!("yt-watcher-test-reload-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, config_file("old-key", "Info")).unwrap();
+        let (sender, mut receiver) = watch::channel(read_config(&path).unwrap());
+        // make sure every write changes the modified time
+        let write = |content: String, seconds: u64| {
+            std::fs::write(&path, content).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::now() + Duration::from_secs(seconds))
+                .unwrap();
+        };
+        TOKIO_RUNTIME.block_on(async {
+            let watcher = tokio::spawn(watch_config(path.clone(), sender));
+            tokio::time::sleep(POLL_INTERVAL * 3).await;
+
+            write(config_file("invalid-key", "Loud"), 1);
+            tokio::time::sleep(POLL_INTERVAL * 5).await;
+            assert!(!receiver.has_changed().unwrap());
+
+            write(config_file("new-key", "Info"), 2);
+            tokio::time::timeout(Duration::from_secs(5), receiver.changed())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(receiver.borrow().api_keys(), ["new-key"]);
+            watcher.abort();
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -7,18 +7,36 @@ use std::{
 };
 
 use crate::{
-    sync,
+    backup,
+    context::AppContext,
+    sync::WebhookKind,
     tw_api::{structs::*, *},
     yt_api::{structs::*, *},
     TwAppKey,
 };
 use chrono::{DateTime, Timelike, Utc};
-use icalendar::{Alarm, Component, EventLike};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
+use utoipa::ToSchema;
 use warp::{hyper::Response, Filter};
 
-#[derive(Debug, Serialize, Deserialize)]
+use self::subscription::Subscription;
+
+pub(crate) mod admin;
+pub(crate) mod api;
+mod calendar;
+mod feed;
+pub(crate) mod history;
+mod openapi;
+mod subscription;
+mod webhook;
+
+/// How long the open connections, like the event streams, may take to finish on shutdown
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+/// How long after its start a cancelled event is kept in the calendars
+const CANCELLED_EVENT_RETENTION_MIN: i64 = 24 * 60;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ChannelInfoData {
     id: String,
     title: String,
@@ -26,111 +44,265 @@ pub struct ChannelInfoData {
     thumbnail: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The channel found by `/yt-ch` or `/tw-ch`, or why it wasn't
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 #[allow(non_camel_case_types)]
 pub enum YtChannelInfoResponse {
     data(ChannelInfoData),
     error(String),
 }
 
-const CHANNELS_SAVE_FILE: &str = "channels.json";
-const VIDEOS_SAVE_FILE: &str = "videos.txt";
-pub async fn server_start(config: &crate::Config) {
+/// Serve until `shutdown` resolves, then stop the background tasks and save every store. The
+/// configs received from `config_updates` are applied live.
+pub async fn server_start(
+    mut config_updates: tokio::sync::watch::Receiver<crate::Config>,
+    context: Arc<AppContext>,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    let config = config_updates.borrow_and_update().clone();
     let http_socket = match SocketAddr::from_str(&config.socket) {
         Ok(s) => s,
         Err(e) => panic!("Invalid socket: {}", e),
     };
-    let server_data = Arc::new(RwLock::new(
-        ServerData::new(
-            &config.api_key,
-            CHANNELS_SAVE_FILE.to_string(),
-            VIDEOS_SAVE_FILE.to_string(),
-            config.channel_expire_min,
-            &config.twitch_key,
-        )
-        .await,
-    ));
+    let server_data = match ServerData::new(
+        context.clone(),
+        config.channel_expire_min,
+        config.ended_event_retention_min.unwrap_or(0),
+        &config.twitch_key,
+    )
+    .await
+    {
+        Ok(data) => Arc::new(RwLock::new(data)),
+        Err(e) => panic!("{e}"),
+    };
+
+    context.spawn_savers();
+
+    let backup_count = config.backup_count.unwrap_or(backup::DEFAULT_BACKUP_COUNT);
+    if backup_count > 0 {
+        let backup_interval = config
+            .backup_interval_min
+            .unwrap_or(backup::DEFAULT_BACKUP_INTERVAL_MIN);
+        let storage = context.storage.clone();
+        let paths = context.paths.clone();
+        context.spawn("backup", async move {
+            loop {
+                let storage = storage.clone();
+                let paths = paths.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    backup::rotate(&paths.database, &paths.backups, backup_count, |temp| {
+                        storage.backup(temp).map_err(std::io::Error::other)
+                    })?;
+                    if paths.quota.exists() {
+                        backup::rotate(&paths.quota, &paths.backups, backup_count, |temp| {
+                            backup::copy_synced(&paths.quota, temp)
+                        })?;
+                    }
+                    std::io::Result::Ok(())
+                })
+                .await;
+                match result {
+                    Ok(Ok(())) => log::info!("Backed up the save files"),
+                    Ok(Err(e)) => log::error!("Back up the save files failed: {e}"),
+                    Err(e) => log::error!("Back up task failed: {e}"),
+                }
+                tokio::time::sleep(Duration::from_secs(60 * backup_interval.max(1))).await;
+            }
+        });
+    }
 
     {
+        server_data.write().await.video_refresh_interval = config.video_refresh_interval;
         server_data.write().await.restore().await;
         server_data.write().await.check_upcoming_event(false).await;
     }
 
+    let server_data_clone = server_data.clone();
+    let mut refresh_config = config_updates.clone();
+    context.spawn("event refresh", async move {
+        loop {
+            let config = refresh_config.borrow_and_update().clone();
+            let video_refresh_interval = config.video_refresh_interval;
+            let video_refresh_delay = config.video_refresh_delay.unwrap_or(60);
+            let use_youtube_api_per_hour = config.use_youtube_api_per_hour as u64;
+            // the budget of every key by default
+            let adaptive_polling_budget = config.adaptive_polling.as_ref().map(|a| {
+                a.quota_budget.unwrap_or(
+                    config
+                        .youtube_quota_budget
+                        .unwrap_or(quota::DEFAULT_DAILY_BUDGET)
+                        * config.api_keys().len() as u32,
+                )
+            });
+            let delay = if video_refresh_interval > 1 && video_refresh_interval <= 60 {
+                let now = Utc::now();
+                let minutes =
+                    (video_refresh_interval - 1) - now.minute() as u64 % video_refresh_interval;
+                let seconds = 60 - now.second() as u64;
+                Duration::from_secs(
+                    (minutes * 60 + seconds + video_refresh_delay) % (video_refresh_interval * 60),
+                )
+            } else {
+                Duration::from_secs(60 * video_refresh_interval)
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                // re-time the refresh with the new config
+                Ok(()) = refresh_config.changed() => continue,
+            }
+            log::info!("Updating upcoming event");
+            let mut data = server_data_clone.write().await;
+            let now = Utc::now();
+            if let Some(budget) = adaptive_polling_budget {
+                let refreshes_left = quota::until_reset(now)
+                    .num_minutes()
+                    .div_euclid(video_refresh_interval.max(1) as i64)
+                    + 1;
+                data.check_upcoming_event_adaptive(budget, refreshes_left as u32)
+                    .await;
+            } else if use_youtube_api_per_hour != 0
+                && (now.minute() as u64 % (60 / use_youtube_api_per_hour))
+                    + if now.second() == 0 { 0 } else { 1 }
+                    < video_refresh_interval
+            {
+                data.check_upcoming_event(true).await;
+            } else {
+                data.check_upcoming_event(false).await;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+
+    let webhook_policy = webhook::WebhookPolicy::new(config.webhook_allowed_hosts.clone());
+    webhook::spawn_dispatcher(
+        server_data.read().await.subscribe_event_changes(),
+        &context,
+        webhook_policy.clone(),
+    );
+
+    if config.websub.is_some() {
+        server_data.write().await.websub = config.websub.clone();
+        let server_data_clone = server_data.clone();
+        let wakeup = server_data.read().await.websub_wakeup.clone();
+        context.spawn("websub renewal", async move {
+            loop {
+                ServerData::renew_websub_subscriptions(&server_data_clone).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(60 * 10)) => {}
+                    _ = wakeup.notified() => {}
+                }
+            }
+        });
+    }
+
+    if config.twitch_eventsub.is_some() {
+        {
+            let mut data = server_data.write().await;
+            data.tw_eventsub = config.twitch_eventsub.clone();
+            data.restore_tw_eventsub_subscriptions().await;
+        }
+        let server_data_clone = server_data.clone();
+        let wakeup = server_data.read().await.tw_eventsub_wakeup.clone();
+        context.spawn("eventsub sync", async move {
+            loop {
+                ServerData::sync_tw_eventsub_subscriptions(&server_data_clone).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(60 * 10)) => {}
+                    _ = wakeup.notified() => {}
+                }
+            }
+        });
+    }
+
+    let server_data_clone = server_data.clone();
+    let mut refresh_config = config_updates.clone();
+    context.spawn("channel refresh", async move {
+        loop {
+            let channel_refresh_interval =
+                refresh_config.borrow_and_update().channel_refresh_interval;
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(60 * channel_refresh_interval)) => {}
+                Ok(()) = refresh_config.changed() => continue,
+            }
+            log::info!("Updating channel info");
+            let mut data = server_data_clone.write().await;
+            data.update_channel_info().await;
+        }
+    });
+    let server_data_clone = server_data.clone();
+    let mut current = config.clone();
+    context.spawn("config reload", async move {
+        while config_updates.changed().await.is_ok() {
+            let new = config_updates.borrow_and_update().clone();
+            ServerData::apply_config(&server_data_clone, &current, &new).await;
+            current = new;
+        }
+    });
+
+    let tw_eventsub_secret = config.twitch_eventsub.as_ref().map(|c| c.secret.clone());
+    let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+    let (address, server) = warp::serve(routes(
+        server_data.clone(),
+        context.clone(),
+        tw_eventsub_secret,
+        config.admin_token.clone(),
+        webhook_policy,
+        config.public_url.clone(),
+    ))
+    .bind_with_graceful_shutdown(http_socket, async {
+        let _ = stop_receiver.await;
+    });
+    log::info!("Listening on {address}");
+    let server = tokio::spawn(server);
+
+    shutdown.await;
+    log::info!("Shutting down, no longer accepting requests");
+    let _ = stop_sender.send(());
+    if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, server)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "Connections still open after {} seconds are dropped",
+            SHUTDOWN_GRACE_PERIOD.as_secs()
+        );
+    }
+    context.shutdown().await;
+    server_data.read().await.save().await;
+}
+
+/// The http endpoints of the server
+pub fn routes(
+    server_data: Arc<RwLock<ServerData>>,
+    context: Arc<AppContext>,
+    tw_eventsub_secret: Option<String>,
+    admin_token: Option<String>,
+    webhook_policy: webhook::WebhookPolicy,
+    public_url: Option<String>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_v1_endpoints =
+        api::routes(server_data.clone(), context.clone(), webhook_policy.clone());
+    let subscription = subscription::subscription(server_data.clone(), context.clone());
+    let with_context = warp::any().map(move || context.clone());
+
     let server_data_clone = server_data.clone();
     let get_yt_channel_info = warp::get()
         .and(warp::path("yt-ch"))
         .and(warp::query::<HashMap<String, String>>())
-        .then(move |query: HashMap<String, String>| {
-            let server_data_clone2: Arc<RwLock<ServerData>> = server_data_clone.clone();
-            async move {
-                let response_data = match query.get("q") {
-                    None => YtChannelInfoResponse::error(
-                        "Please provide channel name with \"q\" get parameter".to_string(),
-                    ),
-                    Some(url) => {
-                        if !validate_custom_url(
-                            url.trim_start_matches("https://www.youtube.com/@")
-                                .trim_start_matches("https://www.youtube.com/channel/")
-                                .trim_start_matches("https://youtube.com/@")
-                                .trim_start_matches("https://youtube.com/channel/")
-                                .trim_start_matches('@')
-                                .trim_end_matches("/featured"),
-                        ) {
-                            return serde_json::to_string(&YtChannelInfoResponse::error(
-                                "The query string does not like a youtube user".to_string(),
-                            ))
-                            .unwrap();
-                        }
-                        match get_channel_id_by_url(&format!(
-                            "https://www.youtube.com/channel/{}",
-                            try_youtube_id(url).await
-                        ))
-                        .await
-                        {
-                            Ok(id) => {
-                                if !server_data_clone2
-                                    .read()
-                                    .await
-                                    .yt_channels
-                                    .contains_key(&id)
-                                {
-                                    if let Err(e) = {
-                                        server_data_clone2
-                                            .write()
-                                            .await
-                                            .track_new_yt_channels(&[&id])
-                                            .await
-                                    } {
-                                        log::error!("Track new youtube channel failed: {:?}", e);
-                                        return serde_json::to_string(
-                                            &YtChannelInfoResponse::error(format!(
-                                                "Track new youtube channel failed: {:?}",
-                                                e
-                                            )),
-                                        )
-                                        .unwrap();
-                                    }
-                                }
-                                let mut server_data = server_data_clone2.write().await;
-                                server_data.touch_yt_channel(&id);
-                                let channel_save = server_data.yt_channels.get(&id).unwrap();
-                                YtChannelInfoResponse::data(ChannelInfoData {
-                                    id,
-                                    title: channel_save.title.clone(),
-                                    custom_url: channel_save.custom_url.clone(),
-                                    thumbnail: channel_save.thumbnail.clone(),
-                                })
-                            }
-                            Err(e) => YtChannelInfoResponse::error(format!(
-                                "Failed to get channel id: {:?}",
-                                e
-                            )),
-                        }
-                    }
-                };
-                serde_json::to_string(&response_data).unwrap()
-            }
-        });
+        .and(with_context.clone())
+        .then(
+            move |query: HashMap<String, String>, context: Arc<AppContext>| {
+                let server_data_clone2: Arc<RwLock<ServerData>> = server_data_clone.clone();
+                async move {
+                    let response_data =
+                        match api::yt_channel_info(&server_data_clone2, &context, &query).await {
+                            Ok(data) => YtChannelInfoResponse::data(data),
+                            Err(e) => YtChannelInfoResponse::error(e.message),
+                        };
+                    serde_json::to_string(&response_data).unwrap()
+                }
+            },
+        );
 
     let server_data_clone = server_data.clone();
     let get_tw_channel_info = warp::get()
@@ -139,156 +311,22 @@ pub async fn server_start(config: &crate::Config) {
         .then(move |query: HashMap<String, String>| {
             let server_data_clone2: Arc<RwLock<ServerData>> = server_data_clone.clone();
             async move {
-                let query_string = match query.get("q") {
-                    Some(s) => s,
-                    None => {
-                        return serde_json::to_string(&YtChannelInfoResponse::error(
-                            "Please provide channel name with \"q\" get parameter".to_string(),
-                        ))
-                        .unwrap();
-                    }
+                let response_data = match api::tw_channel_info(&server_data_clone2, &query).await {
+                    Ok(data) => YtChannelInfoResponse::data(data),
+                    Err(e) => YtChannelInfoResponse::error(e.message),
                 };
-                let query_string = query_string
-                    .trim_start_matches("https://www.twitch.tv/")
-                    .to_string();
-                if !validate_user_login(&query_string) {
-                    return serde_json::to_string(&YtChannelInfoResponse::error(
-                        "The query string does not like a twitter user login".to_string(),
-                    ))
-                    .unwrap();
-                }
-                let search_result =
-                    if let Some(client) = &mut server_data_clone2.write().await.tw_client {
-                        match client
-                            .get_user_info(&[UserIdentity::Login(query_string)])
-                            .await
-                        {
-                            Ok(r) => r,
-                            Err(e) => {
-                                log::error!("Search channel failed: {e}");
-                                return serde_json::to_string(&YtChannelInfoResponse::error(
-                                    format!("Search channel failed: {e}",),
-                                ))
-                                .unwrap();
-                            }
-                        }
-                    } else {
-                        log::error!("Twitch client is not initialized");
-                        return serde_json::to_string(&YtChannelInfoResponse::error(
-                            "Twitch client is not initialized".to_string(),
-                        ))
-                        .unwrap();
-                    };
-                if let Some(c) = search_result.first() {
-                    let mut server_data = server_data_clone2.write().await;
-                    if !server_data.tw_channels.contains_key(&c.login) {
-                        server_data.tw_channels.insert(
-                            c.login.clone(),
-                            TwChannelSave {
-                                id: c.id.clone(),
-                                login: c.login.clone(),
-                                profile_img: c.profile_image_url.clone(),
-                                name: c.display_name.clone(),
-                                last_time_used: Utc::now(),
-                            },
-                        );
-                        server_data.check_tw_upcoming_event(None).await;
-                        server_data.save().await;
-                    }
-                    server_data.touch_tw_channel(&c.login);
-                    let channel_save = server_data.tw_channels.get(&c.login).unwrap();
-                    serde_json::to_string(&YtChannelInfoResponse::data(ChannelInfoData {
-                        id: channel_save.id.clone(),
-                        custom_url: channel_save.login.clone(),
-                        title: channel_save.name.clone(),
-                        thumbnail: channel_save.profile_img.clone(),
-                    }))
-                    .unwrap()
-                } else {
-                    log::error!("Search channel failed: Not found");
-                    serde_json::to_string(&YtChannelInfoResponse::error(
-                        "Search channel failed: Not found".to_string(),
-                    ))
-                    .unwrap()
-                }
+                serde_json::to_string(&response_data).unwrap()
             }
         });
 
     let server_data_clone = server_data.clone();
     let get_data_endpoint = warp::get()
         .and(warp::path("data"))
-        .and(warp::query::<HashMap<String, String>>())
-        .then(move |query: HashMap<String, String>| {
+        .and(subscription.clone())
+        .then(move |subscription: Subscription| {
             let server_data_clone2 = server_data_clone.clone();
             async move {
-                let mut response: Vec<UpcomingEvent> = vec![];
-                let mut yt_channel_ids: Vec<String> = vec![];
-                let mut tw_channel_logins: Vec<String> = vec![];
-                if let Some(query_str) = query.get("yt-ch") {
-                    for id in query_str.split(',') {
-                        yt_channel_ids.push(try_youtube_id(id).await);
-                    }
-                }
-                if let Some(query_str) = query.get("tw-ch") {
-                    tw_channel_logins.extend(query_str.split(',').map(|s| s.to_string()));
-                }
-                if let Some(sync_key) = query.get("key") {
-                    let key = uuid::Uuid::from_str(sync_key).unwrap_or_default();
-                    if let Some(ch) = sync::get_yt_channel(&key).await {
-                        for id in ch.iter() {
-                            yt_channel_ids.push(try_youtube_id(id).await);
-                        }
-                    }
-
-                    if let Some(ch) = sync::get_tw_channel(&key).await {
-                        tw_channel_logins.extend(ch.iter().cloned());
-                    }
-                }
-                let new_yt_channel_ids = {
-                    server_data_clone2
-                        .read()
-                        .await
-                        .filter_new_yt_channel_id(&yt_channel_ids)
-                };
-                if !new_yt_channel_ids.is_empty() {
-                    if let Err(e) = server_data_clone2
-                        .write()
-                        .await
-                        .track_new_yt_channels(&new_yt_channel_ids)
-                        .await
-                    {
-                        log::error!("Track new youtube channel failed: {:?}", e);
-                    };
-                }
-
-                let new_tw_channel_logins = {
-                    server_data_clone2
-                        .read()
-                        .await
-                        .filter_new_tw_channel_login(&tw_channel_logins)
-                };
-                if !new_tw_channel_logins.is_empty() {
-                    server_data_clone2
-                        .write()
-                        .await
-                        .track_new_tw_channels(&new_tw_channel_logins)
-                        .await;
-                }
-                let events = {
-                    let mut server_data = server_data_clone2.write().await;
-                    for id in yt_channel_ids.iter() {
-                        server_data.touch_yt_channel(id);
-                    }
-                    for login in tw_channel_logins.iter() {
-                        server_data.touch_tw_channel(login);
-                    }
-                    server_data.events.clone()
-                };
-                response.extend(events.into_iter().filter(|e| match &e.source {
-                    EventSource::YoutubeChannel(c) => yt_channel_ids.contains(&c.id),
-                    EventSource::TwitchChannel(c) => tw_channel_logins.contains(&c.login),
-                }));
-                response.sort();
+                let response = subscription.events(&server_data_clone2).await;
                 serde_json::to_string(&response).unwrap()
             }
         });
@@ -297,161 +335,272 @@ pub async fn server_start(config: &crate::Config) {
     let get_calendar_endpoint = warp::get()
         .and(warp::path("cal"))
         .and(warp::query::<HashMap<String, String>>())
-        .then(move |query: HashMap<String, String>| {
-            let server_data_clone2 = server_data_clone.clone();
-            async move {
-                let mut cal = icalendar::Calendar::new();
-                let alarm_enabled = match query.get("alarm") {
-                    Some(v) => v.to_lowercase() == "true" || v.to_lowercase() == "yes",
-                    None => false,
-                };
-                cal.name("Stream Calendar");
-                let mut yt_channel_ids: Vec<String> = vec![];
-                let mut tw_channel_logins = vec![];
-                if let Some(query_str) = query.get("yt-ch") {
-                    for id in query_str.split(',') {
-                        yt_channel_ids.push(try_youtube_id(id).await);
+        .and(with_context.clone())
+        .then(
+            move |query: HashMap<String, String>, context: Arc<AppContext>| {
+                let server_data_clone2 = server_data_clone.clone();
+                async move {
+                    // checked before the channels are tracked, a rejected request tracks nothing
+                    if let Err(e) = subscription::timezone(&query) {
+                        return Response::builder().status(400).body(e);
                     }
+                    let subscription =
+                        Subscription::track(&server_data_clone2, &context, &query).await;
+                    // the calendar keeps the ended streams
+                    let subscription = Subscription {
+                        include_ended: true,
+                        ..subscription
+                    };
+                    let events = subscription.events(&server_data_clone2).await;
+                    let cancelled = subscription.cancelled_events(&server_data_clone2).await;
+                    let refresh_interval = server_data_clone2.read().await.video_refresh_interval;
+                    Response::builder()
+                        .header("Content-Type", "text/calendar")
+                        .body(calendar::calendar(
+                            &events,
+                            &cancelled,
+                            &subscription,
+                            refresh_interval,
+                        ))
                 }
-                if let Some(query_str) = query.get("tw-ch") {
-                    tw_channel_logins.extend(query_str.split(',').map(|s| s.to_string()));
-                }
-                if let Some(sync_key) = query.get("key") {
-                    let key = uuid::Uuid::from_str(sync_key).unwrap_or_default();
-                    if let Some(ch) = sync::get_yt_channel(&key).await {
-                        for id in ch.iter() {
-                            yt_channel_ids.push(try_youtube_id(id).await);
-                        }
-                    }
+            },
+        );
 
-                    if let Some(ch) = sync::get_tw_channel(&key).await {
-                        tw_channel_logins.extend(ch.iter().cloned());
-                    }
-                }
-                let new_yt_channel_ids = {
-                    server_data_clone2
-                        .read()
-                        .await
-                        .filter_new_yt_channel_id(&yt_channel_ids)
-                };
-                if !new_yt_channel_ids.is_empty() {
-                    if let Err(e) = server_data_clone2
-                        .write()
-                        .await
-                        .track_new_yt_channels(&new_yt_channel_ids)
-                        .await
-                    {
-                        log::error!("Track new youtube channel failed: {:?}", e);
+    let server_data_clone = server_data.clone();
+    let feed_endpoint = warp::get()
+        .and(
+            warp::path!("feed.xml")
+                .map(|| false)
+                .or(warp::path!("feed.rss").map(|| true))
+                .unify(),
+        )
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("host"))
+        .and(subscription.clone())
+        .then(
+            move |rss: bool,
+                  path: warp::path::FullPath,
+                  query: String,
+                  host: Option<String>,
+                  subscription: Subscription| {
+                let server_data_clone2 = server_data_clone.clone();
+                let public_url = public_url.clone();
+                async move {
+                    let events = subscription.events(&server_data_clone2).await;
+                    let path = match query.is_empty() {
+                        true => path.as_str().to_string(),
+                        false => format!("{}?{query}", path.as_str()),
                     };
+                    let link = feed::link(public_url.as_deref(), host.as_deref(), &path);
+                    if rss {
+                        Response::builder()
+                            .header("Content-Type", "application/rss+xml")
+                            .body(feed::rss(&events, &link))
+                    } else {
+                        Response::builder()
+                            .header("Content-Type", "application/atom+xml")
+                            .body(feed::atom(&events, &link))
+                    }
                 }
+            },
+        );
 
-                let new_tw_channel_logins = {
-                    server_data_clone2
-                        .read()
-                        .await
-                        .filter_new_tw_channel_login(&tw_channel_logins)
-                };
-                if !new_tw_channel_logins.is_empty() {
-                    server_data_clone2
-                        .write()
-                        .await
-                        .track_new_tw_channels(&new_tw_channel_logins)
-                        .await;
-                }
-                let events = {
-                    let mut server_data = server_data_clone2.write().await;
-                    for id in yt_channel_ids.iter() {
-                        server_data.touch_yt_channel(id);
-                    }
-                    for login in tw_channel_logins.iter() {
-                        server_data.touch_tw_channel(login);
+    let server_data_clone = server_data.clone();
+    let history_endpoint = warp::get()
+        .and(warp::path("history"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_context.clone())
+        .then(
+            move |query: HashMap<String, String>, context: Arc<AppContext>| {
+                let server_data_clone2 = server_data_clone.clone();
+                async move {
+                    match api::history_page(&server_data_clone2, &context, &query).await {
+                        Ok((csv, page)) => api::history_reply(csv, page),
+                        Err(e) => Response::builder().status(400).body(
+                            serde_json::to_string(&api::LegacyError { error: e.message })
+                                .unwrap_or_default(),
+                        ),
                     }
-                    server_data.events.clone()
-                };
-                cal.extend(
-                    events
-                        .into_iter()
-                        .filter(|e: &UpcomingEvent| match &e.source {
-                            EventSource::YoutubeChannel(c) => yt_channel_ids.contains(&c.id),
-                            EventSource::TwitchChannel(c) => tw_channel_logins.contains(&c.login),
-                        })
-                        .map(|e: UpcomingEvent| e.to_ical_event(alarm_enabled)),
-                );
-                Response::builder()
-                    .header("Content-Type", "text/calendar")
-                    .body(cal.done().to_string())
-            }
-        });
+                }
+            },
+        );
 
     let sync_key_endpoint = warp::get().and(warp::path("sync")).and(
         warp::path("new")
-            .then(|| async {
-                serde_json::to_string(&HashMap::from([("key", sync::new_key().await)]))
-                    .unwrap_or_default()
+            .and(with_context.clone())
+            .then(|context: Arc<AppContext>| async move {
+                serde_json::to_string(&api::NewSyncKey {
+                    key: context.sync_store.new_key().await,
+                })
+                .unwrap_or_default()
             })
             .or(warp::path("push")
                 .and(warp::query::<HashMap<String, String>>())
-                .then(|query: HashMap<String, String>| async move {
-                    if let Some(key) = query.get("key") {
-                        let key = uuid::Uuid::from_str(key).unwrap_or_default();
-                        if let Some(yt_ch) = query.get("yt-ch") {
-                            if sync::set_yt_channels(
-                                &key,
-                                yt_ch.split(',').filter(|s| !s.is_empty()),
-                            )
-                            .await
-                            .is_err()
-                            {
-                                return serde_json::to_string(&HashMap::from([(
-                                    "result".to_string(),
-                                    "failed",
-                                )]))
-                                .unwrap_or_default();
+                .and(with_context.clone())
+                .then(
+                    |query: HashMap<String, String>, context: Arc<AppContext>| async move {
+                        if let Some(key) = query.get("key") {
+                            let key = uuid::Uuid::from_str(key).unwrap_or_default();
+                            if let Some(yt_ch) = query.get("yt-ch") {
+                                if context
+                                    .sync_store
+                                    .set_yt_channels(
+                                        &key,
+                                        yt_ch.split(',').filter(|s| !s.is_empty()),
+                                    )
+                                    .await
+                                    .is_err()
+                                {
+                                    return serde_json::to_string(&api::ChangeResult::new(
+                                        "failed",
+                                    ))
+                                    .unwrap_or_default();
+                                }
                             }
-                        }
-                        if let Some(tw_ch) = query.get("tw-ch") {
-                            if sync::set_tw_channels(
-                                &key,
-                                tw_ch.split(',').filter(|s| !s.is_empty()),
-                            )
-                            .await
-                            .is_err()
-                            {
-                                return serde_json::to_string(&HashMap::from([(
-                                    "result".to_string(),
-                                    "failed",
-                                )]))
-                                .unwrap_or_default();
+                            if let Some(tw_ch) = query.get("tw-ch") {
+                                if context
+                                    .sync_store
+                                    .set_tw_channels(
+                                        &key,
+                                        tw_ch.split(',').filter(|s| !s.is_empty()),
+                                    )
+                                    .await
+                                    .is_err()
+                                {
+                                    return serde_json::to_string(&api::ChangeResult::new(
+                                        "failed",
+                                    ))
+                                    .unwrap_or_default();
+                                }
                             }
-                        }
-                        serde_json::to_string(&HashMap::from([("result".to_string(), "Ok")]))
+                            serde_json::to_string(&api::ChangeResult::new("Ok")).unwrap_or_default()
+                        } else {
+                            serde_json::to_string(&api::ChangeResult::new(
+                                "error: No key specified",
+                            ))
                             .unwrap_or_default()
-                    } else {
-                        serde_json::to_string(&HashMap::from([(
-                            "result",
-                            "error: No key specified",
-                        )]))
-                        .unwrap_or_default()
-                    }
-                }))
+                        }
+                    },
+                ))
+            .or(warp::path("webhook").and(
+                warp::path("add")
+                    .and(warp::query::<HashMap<String, String>>())
+                    .and(with_context.clone())
+                    .then(
+                        move |query: HashMap<String, String>, context: Arc<AppContext>| {
+                            let webhook_policy = webhook_policy.clone();
+                            async move {
+                                let (Some(key), Some(url)) = (query.get("key"), query.get("url"))
+                                else {
+                                    return serde_json::to_string(&api::ChangeResult::new(
+                                        "error: No key or url specified",
+                                    ))
+                                    .unwrap_or_default();
+                                };
+                                if let Err(e) = webhook_policy.check_url(url).await {
+                                    return serde_json::to_string(&api::ChangeResult::new(
+                                        &format!("error: {e}"),
+                                    ))
+                                    .unwrap_or_default();
+                                }
+                                let Ok(kind) = query
+                                    .get("kind")
+                                    .map(|k| k.as_str())
+                                    .unwrap_or("json")
+                                    .parse::<WebhookKind>()
+                                else {
+                                    return serde_json::to_string(&api::ChangeResult::new(
+                                        "error: Invalid kind",
+                                    ))
+                                    .unwrap_or_default();
+                                };
+                                let key = uuid::Uuid::from_str(key).unwrap_or_default();
+                                match context.sync_store.add_webhook(&key, url, kind).await {
+                                    Ok(id) => serde_json::to_string(&api::ChangeResult {
+                                        id: Some(id),
+                                        ..api::ChangeResult::new("Ok")
+                                    })
+                                    .unwrap_or_default(),
+                                    Err(()) => {
+                                        serde_json::to_string(&api::ChangeResult::new("failed"))
+                                            .unwrap_or_default()
+                                    }
+                                }
+                            }
+                        },
+                    )
+                    .or(warp::path("remove")
+                        .and(warp::query::<HashMap<String, String>>())
+                        .and(with_context.clone())
+                        .then(
+                            |query: HashMap<String, String>, context: Arc<AppContext>| async move {
+                                let (Some(key), Some(id)) = (query.get("key"), query.get("id"))
+                                else {
+                                    return serde_json::to_string(&api::ChangeResult::new(
+                                        "error: No key or id specified",
+                                    ))
+                                    .unwrap_or_default();
+                                };
+                                let key = uuid::Uuid::from_str(key).unwrap_or_default();
+                                let id = uuid::Uuid::from_str(id).unwrap_or_default();
+                                let result =
+                                    match context.sync_store.remove_webhook(&key, &id).await {
+                                        Ok(()) => "Ok",
+                                        Err(()) => "failed",
+                                    };
+                                serde_json::to_string(&api::ChangeResult::new(result))
+                                    .unwrap_or_default()
+                            },
+                        ))
+                    .or(warp::path("list")
+                        .and(warp::query::<HashMap<String, String>>())
+                        .and(with_context.clone())
+                        .then(
+                            |query: HashMap<String, String>, context: Arc<AppContext>| async move {
+                                let key = query
+                                    .get("key")
+                                    .map(|k| uuid::Uuid::from_str(k).unwrap_or_default());
+                                match key {
+                                    Some(key) => {
+                                        match context.sync_store.get_webhooks(&key).await {
+                                            Some(webhooks) => {
+                                                serde_json::to_string(&webhooks).unwrap_or_default()
+                                            }
+                                            None => serde_json::to_string(&api::LegacyError {
+                                                error: "Key not found".to_string(),
+                                            })
+                                            .unwrap_or_default(),
+                                        }
+                                    }
+                                    None => serde_json::to_string(&api::LegacyError {
+                                        error: "No key specified".to_string(),
+                                    })
+                                    .unwrap_or_default(),
+                                }
+                            },
+                        )),
+            ))
             .or(warp::path("pull")
                 .and(warp::query::<HashMap<String, String>>())
-                .then(|query: HashMap<String, String>| async move {
-                    if let Some(key) = query.get("key") {
-                        let mut response: HashMap<&str, HashSet<String>> = HashMap::new();
-                        let key = uuid::Uuid::from_str(key).unwrap_or_default();
-                        if let Some(yt_ch) = sync::get_yt_channel(&key).await {
-                            response.insert("yt_ch", yt_ch);
-                        }
-                        if let Some(tw_ch) = sync::get_tw_channel(&key).await {
-                            response.insert("tw_ch", tw_ch);
-                        }
-                        serde_json::to_string(&response).unwrap_or_default()
-                    } else {
-                        serde_json::to_string(&HashMap::from([("error", "No key specified")]))
+                .and(with_context.clone())
+                .then(
+                    |query: HashMap<String, String>, context: Arc<AppContext>| async move {
+                        if let Some(key) = query.get("key") {
+                            let key = uuid::Uuid::from_str(key).unwrap_or_default();
+                            serde_json::to_string(&api::SyncChannels {
+                                yt_ch: context.sync_store.get_yt_channel(&key).await,
+                                tw_ch: context.sync_store.get_tw_channel(&key).await,
+                            })
                             .unwrap_or_default()
-                    }
-                })),
+                        } else {
+                            serde_json::to_string(&api::LegacyError {
+                                error: "No key specified".to_string(),
+                            })
+                            .unwrap_or_default()
+                        }
+                    },
+                )),
     );
 
     let server_data_clone = server_data.clone();
@@ -469,96 +618,288 @@ pub async fn server_start(config: &crate::Config) {
                             .yt_videos
                             .push_checked(id.to_string());
                     }
-                    serde_json::to_string(&HashMap::from([("result", "Ok")])).unwrap_or_default()
+                    serde_json::to_string(&api::ChangeResult::new("Ok")).unwrap_or_default()
                 } else {
-                    serde_json::to_string(&HashMap::from([(
-                        "result",
-                        "no 'id' parameter is provided",
-                    )]))
-                    .unwrap_or_default()
+                    serde_json::to_string(&api::ChangeResult::new("no 'id' parameter is provided"))
+                        .unwrap_or_default()
                 }
             }
         });
 
     let server_data_clone = server_data.clone();
-    let video_refresh_interval = config.video_refresh_interval;
-    let video_refresh_delay = config.video_refresh_delay.unwrap_or(60);
-    let use_youtube_api_per_hour = config.use_youtube_api_per_hour as u64;
-    let _handle = tokio::spawn(async move {
-        loop {
-            if video_refresh_interval > 1 && video_refresh_interval <= 60 {
-                let now = Utc::now();
-                let minutes =
-                    (video_refresh_interval - 1) - now.minute() as u64 % video_refresh_interval;
-                let seconds = 60 - now.second() as u64;
-                tokio::time::sleep(Duration::from_secs(
-                    (minutes * 60 + seconds + video_refresh_delay) % (video_refresh_interval * 60),
-                ))
-                .await;
-            } else {
-                tokio::time::sleep(Duration::from_secs(60 * video_refresh_interval)).await;
+    let websub_verify = warp::get()
+        .and(warp::query::<HashMap<String, String>>())
+        .then(move |query: HashMap<String, String>| {
+            let server_data_clone2 = server_data_clone.clone();
+            async move {
+                let confirmed = match (
+                    query.get("hub.mode").and_then(|m| websub::Mode::parse(m)),
+                    query.get("hub.topic"),
+                    query.get("hub.challenge"),
+                ) {
+                    (Some(mode), Some(topic), Some(challenge)) => server_data_clone2
+                        .write()
+                        .await
+                        .verify_websub(
+                            mode,
+                            topic,
+                            query.get("hub.lease_seconds").and_then(|s| s.parse().ok()),
+                        )
+                        .then(|| challenge.clone()),
+                    _ => None,
+                };
+                match confirmed {
+                    Some(challenge) => Response::builder().status(200).body(challenge),
+                    None => Response::builder().status(404).body(String::new()),
+                }
             }
-            log::info!("Updating upcoming event");
-            let mut data = server_data_clone.write().await;
-            let now = Utc::now();
-            if use_youtube_api_per_hour != 0
-                && (now.minute() as u64 % (60 / use_youtube_api_per_hour))
-                    + if now.second() == 0 { 0 } else { 1 }
-                    < video_refresh_interval
-            {
-                data.check_upcoming_event(true).await;
-            } else {
-                data.check_upcoming_event(false).await;
+        });
+    let server_data_clone = server_data.clone();
+    let websub_notify = warp::post()
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>(websub::SIGNATURE_HEADER))
+        .and(warp::body::content_length_limit(
+            websub::MAX_NOTIFICATION_BYTES,
+        ))
+        .and(warp::body::bytes())
+        .then(
+            move |query: HashMap<String, String>,
+                  signature: Option<String>,
+                  body: warp::hyper::body::Bytes| {
+                let server_data_clone2 = server_data_clone.clone();
+                async move {
+                    let accepted = match query.get("channel_id") {
+                        Some(channel_id) => server_data_clone2
+                            .write()
+                            .await
+                            .handle_websub_notification(channel_id, signature.as_deref(), &body),
+                        None => false,
+                    };
+                    let status = if accepted { 204 } else { 403 };
+                    Response::builder().status(status).body(String::new())
+                }
+            },
+        );
+    let websub_endpoint = warp::path("websub").and(
+        websub_verify
+            .map(|r| Box::new(r) as Box<dyn warp::Reply>)
+            .or(websub_notify.map(|r| Box::new(r) as Box<dyn warp::Reply>))
+            .unify(),
+    );
+
+    let server_data_clone = server_data.clone();
+    let tw_eventsub_endpoint = warp::post()
+        .and(warp::path("tw-eventsub"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(
+            eventsub::MAX_MESSAGE_BYTES,
+        ))
+        .and(warp::body::bytes())
+        .then(
+            move |headers: warp::http::HeaderMap, body: warp::hyper::body::Bytes| {
+                let server_data_clone2 = server_data_clone.clone();
+                let secret = tw_eventsub_secret.clone();
+                async move {
+                    let Some(secret) = secret else {
+                        return Response::builder().status(404).body(String::new());
+                    };
+                    match eventsub::parse_message(
+                        &secret,
+                        |name| headers.get(name).and_then(|v| v.to_str().ok()),
+                        &body,
+                        Utc::now(),
+                    ) {
+                        Ok(eventsub::Message::Verification(challenge)) => Response::builder()
+                            .status(200)
+                            .header("Content-Type", "text/plain")
+                            .body(challenge),
+                        Ok(eventsub::Message::Notification(event)) => {
+                            ServerData::handle_tw_eventsub_event(&server_data_clone2, event).await;
+                            Response::builder().status(204).body(String::new())
+                        }
+                        Ok(eventsub::Message::Revocation(subscription)) => {
+                            server_data_clone2
+                                .write()
+                                .await
+                                .revoke_tw_eventsub(&subscription);
+                            Response::builder().status(204).body(String::new())
+                        }
+                        Err(e) => {
+                            log::warn!("Rejected EventSub message: {e:?}");
+                            let status = match e {
+                                eventsub::EventSubError::DeserializeFailed(_)
+                                | eventsub::EventSubError::UnknownMessageType(_) => 400,
+                                _ => 403,
+                            };
+                            Response::builder().status(status).body(String::new())
+                        }
+                    }
+                }
+            },
+        );
+
+    let server_data_clone = server_data.clone();
+    let event_stream_endpoint = warp::get()
+        .and(warp::path!("events" / "stream"))
+        .and(subscription.clone())
+        .then(move |subscription: Subscription| {
+            let server_data_clone2 = server_data_clone.clone();
+            async move {
+                let receiver = server_data_clone2.read().await.subscribe_event_changes();
+                let stream = futures::stream::unfold(
+                    (receiver, subscription),
+                    |(mut receiver, subscription)| async move {
+                        loop {
+                            match receiver.recv().await {
+                                Ok(change) if subscription.includes(change.event()) => {
+                                    let sse_event = warp::sse::Event::default()
+                                        .event(change.name())
+                                        .json_data(&change);
+                                    return Some((sse_event, (receiver, subscription)));
+                                }
+                                Ok(_) => {}
+                                Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                                    log::warn!("Event stream client lagged, {n} changes dropped")
+                                }
+                                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                                    return None
+                                }
+                            }
+                        }
+                    },
+                );
+                warp::sse::reply(warp::sse::keep_alive().stream(stream))
             }
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
-    });
+        });
 
     let server_data_clone = server_data.clone();
-    let channel_refresh_interval = config.channel_refresh_interval;
-    let _handle = tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(Duration::from_secs(60 * channel_refresh_interval)).await;
-            log::info!("Updating channel info");
-            let mut data = server_data_clone.write().await;
-            data.update_channel_info().await;
-        }
+    let admin_enabled = admin_token.is_some();
+    // the body is only read once the token is checked
+    let admin_authorized = warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let admin_token = admin_token.clone();
+            async move {
+                match admin_token {
+                    Some(token) if admin::is_authorized(authorization.as_deref(), &token) => Ok(()),
+                    _ => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .untuple_one();
+    let admin_command = admin_authorized
+        .and(warp::body::bytes())
+        .and(with_context.clone())
+        .then(
+            move |body: warp::hyper::body::Bytes, context: Arc<AppContext>| {
+                let server_data_clone2 = server_data_clone.clone();
+                async move {
+                    let result = match serde_json::from_slice::<admin::AdminCommand>(&body) {
+                        Ok(command) => admin::execute(&server_data_clone2, &context, command).await,
+                        Err(e) => Err(format!("Invalid command: {e}")),
+                    };
+                    match result {
+                        Ok(data) => Response::builder()
+                            .header("Content-Type", "application/json")
+                            .body(
+                                serde_json::to_string(&HashMap::from([("data", data)]))
+                                    .unwrap_or_default(),
+                            ),
+                        Err(e) => Response::builder()
+                            .status(400)
+                            .header("Content-Type", "application/json")
+                            .body(
+                                serde_json::to_string(&HashMap::from([("error", e)]))
+                                    .unwrap_or_default(),
+                            ),
+                    }
+                }
+            },
+        );
+    let admin_endpoint = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::body::content_length_limit(admin::MAX_COMMAND_BYTES))
+        .and(
+            admin_command
+                .or(warp::any().map(move || {
+                    let status = if admin_enabled { 401 } else { 404 };
+                    Response::builder().status(status).body(String::new())
+                }))
+                .unify(),
+        );
+
+    let openapi_endpoint = warp::path!("api" / "openapi.json").map(|| {
+        Response::builder()
+            .header("Content-Type", "application/json")
+            .body(openapi::document_json())
     });
-    warp::serve(
-        warp::get().and(
-            get_yt_channel_info
-                .or(get_data_endpoint)
-                .or(get_calendar_endpoint)
+
+    let quota_endpoint = warp::get()
+        .and(warp::path("quota"))
+        .and(with_context.clone())
+        .map(|context: Arc<AppContext>| {
+            serde_json::to_string(&context.quota.status()).unwrap_or_default()
+        });
+
+    // the endpoints which can't fail are the same in the versioned api
+    let infallible_endpoints = get_data_endpoint
+        .or(get_calendar_endpoint)
+        .or(feed_endpoint)
+        .or(event_stream_endpoint)
+        .or(quota_endpoint);
+    let api_v1 = warp::path!("api" / "v1" / ..).and(
+        api_v1_endpoints
+            .or(infallible_endpoints.clone())
+            .or(warp::any().map(api::unknown_endpoint)),
+    );
+
+    warp::get()
+        .and(
+            api_v1
+                .or(openapi_endpoint)
+                .or(get_yt_channel_info)
                 .or(get_tw_channel_info)
                 .or(notice_yt_video_endpoint)
-                .or(sync_key_endpoint),
-        ),
-    )
-    .run(http_socket)
-    .await;
+                .or(sync_key_endpoint)
+                .or(history_endpoint)
+                .or(infallible_endpoints),
+        )
+        .or(websub_endpoint)
+        .or(tw_eventsub_endpoint)
+        .or(admin_endpoint)
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct ChannelSave {
-    yt: HashMap<String, YtChannelSave>,
-    tw: HashMap<String, TwChannelSave>,
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ChannelSave {
+    pub(crate) yt: HashMap<String, YtChannelSave>,
+    pub(crate) tw: HashMap<String, TwChannelSave>,
+}
+
+/// The events of the calendars, saved so their sequence and last modified time continue after
+/// a restart
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CalendarSave {
+    pub(crate) events: Vec<UpcomingEvent>,
+    pub(crate) cancelled: Vec<UpcomingEvent>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-struct YtChannelSave {
+pub(crate) struct YtChannelSave {
     custom_url: String,
-    id: String,
+    pub(crate) id: String,
     title: String,
     thumbnail: String,
     upload_playlist: String,
     last_time_used: DateTime<Utc>,
     first_video_after_all_stream: String,
+    /// How many streams of the channel went live at each hour of the day (in utc)
+    #[serde(default)]
+    live_hours: [u32; 24],
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-struct TwChannelSave {
+pub(crate) struct TwChannelSave {
     id: String,
-    login: String,
+    pub(crate) login: String,
     profile_img: String,
     name: String,
     last_time_used: DateTime<Utc>,
@@ -609,34 +950,9 @@ impl YtVideosSave {
     //fn remove(&mut self, value: &String) {
     //    self.ids.remove(value);
     //}
-
-    fn extend_from_str(&mut self, value: &str) {
-        let mut new_value = value
-            .split('\n')
-            .filter(|s| !s.trim().is_empty())
-            .map(|s| s.to_string())
-            .collect();
-        self.dump(&mut new_value);
-        self.set(HashSet::from_iter(new_value));
-    }
 }
 
-impl ToString for YtVideosSave {
-    fn to_string(&self) -> String {
-        let mut s = String::new();
-        let mut iter = self.ids.iter();
-        if let Some(v) = iter.next() {
-            s += v;
-        }
-        for v in iter {
-            s += "\n";
-            s += v;
-        }
-        s
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
 pub struct YtChannelBrief {
     id: String,
     thumbnail_url: String,
@@ -644,7 +960,7 @@ pub struct YtChannelBrief {
     custom_url: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
 pub struct TwChannelBrief {
     id: String,
     thumbnail_url: String,
@@ -652,13 +968,13 @@ pub struct TwChannelBrief {
     login: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
 pub enum EventSource {
     YoutubeChannel(YtChannelBrief),
     TwitchChannel(TwChannelBrief),
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct UpcomingEvent {
     start_date_time: DateTime<Utc>,
     start_timestamp_millis: i64,
@@ -669,38 +985,42 @@ pub struct UpcomingEvent {
     ongoing: bool,
     source: EventSource,
     uid: String,
+    /// The actual end time of an ended stream, or the scheduled one of an upcoming stream
+    #[serde(default)]
+    end_date_time: Option<DateTime<Utc>>,
+    #[serde(default)]
+    ended: bool,
+    /// The scheduled start of a youtube stream, which is kept after it went live
+    #[serde(default)]
+    scheduled_start_date_time: Option<DateTime<Utc>>,
+    /// The current viewers of a live stream
+    #[serde(default)]
+    viewer_count: Option<u64>,
+    /// How many times the time, title or details changed
+    #[serde(default)]
+    sequence: u32,
+    /// When the time, title or details last changed, or the event was found
+    #[serde(default)]
+    last_modified: Option<DateTime<Utc>>,
 }
 
 impl UpcomingEvent {
-    fn to_ical_event(&self, alarm_enabled: bool) -> icalendar::Event {
-        let mut builder = icalendar::Event::new();
-        builder.starts(self.start_date_time);
-        if self.ongoing {
-            builder.summary(&format!("🔴{}", self.title));
-            builder.ends(Utc::now() + chrono::Duration::hours(1));
-        } else {
-            builder.summary(&self.title);
-            builder.ends(self.start_date_time + chrono::Duration::hours(1));
-        }
-        let mut description = format!("{}\n\n", self.target_url);
+    pub fn uid(&self) -> &str {
+        &self.uid
+    }
+
+    fn end_at(mut self, time: DateTime<Utc>) -> Self {
+        self.ongoing = false;
+        self.ended = true;
+        self.end_date_time = Some(time);
+        self
+    }
+
+    fn is_from(&self, yt_channel_ids: &[String], tw_channel_logins: &[String]) -> bool {
         match &self.source {
-            EventSource::YoutubeChannel(c) => {
-                description += &format!("{}\n{}\n\n", c.title, c.custom_url);
-                builder.location(&format!("{}@Youtube", c.title));
-            }
-            EventSource::TwitchChannel(c) => {
-                description += &format!("{}({})\n\n", c.title, c.login);
-                builder.location(&format!("{}@Twitch", c.title));
-            }
+            EventSource::YoutubeChannel(c) => yt_channel_ids.contains(&c.id),
+            EventSource::TwitchChannel(c) => tw_channel_logins.contains(&c.login),
         }
-        description += &self.description;
-        builder.description(&description);
-        builder.url(&self.target_url);
-        if alarm_enabled {
-            builder.alarm(Alarm::display(&self.title, -chrono::Duration::minutes(5)));
-        }
-        builder.uid(&self.uid);
-        builder.done()
     }
 }
 
@@ -714,7 +1034,7 @@ impl Eq for UpcomingEvent {}
 
 impl PartialOrd for UpcomingEvent {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.start_date_time.partial_cmp(&other.start_date_time)
+        Some(self.cmp(other))
     }
 }
 
@@ -724,6 +1044,7 @@ impl Ord for UpcomingEvent {
     }
 }
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum ConvertToUpcomingEventError {
     AlreadyDone(String),
     MissingInformation(String),
@@ -735,7 +1056,27 @@ pub enum ConvertToUpcomingEventError {
 impl TryFrom<(&Video::Resource, &ServerData)> for UpcomingEvent {
     type Error = ConvertToUpcomingEventError;
     fn try_from(value: (&Video::Resource, &ServerData)) -> Result<Self, Self::Error> {
-        let start_time: DateTime<Utc> = if let Some(live_info) = &value.0.liveStreamingDetails {
+        let parse_time = |time: &String| {
+            chrono::DateTime::from_str(time)
+                .map_err(|e| ConvertToUpcomingEventError::DecodeError(format!("{}", e)))
+        };
+        let Some(live_info) = &value.0.liveStreamingDetails else {
+            return Err(ConvertToUpcomingEventError::MissingInformation(
+                "liveStreamingDetails".to_string(),
+            ));
+        };
+        let end_time: Option<DateTime<Utc>> = live_info
+            .actualEndTime
+            .as_ref()
+            .or(live_info.scheduledEndTime.as_ref())
+            .map(parse_time)
+            .transpose()?;
+        let scheduled_start_time: Option<DateTime<Utc>> = live_info
+            .scheduledStartTime
+            .as_ref()
+            .map(parse_time)
+            .transpose()?;
+        let start_time: DateTime<Utc> = {
             if let Some(actual_start_time) = &live_info.actualStartTime {
                 chrono::DateTime::from_str(actual_start_time)
                     .map_err(|e| ConvertToUpcomingEventError::DecodeError(format!("{}", e)))?
@@ -747,10 +1088,6 @@ impl TryFrom<(&Video::Resource, &ServerData)> for UpcomingEvent {
                     "start time".to_string(),
                 ));
             }
-        } else {
-            return Err(ConvertToUpcomingEventError::MissingInformation(
-                "liveStreamingDetails".to_string(),
-            ));
         };
 
         match &value.0.snippet {
@@ -759,12 +1096,24 @@ impl TryFrom<(&Video::Resource, &ServerData)> for UpcomingEvent {
             )),
             Some(snippet) => {
                 let on_going;
+                let ended;
                 match snippet.liveBroadcastContent.as_str() {
+                    // ended streams are archived, and kept in the events for the retention time
+                    "none" if live_info.actualEndTime.is_some() => {
+                        on_going = false;
+                        ended = true;
+                    }
                     "none" => {
                         return Err(ConvertToUpcomingEventError::AlreadyDone(value.0.id.clone()))
                     }
-                    "live" => on_going = true,
-                    "upcoming" => on_going = false,
+                    "live" => {
+                        on_going = true;
+                        ended = false;
+                    }
+                    "upcoming" => {
+                        on_going = false;
+                        ended = false;
+                    }
                     _ => return Err(ConvertToUpcomingEventError::Unknown(value.0.id.clone())),
                 }
                 let thumbnail_url = if let Some(t) = snippet.thumbnails.get("maxres") {
@@ -775,7 +1124,7 @@ impl TryFrom<(&Video::Resource, &ServerData)> for UpcomingEvent {
                     ));
                 };
 
-                return Ok(UpcomingEvent {
+                Ok(UpcomingEvent {
                     start_date_time: start_time,
                     start_timestamp_millis: start_time.timestamp_millis(),
                     title: snippet.title.clone(),
@@ -799,7 +1148,16 @@ impl TryFrom<(&Video::Resource, &ServerData)> for UpcomingEvent {
                         },
                     ),
                     uid: format!("{}@yt@yt-watcher", value.0.id),
-                });
+                    end_date_time: end_time,
+                    ended,
+                    scheduled_start_date_time: scheduled_start_time,
+                    viewer_count: live_info
+                        .concurrentViewers
+                        .as_ref()
+                        .and_then(|v| v.parse().ok()),
+                    sequence: 0,
+                    last_modified: None,
+                })
             }
         }
     }
@@ -816,6 +1174,12 @@ impl From<(StreamInformation, String)> for UpcomingEvent {
             target_url: format!("https://www.twitch.tv/{}", &value.0.user_login),
             ongoing: true,
             uid: format!("{}@twitch@yt-watcher", &value.0.user_login),
+            end_date_time: None,
+            ended: false,
+            scheduled_start_date_time: None,
+            viewer_count: Some(value.0.viewer_count as u64),
+            sequence: 0,
+            last_modified: None,
             source: EventSource::TwitchChannel(TwChannelBrief {
                 id: value.0.user_id,
                 title: value.0.user_name,
@@ -826,64 +1190,324 @@ impl From<(StreamInformation, String)> for UpcomingEvent {
     }
 }
 
-#[derive(Default)]
+/// A change of the events, pushed to the `/events/stream` clients
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventChange {
+    Added {
+        event: UpcomingEvent,
+    },
+    /// The title, time or details of an event changed
+    Updated {
+        event: UpcomingEvent,
+        previous: Box<UpcomingEvent>,
+    },
+    Started {
+        event: UpcomingEvent,
+    },
+    Ended {
+        event: UpcomingEvent,
+    },
+    /// An upcoming event was cancelled, or an ended event passed the retention
+    Removed {
+        event: UpcomingEvent,
+    },
+}
+
+impl EventChange {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventChange::Added { .. } => "added",
+            EventChange::Updated { .. } => "updated",
+            EventChange::Started { .. } => "started",
+            EventChange::Ended { .. } => "ended",
+            EventChange::Removed { .. } => "removed",
+        }
+    }
+
+    pub fn event(&self) -> &UpcomingEvent {
+        match self {
+            EventChange::Added { event }
+            | EventChange::Updated { event, .. }
+            | EventChange::Started { event }
+            | EventChange::Ended { event }
+            | EventChange::Removed { event } => event,
+        }
+    }
+}
+
+/// Whether the time, title or details of `event` changed since `previous`
+fn is_modified(previous: &UpcomingEvent, event: &UpcomingEvent) -> bool {
+    previous.start_date_time != event.start_date_time
+        || previous.title != event.title
+        || previous.description != event.description
+        || previous.thumbnail_url != event.thumbnail_url
+        || previous.end_date_time != event.end_date_time
+        || previous.ongoing != event.ongoing
+        || previous.ended != event.ended
+}
+
+fn diff_events(old: &[UpcomingEvent], new: &[UpcomingEvent]) -> Vec<EventChange> {
+    let mut changes = vec![];
+    for event in new {
+        match old.iter().find(|e| *e == event) {
+            None => changes.push(EventChange::Added {
+                event: event.clone(),
+            }),
+            Some(previous) if !previous.ended && event.ended => changes.push(EventChange::Ended {
+                event: event.clone(),
+            }),
+            Some(previous) if !previous.ongoing && event.ongoing => {
+                changes.push(EventChange::Started {
+                    event: event.clone(),
+                })
+            }
+            Some(previous) if is_modified(previous, event) => changes.push(EventChange::Updated {
+                event: event.clone(),
+                previous: Box::new(previous.clone()),
+            }),
+            Some(_) => {}
+        }
+    }
+    for event in old.iter().filter(|e| !new.contains(e)) {
+        changes.push(if event.ongoing {
+            EventChange::Ended {
+                event: event.clone(),
+            }
+        } else {
+            EventChange::Removed {
+                event: event.clone(),
+            }
+        });
+    }
+    changes
+}
+
+struct EventChangeSender(tokio::sync::broadcast::Sender<EventChange>);
+
+impl Default for EventChangeSender {
+    fn default() -> Self {
+        Self(tokio::sync::broadcast::channel(256).0)
+    }
+}
+
 pub struct ServerData {
+    context: Arc<AppContext>,
     yt_channels: HashMap<String, YtChannelSave>,
     yt_videos: YtVideosSave,
     tw_channels: HashMap<String, TwChannelSave>,
     tw_client: Option<TwApiClient>,
     events: Vec<UpcomingEvent>,
-    api_key: String,
-    channel_save_path: String,
-    video_save_path: String,
+    /// The upcoming events which disappeared, kept for the calendars until a day after their
+    /// start
+    cancelled_events: Vec<UpcomingEvent>,
+    /// The events saved before the restart which weren't found again yet
+    saved_events: Vec<UpcomingEvent>,
+    /// How often the events are refreshed, in minutes
+    video_refresh_interval: u64,
     channel_expire_min: i64,
+    ended_event_retention_min: i64,
+    history: history::History,
+    websub: Option<crate::WebSub>,
+    /// When the websub subscription of each youtube channel should be renewed
+    websub_renew_at: HashMap<String, DateTime<Utc>>,
+    /// The (un)subscriptions requested to the hub which it hasn't verified yet, by channel id.
+    /// Only these are confirmed.
+    websub_pending: HashMap<String, websub::Mode>,
+    /// Wakes the websub renewal up to subscribe new channels
+    websub_wakeup: Arc<tokio::sync::Notify>,
+    tw_eventsub: Option<crate::TwEventSub>,
+    /// EventSub subscriptions of each twitch channel, by login
+    tw_eventsub_subscriptions: HashMap<String, Vec<EventSubSubscription>>,
+    /// Wakes the EventSub sync up to subscribe new channels
+    tw_eventsub_wakeup: Arc<tokio::sync::Notify>,
+    event_changes: EventChangeSender,
 }
 
 impl ServerData {
     async fn new(
-        api_key: &str,
-        channel_save_path: String,
-        video_save_path: String,
+        context: Arc<AppContext>,
         channel_expire_min: i64,
+        ended_event_retention_min: i64,
         twitch_app_key: &Option<TwAppKey>,
-    ) -> Self {
-        if let Some(tw_key) = twitch_app_key {
-            Self {
-                api_key: api_key.to_string(),
-                channel_save_path,
-                video_save_path,
-                channel_expire_min,
-                tw_client: Some(
-                    TwApiClient::new(tw_key.client_id.clone(), tw_key.client_secret.clone())
-                        .await
-                        .expect("Incorrect Twitch app key"),
-                ),
-                ..Default::default()
+    ) -> Result<Self, String> {
+        let tw_client = match twitch_app_key {
+            Some(tw_key) => Some(
+                TwApiClient::new(
+                    context.http.clone(),
+                    context.upstream.clone(),
+                    tw_key.client_id.clone(),
+                    tw_key.client_secret.clone(),
+                )
+                .await
+                .map_err(|e| format!("Incorrect Twitch app key: {e}"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            context,
+            yt_channels: HashMap::new(),
+            yt_videos: YtVideosSave::default(),
+            tw_channels: HashMap::new(),
+            tw_client,
+            events: vec![],
+            cancelled_events: vec![],
+            saved_events: vec![],
+            video_refresh_interval: 0,
+            channel_expire_min,
+            ended_event_retention_min,
+            history: history::History::default(),
+            websub: None,
+            websub_renew_at: HashMap::new(),
+            websub_pending: HashMap::new(),
+            websub_wakeup: Arc::new(tokio::sync::Notify::new()),
+            tw_eventsub: None,
+            tw_eventsub_subscriptions: HashMap::new(),
+            tw_eventsub_wakeup: Arc::new(tokio::sync::Notify::new()),
+            event_changes: EventChangeSender::default(),
+        })
+    }
+
+    /// Apply the settings of a reloaded config which can change while running. A new twitch
+    /// client is built before the lock is taken.
+    pub async fn apply_config(
+        server_data: &RwLock<Self>,
+        old: &crate::Config,
+        new: &crate::Config,
+    ) {
+        // the new twitch client, if it changes
+        let tw_client = if new.twitch_key != old.twitch_key {
+            match &new.twitch_key {
+                None => {
+                    log::info!("Twitch app key is removed");
+                    Some(None)
+                }
+                Some(key) => {
+                    let context = server_data.read().await.context.clone();
+                    match TwApiClient::new(
+                        context.http.clone(),
+                        context.upstream.clone(),
+                        &key.client_id,
+                        &key.client_secret,
+                    )
+                    .await
+                    {
+                        Ok(client) => {
+                            log::info!("Twitch client is rebuilt with the new app key");
+                            Some(Some(client))
+                        }
+                        Err(e) => {
+                            log::error!(
+                                "Rebuild the twitch client failed, the old app key stays in effect: {e}"
+                            );
+                            None
+                        }
+                    }
+                }
             }
         } else {
-            Self {
-                api_key: api_key.to_string(),
-                channel_save_path,
-                video_save_path,
-                channel_expire_min,
-                tw_client: None,
-                ..Default::default()
-            }
+            None
+        };
+        let mut data = server_data.write().await;
+        if new.api_keys() != old.api_keys() {
+            log::info!("Youtube api keys are changed");
+            data.context.quota.set_keys(new.api_keys());
+        }
+        data.channel_expire_min = new.channel_expire_min;
+        data.video_refresh_interval = new.video_refresh_interval;
+        data.ended_event_retention_min = new.ended_event_retention_min.unwrap_or(0);
+        if new.youtube_quota_budget != old.youtube_quota_budget {
+            data.context.quota.set_budget(
+                new.youtube_quota_budget
+                    .unwrap_or(quota::DEFAULT_DAILY_BUDGET),
+            );
+        }
+        if let Some(tw_client) = tw_client {
+            data.tw_client = tw_client;
         }
     }
 
     pub async fn check_upcoming_event(&mut self, use_youtube_channel_api: bool) {
+        let playlist_channels = if use_youtube_channel_api
+            && !self
+                .context
+                .quota
+                .can_afford(self.estimate_api_update_cost())
+        {
+            log::warn!("Not enough quota to use the playlist api, fall back to rss");
+            HashSet::new()
+        } else if use_youtube_channel_api {
+            self.yt_channels.keys().cloned().collect()
+        } else {
+            HashSet::new()
+        };
+        self.update_events(&playlist_channels).await;
+    }
+
+    /// Update the events with the playlist api only for the channels that are likely to
+    /// go live soon. The quota spent in the quota day stays under `budget`, spread evenly
+    /// over the `refreshes_left` updates before the quota resets.
+    pub async fn check_upcoming_event_adaptive(&mut self, budget: u32, refreshes_left: u32) {
+        let now = Utc::now();
+        let remaining = budget.saturating_sub(self.context.quota.status().used);
+        // the video requests have to be paid in any mode
+        let video_cost = (self.yt_videos.ids.len() as u32).div_ceil(50) + 1;
+        let allowance = (remaining / refreshes_left.max(1)).saturating_sub(video_cost)
+            / quota::Endpoint::PlaylistItems.cost();
+        let playlist_channels = self.plan_playlist_polling(now, allowance as usize);
+        log::info!(
+            "Adaptive polling: {} of {} channels use the playlist api ({} quota left in budget)",
+            playlist_channels.len(),
+            self.yt_channels.len(),
+            remaining
+        );
+        self.update_events(&playlist_channels).await;
+    }
+
+    /// Pick at most `allowance` channels to poll through the playlist api. Channels with a
+    /// stream starting within the next hour come first, then the channels which went live
+    /// most often at the current hour of the day.
+    fn plan_playlist_polling(&self, now: DateTime<Utc>, allowance: usize) -> HashSet<String> {
+        let mut candidates: Vec<(u32, &str)> = self
+            .yt_channels
+            .values()
+            .filter_map(|c| {
+                let upcoming_soon = self.events.iter().any(|e| {
+                    !e.ongoing
+                        && !e.ended
+                        && e.start_date_time <= now + chrono::Duration::hours(1)
+                        && matches!(&e.source, EventSource::YoutubeChannel(b) if b.id == c.id)
+                });
+                if upcoming_soon {
+                    Some((u32::MAX, c.id.as_str()))
+                } else {
+                    match c.live_hours[now.hour() as usize] {
+                        0 => None,
+                        count => Some((count, c.id.as_str())),
+                    }
+                }
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        candidates
+            .into_iter()
+            .take(allowance)
+            .map(|(_, id)| id.to_string())
+            .collect()
+    }
+
+    async fn update_events(&mut self, playlist_channels: &HashSet<String>) {
         let mut events = vec![];
         let mut unchecked_video_ids = vec![];
         let mut first_video_after_all_stream_map: HashMap<String, String> = HashMap::new();
         self.yt_videos.dump(&mut unchecked_video_ids);
         for c in self.yt_channels.values() {
-            if use_youtube_channel_api {
+            if playlist_channels.contains(&c.id) {
                 match get_playlist_items(
+                    &self.context,
                     &c.upload_playlist,
                     &GetPlaylistItemParts::default().content_details(),
                     None,
-                    &self.api_key,
                 )
                 .await
                 {
@@ -907,7 +1531,7 @@ impl ServerData {
                     }
                 }
             } else {
-                match get_video_list_through_rss(&c.id).await {
+                match get_video_list_through_rss(&self.context, &c.id).await {
                     Err(e) => log::error!(
                         "Fail to get video list through rss of channel {}: {:?}",
                         c.id,
@@ -928,13 +1552,33 @@ impl ServerData {
         }
 
         match get_all_video_items(
+            &self.context,
             unchecked_video_ids.as_slice(),
             &GetVideoParts::default().snippet().live_streaming_details(),
-            &self.api_key,
         )
         .await
         {
-            Err(e) => log::error!("Fail to get video items: {:?}", e),
+            Err(e) => {
+                match e {
+                    YtApiError::QuotaExceeded => log::error!(
+                        "Fail to get video items: quota exceeded. Keep the previous youtube events"
+                    ),
+                    e => log::error!(
+                        "Fail to get video items: {:?}. Keep the previous youtube events",
+                        e
+                    ),
+                }
+                // the streams didn't end, so they stay as they were until the next update
+                events.extend(
+                    self.events
+                        .iter()
+                        .filter(|e| matches!(e.source, EventSource::YoutubeChannel(_)))
+                        .cloned(),
+                );
+                for id in unchecked_video_ids {
+                    self.yt_videos.push_checked(id);
+                }
+            }
             Ok(resp) => {
                 for v in resp.iter() {
                     if let Some(snippet) = &v.snippet {
@@ -1005,9 +1649,123 @@ impl ServerData {
                 }
             });
         self.check_tw_upcoming_event(Some(&mut events)).await;
-        self.events = events;
+        self.record_live_hours(&events);
+        self.set_events(events);
         self.save().await;
     }
+
+    /// Replace the events, archive the live and ended streams and notify the subscribers of
+    /// the changes. The streams which were live but are gone from `events` are kept as ended
+    /// for the retention time.
+    fn set_events(&mut self, mut events: Vec<UpcomingEvent>) {
+        let now = Utc::now();
+        for old in self.events.iter() {
+            if events.contains(old) {
+                continue;
+            }
+            if old.ended {
+                events.push(old.clone());
+            } else if old.ongoing {
+                events.push(old.clone().end_at(now));
+            }
+        }
+        for e in events.iter() {
+            self.history.record(e);
+        }
+        events.retain(|e| {
+            !e.ended
+                || e.end_date_time
+                    .is_some_and(|end| self.is_retained(end, now))
+        });
+        for e in events.iter_mut() {
+            let previous = self
+                .events
+                .iter()
+                .chain(self.saved_events.iter())
+                .find(|old| *old == e);
+            let cancelled = self.cancelled_events.iter().find(|old| *old == e);
+            match (previous, cancelled) {
+                (Some(previous), _) if !is_modified(previous, e) => {
+                    e.sequence = previous.sequence;
+                    e.last_modified = previous.last_modified;
+                }
+                // rescheduled, or back after it was cancelled
+                (Some(previous), _) | (None, Some(previous)) => {
+                    e.sequence = previous.sequence + 1;
+                    e.last_modified = Some(now);
+                }
+                (None, None) => e.last_modified = Some(now),
+            }
+        }
+        self.cancelled_events.retain(|e| {
+            !events.contains(e)
+                && now - e.start_date_time
+                    < chrono::Duration::minutes(CANCELLED_EVENT_RETENTION_MIN)
+        });
+        self.saved_events.retain(|e| {
+            !events.contains(e)
+                && now - e.start_date_time
+                    < chrono::Duration::minutes(CANCELLED_EVENT_RETENTION_MIN)
+        });
+        for old in self.events.iter() {
+            if !old.ended && !old.ongoing && !events.contains(old) {
+                self.cancelled_events.push(UpcomingEvent {
+                    sequence: old.sequence + 1,
+                    last_modified: Some(now),
+                    ..old.clone()
+                });
+            }
+        }
+        for change in diff_events(&self.events, &events) {
+            log::debug!("Event {} {}", change.event().uid, change.name());
+            // fails only when nobody is subscribed
+            let _ = self.event_changes.0.send(change);
+        }
+        self.events = events;
+    }
+
+    /// Whether a stream ended at `end` is still kept in the events
+    fn is_retained(&self, end: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        self.ended_event_retention_min > 0
+            && now - end < chrono::Duration::minutes(self.ended_event_retention_min)
+    }
+
+    fn modify_events(&mut self, f: impl FnOnce(&mut Vec<UpcomingEvent>)) {
+        let mut events = self.events.clone();
+        f(&mut events);
+        self.set_events(events);
+    }
+
+    pub fn subscribe_event_changes(&self) -> tokio::sync::broadcast::Receiver<EventChange> {
+        self.event_changes.0.subscribe()
+    }
+
+    /// Count the hour of day of every youtube stream that went live since the last update
+    fn record_live_hours(&mut self, events: &[UpcomingEvent]) {
+        for e in events.iter().filter(|e| e.ongoing) {
+            if let EventSource::YoutubeChannel(c) = &e.source {
+                let was_live = self
+                    .events
+                    .iter()
+                    .any(|old| old.uid == e.uid && old.ongoing);
+                if !was_live {
+                    if let Some(channel) = self.yt_channels.get_mut(&c.id) {
+                        channel.live_hours[e.start_date_time.hour() as usize] += 1;
+                    }
+                }
+            }
+        }
+    }
+    /// The worst case quota cost of an update which reads every upload playlist: one playlist
+    /// request per channel, and the video requests for the tracked videos plus a full page of
+    /// new videos per channel.
+    fn estimate_api_update_cost(&self) -> u32 {
+        let channels = self.yt_channels.len() as u32;
+        let videos = self.yt_videos.ids.len() as u32;
+        channels * quota::Endpoint::PlaylistItems.cost()
+            + (videos.div_ceil(50) + channels) * quota::Endpoint::Videos.cost()
+    }
+
     pub async fn check_tw_upcoming_event(&mut self, events: Option<&mut Vec<UpcomingEvent>>) {
         let mut events_vec = vec![];
         let is_none = events.is_none();
@@ -1034,58 +1792,75 @@ impl ServerData {
         }
 
         if is_none {
-            for e in events_vec.into_iter() {
-                if let Some(n) = self.events.iter().position(|event| *event == e) {
-                    self.events.remove(n);
+            self.modify_events(|events| {
+                for e in events_vec.into_iter() {
+                    if let Some(n) = events.iter().position(|event| *event == e) {
+                        events.remove(n);
+                    }
+                    events.push(e);
                 }
-                self.events.push(e);
-            }
+            });
         }
     }
-    pub async fn track_new_yt_channels(&mut self, ids: &[&str]) -> Result<(), YtApiError> {
+    /// Track the youtube channels with their upcoming streams. The channels and their videos
+    /// are requested without holding the lock.
+    pub async fn track_new_yt_channels(
+        server_data: &RwLock<Self>,
+        ids: &[&str],
+    ) -> Result<(), YtApiError> {
+        let context = server_data.read().await.context.clone();
         let channels = get_all_channels(
+            &context,
             ids,
             &GetChannelParts::default().snippet().content_details(),
-            &self.api_key,
         )
         .await?;
+        let mut fetched = vec![];
         for c in channels {
             if c.contentDetails.is_none() {
                 log::error!("The response of get channel {} request doesn't has the contentDetail field. Does youtube api updated?", c.id);
             } else if c.snippet.is_none() {
                 log::error!("The response of get channel {} request doesn't has the snippet field. Does youtube api updated?", c.id);
             } else {
-                let snippet = c.snippet.expect("The response of get channel request doesn't has the snippet field. Does youtube api updated?");
-                let content_detail = c.contentDetails.expect("The response of get channel request doesn't has the contentDetail field. Does youtube api updated?");
-                log::info!(
-                    "Tracking new channel: {} ({}, {})",
-                    c.id,
-                    snippet.title,
-                    snippet.customUrl
-                );
                 //let list_item = get_playlist_items(
                 //    &content_detail.relatedPlaylists.uploads,
                 //    &GetPlaylistItemParts::default().content_details(),
                 //    None,
-                //    &self.api_key,
                 //)
                 //.await?;
                 //let video_ids:Vec<String> = list_item.value.iter().map(|item| item.contentDetails.as_ref().expect("The response of get playlist request doesn't has the contentDetail field. Does youtube api updated?").videoId.clone()).collect();
-                let video_ids = get_video_list_through_rss(&c.id).await?;
+                let video_ids = get_video_list_through_rss(&context, &c.id).await?;
                 let videos = get_all_video_items(
+                    &context,
                     video_ids.as_slice(),
                     &GetVideoParts::default().snippet().live_streaming_details(),
-                    &self.api_key,
                 )
                 .await?;
+                fetched.push((c, videos));
+            }
+        }
 
+        let mut data = server_data.write().await;
+        let mut new_events = vec![];
+        for (c, videos) in fetched {
+            // tracked by another request meanwhile
+            if data.yt_channels.contains_key(&c.id) {
+                continue;
+            }
+            if let (Some(snippet), Some(content_detail)) = (c.snippet, c.contentDetails) {
+                log::info!(
+                    "Tracking new channel: {} ({}, {})",
+                    c.id,
+                    snippet.title,
+                    snippet.customUrl
+                );
                 let mut max_thumbnail: (&str, u32) = ("", 0);
                 for thumb in snippet.thumbnails.values() {
                     if thumb.width > max_thumbnail.1 && thumb.width <= 240 {
                         max_thumbnail = (thumb.url.as_str(), thumb.width);
                     }
                 }
-                self.yt_channels.insert(
+                data.yt_channels.insert(
                     c.id.clone(),
                     YtChannelSave {
                         custom_url: snippet.customUrl,
@@ -1095,13 +1870,14 @@ impl ServerData {
                         upload_playlist: content_detail.relatedPlaylists.uploads.clone(),
                         first_video_after_all_stream: String::new(),
                         last_time_used: Utc::now(),
+                        live_hours: [0; 24],
                     },
                 );
 
                 let mut first_video_after_all_stream = None;
                 for v in videos {
-                    match UpcomingEvent::try_from((&v, &*self)) {
-                        Ok(e) => self.events.push(e),
+                    match UpcomingEvent::try_from((&v, &*data)) {
+                        Ok(e) => new_events.push(e),
                         Err(e) => match e {
                             ConvertToUpcomingEventError::AlreadyDone(_) => {}
                             ConvertToUpcomingEventError::MissingInformation(msg) => {
@@ -1121,73 +1897,102 @@ impl ServerData {
                             }
                         } else {
                             first_video_after_all_stream = None;
-                            self.yt_videos.push_checked(v.id.clone());
+                            data.yt_videos.push_checked(v.id.clone());
                         }
                     }
                 }
 
-                if let Some(save) = self.yt_channels.get_mut(&c.id) {
+                if let Some(save) = data.yt_channels.get_mut(&c.id) {
                     save.first_video_after_all_stream =
                         first_video_after_all_stream.unwrap_or("".to_string());
                 }
             }
         }
-        self.save().await;
+        data.modify_events(|events| events.extend(new_events));
+        data.save().await;
+        if data.websub.is_some() {
+            // subscribe the new channels now rather than at the next renewal
+            data.websub_wakeup.notify_one();
+        }
         Ok(())
     }
 
-    pub async fn track_new_tw_channels(&mut self, logins: &[String]) {
-        match &mut self.tw_client {
-            Some(client) => match client
-                .get_user_info(
-                    &logins
-                        .iter()
-                        .map(|s| UserIdentity::Login(s.clone()))
-                        .collect::<Vec<UserIdentity>>(),
-                )
-                .await
-            {
-                Ok(channels) => {
-                    self.tw_channels.extend(channels.iter().map(|c| {
-                        log::info!("Tracking new twitch channel: {}", &c.login);
-                        (c.login.clone(), c.clone().into())
-                    }));
-                    match client
-                        .get_stream_info(
-                            channels
-                                .iter()
-                                .map(|c| UserIdentity::Login(c.login.clone()))
-                                .collect::<Vec<UserIdentity>>()
-                                .as_slice(),
-                        )
-                        .await
-                    {
-                        Ok(streams) => self.events.extend(streams.into_iter().map(|s| {
-                            (
-                                s.clone(),
-                                self.tw_channels
-                                    .get(&s.user_login)
-                                    .unwrap()
-                                    .profile_img
-                                    .clone(),
-                            )
-                                .into()
-                        })),
-                        Err(e) => log::error!(
-                            "Get stream info of channel {:?} failed: {e}",
-                            channels
-                                .iter()
-                                .map(|c| c.login.clone())
-                                .collect::<Vec<String>>()
-                        ),
+    /// Track the twitch channels with their live streams. The channels and their streams are
+    /// requested without holding the lock.
+    pub async fn track_new_tw_channels(server_data: &RwLock<Self>, logins: &[String]) {
+        let Some(mut client) = server_data.read().await.tw_client.clone() else {
+            log::error!("Twitch client is not initialized");
+            return;
+        };
+        let channels = match client
+            .get_user_info(
+                &logins
+                    .iter()
+                    .map(|s| UserIdentity::Login(s.clone()))
+                    .collect::<Vec<UserIdentity>>(),
+            )
+            .await
+        {
+            Ok(channels) => channels,
+            Err(e) => {
+                log::error!("Get user info failed: {e}");
+                return;
+            }
+        };
+        let streams = client
+            .get_stream_info(
+                channels
+                    .iter()
+                    .map(|c| UserIdentity::Login(c.login.clone()))
+                    .collect::<Vec<UserIdentity>>()
+                    .as_slice(),
+            )
+            .await;
+
+        let mut data = server_data.write().await;
+        for c in channels.iter() {
+            // tracked by another request meanwhile
+            if !data.tw_channels.contains_key(&c.login) {
+                log::info!("Tracking new twitch channel: {}", &c.login);
+                data.tw_channels.insert(c.login.clone(), c.clone().into());
+            }
+        }
+        match streams {
+            Ok(streams) => {
+                let new_events: Vec<UpcomingEvent> = streams
+                    .into_iter()
+                    .map(|s| {
+                        let profile_img = data
+                            .tw_channels
+                            .get(&s.user_login)
+                            .unwrap()
+                            .profile_img
+                            .clone();
+                        (s, profile_img).into()
+                    })
+                    .collect();
+                data.modify_events(|events| {
+                    for e in new_events.into_iter() {
+                        if let Some(n) = events.iter().position(|event| *event == e) {
+                            events.remove(n);
+                        }
+                        events.push(e);
                     }
-                }
-                Err(e) => log::error!("Get user info failed: {e}"),
-            },
-            None => log::error!("Twitch client is not initialized"),
+                });
+            }
+            Err(e) => log::error!(
+                "Get stream info of channel {:?} failed: {e}",
+                channels
+                    .iter()
+                    .map(|c| c.login.clone())
+                    .collect::<Vec<String>>()
+            ),
+        }
+        if data.tw_eventsub.is_some() {
+            // subscribe the new channels now rather than at the next sync
+            data.tw_eventsub_wakeup.notify_one();
         }
-        self.check_tw_upcoming_event(None).await;
-        self.save().await;
+        data.save().await;
     }
 
     fn filter_new_yt_channel_id<'a>(&self, channel_ids: &'a [String]) -> Vec<&'a str> {
@@ -1207,49 +2012,95 @@ impl ServerData {
     }
 
     async fn save(&self) {
-        match serde_json::to_string(&ChannelSave {
+        let storage = self.context.storage.clone();
+        let channels = ChannelSave {
             yt: self.yt_channels.clone(),
             tw: self.tw_channels.clone(),
-        }) {
-            Ok(save_string) => {
-                if let Err(e) = tokio::fs::write(&self.channel_save_path, save_string).await {
-                    log::error!(
-                        "Write to save file {} failed: {}",
-                        self.channel_save_path,
-                        e
-                    );
-                }
+        };
+        let video_ids = self.yt_videos.ids.clone();
+        let calendar = CalendarSave {
+            events: self
+                .events
+                .iter()
+                .chain(self.saved_events.iter())
+                .cloned()
+                .collect(),
+            cancelled: self.cancelled_events.clone(),
+        };
+        let changed = self.history.take_changed();
+        // the storage calls block, so they don't run on the runtime threads
+        let saved = tokio::task::spawn_blocking(move || {
+            if let Err(e) = storage.save_channels(&channels) {
+                log::error!("Save channels failed: {}", e);
+            }
+
+            if let Err(e) = storage.save_yt_videos(&video_ids) {
+                log::error!("Save videos failed: {}", e);
             }
-            Err(e) => log::error!("Serialize channel save failed: {}", e),
-        }
 
-        if let Err(e) = tokio::fs::write(&self.video_save_path, self.yt_videos.to_string()).await {
-            log::error!("Write to save file {} failed: {}", self.video_save_path, e);
+            if let Err(e) = storage.save_calendar(&calendar) {
+                log::error!("Save calendar failed: {}", e);
+            }
+
+            if !changed.is_empty() {
+                if let Err(e) = storage.save_history(&changed) {
+                    log::error!("Save history failed: {}", e);
+                    return Err(changed);
+                }
+            }
+            Ok(())
+        })
+        .await;
+        match saved {
+            Ok(Ok(())) => {}
+            Ok(Err(changed)) => self.history.mark_changed(&changed),
+            Err(e) => log::error!("Save task failed: {}", e),
         }
     }
 
     async fn restore(&mut self) {
-        match tokio::fs::read_to_string(&self.channel_save_path).await {
-            Ok(save_string) => match serde_json::from_str::<ChannelSave>(&save_string) {
-                Ok(save) => {
-                    self.yt_channels = save.yt;
-                    self.tw_channels = save.tw;
-                }
-                Err(e) => log::error!(
-                    "Deserialize save file {} failed: {}",
-                    self.channel_save_path,
-                    e
-                ),
-            },
+        let storage = self.context.storage.clone();
+        let loaded = tokio::task::spawn_blocking(move || {
+            (
+                storage.load_channels(),
+                storage.load_yt_videos(),
+                storage.load_history(),
+                storage.load_calendar(),
+            )
+        })
+        .await;
+        let (channels, video_ids, history, calendar) = match loaded {
+            Ok(loaded) => loaded,
             Err(e) => {
-                log::error!("Read save file {} failed: {}", self.channel_save_path, e);
+                log::error!("Load task failed: {}", e);
                 return;
             }
+        };
+
+        match channels {
+            Ok(save) => {
+                self.yt_channels = save.yt;
+                self.tw_channels = save.tw;
+            }
+            Err(e) => log::error!("Load channels failed: {}", e),
+        }
+
+        match video_ids {
+            Ok(ids) => self.yt_videos.ids.extend(ids),
+            Err(e) => log::error!("Load videos failed: {}", e),
+        }
+
+        match history {
+            Ok(records) => self.history = history::History::new(records),
+            Err(e) => log::error!("Load history failed: {}", e),
         }
 
-        match tokio::fs::read_to_string(&self.video_save_path).await {
-            Ok(save_string) => self.yt_videos.extend_from_str(&save_string),
-            Err(e) => log::error!("Read save file {} failed: {}", self.video_save_path, e),
+        match calendar {
+            Ok(save) => {
+                self.saved_events = save.events;
+                self.cancelled_events = save.cancelled;
+            }
+            Err(e) => log::error!("Load calendar failed: {}", e),
         }
     }
 
@@ -1270,6 +2121,7 @@ impl ServerData {
                     self.channel_expire_min
                 );
                 self.yt_channels.remove(id);
+                self.unsubscribe_websub(id).await;
             }
         }
         let tw_channel_logins = self.tw_channels.keys().cloned().collect::<Vec<String>>();
@@ -1287,19 +2139,20 @@ impl ServerData {
                     self.channel_expire_min
                 );
                 self.tw_channels.remove(login);
+                self.unsubscribe_tw_eventsub(login).await;
             }
         }
         if self.yt_channels.is_empty() && self.tw_channels.is_empty() {
             return;
         }
         match get_all_channels(
+            &self.context,
             self.yt_channels
                 .keys()
                 .map(|s| s.as_str())
                 .collect::<Vec<&str>>()
                 .as_slice(),
             &GetChannelParts::default().snippet().content_details(),
-            &self.api_key,
         )
         .await
         {
@@ -1316,9 +2169,9 @@ impl ServerData {
                             "The channel {} resource doesn't has contentDetails property",
                             res.id
                         );
-                    } else {
-                        let snippet = res.snippet.unwrap();
-                        let content_details = res.contentDetails.unwrap();
+                    } else if let (Some(snippet), Some(content_details)) =
+                        (res.snippet, res.contentDetails)
+                    {
                         let channel_save = self
                             .yt_channels
                             .get_mut(&res.id)
@@ -1376,4 +2229,1397 @@ impl ServerData {
             ch.last_time_used = Utc::now();
         }
     }
+
+    /// Stop tracking a youtube channel and drop its events. Returns whether it was tracked.
+    pub async fn untrack_yt_channel(&mut self, id: &str) -> bool {
+        if self.yt_channels.remove(id).is_none() {
+            return false;
+        }
+        log::info!("Untracked channel {id}");
+        self.unsubscribe_websub(id).await;
+        let ids = [id.to_string()];
+        self.modify_events(|events| events.retain(|e| !e.is_from(&ids, &[])));
+        self.save().await;
+        true
+    }
+
+    /// Stop tracking a twitch channel and drop its events. Returns whether it was tracked.
+    pub async fn untrack_tw_channel(&mut self, login: &str) -> bool {
+        if self.tw_channels.remove(login).is_none() {
+            return false;
+        }
+        log::info!("Untracked twitch channel {login}");
+        self.unsubscribe_tw_eventsub(login).await;
+        let logins = [login.to_string()];
+        self.modify_events(|events| events.retain(|e| !e.is_from(&[], &logins)));
+        self.save().await;
+        true
+    }
+
+    /// Subscribe the youtube channels which are not subscribed yet, or whose lease is
+    /// about to expire, to the websub hub. The hub is requested without holding the lock.
+    pub async fn renew_websub_subscriptions(server_data: &RwLock<Self>) {
+        let (context, config, channel_ids) = {
+            let mut data = server_data.write().await;
+            let Some(config) = data.websub.clone() else {
+                return;
+            };
+            let now = Utc::now();
+            let channel_ids = data
+                .yt_channels
+                .keys()
+                .filter(|id| data.websub_renew_at.get(*id).is_none_or(|t| *t <= now))
+                .cloned()
+                .collect::<Vec<String>>();
+            // pending before the request, the hub may verify it before the request returns
+            for id in channel_ids.iter() {
+                data.websub_pending
+                    .insert(id.clone(), websub::Mode::Subscribe);
+            }
+            (data.context.clone(), config, channel_ids)
+        };
+        let lease_seconds = config
+            .lease_seconds
+            .unwrap_or(websub::DEFAULT_LEASE_SECONDS);
+        let mut requested = vec![];
+        let mut failed = vec![];
+        for id in channel_ids {
+            match websub::request(
+                &context,
+                websub::Mode::Subscribe,
+                &id,
+                &config.callback_url,
+                &config.secret,
+                lease_seconds,
+            )
+            .await
+            {
+                Ok(()) => {
+                    log::info!("Requested websub subscription of channel {id}");
+                    requested.push(id);
+                }
+                Err(e) => {
+                    log::error!("Subscribe websub of channel {id} failed: {e:?}");
+                    failed.push(id);
+                }
+            }
+        }
+        if requested.is_empty() && failed.is_empty() {
+            return;
+        }
+        let mut data = server_data.write().await;
+        for id in failed {
+            if data.websub_pending.get(&id) == Some(&websub::Mode::Subscribe) {
+                data.websub_pending.remove(&id);
+            }
+        }
+        // retry if the hub doesn't verify the subscription in time
+        let retry_at = Utc::now() + chrono::Duration::hours(1);
+        for id in requested {
+            if data.yt_channels.contains_key(&id) {
+                // the hub may have verified it already
+                let renew_at = data.websub_renew_at.entry(id).or_insert(retry_at);
+                *renew_at = (*renew_at).max(retry_at);
+            }
+        }
+    }
+
+    async fn unsubscribe_websub(&mut self, channel_id: &str) {
+        let Some(config) = &self.websub else {
+            return;
+        };
+        if self.websub_renew_at.remove(channel_id).is_none() {
+            return;
+        }
+        self.websub_pending
+            .insert(channel_id.to_string(), websub::Mode::Unsubscribe);
+        if let Err(e) = websub::request(
+            &self.context,
+            websub::Mode::Unsubscribe,
+            channel_id,
+            &config.callback_url,
+            &config.secret,
+            0,
+        )
+        .await
+        {
+            log::error!("Unsubscribe websub of channel {channel_id} failed: {e:?}");
+            self.websub_pending.remove(channel_id);
+        }
+    }
+
+    /// Answer a verification request of the hub. Only the (un)subscriptions this server
+    /// requested and which are not verified yet are confirmed, the lease is at most the
+    /// requested one. Returns whether the intent is confirmed.
+    pub fn verify_websub(
+        &mut self,
+        mode: websub::Mode,
+        topic: &str,
+        lease_seconds: Option<u32>,
+    ) -> bool {
+        let Some(channel_id) = websub::channel_id_of_topic(&self.context, topic) else {
+            return false;
+        };
+        if self.websub_pending.get(channel_id) != Some(&mode) {
+            return false;
+        }
+        let tracked = self.yt_channels.contains_key(channel_id);
+        let confirmed = match mode {
+            websub::Mode::Subscribe if tracked => {
+                let requested = self
+                    .websub
+                    .as_ref()
+                    .and_then(|config| config.lease_seconds)
+                    .unwrap_or(websub::DEFAULT_LEASE_SECONDS);
+                let lease_seconds = lease_seconds.unwrap_or(requested).min(requested) as i64;
+                // renew when 90% of the lease has passed
+                self.websub_renew_at.insert(
+                    channel_id.to_string(),
+                    Utc::now() + chrono::Duration::seconds(lease_seconds * 9 / 10),
+                );
+                log::info!("Websub subscription of channel {channel_id} is verified");
+                true
+            }
+            websub::Mode::Unsubscribe if !tracked => true,
+            _ => false,
+        };
+        if confirmed {
+            self.websub_pending.remove(channel_id);
+        }
+        confirmed
+    }
+
+    /// Adopt the subscriptions created by the previous run, and delete the ones of channels
+    /// which are not tracked anymore
+    pub async fn restore_tw_eventsub_subscriptions(&mut self) {
+        let (Some(config), Some(client)) = (&self.tw_eventsub, &mut self.tw_client) else {
+            return;
+        };
+        let subscriptions = match client.get_eventsub_subscriptions().await {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("Get EventSub subscriptions failed: {e}");
+                return;
+            }
+        };
+        for s in subscriptions {
+            if s.transport.callback.as_ref() != Some(&config.callback_url) {
+                continue;
+            }
+            match self
+                .tw_channels
+                .values()
+                .find(|c| c.id == s.condition.broadcaster_user_id)
+            {
+                Some(c) => self
+                    .tw_eventsub_subscriptions
+                    .entry(c.login.clone())
+                    .or_default()
+                    .push(s),
+                None => {
+                    if let Err(e) = client.delete_eventsub_subscription(&s.id).await {
+                        log::error!("Delete EventSub subscription {} failed: {e}", s.id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Create the missing EventSub subscriptions of the tracked twitch channels. Twitch is
+    /// requested without holding the lock.
+    pub async fn sync_tw_eventsub_subscriptions(server_data: &RwLock<Self>) {
+        let (config, mut client, missing) = {
+            let data = server_data.read().await;
+            let (Some(config), Some(client)) = (data.tw_eventsub.clone(), data.tw_client.clone())
+            else {
+                return;
+            };
+            let mut missing = vec![];
+            for c in data.tw_channels.values() {
+                let subscriptions = data.tw_eventsub_subscriptions.get(&c.login);
+                for t in eventsub::SubscriptionType::ALL {
+                    if !subscriptions
+                        .is_some_and(|s| s.iter().any(|s| s.subscription_type == t.name()))
+                    {
+                        missing.push((c.login.clone(), c.id.clone(), t));
+                    }
+                }
+            }
+            (config, client, missing)
+        };
+        let mut created = vec![];
+        for (login, id, t) in missing {
+            match client
+                .create_eventsub_subscription(t, &id, &config.callback_url, &config.secret)
+                .await
+            {
+                Ok(s) => {
+                    log::info!("Subscribed {} of twitch channel {login}", t.name());
+                    created.push((login, s));
+                }
+                Err(e) => log::error!(
+                    "Subscribe {} of twitch channel {login} failed: {e}",
+                    t.name()
+                ),
+            }
+        }
+        if created.is_empty() {
+            return;
+        }
+        let mut untracked = vec![];
+        {
+            let mut data = server_data.write().await;
+            for (login, s) in created {
+                if data.tw_channels.contains_key(&login) {
+                    data.tw_eventsub_subscriptions
+                        .entry(login)
+                        .or_default()
+                        .push(s);
+                } else {
+                    untracked.push(s);
+                }
+            }
+        }
+        // the channels which were untracked meanwhile
+        for s in untracked {
+            if let Err(e) = client.delete_eventsub_subscription(&s.id).await {
+                log::error!("Delete EventSub subscription {} failed: {e}", s.id);
+            }
+        }
+    }
+
+    async fn unsubscribe_tw_eventsub(&mut self, login: &str) {
+        let (Some(subscriptions), Some(client)) = (
+            self.tw_eventsub_subscriptions.remove(login),
+            &mut self.tw_client,
+        ) else {
+            return;
+        };
+        for s in subscriptions {
+            if let Err(e) = client.delete_eventsub_subscription(&s.id).await {
+                log::error!("Delete EventSub subscription {} failed: {e}", s.id);
+            }
+        }
+    }
+
+    /// Forget a subscription twitch revoked. It is created again at the next sync.
+    pub fn revoke_tw_eventsub(&mut self, subscription: &EventSubSubscription) {
+        log::warn!(
+            "EventSub subscription {} ({} of {}) was revoked: {}",
+            subscription.id,
+            subscription.subscription_type,
+            subscription.condition.broadcaster_user_id,
+            subscription.status
+        );
+        for subscriptions in self.tw_eventsub_subscriptions.values_mut() {
+            subscriptions.retain(|s| s.id != subscription.id);
+        }
+    }
+
+    /// Apply an EventSub notification to the events
+    pub async fn handle_tw_eventsub_event(server_data: &RwLock<Self>, event: eventsub::Event) {
+        match event {
+            eventsub::Event::StreamOnline(e) => {
+                log::info!("Twitch channel {} went live", e.broadcaster_user_login);
+                // the stream is requested without holding the lock
+                let (channel, mut client) = {
+                    let data = server_data.read().await;
+                    let (Some(channel), Some(client)) = (
+                        data.tw_channels.get(&e.broadcaster_user_login).cloned(),
+                        data.tw_client.clone(),
+                    ) else {
+                        return;
+                    };
+                    (channel, client)
+                };
+                let event: UpcomingEvent = match client
+                    .get_stream_info(&[UserIdentity::Id(e.broadcaster_user_id.clone())])
+                    .await
+                {
+                    Ok(mut streams) if !streams.is_empty() => {
+                        (streams.remove(0), channel.profile_img.clone()).into()
+                    }
+                    // the stream may not be listed yet right after it went online
+                    _ => match client
+                        .get_channel_info(std::slice::from_ref(&e.broadcaster_user_id))
+                        .await
+                    {
+                        Ok(mut info) if !info.is_empty() => {
+                            let info = info.remove(0);
+                            UpcomingEvent {
+                                start_date_time: e.started_at,
+                                start_timestamp_millis: e.started_at.timestamp_millis(),
+                                thumbnail_url: None,
+                                title: info.title,
+                                description: info.game_name,
+                                target_url: format!("https://www.twitch.tv/{}", channel.login),
+                                ongoing: true,
+                                uid: format!("{}@twitch@yt-watcher", channel.login),
+                                end_date_time: None,
+                                ended: false,
+                                scheduled_start_date_time: None,
+                                viewer_count: None,
+                                sequence: 0,
+                                last_modified: None,
+                                source: EventSource::TwitchChannel(TwChannelBrief {
+                                    id: channel.id.clone(),
+                                    title: channel.name.clone(),
+                                    login: channel.login.clone(),
+                                    thumbnail_url: channel.profile_img.clone(),
+                                }),
+                            }
+                        }
+                        r => {
+                            log::error!(
+                                "Get channel info of {} failed: {:?}",
+                                channel.login,
+                                r.err()
+                            );
+                            return;
+                        }
+                    },
+                };
+                let mut data = server_data.write().await;
+                // untracked meanwhile
+                if !data.tw_channels.contains_key(&channel.login) {
+                    return;
+                }
+                data.modify_events(|events| {
+                    if let Some(n) = events.iter().position(|old| *old == event) {
+                        events.remove(n);
+                    }
+                    events.push(event);
+                });
+            }
+            eventsub::Event::StreamOffline(e) => {
+                log::info!("Twitch channel {} went offline", e.broadcaster_user_login);
+                let uid = format!("{}@twitch@yt-watcher", e.broadcaster_user_login);
+                server_data
+                    .write()
+                    .await
+                    .modify_events(|events| events.retain(|event| event.uid != uid));
+            }
+            eventsub::Event::ChannelUpdate(e) => {
+                let uid = format!("{}@twitch@yt-watcher", e.broadcaster_user_login);
+                server_data.write().await.modify_events(|events| {
+                    if let Some(event) = events.iter_mut().find(|event| event.uid == uid) {
+                        event.title = e.title;
+                        event.description = e.category_name;
+                    }
+                });
+            }
+        }
+    }
+
+    /// Queue the videos of a websub notification of the channel to be checked at the next
+    /// update. Returns whether the notification is signed by the hub and the channel is tracked.
+    pub fn handle_websub_notification(
+        &mut self,
+        channel_id: &str,
+        signature: Option<&str>,
+        body: &[u8],
+    ) -> bool {
+        let Some(config) = &self.websub else {
+            return false;
+        };
+        if !self.yt_channels.contains_key(channel_id)
+            || !websub::verify_signature(&config.secret, channel_id, signature, body)
+        {
+            log::warn!("Rejected websub notification of channel {channel_id}");
+            return false;
+        }
+        for id in websub::parse_notification(&String::from_utf8_lossy(body)) {
+            log::info!("Got websub notification of video {id}");
+            self.yt_videos.push_checked(id);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock_upstream::{MockStream, VideoState};
+    use crate::test::*;
+
+    async fn new_server_data() -> ServerData {
+        server_data_with_context(new_context()).await
+    }
+
+    async fn server_data_with_context(context: Arc<AppContext>) -> ServerData {
+        ServerData::new(
+            context,
+            CONFIG.channel_expire_min,
+            CONFIG.ended_event_retention_min.unwrap_or(0),
+            &CONFIG.twitch_key,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check_upcoming_event_transitions() {
+        const CHANNEL_ID: &str = "UCserver-transition-test";
+        const STREAM_ID: &str = "server-transition-stream";
+        let start = Utc::now() + chrono::Duration::hours(1);
+        MOCK.add_yt_channel(CHANNEL_ID, "ServerTransitionTest", "Transition Test");
+        MOCK.add_video(
+            CHANNEL_ID,
+            "server-transition-upload",
+            "Upload",
+            vec![VideoState::Upload],
+        );
+        MOCK.add_video(
+            CHANNEL_ID,
+            STREAM_ID,
+            "Stream",
+            vec![
+                VideoState::Upcoming(start),
+                VideoState::Live(start),
+                VideoState::Ended(start, start + chrono::Duration::hours(1)),
+            ],
+        );
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            ServerData::track_new_yt_channels(&server_data, &[CHANNEL_ID])
+                .await
+                .unwrap();
+            let mut data = server_data.into_inner();
+            assert_eq!(data.events.len(), 1);
+            assert_eq!(data.events[0].uid, format!("{STREAM_ID}@yt@yt-watcher"));
+            assert!(!data.events[0].ongoing);
+
+            MOCK.advance_video(STREAM_ID);
+            data.check_upcoming_event(false).await;
+            assert_eq!(data.events.len(), 1);
+            assert!(data.events[0].ongoing);
+            assert_eq!(
+                data.yt_channels[CHANNEL_ID].live_hours[start.hour() as usize],
+                1
+            );
+
+            MOCK.advance_video(STREAM_ID);
+            data.check_upcoming_event(true).await;
+            assert!(data.events.is_empty());
+            assert_eq!(
+                data.yt_channels[CHANNEL_ID].first_video_after_all_stream,
+                STREAM_ID
+            );
+        });
+    }
+
+    #[test]
+    fn test_adaptive_polling() {
+        const SOON_ID: &str = "UCadaptive-soon";
+        const USUAL_ID: &str = "UCadaptive-usual";
+        const QUIET_ID: &str = "UCadaptive-quiet";
+        let now = Utc::now();
+        MOCK.add_yt_channel(SOON_ID, "AdaptiveSoon", "Adaptive Soon");
+        MOCK.add_yt_channel(USUAL_ID, "AdaptiveUsual", "Adaptive Usual");
+        MOCK.add_yt_channel(QUIET_ID, "AdaptiveQuiet", "Adaptive Quiet");
+        MOCK.add_video(
+            SOON_ID,
+            "adaptive-soon-stream",
+            "Stream",
+            vec![VideoState::Upcoming(now + chrono::Duration::minutes(30))],
+        );
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            ServerData::track_new_yt_channels(&server_data, &[SOON_ID, USUAL_ID, QUIET_ID])
+                .await
+                .unwrap();
+            let mut data = server_data.into_inner();
+            data.yt_channels.get_mut(USUAL_ID).unwrap().live_hours[now.hour() as usize] = 3;
+
+            assert!(data.plan_playlist_polling(now, 0).is_empty());
+            assert_eq!(
+                data.plan_playlist_polling(now, 1),
+                HashSet::from([SOON_ID.to_string()])
+            );
+            assert_eq!(
+                data.plan_playlist_polling(now, 10),
+                HashSet::from([SOON_ID.to_string(), USUAL_ID.to_string()])
+            );
+
+            let playlist_requests = |requests: &[String]| {
+                requests
+                    .iter()
+                    .filter(|r| r.contains("playlistItems"))
+                    .count()
+            };
+            let before = playlist_requests(&MOCK.requests());
+            data.check_upcoming_event_adaptive(quota::DEFAULT_DAILY_BUDGET, 1)
+                .await;
+            assert_eq!(data.events.len(), 1);
+            assert!(playlist_requests(&MOCK.requests()) >= before + 2);
+        });
+    }
+
+    #[test]
+    fn test_websub_subscription() {
+        const CHANNEL_ID: &str = "UCwebsub-test";
+        MOCK.add_yt_channel(CHANNEL_ID, "WebSubTest", "WebSub Test");
+        let topic = websub::topic_url(&new_context(), CHANNEL_ID);
+        let hub_requests = |mode: &str| {
+            MOCK.hub_requests()
+                .into_iter()
+                .filter(|(m, t)| m == mode && *t == topic)
+                .count()
+        };
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            server_data.write().await.websub = Some(crate::WebSub {
+                callback_url: "http://127.0.0.1/websub".to_string(),
+                secret: "websub-secret".to_string(),
+                lease_seconds: Some(3600),
+            });
+            let wakeup = server_data.read().await.websub_wakeup.clone();
+            ServerData::track_new_yt_channels(&server_data, &[CHANNEL_ID])
+                .await
+                .unwrap();
+            // the renewal is woken up by the new channel
+            tokio::time::timeout(Duration::from_secs(1), wakeup.notified())
+                .await
+                .unwrap();
+            ServerData::renew_websub_subscriptions(&server_data).await;
+            assert_eq!(hub_requests("subscribe"), 1);
+            // waiting for the verification, not renewed again
+            ServerData::renew_websub_subscriptions(&server_data).await;
+            assert_eq!(hub_requests("subscribe"), 1);
+
+            let mut data = server_data.write().await;
+            assert!(!data.verify_websub(websub::Mode::Unsubscribe, &topic, None));
+            // the lease is cut to the requested one
+            assert!(data.verify_websub(websub::Mode::Subscribe, &topic, Some(u32::MAX)));
+            assert!(
+                data.websub_renew_at[CHANNEL_ID] <= Utc::now() + chrono::Duration::seconds(3600)
+            );
+            // verified already, or never requested
+            assert!(!data.verify_websub(websub::Mode::Subscribe, &topic, Some(3600)));
+            assert!(!data.verify_websub(
+                websub::Mode::Subscribe,
+                &websub::topic_url(&new_context(), "UCnot-tracked"),
+                Some(3600)
+            ));
+
+            let body = b"<feed><entry><yt:videoId>websub-pushed-video</yt:videoId></entry></feed>";
+            assert!(!data.handle_websub_notification(CHANNEL_ID, None, body));
+            assert!(!data.handle_websub_notification(
+                CHANNEL_ID,
+                Some(&websub::sign("other-secret", CHANNEL_ID, body)),
+                body
+            ));
+            assert!(!data.yt_videos.ids.contains("websub-pushed-video"));
+            let signature = websub::sign("websub-secret", CHANNEL_ID, body);
+            assert!(data.handle_websub_notification(CHANNEL_ID, Some(&signature), body));
+            assert!(data.yt_videos.ids.contains("websub-pushed-video"));
+
+            data.yt_channels.get_mut(CHANNEL_ID).unwrap().last_time_used =
+                Utc::now() - chrono::Duration::minutes(CONFIG.channel_expire_min + 1);
+            data.update_channel_info().await;
+            assert!(!data.yt_channels.contains_key(CHANNEL_ID));
+            assert_eq!(hub_requests("unsubscribe"), 1);
+            assert!(!data.verify_websub(websub::Mode::Subscribe, &topic, Some(3600)));
+            assert!(data.verify_websub(websub::Mode::Unsubscribe, &topic, None));
+            assert!(!data.verify_websub(websub::Mode::Unsubscribe, &topic, None));
+        });
+    }
+
+    #[test]
+    fn test_tw_eventsub() {
+        const ID: &str = "server-eventsub-test";
+        const LOGIN: &str = "server_eventsub_test";
+        MOCK.add_tw_user(ID, LOGIN, "Server EventSub Test");
+        let subscriptions = || {
+            MOCK.eventsub_subscriptions()
+                .into_iter()
+                .filter(|(_, id)| id == ID)
+                .map(|(t, _)| t)
+                .collect::<HashSet<String>>()
+        };
+        let user = || eventsub::StreamOfflineEvent {
+            broadcaster_user_id: ID.to_string(),
+            broadcaster_user_login: LOGIN.to_string(),
+            broadcaster_user_name: "Server EventSub Test".to_string(),
+        };
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            server_data.write().await.tw_eventsub = Some(crate::TwEventSub {
+                callback_url: "https://127.0.0.1/tw-eventsub".to_string(),
+                secret: "eventsub-test-secret".to_string(),
+            });
+            let wakeup = server_data.read().await.tw_eventsub_wakeup.clone();
+            ServerData::track_new_tw_channels(&server_data, &[LOGIN.to_string()]).await;
+            // the sync is woken up by the new channel
+            tokio::time::timeout(Duration::from_secs(1), wakeup.notified())
+                .await
+                .unwrap();
+            ServerData::sync_tw_eventsub_subscriptions(&server_data).await;
+            assert_eq!(
+                server_data.read().await.tw_eventsub_subscriptions[LOGIN].len(),
+                3
+            );
+            assert_eq!(
+                subscriptions(),
+                HashSet::from(
+                    ["stream.online", "stream.offline", "channel.update"].map(String::from)
+                )
+            );
+
+            let started_at = Utc::now();
+            MOCK.set_tw_stream(
+                LOGIN,
+                Some(MockStream {
+                    title: "Live".to_string(),
+                    game_name: "Just Chatting".to_string(),
+                    started_at,
+                    viewer_count: 10,
+                }),
+            );
+            ServerData::handle_tw_eventsub_event(
+                &server_data,
+                eventsub::Event::StreamOnline(eventsub::StreamOnlineEvent {
+                    broadcaster_user_id: ID.to_string(),
+                    broadcaster_user_login: LOGIN.to_string(),
+                    broadcaster_user_name: "Server EventSub Test".to_string(),
+                    started_at,
+                }),
+            )
+            .await;
+            assert_eq!(server_data.read().await.events.len(), 1);
+            assert_eq!(server_data.read().await.events[0].title, "Live");
+
+            ServerData::handle_tw_eventsub_event(
+                &server_data,
+                eventsub::Event::ChannelUpdate(eventsub::ChannelUpdateEvent {
+                    broadcaster_user_id: ID.to_string(),
+                    broadcaster_user_login: LOGIN.to_string(),
+                    broadcaster_user_name: "Server EventSub Test".to_string(),
+                    title: "New title".to_string(),
+                    category_name: "Art".to_string(),
+                }),
+            )
+            .await;
+            assert_eq!(server_data.read().await.events[0].title, "New title");
+            assert_eq!(server_data.read().await.events[0].description, "Art");
+
+            ServerData::handle_tw_eventsub_event(
+                &server_data,
+                eventsub::Event::StreamOffline(user()),
+            )
+            .await;
+            let mut data = server_data.write().await;
+            assert!(data.events.is_empty());
+            MOCK.set_tw_stream(LOGIN, None);
+
+            let revoked = data.tw_eventsub_subscriptions[LOGIN][0].clone();
+            data.revoke_tw_eventsub(&revoked);
+            assert_eq!(data.tw_eventsub_subscriptions[LOGIN].len(), 2);
+
+            data.tw_channels.get_mut(LOGIN).unwrap().last_time_used =
+                Utc::now() - chrono::Duration::minutes(CONFIG.channel_expire_min + 1);
+            data.update_channel_info().await;
+            assert!(!data.tw_eventsub_subscriptions.contains_key(LOGIN));
+            // the revoked subscription is left to twitch, which drops it on its side
+            assert_eq!(subscriptions().len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_event_changes() {
+        const CHANNEL_ID: &str = "UCserver-event-changes";
+        const STREAM_ID: &str = "server-event-changes-stream";
+        let start = Utc::now() + chrono::Duration::hours(2);
+        MOCK.add_yt_channel(CHANNEL_ID, "ServerEventChanges", "Event Changes");
+        MOCK.add_video(
+            CHANNEL_ID,
+            STREAM_ID,
+            "Stream",
+            vec![
+                VideoState::Upcoming(start),
+                VideoState::Upcoming(start + chrono::Duration::hours(1)),
+                VideoState::Live(start),
+                VideoState::Ended(start, start + chrono::Duration::hours(1)),
+            ],
+        );
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            let mut receiver = server_data.read().await.subscribe_event_changes();
+            let mut next_change = || {
+                let change = receiver.try_recv().unwrap();
+                assert_eq!(change.event().uid, format!("{STREAM_ID}@yt@yt-watcher"));
+                change.name()
+            };
+
+            ServerData::track_new_yt_channels(&server_data, &[CHANNEL_ID])
+                .await
+                .unwrap();
+            let mut data = server_data.into_inner();
+            assert_eq!(next_change(), "added");
+            data.check_upcoming_event(false).await;
+            MOCK.advance_video(STREAM_ID);
+            data.check_upcoming_event(false).await;
+            assert_eq!(next_change(), "updated");
+            MOCK.advance_video(STREAM_ID);
+            data.check_upcoming_event(false).await;
+            assert_eq!(next_change(), "started");
+            MOCK.advance_video(STREAM_ID);
+            data.check_upcoming_event(false).await;
+            assert_eq!(next_change(), "ended");
+            assert!(receiver.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn test_failed_video_request_keeps_events() {
+        const CHANNEL_ID: &str = "UCserver-failed-videos";
+        const LIVE_ID: &str = "server-failed-videos-live";
+        const UPCOMING_ID: &str = "server-failed-videos-upcoming";
+        let start = Utc::now() + chrono::Duration::hours(2);
+        MOCK.add_yt_channel(CHANNEL_ID, "ServerFailedVideos", "Failed Videos");
+        MOCK.add_video(
+            CHANNEL_ID,
+            LIVE_ID,
+            "Live",
+            vec![VideoState::Live(Utc::now())],
+        );
+        MOCK.add_video(
+            CHANNEL_ID,
+            UPCOMING_ID,
+            "Upcoming",
+            vec![VideoState::Upcoming(start)],
+        );
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            ServerData::track_new_yt_channels(&server_data, &[CHANNEL_ID])
+                .await
+                .unwrap();
+            let mut data = server_data.into_inner();
+            assert_eq!(data.events.len(), 2);
+            let by_uid = |events: &[UpcomingEvent]| {
+                let mut events = events.to_vec();
+                events.sort_by(|a, b| a.uid.cmp(&b.uid));
+                events
+            };
+            let events = by_uid(&data.events);
+            let mut receiver = data.subscribe_event_changes();
+
+            MOCK.fail_next_video_request(LIVE_ID);
+            data.check_upcoming_event(false).await;
+            assert_eq!(by_uid(&data.events), events);
+            assert!(data.events.iter().all(|e| !e.ended));
+            assert!(data.cancelled_events.is_empty());
+            assert!(receiver.try_recv().is_err());
+
+            // nothing changes once the request works again
+            data.check_upcoming_event(false).await;
+            assert_eq!(by_uid(&data.events), events);
+            assert!(receiver.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn test_event_sequence() {
+        const CHANNEL_ID: &str = "UCserver-event-sequence";
+        const STREAM_ID: &str = "server-event-sequence-stream";
+        let start = Utc::now() + chrono::Duration::hours(2);
+        MOCK.add_yt_channel(CHANNEL_ID, "ServerEventSequence", "Event Sequence");
+        MOCK.add_video(
+            CHANNEL_ID,
+            STREAM_ID,
+            "Stream",
+            vec![
+                VideoState::Upcoming(start),
+                VideoState::Upcoming(start + chrono::Duration::hours(1)),
+                VideoState::Upload,
+            ],
+        );
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            ServerData::track_new_yt_channels(&server_data, &[CHANNEL_ID])
+                .await
+                .unwrap();
+            let mut data = server_data.into_inner();
+            let found = data.events[0].clone();
+            assert_eq!(found.sequence, 0);
+            assert!(found.last_modified.is_some());
+            data.check_upcoming_event(false).await;
+            assert_eq!(data.events[0].sequence, 0);
+            assert_eq!(data.events[0].last_modified, found.last_modified);
+
+            MOCK.advance_video(STREAM_ID);
+            data.check_upcoming_event(false).await;
+            assert_eq!(data.events[0].sequence, 1);
+
+            // no longer a stream, so cancelled
+            MOCK.advance_video(STREAM_ID);
+            data.check_upcoming_event(false).await;
+            assert!(data.events.is_empty());
+            assert_eq!(data.cancelled_events.len(), 1);
+            assert_eq!(data.cancelled_events[0].sequence, 2);
+
+            // back after it was cancelled
+            data.set_events(vec![found.clone()]);
+            assert!(data.cancelled_events.is_empty());
+            assert_eq!(data.events[0].sequence, 3);
+
+            // forgotten a day after its start
+            data.set_events(vec![]);
+            data.cancelled_events[0].start_date_time = Utc::now() - chrono::Duration::days(2);
+            data.set_events(vec![]);
+            assert!(data.cancelled_events.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_event_sequence_after_restart() {
+        const CHANNEL_ID: &str = "UCserver-sequence-restart";
+        const STREAM_ID: &str = "server-sequence-restart-stream";
+        let start = Utc::now() + chrono::Duration::hours(2);
+        MOCK.add_yt_channel(CHANNEL_ID, "ServerSequenceRestart", "Sequence Restart");
+        MOCK.add_video(
+            CHANNEL_ID,
+            STREAM_ID,
+            "Stream",
+            vec![
+                VideoState::Upcoming(start),
+                VideoState::Upcoming(start + chrono::Duration::hours(1)),
+                VideoState::Upload,
+            ],
+        );
+        TOKIO_RUNTIME.block_on(async {
+            let context = Arc::new(AppContext::new(
+                crate::context::http_client(),
+                MOCK.upstream(),
+                Arc::new(crate::storage::SqliteStorage::open_in_memory().unwrap()),
+                quota::Quota::new(
+                    vec![crate::mock_upstream::API_KEY.to_string()],
+                    quota::DEFAULT_DAILY_BUDGET,
+                ),
+                crate::paths::DataPaths::in_dir(&std::env::temp_dir()),
+            ));
+            let server_data = RwLock::new(server_data_with_context(context.clone()).await);
+            ServerData::track_new_yt_channels(&server_data, &[CHANNEL_ID])
+                .await
+                .unwrap();
+            let mut data = server_data.into_inner();
+            MOCK.advance_video(STREAM_ID);
+            data.check_upcoming_event(false).await;
+            let rescheduled = data.events[0].clone();
+            assert_eq!(rescheduled.sequence, 1);
+
+            // the sequence continues instead of starting over
+            let mut restarted = server_data_with_context(context.clone()).await;
+            restarted.restore().await;
+            restarted.check_upcoming_event(false).await;
+            assert_eq!(restarted.events[0].sequence, 1);
+            assert_eq!(restarted.events[0].last_modified, rescheduled.last_modified);
+            assert!(restarted.saved_events.is_empty());
+
+            // the cancelled events stay in the calendars
+            MOCK.advance_video(STREAM_ID);
+            restarted.check_upcoming_event(false).await;
+            assert_eq!(restarted.cancelled_events.len(), 1);
+            let mut restarted = server_data_with_context(context).await;
+            restarted.restore().await;
+            assert_eq!(restarted.cancelled_events.len(), 1);
+            assert_eq!(restarted.cancelled_events[0].sequence, 2);
+        });
+    }
+
+    #[test]
+    fn test_ended_event_retention() {
+        const CHANNEL_ID: &str = "UCserver-ended-retention";
+        const STREAM_ID: &str = "server-ended-retention-stream";
+        const LOGIN: &str = "server_ended_retention";
+        let start = Utc::now() - chrono::Duration::hours(2);
+        let end = Utc::now() - chrono::Duration::minutes(10);
+        MOCK.add_yt_channel(CHANNEL_ID, "ServerEndedRetention", "Ended Retention");
+        MOCK.add_video(
+            CHANNEL_ID,
+            STREAM_ID,
+            "Stream",
+            vec![VideoState::Live(start), VideoState::Ended(start, end)],
+        );
+        MOCK.add_tw_user("server-ended-retention", LOGIN, "Ended Retention");
+        MOCK.set_tw_stream(
+            LOGIN,
+            Some(MockStream {
+                title: "Live".to_string(),
+                game_name: "Just Chatting".to_string(),
+                started_at: start,
+                viewer_count: 10,
+            }),
+        );
+        TOKIO_RUNTIME.block_on(async {
+            let mut data = new_server_data().await;
+            data.ended_event_retention_min = 60;
+            let server_data = RwLock::new(data);
+            ServerData::track_new_yt_channels(&server_data, &[CHANNEL_ID])
+                .await
+                .unwrap();
+            ServerData::track_new_tw_channels(&server_data, &[LOGIN.to_string()]).await;
+            let mut data = server_data.into_inner();
+            assert_eq!(data.events.len(), 2);
+            assert!(data.events.iter().all(|e| e.ongoing && !e.ended));
+
+            MOCK.advance_video(STREAM_ID);
+            MOCK.set_tw_stream(LOGIN, None);
+            data.check_upcoming_event(false).await;
+            assert_eq!(data.events.len(), 2);
+            assert!(data.events.iter().all(|e| !e.ongoing && e.ended));
+            let yt_event = data
+                .events
+                .iter()
+                .find(|e| e.uid == format!("{STREAM_ID}@yt@yt-watcher"))
+                .unwrap();
+            assert_eq!(
+                yt_event.end_date_time.map(|t| t.timestamp()),
+                Some(end.timestamp())
+            );
+            let tw_event = data
+                .events
+                .iter()
+                .find(|e| e.uid == format!("{LOGIN}@twitch@yt-watcher"))
+                .unwrap();
+            assert!(tw_event.end_date_time.unwrap() > end);
+
+            // the youtube stream ended 10 minutes ago
+            data.ended_event_retention_min = 5;
+            data.check_upcoming_event(false).await;
+            assert_eq!(data.events.len(), 1);
+            assert_eq!(data.events[0].uid, format!("{LOGIN}@twitch@yt-watcher"));
+        });
+    }
+
+    #[test]
+    fn test_history() {
+        const CHANNEL_ID: &str = "UCserver-history";
+        const STREAM_ID: &str = "server-history-stream";
+        let start = Utc::now() - chrono::Duration::hours(2);
+        let end = Utc::now() - chrono::Duration::minutes(10);
+        MOCK.add_yt_channel(CHANNEL_ID, "ServerHistory", "History");
+        MOCK.add_video(
+            CHANNEL_ID,
+            STREAM_ID,
+            "Stream",
+            vec![VideoState::Live(start), VideoState::Ended(start, end)],
+        );
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            ServerData::track_new_yt_channels(&server_data, &[CHANNEL_ID])
+                .await
+                .unwrap();
+            let mut data = server_data.into_inner();
+            MOCK.advance_video(STREAM_ID);
+            data.check_upcoming_event(false).await;
+            // dropped from the events without retention, but archived
+            assert!(data.events.is_empty());
+            let query = history::HistoryQuery::parse(
+                &HashMap::new(),
+                vec![CHANNEL_ID.to_string()],
+                vec![],
+                history::DEFAULT_PAGE_SIZE,
+                history::MAX_PAGE_SIZE,
+            )
+            .unwrap();
+            let page = data.history.query(&query);
+            assert_eq!(page.total, 1);
+            let record = &page.records[0];
+            assert_eq!(record.uid, format!("{STREAM_ID}@yt@yt-watcher"));
+            assert_eq!(record.peak_viewers, Some(1000));
+            assert_eq!(
+                record.scheduled_start.map(|t| t.timestamp()),
+                Some(start.timestamp())
+            );
+            assert_eq!(record.end.map(|t| t.timestamp()), Some(end.timestamp()));
+            assert_eq!(record.duration_seconds, Some((end - start).num_seconds()));
+
+            let mut restored = server_data_with_context(data.context.clone()).await;
+            restored.restore().await;
+            assert_eq!(restored.history.query(&query).records, page.records);
+        });
+    }
+
+    #[test]
+    fn test_check_tw_upcoming_event() {
+        const LOGIN: &str = "server_tw_test";
+        MOCK.add_tw_user("server-tw-test", LOGIN, "Server Twitch Test");
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            ServerData::track_new_tw_channels(&server_data, &[LOGIN.to_string()]).await;
+            let mut data = server_data.into_inner();
+            assert!(data.tw_channels.contains_key(LOGIN));
+            assert!(data.events.is_empty());
+
+            MOCK.set_tw_stream(
+                LOGIN,
+                Some(MockStream {
+                    title: "Live".to_string(),
+                    game_name: "Just Chatting".to_string(),
+                    started_at: Utc::now(),
+                    viewer_count: 10,
+                }),
+            );
+            data.check_upcoming_event(false).await;
+            assert_eq!(data.events.len(), 1);
+            assert!(data.events[0].ongoing);
+
+            MOCK.set_tw_stream(LOGIN, None);
+            data.check_upcoming_event(false).await;
+            assert!(data.events.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_sync_endpoints() {
+        TOKIO_RUNTIME.block_on(async {
+            let context = new_context();
+            let routes = routes(
+                Arc::new(RwLock::new(server_data_with_context(context.clone()).await)),
+                context.clone(),
+                None,
+                None,
+                webhook::WebhookPolicy::default(),
+                None,
+            );
+            let get = |path: String| {
+                let routes = routes.clone();
+                async move {
+                    let response = warp::test::request().path(&path).reply(&routes).await;
+                    serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+                }
+            };
+
+            let key = get("/sync/new".to_string()).await["key"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            let pushed = get(format!("/sync/push?key={key}&tw-ch=sync_a,sync_b")).await;
+            assert_eq!(pushed["result"], "Ok");
+            let pulled = get(format!("/sync/pull?key={key}")).await;
+            let mut tw_ch = pulled["tw_ch"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v.as_str().unwrap())
+                .collect::<Vec<&str>>();
+            tw_ch.sort();
+            assert_eq!(tw_ch, ["sync_a", "sync_b"]);
+            assert_eq!(
+                context
+                    .sync_store
+                    .get_tw_channel(&uuid::Uuid::from_str(&key).unwrap())
+                    .await
+                    .map(|c| c.len()),
+                Some(2)
+            );
+            assert!(get("/sync/pull?key=unknown".to_string())
+                .await
+                .as_object()
+                .unwrap()
+                .is_empty());
+        });
+    }
+
+    #[test]
+    fn test_tw_eventsub_body_limit() {
+        TOKIO_RUNTIME.block_on(async {
+            let context = new_context();
+            let routes = routes(
+                Arc::new(RwLock::new(server_data_with_context(context.clone()).await)),
+                context,
+                Some("eventsub-limit-secret".to_string()),
+                None,
+                webhook::WebhookPolicy::default(),
+                None,
+            );
+            let response = warp::test::request()
+                .method("POST")
+                .path("/tw-eventsub")
+                .body(vec![b' '; eventsub::MAX_MESSAGE_BYTES as usize + 1])
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 413);
+        });
+    }
+
+    #[test]
+    fn test_calendar_unknown_timezone() {
+        MOCK.add_tw_user("server-calendar-tz", "calendar_tz", "Calendar Timezone");
+        TOKIO_RUNTIME.block_on(async {
+            let context = new_context();
+            let server_data =
+                Arc::new(RwLock::new(server_data_with_context(context.clone()).await));
+            let routes = routes(
+                server_data.clone(),
+                context,
+                None,
+                None,
+                webhook::WebhookPolicy::default(),
+                None,
+            );
+            let response = warp::test::request()
+                .path("/cal?tw-ch=calendar_tz&tz=Mars/Olympus")
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 400);
+            assert!(!server_data
+                .read()
+                .await
+                .tw_channels
+                .contains_key("calendar_tz"));
+
+            let response = warp::test::request()
+                .path("/cal?tw-ch=calendar_tz&tz=Asia/Tokyo")
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 200);
+            assert!(server_data
+                .read()
+                .await
+                .tw_channels
+                .contains_key("calendar_tz"));
+        });
+    }
+
+    #[test]
+    fn test_api_v1() {
+        MOCK.add_yt_channel("UCapi-v1-test", "ApiV1Test", "Api V1 Test");
+        TOKIO_RUNTIME.block_on(async {
+            // no api key, so every youtube api request exceeds the quota
+            let context = Arc::new(AppContext::new(
+                crate::context::http_client(),
+                MOCK.upstream(),
+                Arc::new(crate::storage::MemoryStorage::default()),
+                quota::Quota::new(vec![], quota::DEFAULT_DAILY_BUDGET),
+                crate::paths::DataPaths::in_dir(&std::env::temp_dir()),
+            ));
+            let routes = routes(
+                Arc::new(RwLock::new(server_data_with_context(context.clone()).await)),
+                context.clone(),
+                None,
+                None,
+                webhook::WebhookPolicy::default(),
+                None,
+            );
+            let get = |path: String| {
+                let routes = routes.clone();
+                async move {
+                    let response = warp::test::request().path(&path).reply(&routes).await;
+                    (
+                        response.status().as_u16(),
+                        serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+                    )
+                }
+            };
+
+            let (status, body) = get("/api/v1/yt-ch".to_string()).await;
+            assert_eq!(status, 400);
+            assert_eq!(body["error"]["code"], "invalid_parameter");
+            assert_eq!(body["error"]["details"]["parameter"], "q");
+            assert_eq!(
+                get("/api/v1/yt-ch?q=@NoSuchChannel".to_string()).await.0,
+                404
+            );
+            let (status, body) = get("/api/v1/yt-ch?q=@ApiV1Test".to_string()).await;
+            assert_eq!(status, 429);
+            assert!(body["error"]["details"]["retry_after"].is_u64());
+            // the unversioned endpoint keeps answering with a status 200
+            let (status, body) = get("/yt-ch?q=@ApiV1Test".to_string()).await;
+            assert_eq!(status, 200);
+            assert!(body["error"].is_string());
+
+            let key = get("/api/v1/sync/new".to_string()).await.1["key"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            assert_eq!(
+                get(format!("/api/v1/sync/push?key={key}&tw-ch=a")).await.0,
+                200
+            );
+            assert_eq!(
+                get(format!("/api/v1/sync/pull?key={key}")).await.1["tw_ch"][0],
+                "a"
+            );
+            assert_eq!(
+                get("/api/v1/sync/pull?key=invalid".to_string()).await.0,
+                400
+            );
+            let unknown = uuid::Uuid::new_v4();
+            assert_eq!(get(format!("/api/v1/sync/pull?key={unknown}")).await.0, 404);
+            assert_eq!(
+                get(format!("/api/v1/sync/webhook/add?key={key}&url=ftp://a"))
+                    .await
+                    .0,
+                400
+            );
+            assert_eq!(get("/api/v1/history?format=xml".to_string()).await.0, 400);
+            assert_eq!(get("/history?format=xml".to_string()).await.0, 400);
+
+            let (status, body) = get("/api/v1/data".to_string()).await;
+            assert_eq!(status, 200);
+            assert!(body.is_array());
+            assert!(get("/api/v1/quota".to_string()).await.1["keys"].is_array());
+            let (status, body) = get("/api/v1/unknown".to_string()).await;
+            assert_eq!(status, 404);
+            assert_eq!(body["error"]["code"], "not_found");
+        });
+    }
+
+    #[test]
+    fn test_admin_endpoint() {
+        MOCK.add_tw_user("9100", "admin_endpoint", "Admin Endpoint");
+        TOKIO_RUNTIME.block_on(async {
+            let context = new_context();
+            let server_data =
+                Arc::new(RwLock::new(server_data_with_context(context.clone()).await));
+            let routes = routes(
+                server_data.clone(),
+                context.clone(),
+                None,
+                Some("admin-secret".to_string()),
+                webhook::WebhookPolicy::default(),
+                None,
+            );
+            let post = |token: &str, body: &str| {
+                warp::test::request()
+                    .method("POST")
+                    .path("/admin")
+                    .header("authorization", format!("Bearer {token}"))
+                    .body(body)
+                    .reply(&routes)
+            };
+
+            assert_eq!(post("wrong", r#"{"command":"quota"}"#).await.status(), 401);
+            let large = " ".repeat(admin::MAX_COMMAND_BYTES as usize + 1);
+            assert_eq!(post("admin-secret", &large).await.status(), 413);
+            let response = post(
+                "admin-secret",
+                r#"{"command":"track-tw","login":"admin_endpoint"}"#,
+            )
+            .await;
+            assert_eq!(response.status(), 200);
+            let body = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+            assert_eq!(body["data"]["name"], "Admin Endpoint");
+            assert!(server_data
+                .read()
+                .await
+                .tw_channels
+                .contains_key("admin_endpoint"));
+
+            let response = post(
+                "admin-secret",
+                &format!(
+                    r#"{{"command":"revoke-sync-key","key":"{}"}}"#,
+                    uuid::Uuid::new_v4()
+                ),
+            )
+            .await;
+            assert_eq!(response.status(), 400);
+            let body = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+            assert!(body["error"].is_string());
+            assert_eq!(
+                post("admin-secret", r#"{"command":"unknown"}"#)
+                    .await
+                    .status(),
+                400
+            );
+
+            // the endpoint is disabled without a token
+            let routes = super::routes(
+                server_data,
+                context,
+                None,
+                None,
+                webhook::WebhookPolicy::default(),
+                None,
+            );
+            let response = warp::test::request()
+                .method("POST")
+                .path("/admin")
+                .body(r#"{"command":"quota"}"#)
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 404);
+        });
+    }
+
+    #[test]
+    fn test_apply_config() {
+        TOKIO_RUNTIME.block_on(async {
+            let server_data = RwLock::new(new_server_data().await);
+            let mut new = CONFIG.clone();
+            new.api_keys = vec!["rotated-key".to_string()];
+            new.twitch_key = None;
+            new.youtube_quota_budget = Some(5);
+            ServerData::apply_config(&server_data, &CONFIG, &new).await;
+            let data = server_data.read().await;
+            let status = data.context.quota.status();
+            assert_eq!(
+                status
+                    .keys
+                    .iter()
+                    .map(|k| k.key.as_str())
+                    .collect::<Vec<&str>>(),
+                [
+                    quota::key_id(crate::mock_upstream::API_KEY),
+                    quota::key_id("rotated-key")
+                ]
+            );
+            assert!(data.tw_client.is_none());
+            assert_eq!(status.budget, 10);
+            drop(data);
+
+            ServerData::apply_config(&server_data, &new, &CONFIG).await;
+            let data = server_data.read().await;
+            assert_eq!(data.context.quota.status().keys.len(), 1);
+            assert!(data.tw_client.is_some());
+        });
+    }
+
+    #[test]
+    fn test_server_shutdown() {
+        let context = new_context();
+        let key = TOKIO_RUNTIME.block_on(async {
+            let key = context.sync_store.new_key().await;
+            let webhook = context
+                .sync_store
+                .add_webhook(&key, "https://example.com/hook", WebhookKind::Json)
+                .await
+                .unwrap();
+            // the deliveries are saved with the next change, or on shutdown
+            context
+                .sync_store
+                .record_delivery(
+                    &key,
+                    &webhook,
+                    crate::sync::Delivery {
+                        time: Utc::now(),
+                        event_uid: "uid".to_string(),
+                        change: "started".to_string(),
+                        attempts: 1,
+                        status: Some(204),
+                        error: None,
+                    },
+                )
+                .await;
+            let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+            let (_config_sender, config_receiver) = tokio::sync::watch::channel(CONFIG.clone());
+            let server = server_start(config_receiver, context.clone(), async {
+                let _ = stop_receiver.await;
+            });
+            let stop = async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                stop_sender.send(()).unwrap();
+            };
+            tokio::time::timeout(Duration::from_secs(5), futures::future::join(server, stop))
+                .await
+                .expect("The server didn't shut down");
+            key
+        });
+        let keys = context.storage.load_sync_keys().unwrap();
+        let mut saved = keys.into_iter().find(|k| *k.key() == key).unwrap();
+        assert_eq!(saved.webhooks()[0].deliveries.len(), 1);
+    }
 }
@@ -0,0 +1,238 @@
+//! Administrative commands, run by the command line either through the `/admin` endpoint of a
+//! running server or, when no server is running, on the stores directly.
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use super::{ChannelSave, ServerData};
+use crate::{context::AppContext, yt_api::try_youtube_id};
+
+/// Commands are a small json object, larger bodies are refused
+pub const MAX_COMMAND_BYTES: u64 = 16 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum AdminCommand {
+    /// `channel` is a channel id, a handle or the url of a channel
+    TrackYt {
+        channel: String,
+    },
+    TrackTw {
+        login: String,
+    },
+    UntrackYt {
+        channel: String,
+    },
+    UntrackTw {
+        login: String,
+    },
+    ListChannels,
+    RefreshNow,
+    ListSyncKeys,
+    RevokeSyncKey {
+        key: Uuid,
+    },
+    ExportSyncKeys,
+    Quota,
+}
+
+impl AdminCommand {
+    /// Whether the command only makes sense on a running server
+    pub fn needs_server(&self) -> bool {
+        matches!(self, AdminCommand::RefreshNow)
+    }
+}
+
+/// Whether the `authorization` header carries `token` as a bearer token. The token is compared
+/// in constant time, so the time of the check doesn't reveal how much of it was guessed.
+pub fn is_authorized(authorization: Option<&str>, token: &str) -> bool {
+    authorization
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .is_some_and(|t| bool::from(t.as_bytes().ct_eq(token.as_bytes())))
+}
+
+pub async fn execute(
+    server_data: &RwLock<ServerData>,
+    ctx: &AppContext,
+    command: AdminCommand,
+) -> Result<Value, String> {
+    log::info!("Admin command: {command:?}");
+    match command {
+        AdminCommand::TrackYt { channel } => {
+            let id = try_youtube_id(ctx, &channel).await;
+            if !server_data.read().await.yt_channels.contains_key(&id) {
+                ServerData::track_new_yt_channels(server_data, &[id.as_str()])
+                    .await
+                    .map_err(|e| format!("Track channel {id} failed: {e:?}"))?;
+            }
+            let mut data = server_data.write().await;
+            data.touch_yt_channel(&id);
+            let channel = data
+                .yt_channels
+                .get(&id)
+                .ok_or_else(|| format!("Channel {channel} is not found"))?;
+            Ok(json!({ "id": channel.id, "title": channel.title }))
+        }
+        AdminCommand::TrackTw { login } => {
+            let (configured, tracked) = {
+                let data = server_data.read().await;
+                (
+                    data.tw_client.is_some(),
+                    data.tw_channels.contains_key(&login),
+                )
+            };
+            if !configured {
+                return Err("Twitch is not configured".to_string());
+            }
+            if !tracked {
+                ServerData::track_new_tw_channels(server_data, std::slice::from_ref(&login)).await;
+            }
+            let mut data = server_data.write().await;
+            data.touch_tw_channel(&login);
+            let channel = data
+                .tw_channels
+                .get(&login)
+                .ok_or_else(|| format!("Twitch channel {login} is not found"))?;
+            Ok(json!({ "login": channel.login, "name": channel.name }))
+        }
+        AdminCommand::UntrackYt { channel } => {
+            let id = try_youtube_id(ctx, &channel).await;
+            match server_data.write().await.untrack_yt_channel(&id).await {
+                true => Ok(json!({ "id": id })),
+                false => Err(format!("Channel {channel} is not tracked")),
+            }
+        }
+        AdminCommand::UntrackTw { login } => {
+            match server_data.write().await.untrack_tw_channel(&login).await {
+                true => Ok(json!({ "login": login })),
+                false => Err(format!("Twitch channel {login} is not tracked")),
+            }
+        }
+        AdminCommand::ListChannels => {
+            let data = server_data.read().await;
+            serde_json::to_value(ChannelSave {
+                yt: data.yt_channels.clone(),
+                tw: data.tw_channels.clone(),
+            })
+            .map_err(|e| e.to_string())
+        }
+        AdminCommand::RefreshNow => {
+            let mut data = server_data.write().await;
+            data.check_upcoming_event(true).await;
+            Ok(json!({ "events": data.events.len() }))
+        }
+        AdminCommand::ListSyncKeys => {
+            serde_json::to_value(ctx.sync_store.summaries().await).map_err(|e| e.to_string())
+        }
+        AdminCommand::RevokeSyncKey { key } => match ctx.sync_store.revoke(&key).await {
+            true => Ok(json!({ "key": key })),
+            false => Err(format!("Sync key {key} doesn't exist")),
+        },
+        AdminCommand::ExportSyncKeys => {
+            serde_json::to_value(ctx.sync_store.export().await).map_err(|e| e.to_string())
+        }
+        AdminCommand::Quota => serde_json::to_value(ctx.quota.status()).map_err(|e| e.to_string()),
+    }
+}
+
+/// Run `command` on the stores of `ctx` while no server is running, and save them afterwards
+pub async fn execute_locally(
+    config: &crate::Config,
+    ctx: Arc<AppContext>,
+    command: AdminCommand,
+) -> Result<Value, String> {
+    if command.needs_server() {
+        return Err("The command needs a running server".to_string());
+    }
+    // the twitch client is only built when needed, which requests a token
+    let twitch_key = match command {
+        AdminCommand::TrackTw { .. } => config.twitch_key.clone(),
+        _ => None,
+    };
+    let server_data = RwLock::new(
+        ServerData::new(
+            ctx.clone(),
+            config.channel_expire_min,
+            config.ended_event_retention_min.unwrap_or(0),
+            &twitch_key,
+        )
+        .await?,
+    );
+    server_data.write().await.restore().await;
+    let result = execute(&server_data, &ctx, command).await;
+    ctx.shutdown().await;
+    server_data.read().await.save().await;
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_is_authorized() {
+        assert!(is_authorized(Some("Bearer admin-secret"), "admin-secret"));
+        assert!(!is_authorized(Some("Bearer admin-secreT"), "admin-secret"));
+        assert!(!is_authorized(Some("Bearer admin"), "admin-secret"));
+        assert!(!is_authorized(Some("admin-secret"), "admin-secret"));
+        assert!(!is_authorized(None, "admin-secret"));
+    }
+
+    #[test]
+    fn test_execute_locally() {
+        const CHANNEL_ID: &str = "UCadmin-local-test";
+        MOCK.add_yt_channel(CHANNEL_ID, "AdminLocalTest", "Admin Local Test");
+        TOKIO_RUNTIME.block_on(async {
+            let context = new_context();
+            let run = |command| execute_locally(&CONFIG, context.clone(), command);
+
+            let tracked = run(AdminCommand::TrackYt {
+                channel: CHANNEL_ID.to_string(),
+            })
+            .await
+            .unwrap();
+            assert_eq!(tracked["title"], "Admin Local Test");
+            assert!(context
+                .storage
+                .load_channels()
+                .unwrap()
+                .yt
+                .contains_key(CHANNEL_ID));
+            let channels = run(AdminCommand::ListChannels).await.unwrap();
+            assert!(channels["yt"][CHANNEL_ID].is_object());
+
+            run(AdminCommand::UntrackYt {
+                channel: CHANNEL_ID.to_string(),
+            })
+            .await
+            .unwrap();
+            assert!(context.storage.load_channels().unwrap().yt.is_empty());
+            assert!(run(AdminCommand::UntrackYt {
+                channel: CHANNEL_ID.to_string(),
+            })
+            .await
+            .is_err());
+            assert!(run(AdminCommand::RefreshNow).await.is_err());
+
+            let mut config = CONFIG.clone();
+            if let Some(key) = &mut config.twitch_key {
+                key.client_secret = "wrong-secret".to_string();
+            }
+            let error = execute_locally(
+                &config,
+                context.clone(),
+                AdminCommand::TrackTw {
+                    login: "admin_local_test".to_string(),
+                },
+            )
+            .await
+            .unwrap_err();
+            assert!(error.starts_with("Incorrect Twitch app key"));
+        });
+    }
+}
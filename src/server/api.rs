@@ -0,0 +1,697 @@
+//! The versioned json api under `/api/v1/`. Its endpoints take the same parameters and return
+//! the same data as the unversioned ones, which are kept as aliases for the existing clients,
+//! but a failed request gets an error status and an [`ApiError`] body instead of a status 200.
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::{filters::BoxedFilter, http::StatusCode, hyper::Response, Filter};
+
+use super::{
+    history, subscription::Subscription, webhook::WebhookPolicy, ChannelInfoData, ServerData,
+    TwChannelSave,
+};
+use crate::{
+    context::AppContext,
+    sync::{Webhook, WebhookKind},
+    tw_api::{validate_user_login, UserIdentity},
+    yt_api::{get_channel_id_by_url, quota, try_youtube_id, validate_custom_url, YtApiError},
+};
+
+pub type ApiResponse = warp::http::Result<Response<String>>;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// A parameter is missing or malformed
+    InvalidParameter,
+    NotFound,
+    /// The youtube api quota of every key is used up until the quota resets
+    QuotaExceeded,
+    /// Youtube or twitch failed or answered something unexpected
+    UpstreamFailed,
+    /// Youtube or twitch can't be reached, or isn't configured
+    Unavailable,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidParameter => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::UpstreamFailed => StatusCode::BAD_GATEWAY,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// The error of a failed request, sent in an [`ApiErrorBody`]
+#[derive(Debug, Serialize, Clone, PartialEq, ToSchema)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<ErrorDetails>,
+    /// Seconds until the request can succeed, sent as the `Retry-After` header
+    #[serde(skip)]
+    retry_after: Option<u64>,
+}
+
+/// What an [`ApiError`] is about, depending on its code
+#[derive(Debug, Serialize, Clone, PartialEq, Default, ToSchema)]
+pub struct ErrorDetails {
+    /// The missing or malformed parameter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameter: Option<String>,
+    /// The status youtube or twitch responded with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_status: Option<u16>,
+    /// Seconds until the quota resets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// Why the response of youtube couldn't be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+            retry_after: None,
+        }
+    }
+
+    fn with_details(mut self, details: ErrorDetails) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// Prefix the message with what was being done
+    fn context(mut self, action: &str) -> Self {
+        self.message = format!("{action}: {}", self.message);
+        self
+    }
+
+    pub fn missing(parameter: &str) -> Self {
+        Self::invalid(parameter, format!("No {parameter} specified"))
+    }
+
+    pub fn invalid(parameter: &str, message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidParameter, message).with_details(ErrorDetails {
+            parameter: Some(parameter.to_string()),
+            ..Default::default()
+        })
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    /// The error of a failed request to twitch
+    pub fn from_twitch(e: &reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) if status.as_u16() == 404 => Self::not_found("Not found on twitch"),
+            Some(status) => Self::new(
+                ErrorCode::UpstreamFailed,
+                format!("Twitch responded with status {status}"),
+            )
+            .with_details(ErrorDetails {
+                upstream_status: Some(status.as_u16()),
+                ..Default::default()
+            }),
+            None if e.is_connect() || e.is_timeout() => {
+                Self::new(ErrorCode::Unavailable, "Twitch is unreachable")
+            }
+            None => Self::new(
+                ErrorCode::UpstreamFailed,
+                format!("Twitch request failed: {e}"),
+            ),
+        }
+    }
+
+    pub fn into_response(self) -> ApiResponse {
+        let mut response = Response::builder()
+            .status(self.code.status())
+            .header("Content-Type", "application/json");
+        if let Some(seconds) = self.retry_after {
+            response = response.header("Retry-After", seconds);
+        }
+        response.body(serde_json::to_string(&ApiErrorBody { error: self }).unwrap_or_default())
+    }
+}
+
+/// The body of a request of the versioned api which failed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiErrorBody {
+    pub error: ApiError,
+}
+
+/// The error of the unversioned endpoints which report one
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LegacyError {
+    pub error: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NewSyncKey {
+    pub key: Uuid,
+}
+
+/// The result of a change. The unversioned endpoints report their failures in it too.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChangeResult {
+    /// `Ok` when the change is made
+    pub result: String,
+    /// The id of the added webhook
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+}
+
+impl ChangeResult {
+    pub fn new(result: &str) -> Self {
+        Self {
+            result: result.to_string(),
+            id: None,
+        }
+    }
+}
+
+/// The channels of a sync key, absent when the key is not found
+#[derive(Debug, Serialize, ToSchema, Default)]
+pub struct SyncChannels {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yt_ch: Option<HashSet<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tw_ch: Option<HashSet<String>>,
+}
+
+impl From<YtApiError> for ApiError {
+    fn from(e: YtApiError) -> Self {
+        match e {
+            YtApiError::InvalidParameter => {
+                Self::new(ErrorCode::InvalidParameter, "Invalid youtube api parameter")
+            }
+            YtApiError::NotFound => Self::not_found("Not found on youtube"),
+            YtApiError::QuotaExceeded => {
+                let seconds = quota::until_reset(Utc::now()).num_seconds().max(0) as u64;
+                Self {
+                    retry_after: Some(seconds),
+                    ..Self::new(
+                        ErrorCode::QuotaExceeded,
+                        "The youtube api quota of today is used up",
+                    )
+                    .with_details(ErrorDetails {
+                        retry_after: Some(seconds),
+                        ..Default::default()
+                    })
+                }
+            }
+            YtApiError::RequestFailed(Some(status)) if status.as_u16() == 404 => {
+                Self::not_found("Not found on youtube")
+            }
+            YtApiError::RequestFailed(Some(status)) => Self::new(
+                ErrorCode::UpstreamFailed,
+                format!("Youtube responded with status {status}"),
+            )
+            .with_details(ErrorDetails {
+                upstream_status: Some(status.as_u16()),
+                ..Default::default()
+            }),
+            YtApiError::RequestFailed(None) => {
+                Self::new(ErrorCode::Unavailable, "Youtube is unreachable")
+            }
+            YtApiError::DeserializeFailed(reason) => Self::new(
+                ErrorCode::UpstreamFailed,
+                "Youtube returned an unexpected response",
+            )
+            .with_details(ErrorDetails {
+                reason: Some(reason),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// `data` as json, or the error with its status
+pub fn reply(result: Result<impl Serialize, ApiError>) -> ApiResponse {
+    match result {
+        Ok(data) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&data).unwrap_or_default()),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Find the youtube channel of the `q` parameter, which is tracked if it is new
+pub async fn yt_channel_info(
+    server_data: &RwLock<ServerData>,
+    ctx: &AppContext,
+    query: &HashMap<String, String>,
+) -> Result<ChannelInfoData, ApiError> {
+    let url = query.get("q").ok_or_else(|| ApiError::missing("q"))?;
+    if !validate_custom_url(
+        url.trim_start_matches("https://www.youtube.com/@")
+            .trim_start_matches("https://www.youtube.com/channel/")
+            .trim_start_matches("https://youtube.com/@")
+            .trim_start_matches("https://youtube.com/channel/")
+            .trim_start_matches('@')
+            .trim_end_matches("/featured"),
+    ) {
+        return Err(ApiError::invalid(
+            "q",
+            "The query string does not look like a youtube channel",
+        ));
+    }
+    let id = get_channel_id_by_url(
+        ctx,
+        &format!(
+            "{}/channel/{}",
+            ctx.upstream.youtube,
+            try_youtube_id(ctx, url).await
+        ),
+    )
+    .await
+    .map_err(|e| ApiError::from(e).context("Failed to get channel id"))?;
+    if !server_data.read().await.yt_channels.contains_key(&id) {
+        if let Err(e) = ServerData::track_new_yt_channels(server_data, &[&id]).await {
+            log::error!("Track new youtube channel failed: {:?}", e);
+            return Err(ApiError::from(e).context("Track new youtube channel failed"));
+        }
+    }
+    let mut server_data = server_data.write().await;
+    let channel_save = server_data
+        .yt_channels
+        .get(&id)
+        .ok_or_else(|| ApiError::not_found(format!("Channel {id} is not found")))?;
+    let info = ChannelInfoData {
+        id: id.clone(),
+        title: channel_save.title.clone(),
+        custom_url: channel_save.custom_url.clone(),
+        thumbnail: channel_save.thumbnail.clone(),
+    };
+    server_data.touch_yt_channel(&id);
+    Ok(info)
+}
+
+/// Find the twitch channel of the `q` parameter, which is tracked if it is new
+pub async fn tw_channel_info(
+    server_data: &RwLock<ServerData>,
+    query: &HashMap<String, String>,
+) -> Result<ChannelInfoData, ApiError> {
+    let login = query
+        .get("q")
+        .ok_or_else(|| ApiError::missing("q"))?
+        .trim_start_matches("https://www.twitch.tv/")
+        .to_string();
+    if !validate_user_login(&login) {
+        return Err(ApiError::invalid(
+            "q",
+            "The query string does not look like a twitch user login",
+        ));
+    }
+    let search_result = if let Some(client) = &mut server_data.write().await.tw_client {
+        client
+            .get_user_info(&[UserIdentity::Login(login)])
+            .await
+            .map_err(|e| {
+                log::error!("Search channel failed: {e}");
+                ApiError::from_twitch(&e).context("Search channel failed")
+            })?
+    } else {
+        log::error!("Twitch client is not initialized");
+        return Err(ApiError::new(
+            ErrorCode::Unavailable,
+            "Twitch client is not initialized",
+        ));
+    };
+    let Some(c) = search_result.first() else {
+        log::error!("Search channel failed: Not found");
+        return Err(ApiError::not_found("Search channel failed: Not found"));
+    };
+    let mut server_data = server_data.write().await;
+    if !server_data.tw_channels.contains_key(&c.login) {
+        server_data.tw_channels.insert(
+            c.login.clone(),
+            TwChannelSave {
+                id: c.id.clone(),
+                login: c.login.clone(),
+                profile_img: c.profile_image_url.clone(),
+                name: c.display_name.clone(),
+                last_time_used: Utc::now(),
+            },
+        );
+        server_data.check_tw_upcoming_event(None).await;
+        server_data.save().await;
+    }
+    server_data.touch_tw_channel(&c.login);
+    let channel_save = &server_data.tw_channels[&c.login];
+    Ok(ChannelInfoData {
+        id: channel_save.id.clone(),
+        custom_url: channel_save.login.clone(),
+        title: channel_save.name.clone(),
+        thumbnail: channel_save.profile_img.clone(),
+    })
+}
+
+/// The page of the history asked by `query`, and whether it is asked as csv
+pub async fn history_page(
+    server_data: &RwLock<ServerData>,
+    ctx: &AppContext,
+    query: &HashMap<String, String>,
+) -> Result<(bool, history::HistoryPage), ApiError> {
+    let csv = match query.get("format").map(|f| f.as_str()) {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(f) => return Err(ApiError::invalid("format", format!("Unknown format: {f}"))),
+    };
+    let subscription = Subscription::resolve(ctx, query).await;
+    // the csv export comes in larger pages
+    let (default_limit, max_limit) = if csv {
+        (history::CSV_PAGE_SIZE, history::CSV_PAGE_SIZE)
+    } else {
+        (history::DEFAULT_PAGE_SIZE, history::MAX_PAGE_SIZE)
+    };
+    let history_query = history::HistoryQuery::parse(
+        query,
+        subscription.yt_channel_ids,
+        subscription.tw_channel_logins,
+        default_limit,
+        max_limit,
+    )
+    .map_err(|e| ApiError::new(ErrorCode::InvalidParameter, e))?;
+    Ok((csv, server_data.read().await.history.query(&history_query)))
+}
+
+pub fn history_reply(csv: bool, page: history::HistoryPage) -> ApiResponse {
+    if csv {
+        Response::builder()
+            .header("Content-Type", "text/csv")
+            .header(
+                "Content-Disposition",
+                "attachment; filename=\"history.csv\"",
+            )
+            .body(history::to_csv(&page.records))
+    } else {
+        reply(Ok::<_, ApiError>(page))
+    }
+}
+
+fn parse_uuid(query: &HashMap<String, String>, parameter: &str) -> Result<Uuid, ApiError> {
+    let value = query
+        .get(parameter)
+        .ok_or_else(|| ApiError::missing(parameter))?;
+    Uuid::from_str(value).map_err(|_| ApiError::invalid(parameter, format!("Invalid {parameter}")))
+}
+
+fn key_not_found(key: &Uuid) -> ApiError {
+    ApiError::not_found(format!("Sync key {key} is not found"))
+}
+
+async fn sync_push(
+    ctx: &AppContext,
+    query: &HashMap<String, String>,
+) -> Result<ChangeResult, ApiError> {
+    let key = parse_uuid(query, "key")?;
+    if ctx.sync_store.get_yt_channel(&key).await.is_none() {
+        return Err(key_not_found(&key));
+    }
+    if let Some(yt_ch) = query.get("yt-ch") {
+        ctx.sync_store
+            .set_yt_channels(&key, yt_ch.split(',').filter(|s| !s.is_empty()))
+            .await
+            .map_err(|()| key_not_found(&key))?;
+    }
+    if let Some(tw_ch) = query.get("tw-ch") {
+        ctx.sync_store
+            .set_tw_channels(&key, tw_ch.split(',').filter(|s| !s.is_empty()))
+            .await
+            .map_err(|()| key_not_found(&key))?;
+    }
+    Ok(ChangeResult::new("Ok"))
+}
+
+async fn sync_pull(
+    ctx: &AppContext,
+    query: &HashMap<String, String>,
+) -> Result<SyncChannels, ApiError> {
+    let key = parse_uuid(query, "key")?;
+    match (
+        ctx.sync_store.get_yt_channel(&key).await,
+        ctx.sync_store.get_tw_channel(&key).await,
+    ) {
+        (Some(yt_ch), Some(tw_ch)) => Ok(SyncChannels {
+            yt_ch: Some(yt_ch),
+            tw_ch: Some(tw_ch),
+        }),
+        _ => Err(key_not_found(&key)),
+    }
+}
+
+async fn webhook_add(
+    ctx: &AppContext,
+    policy: &WebhookPolicy,
+    query: &HashMap<String, String>,
+) -> Result<ChangeResult, ApiError> {
+    let key = parse_uuid(query, "key")?;
+    let url = query.get("url").ok_or_else(|| ApiError::missing("url"))?;
+    policy
+        .check_url(url)
+        .await
+        .map_err(|e| ApiError::invalid("url", e))?;
+    let kind = query
+        .get("kind")
+        .map(|k| k.as_str())
+        .unwrap_or("json")
+        .parse::<WebhookKind>()
+        .map_err(|_| ApiError::invalid("kind", "Invalid kind"))?;
+    let id = ctx
+        .sync_store
+        .add_webhook(&key, url, kind)
+        .await
+        .map_err(|()| key_not_found(&key))?;
+    Ok(ChangeResult {
+        id: Some(id),
+        ..ChangeResult::new("Ok")
+    })
+}
+
+async fn webhook_remove(
+    ctx: &AppContext,
+    query: &HashMap<String, String>,
+) -> Result<ChangeResult, ApiError> {
+    let key = parse_uuid(query, "key")?;
+    let id = parse_uuid(query, "id")?;
+    if ctx.sync_store.get_webhooks(&key).await.is_none() {
+        return Err(key_not_found(&key));
+    }
+    ctx.sync_store
+        .remove_webhook(&key, &id)
+        .await
+        .map_err(|()| ApiError::not_found(format!("Webhook {id} is not found")))?;
+    Ok(ChangeResult::new("Ok"))
+}
+
+async fn webhook_list(
+    ctx: &AppContext,
+    query: &HashMap<String, String>,
+) -> Result<Vec<Webhook>, ApiError> {
+    let key = parse_uuid(query, "key")?;
+    ctx.sync_store
+        .get_webhooks(&key)
+        .await
+        .ok_or_else(|| key_not_found(&key))
+}
+
+async fn notice_yt_video(
+    server_data: &RwLock<ServerData>,
+    query: &HashMap<String, String>,
+) -> Result<ChangeResult, ApiError> {
+    let id_list = query.get("id").ok_or_else(|| ApiError::missing("id"))?;
+    let mut server_data = server_data.write().await;
+    for id in id_list.split(',') {
+        server_data.yt_videos.push_checked(id.to_string());
+    }
+    Ok(ChangeResult::new("Ok"))
+}
+
+/// The endpoints of the api which can fail. The others are served like the unversioned ones.
+pub fn routes(
+    server_data: Arc<RwLock<ServerData>>,
+    context: Arc<AppContext>,
+    webhook_policy: WebhookPolicy,
+) -> BoxedFilter<(ApiResponse,)> {
+    let with_server_data = warp::any().map(move || server_data.clone());
+    let with_context = warp::any().map(move || context.clone());
+    let with_query = warp::query::<HashMap<String, String>>;
+
+    let yt_channel_info_endpoint = warp::path!("yt-ch")
+        .and(with_query())
+        .and(with_server_data.clone())
+        .and(with_context.clone())
+        .then(
+            |query: HashMap<String, String>,
+             server_data: Arc<RwLock<ServerData>>,
+             context: Arc<AppContext>| async move {
+                reply(yt_channel_info(&server_data, &context, &query).await)
+            },
+        );
+
+    let tw_channel_info_endpoint = warp::path!("tw-ch")
+        .and(with_query())
+        .and(with_server_data.clone())
+        .then(
+            |query: HashMap<String, String>, server_data: Arc<RwLock<ServerData>>| async move {
+                reply(tw_channel_info(&server_data, &query).await)
+            },
+        );
+
+    let history_endpoint = warp::path!("history")
+        .and(with_query())
+        .and(with_server_data.clone())
+        .and(with_context.clone())
+        .then(
+            |query: HashMap<String, String>,
+             server_data: Arc<RwLock<ServerData>>,
+             context: Arc<AppContext>| async move {
+                match history_page(&server_data, &context, &query).await {
+                    Ok((csv, page)) => history_reply(csv, page),
+                    Err(e) => e.into_response(),
+                }
+            },
+        );
+
+    let notice_yt_video_endpoint = warp::path!("notice-yt-video")
+        .and(with_query())
+        .and(with_server_data.clone())
+        .then(
+            |query: HashMap<String, String>, server_data: Arc<RwLock<ServerData>>| async move {
+                reply(notice_yt_video(&server_data, &query).await)
+            },
+        );
+
+    let sync_new = warp::path!("sync" / "new").and(with_context.clone()).then(
+        |context: Arc<AppContext>| async move {
+            reply(Ok::<_, ApiError>(NewSyncKey {
+                key: context.sync_store.new_key().await,
+            }))
+        },
+    );
+    let sync_push_endpoint = warp::path!("sync" / "push")
+        .and(with_query())
+        .and(with_context.clone())
+        .then(
+            |query: HashMap<String, String>, context: Arc<AppContext>| async move {
+                reply(sync_push(&context, &query).await)
+            },
+        );
+    let sync_pull_endpoint = warp::path!("sync" / "pull")
+        .and(with_query())
+        .and(with_context.clone())
+        .then(
+            |query: HashMap<String, String>, context: Arc<AppContext>| async move {
+                reply(sync_pull(&context, &query).await)
+            },
+        );
+    let webhook_add_endpoint = warp::path!("sync" / "webhook" / "add")
+        .and(with_query())
+        .and(with_context.clone())
+        .then(
+            move |query: HashMap<String, String>, context: Arc<AppContext>| {
+                let webhook_policy = webhook_policy.clone();
+                async move { reply(webhook_add(&context, &webhook_policy, &query).await) }
+            },
+        );
+    let webhook_remove_endpoint = warp::path!("sync" / "webhook" / "remove")
+        .and(with_query())
+        .and(with_context.clone())
+        .then(
+            |query: HashMap<String, String>, context: Arc<AppContext>| async move {
+                reply(webhook_remove(&context, &query).await)
+            },
+        );
+    let webhook_list_endpoint = warp::path!("sync" / "webhook" / "list")
+        .and(with_query())
+        .and(with_context.clone())
+        .then(
+            |query: HashMap<String, String>, context: Arc<AppContext>| async move {
+                reply(webhook_list(&context, &query).await)
+            },
+        );
+
+    yt_channel_info_endpoint
+        .or(tw_channel_info_endpoint)
+        .unify()
+        .or(history_endpoint)
+        .unify()
+        .or(notice_yt_video_endpoint)
+        .unify()
+        .or(sync_new)
+        .unify()
+        .or(sync_push_endpoint)
+        .unify()
+        .or(sync_pull_endpoint)
+        .unify()
+        .or(webhook_add_endpoint)
+        .unify()
+        .or(webhook_remove_endpoint)
+        .unify()
+        .or(webhook_list_endpoint)
+        .unify()
+        .boxed()
+}
+
+/// The reply to the paths under `/api/v1/` which aren't endpoints
+pub fn unknown_endpoint() -> ApiResponse {
+    ApiError::not_found("Unknown endpoint").into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn test_yt_api_error_status() {
+        let status = |e| ApiError::from(e).code.status().as_u16();
+        assert_eq!(status(YtApiError::InvalidParameter), 400);
+        assert_eq!(status(YtApiError::NotFound), 404);
+        assert_eq!(status(YtApiError::QuotaExceeded), 429);
+        assert_eq!(
+            status(YtApiError::RequestFailed(Some(
+                reqwest::StatusCode::INTERNAL_SERVER_ERROR
+            ))),
+            502
+        );
+        assert_eq!(
+            status(YtApiError::RequestFailed(Some(
+                reqwest::StatusCode::NOT_FOUND
+            ))),
+            404
+        );
+        assert_eq!(status(YtApiError::RequestFailed(None)), 503);
+        assert_eq!(
+            status(YtApiError::DeserializeFailed("eof".to_string())),
+            502
+        );
+
+        let response = ApiError::from(YtApiError::QuotaExceeded)
+            .into_response()
+            .unwrap();
+        assert_eq!(response.status(), 429);
+        assert!(response.headers().contains_key("Retry-After"));
+        let body = serde_json::from_str::<Value>(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "quota_exceeded");
+        assert!(body["error"]["details"]["retry_after"].is_u64());
+    }
+}
@@ -0,0 +1,299 @@
+//! The iCalendar of the upcoming streams, `/cal`. An event keeps the `uid` of its stream, and
+//! its `SEQUENCE` grows with each change, so calendar clients update a rescheduled stream instead
+//! of adding it again. A cancelled stream is kept for a while as a cancelled event.
+use chrono::{DateTime, Offset, TimeZone, Utc};
+use chrono_tz::{OffsetComponents, OffsetName, Tz};
+use icalendar::{Alarm, CalendarDateTime, Component, EventLike, EventStatus, Property};
+
+use super::{subscription::Subscription, EventSource, UpcomingEvent};
+
+const CALENDAR_NAME: &str = "Stream Calendar";
+/// Calendar clients are asked to refresh at most this often, in minutes
+const MIN_REFRESH_INTERVAL_MIN: u64 = 15;
+const DEFAULT_DURATION_MIN: i64 = 60;
+
+/// The calendar of `events` and the `cancelled` ones with the options of `subscription`. The
+/// clients are asked to refresh it every `refresh_interval` minutes.
+pub fn calendar(
+    events: &[UpcomingEvent],
+    cancelled: &[UpcomingEvent],
+    subscription: &Subscription,
+    refresh_interval: u64,
+) -> String {
+    let mut cal = icalendar::Calendar::new();
+    cal.name(CALENDAR_NAME);
+    cal.ttl(&chrono::Duration::minutes(
+        refresh_interval.max(MIN_REFRESH_INTERVAL_MIN) as i64,
+    ));
+    if let Some(tz) = subscription.timezone {
+        cal.timezone(tz.name());
+    }
+    cal.extend(
+        events
+            .iter()
+            .map(|e| e.to_ical_event(subscription, EventStatus::Confirmed)),
+    );
+    cal.extend(
+        cancelled
+            .iter()
+            .map(|e| e.to_ical_event(subscription, EventStatus::Cancelled)),
+    );
+    let cal = cal.done().to_string();
+    match subscription.timezone {
+        // icalendar writes a DTSTAMP and a UID into every component, which a VTIMEZONE can't
+        // have, so it is written by hand before the events
+        Some(tz) => {
+            let at = cal
+                .find("BEGIN:VEVENT")
+                .or_else(|| cal.find("END:VCALENDAR"))
+                .unwrap_or(cal.len());
+            format!("{}{}{}", &cal[..at], vtimezone(tz, Utc::now()), &cal[at..])
+        }
+        None => cal,
+    }
+}
+
+impl UpcomingEvent {
+    fn to_ical_event(&self, subscription: &Subscription, status: EventStatus) -> icalendar::Event {
+        let time = |time: DateTime<Utc>| match subscription.timezone {
+            Some(tz) => CalendarDateTime::from_date_time(time.with_timezone(&tz)),
+            None => CalendarDateTime::from(time),
+        };
+        let duration = subscription
+            .duration
+            .unwrap_or(chrono::Duration::minutes(DEFAULT_DURATION_MIN));
+        let mut builder = icalendar::Event::new();
+        builder.starts(time(self.start_date_time));
+        if self.ongoing {
+            builder.summary(&format!("🔴{}", self.title));
+            builder.ends(time(ongoing_end(
+                self.start_date_time,
+                duration,
+                Utc::now(),
+            )));
+        } else {
+            builder.summary(&self.title);
+            builder.ends(time(
+                self.end_date_time
+                    .unwrap_or(self.start_date_time + duration),
+            ));
+        }
+        let mut description = format!("{}\n\n", self.target_url);
+        let (platform, channel) = match &self.source {
+            EventSource::YoutubeChannel(c) => {
+                description += &format!("{}\n{}\n\n", c.title, c.custom_url);
+                builder.location(&format!("{}@Youtube", c.title));
+                ("Youtube", &c.title)
+            }
+            EventSource::TwitchChannel(c) => {
+                description += &format!("{}({})\n\n", c.title, c.login);
+                builder.location(&format!("{}@Twitch", c.title));
+                ("Twitch", &c.title)
+            }
+        };
+        description += &self.description;
+        builder.description(&description);
+        builder.url(&self.target_url);
+        builder.add_property(
+            "CATEGORIES",
+            &format!("{platform},{}", escape_text(channel)),
+        );
+        if let Some(offset) = subscription.alarm {
+            builder.alarm(Alarm::display(&self.title, -offset));
+        }
+        builder.status(status);
+        builder.sequence(self.sequence);
+        if let Some(last_modified) = self.last_modified {
+            builder.append_property(Property::new(
+                "LAST-MODIFIED",
+                &last_modified.format("%Y%m%dT%H%M%SZ").to_string(),
+            ));
+        }
+        builder.uid(&self.uid);
+        builder.done()
+    }
+}
+
+/// The end of a live stream at `now`: `duration` after its start, extended by a whole `duration`
+/// when it is reached, so it doesn't change on every refresh
+fn ongoing_end(
+    start: DateTime<Utc>,
+    duration: chrono::Duration,
+    now: DateTime<Utc>,
+) -> DateTime<Utc> {
+    let step = duration.num_seconds().max(1);
+    let elapsed = (now - start).num_seconds().max(0);
+    start + chrono::Duration::seconds((elapsed / step + 1) * step)
+}
+
+/// Escape the separators of a text value, which icalendar doesn't
+fn escape_text(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+}
+
+/// The VTIMEZONE of `tz`, with its offset a year before `around` and every transition until a
+/// year after it
+fn vtimezone(tz: Tz, around: DateTime<Utc>) -> String {
+    let from = (around - chrono::Duration::days(366))
+        .date_naive()
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc();
+    let to = around + chrono::Duration::days(366);
+    let offset_at = |time: DateTime<Utc>| tz.offset_from_utc_datetime(&time.naive_utc());
+
+    let mut text = format!("BEGIN:VTIMEZONE\r\nTZID:{}\r\n", tz.name());
+    let mut write_observance = |start: DateTime<Utc>,
+                                before: i32,
+                                offset: &<Tz as TimeZone>::Offset| {
+        let kind = if offset.dst_offset().is_zero() {
+            "STANDARD"
+        } else {
+            "DAYLIGHT"
+        };
+        // the onset is in the local time before the transition
+        let onset = start.naive_utc() + chrono::Duration::seconds(before as i64);
+        text += &format!(
+            "BEGIN:{kind}\r\nDTSTART:{}\r\nTZOFFSETFROM:{}\r\nTZOFFSETTO:{}\r\nTZNAME:{}\r\nEND:{kind}\r\n",
+            onset.format("%Y%m%dT%H%M%S"),
+            format_offset(before),
+            format_offset(offset.fix().local_minus_utc()),
+            offset.abbreviation()
+        );
+    };
+
+    let first = offset_at(from);
+    write_observance(from, first.fix().local_minus_utc(), &first);
+    let mut time = from;
+    while time < to {
+        let next = time + chrono::Duration::days(1);
+        let before = offset_at(time);
+        if offset_at(next) != before {
+            // the first second of the new offset
+            let (mut low, mut high) = (time, next);
+            while high - low > chrono::Duration::seconds(1) {
+                let middle = low + (high - low) / 2;
+                if offset_at(middle) == before {
+                    low = middle;
+                } else {
+                    high = middle;
+                }
+            }
+            write_observance(high, before.fix().local_minus_utc(), &offset_at(high));
+        }
+        time = next;
+    }
+    text + "END:VTIMEZONE\r\n"
+}
+
+/// Like `+0900` or `-0330`
+fn format_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{sign}{:02}{:02}", minutes / 60, minutes % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::YtChannelBrief;
+
+    fn event(uid: &str, start: DateTime<Utc>) -> UpcomingEvent {
+        UpcomingEvent {
+            start_date_time: start,
+            start_timestamp_millis: start.timestamp_millis(),
+            thumbnail_url: None,
+            title: "Stream".to_string(),
+            description: String::new(),
+            target_url: format!("https://www.youtube.com/watch?v={uid}"),
+            ongoing: false,
+            source: EventSource::YoutubeChannel(YtChannelBrief {
+                id: "UCid".to_string(),
+                thumbnail_url: String::new(),
+                title: "Channel, Inc".to_string(),
+                custom_url: "@channel".to_string(),
+            }),
+            uid: format!("{uid}@yt@yt-watcher"),
+            end_date_time: None,
+            ended: false,
+            scheduled_start_date_time: None,
+            viewer_count: None,
+            sequence: 2,
+            last_modified: Some(start - chrono::Duration::days(1)),
+        }
+    }
+
+    #[test]
+    fn test_calendar() {
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let subscription = Subscription {
+            alarm: Some(chrono::Duration::minutes(15)),
+            duration: Some(chrono::Duration::minutes(90)),
+            timezone: Some(chrono_tz::Asia::Tokyo),
+            ..Subscription::default()
+        };
+        let cal = calendar(&[event("a", start)], &[event("b", start)], &subscription, 5);
+        let lines = cal.lines().collect::<Vec<&str>>();
+        for line in [
+            "REFRESH-INTERVAL;VALUE=DURATION:PT900S",
+            "X-PUBLISHED-TTL:PT900S",
+            "X-WR-TIMEZONE:Asia/Tokyo",
+            "TZOFFSETTO:+0900",
+            "DTSTART;TZID=Asia/Tokyo:20240501T210000",
+            "DTEND;TZID=Asia/Tokyo:20240501T223000",
+            "CATEGORIES:Youtube,Channel\\, Inc",
+            "SEQUENCE:2",
+            "LAST-MODIFIED:20240430T120000Z",
+            "STATUS:CONFIRMED",
+            "STATUS:CANCELLED",
+            "UID:a@yt@yt-watcher",
+            "TRIGGER:-PT900S",
+        ] {
+            assert!(lines.contains(&line), "{line} is missing from {cal}");
+        }
+        let vtimezone = cal.find("BEGIN:VTIMEZONE").unwrap();
+        assert!(vtimezone < cal.find("BEGIN:VEVENT").unwrap());
+        assert!(icalendar::parser::read_calendar(&icalendar::parser::unfold(&cal)).is_ok());
+
+        // in utc and without alarms by default
+        let cal = calendar(&[event("a", start)], &[], &Subscription::default(), 30);
+        assert!(cal.contains("DTSTART:20240501T120000Z"));
+        assert!(cal.contains("DTEND:20240501T130000Z"));
+        assert!(cal.contains("X-PUBLISHED-TTL:PT1800S"));
+        assert!(!cal.contains("VTIMEZONE"));
+        assert!(!cal.contains("VALARM"));
+    }
+
+    #[test]
+    fn test_ongoing_end() {
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let hour = chrono::Duration::hours(1);
+        let end = |minutes| ongoing_end(start, hour, start + chrono::Duration::minutes(minutes));
+        assert_eq!(end(0), start + hour);
+        assert_eq!(end(59), start + hour);
+        assert_eq!(end(60), start + hour * 2);
+        assert_eq!(end(150), start + hour * 3);
+    }
+
+    #[test]
+    fn test_vtimezone() {
+        let around = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let berlin = vtimezone(chrono_tz::Europe::Berlin, around);
+        assert!(berlin.contains(
+            "BEGIN:DAYLIGHT\r\nDTSTART:20240331T020000\r\nTZOFFSETFROM:+0100\r\nTZOFFSETTO:+0200\r\nTZNAME:CEST\r\nEND:DAYLIGHT\r\n"
+        ));
+        assert!(berlin.contains(
+            "BEGIN:STANDARD\r\nDTSTART:20241027T030000\r\nTZOFFSETFROM:+0200\r\nTZOFFSETTO:+0100\r\nTZNAME:CET\r\nEND:STANDARD\r\n"
+        ));
+        // a year before and after
+        assert_eq!(berlin.matches("BEGIN:DAYLIGHT").count(), 3);
+
+        let tokyo = vtimezone(chrono_tz::Asia::Tokyo, around);
+        assert_eq!(
+            tokyo,
+            "BEGIN:VTIMEZONE\r\nTZID:Asia/Tokyo\r\nBEGIN:STANDARD\r\nDTSTART:20230601T090000\r\nTZOFFSETFROM:+0900\r\nTZOFFSETTO:+0900\r\nTZNAME:JST\r\nEND:STANDARD\r\nEND:VTIMEZONE\r\n"
+        );
+        assert_eq!(format_offset(-12600), "-0330");
+    }
+}
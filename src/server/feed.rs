@@ -0,0 +1,301 @@
+//! The Atom and RSS feeds of the upcoming streams, `/feed.xml` and `/feed.rss`. The id of an entry
+//! is the `uid` of its event, so a rescheduled stream updates its entry instead of adding one.
+use atom_syndication::{
+    CategoryBuilder as AtomCategoryBuilder, ContentBuilder, EntryBuilder, FeedBuilder,
+    FixedDateTime, LinkBuilder, PersonBuilder, Text,
+};
+use chrono::Utc;
+use rss::{CategoryBuilder, ChannelBuilder, EnclosureBuilder, GuidBuilder, ItemBuilder};
+
+use super::{EventSource, UpcomingEvent};
+
+const FEED_TITLE: &str = "Stream Calendar";
+const FEED_ID: &str = "urn:yt-watcher:feed";
+
+/// The absolute url of the feed at `path`, on `public_url` if it is configured, otherwise on the
+/// `host` the request was sent to
+pub fn link(public_url: Option<&str>, host: Option<&str>, path: &str) -> String {
+    match (public_url, host) {
+        (Some(url), _) => format!("{}{path}", url.trim_end_matches('/')),
+        (None, Some(host)) => format!("http://{host}{path}"),
+        (None, None) => path.to_string(),
+    }
+}
+
+/// The Atom feed of `events`, requested at `link`
+pub fn atom(events: &[UpcomingEvent], link: &str) -> String {
+    let now = Utc::now();
+    // an event found before the field was saved counts as changed now
+    let updated = |e: &UpcomingEvent| e.last_modified.unwrap_or(now);
+    FeedBuilder::default()
+        .title(FEED_TITLE)
+        .id(FEED_ID)
+        .updated(FixedDateTime::from(
+            events.iter().map(updated).max().unwrap_or(now),
+        ))
+        .link(LinkBuilder::default().href(link).rel("self").build())
+        .entries(
+            events
+                .iter()
+                .map(|e| {
+                    let (name, uri) = e.channel();
+                    let mut links = vec![LinkBuilder::default()
+                        .href(e.target_url.clone())
+                        .rel("alternate")
+                        .build()];
+                    if let Some(thumbnail) = &e.thumbnail_url {
+                        links.push(
+                            LinkBuilder::default()
+                                .href(thumbnail.clone())
+                                .rel("enclosure")
+                                .mime_type(Some("image/jpeg".to_string()))
+                                .build(),
+                        );
+                    }
+                    EntryBuilder::default()
+                        .id(e.entry_id())
+                        .title(e.entry_title())
+                        .updated(FixedDateTime::from(updated(e)))
+                        .published(Some(FixedDateTime::from(e.start_date_time)))
+                        .author(PersonBuilder::default().name(name).uri(Some(uri)).build())
+                        .category(AtomCategoryBuilder::default().term(e.state()).build())
+                        .links(links)
+                        .summary(Some(Text::plain(e.description.clone())))
+                        .content(Some(
+                            ContentBuilder::default()
+                                .value(Some(e.entry_html()))
+                                .content_type(Some("html".to_string()))
+                                .build(),
+                        ))
+                        .build()
+                })
+                .collect::<Vec<_>>(),
+        )
+        .build()
+        .to_string()
+}
+
+/// The RSS 2.0 feed of `events`, requested at `link`
+pub fn rss(events: &[UpcomingEvent], link: &str) -> String {
+    ChannelBuilder::default()
+        .title(FEED_TITLE)
+        .link(link)
+        .description("Upcoming and live streams of youtube and twitch channels")
+        .last_build_date(Some(Utc::now().to_rfc2822()))
+        .items(
+            events
+                .iter()
+                .map(|e| {
+                    let (name, uri) = e.channel();
+                    ItemBuilder::default()
+                        .guid(Some(
+                            GuidBuilder::default()
+                                .value(e.entry_id())
+                                .permalink(false)
+                                .build(),
+                        ))
+                        .title(Some(e.entry_title()))
+                        .link(Some(e.target_url.clone()))
+                        .pub_date(Some(e.start_date_time.to_rfc2822()))
+                        .categories(vec![
+                            CategoryBuilder::default().name(e.state()).build(),
+                            CategoryBuilder::default()
+                                .name(name)
+                                .domain(Some(uri))
+                                .build(),
+                        ])
+                        .enclosure(e.thumbnail_url.as_ref().map(|thumbnail| {
+                            EnclosureBuilder::default()
+                                .url(thumbnail.clone())
+                                .length("0")
+                                .mime_type("image/jpeg")
+                                .build()
+                        }))
+                        .description(Some(e.entry_html()))
+                        .build()
+                })
+                .collect::<Vec<_>>(),
+        )
+        .build()
+        .to_string()
+}
+
+impl UpcomingEvent {
+    /// The id of the entry, which stays when the stream is rescheduled
+    fn entry_id(&self) -> String {
+        format!("urn:yt-watcher:event:{}", self.uid)
+    }
+
+    fn entry_title(&self) -> String {
+        if self.ongoing {
+            format!("[LIVE] {}", self.title)
+        } else {
+            self.title.clone()
+        }
+    }
+
+    fn state(&self) -> &'static str {
+        if self.ongoing {
+            "live"
+        } else if self.ended {
+            "ended"
+        } else {
+            "upcoming"
+        }
+    }
+
+    /// The title and url of the channel
+    fn channel(&self) -> (String, String) {
+        match &self.source {
+            EventSource::YoutubeChannel(c) => (
+                c.title.clone(),
+                format!("https://www.youtube.com/channel/{}", c.id),
+            ),
+            EventSource::TwitchChannel(c) => (
+                c.title.clone(),
+                format!("https://www.twitch.tv/{}", c.login),
+            ),
+        }
+    }
+
+    fn entry_html(&self) -> String {
+        let (name, uri) = self.channel();
+        let mut html = String::new();
+        if let Some(thumbnail) = &self.thumbnail_url {
+            html += &format!("<p><img src=\"{}\" alt=\"\"></p>", escape_html(thumbnail));
+        }
+        if self.ongoing {
+            html += "<p><strong>LIVE</strong> since ";
+        } else {
+            html += "<p>Starts at ";
+        }
+        html += &format!(
+            "{} on <a href=\"{}\">{}</a></p>",
+            self.start_date_time.format("%Y-%m-%d %H:%M UTC"),
+            escape_html(&uri),
+            escape_html(&name)
+        );
+        html += &format!(
+            "<p>{}</p>",
+            escape_html(&self.description).replace('\n', "<br>")
+        );
+        html
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::server::{TwChannelBrief, YtChannelBrief};
+
+    #[test]
+    fn test_feeds() {
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let upcoming = UpcomingEvent {
+            start_date_time: start,
+            start_timestamp_millis: start.timestamp_millis(),
+            thumbnail_url: Some("https://i.ytimg.com/vi/v/hqdefault.jpg".to_string()),
+            title: "Karaoke & <chat>".to_string(),
+            description: "Line 1\nLine 2".to_string(),
+            target_url: "https://www.youtube.com/watch?v=v".to_string(),
+            ongoing: false,
+            source: EventSource::YoutubeChannel(YtChannelBrief {
+                id: "UCid".to_string(),
+                thumbnail_url: String::new(),
+                title: "Channel".to_string(),
+                custom_url: "@channel".to_string(),
+            }),
+            uid: "v@yt@yt-watcher".to_string(),
+            end_date_time: None,
+            ended: false,
+            scheduled_start_date_time: None,
+            viewer_count: None,
+            sequence: 0,
+            last_modified: Some(start - chrono::Duration::days(1)),
+        };
+        let live = UpcomingEvent {
+            thumbnail_url: None,
+            title: "Just chatting".to_string(),
+            target_url: "https://www.twitch.tv/login".to_string(),
+            ongoing: true,
+            source: EventSource::TwitchChannel(TwChannelBrief {
+                id: "1".to_string(),
+                thumbnail_url: String::new(),
+                title: "Twitch Channel".to_string(),
+                login: "login".to_string(),
+            }),
+            uid: "login@twitch@yt-watcher".to_string(),
+            ..upcoming.clone()
+        };
+        let events = [upcoming.clone(), live];
+
+        let feed_link = link(Some("https://example.com/"), None, "/feed.xml?yt-ch=UCid");
+        let feed = atom_syndication::Feed::from_str(&atom(&events, &feed_link)).unwrap();
+        assert_eq!(
+            feed.links()[0].href(),
+            "https://example.com/feed.xml?yt-ch=UCid"
+        );
+        let last_modified = FixedDateTime::from(start - chrono::Duration::days(1));
+        assert_eq!(feed.updated(), &last_modified);
+        let entry = &feed.entries()[0];
+        assert_eq!(entry.id(), "urn:yt-watcher:event:v@yt@yt-watcher");
+        assert_eq!(entry.title().as_str(), "Karaoke & <chat>");
+        assert_eq!(entry.updated(), &last_modified);
+        assert_eq!(entry.published(), Some(&FixedDateTime::from(start)));
+        assert_eq!(entry.authors()[0].name(), "Channel");
+        assert_eq!(entry.links()[1].rel(), "enclosure");
+        let html = entry.content().unwrap().value().unwrap();
+        assert!(html.contains("<img src=\"https://i.ytimg.com/vi/v/hqdefault.jpg\""));
+        assert!(html.contains("Starts at 2024-05-01 12:00 UTC"));
+        assert!(html.contains("Line 1<br>Line 2"));
+        let entry = &feed.entries()[1];
+        assert_eq!(entry.title().as_str(), "[LIVE] Just chatting");
+        assert_eq!(entry.categories()[0].term(), "live");
+        assert_eq!(
+            entry.authors()[0].uri(),
+            Some("https://www.twitch.tv/login")
+        );
+
+        let feed_link = link(None, Some("localhost:8080"), "/feed.rss");
+        let channel = rss::Channel::from_str(&rss(&events, &feed_link)).unwrap();
+        assert_eq!(channel.link(), "http://localhost:8080/feed.rss");
+        let item = &channel.items()[0];
+        assert_eq!(
+            item.guid().map(|g| (g.value(), g.is_permalink())),
+            Some(("urn:yt-watcher:event:v@yt@yt-watcher", false))
+        );
+        assert_eq!(item.pub_date(), Some("Wed, 1 May 2024 12:00:00 +0000"));
+        assert_eq!(
+            item.enclosure().map(|e| e.url()),
+            Some("https://i.ytimg.com/vi/v/hqdefault.jpg")
+        );
+        assert_eq!(channel.items()[1].title(), Some("[LIVE] Just chatting"));
+        assert!(channel.items()[1].enclosure().is_none());
+
+        // a rescheduled stream keeps its id
+        let rescheduled = UpcomingEvent {
+            start_date_time: start + chrono::Duration::hours(2),
+            ..upcoming
+        };
+        let channel = rss::Channel::from_str(&rss(&[rescheduled], "/feed.rss")).unwrap();
+        assert_eq!(
+            channel.items()[0].guid().map(|g| g.value()),
+            Some("urn:yt-watcher:event:v@yt@yt-watcher")
+        );
+        assert_eq!(
+            channel.items()[0].pub_date(),
+            Some("Wed, 1 May 2024 14:00:00 +0000")
+        );
+    }
+}
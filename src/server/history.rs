@@ -0,0 +1,373 @@
+//! Archive of every stream the server has seen live, kept after the stream leaves the events.
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{EventSource, UpcomingEvent};
+
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+/// The default and largest page of the csv export
+pub const CSV_PAGE_SIZE: usize = 10_000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Youtube,
+    Twitch,
+}
+
+impl Platform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Youtube => "youtube",
+            Platform::Twitch => "twitch",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct HistoryRecord {
+    /// The uid of the event. Twitch reuses it for every stream of a channel, so a record is
+    /// identified by the uid and the actual start.
+    pub uid: String,
+    pub platform: Platform,
+    /// The youtube channel id or the twitch login
+    pub channel: String,
+    pub channel_title: String,
+    pub title: String,
+    pub url: String,
+    pub scheduled_start: Option<DateTime<Utc>>,
+    pub actual_start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    pub duration_seconds: Option<i64>,
+    pub peak_viewers: Option<u64>,
+}
+
+impl HistoryRecord {
+    fn is_from(&self, yt_channel_ids: &[String], tw_channel_logins: &[String]) -> bool {
+        match self.platform {
+            Platform::Youtube => yt_channel_ids.contains(&self.channel),
+            Platform::Twitch => tw_channel_logins.contains(&self.channel),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    records: Vec<HistoryRecord>,
+    /// Indices of the records changed since the last save
+    changed: Mutex<BTreeSet<usize>>,
+}
+
+impl History {
+    pub fn new(records: Vec<HistoryRecord>) -> Self {
+        Self {
+            records,
+            changed: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// The records changed since the last call, to be saved
+    pub fn take_changed(&self) -> Vec<HistoryRecord> {
+        std::mem::take(&mut *self.changed.lock().unwrap())
+            .into_iter()
+            .map(|n| self.records[n].clone())
+            .collect()
+    }
+
+    /// Save the records again with the next changes, after saving them failed
+    pub fn mark_changed(&self, records: &[HistoryRecord]) {
+        let mut changed = self.changed.lock().unwrap();
+        for record in records {
+            if let Some(n) = self.position(&record.uid, record.actual_start) {
+                changed.insert(n);
+            }
+        }
+    }
+
+    fn position(&self, uid: &str, actual_start: DateTime<Utc>) -> Option<usize> {
+        // the records of live streams are near the end
+        self.records
+            .iter()
+            .rposition(|r| r.uid == uid && r.actual_start == actual_start)
+    }
+
+    /// Add or update the record of a live or ended event. Upcoming events are ignored.
+    pub fn record(&mut self, event: &UpcomingEvent) {
+        if !(event.ongoing || event.ended) {
+            return;
+        }
+        let (platform, channel, channel_title) = match &event.source {
+            EventSource::YoutubeChannel(c) => (Platform::Youtube, &c.id, &c.title),
+            EventSource::TwitchChannel(c) => (Platform::Twitch, &c.login, &c.title),
+        };
+        let end = event.end_date_time.filter(|_| event.ended);
+        let record = HistoryRecord {
+            uid: event.uid.clone(),
+            platform,
+            channel: channel.clone(),
+            channel_title: channel_title.clone(),
+            title: event.title.clone(),
+            url: event.target_url.clone(),
+            scheduled_start: event.scheduled_start_date_time,
+            actual_start: event.start_date_time,
+            end,
+            duration_seconds: end.map(|end| (end - event.start_date_time).num_seconds()),
+            peak_viewers: event.viewer_count,
+        };
+        match self.position(&record.uid, record.actual_start) {
+            Some(n) => {
+                let old = &mut self.records[n];
+                let merged = HistoryRecord {
+                    scheduled_start: record.scheduled_start.or(old.scheduled_start),
+                    end: record.end.or(old.end),
+                    duration_seconds: record.duration_seconds.or(old.duration_seconds),
+                    peak_viewers: old.peak_viewers.max(record.peak_viewers),
+                    ..record
+                };
+                if *old != merged {
+                    *old = merged;
+                    self.changed.lock().unwrap().insert(n);
+                }
+            }
+            None => {
+                self.records.push(record);
+                self.changed.lock().unwrap().insert(self.records.len() - 1);
+            }
+        }
+    }
+
+    /// The records matching the query, the most recent first
+    pub fn query(&self, query: &HistoryQuery) -> HistoryPage {
+        let mut records = self
+            .records
+            .iter()
+            .filter(|r| {
+                (query.yt_channel_ids.is_empty() && query.tw_channel_logins.is_empty())
+                    || r.is_from(&query.yt_channel_ids, &query.tw_channel_logins)
+            })
+            .filter(|r| query.from.is_none_or(|from| r.actual_start >= from))
+            .filter(|r| query.to.is_none_or(|to| r.actual_start < to))
+            .collect::<Vec<&HistoryRecord>>();
+        records.sort_by_key(|r| std::cmp::Reverse(r.actual_start));
+        HistoryPage {
+            total: records.len(),
+            offset: query.offset,
+            limit: query.limit,
+            records: records
+                .into_iter()
+                .skip(query.offset)
+                .take(query.limit)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryQuery {
+    /// Match every channel when both are empty
+    pub yt_channel_ids: Vec<String>,
+    pub tw_channel_logins: Vec<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+    /// Read the `from`, `to`, `offset` and `limit` parameters. `limit` can't exceed `max_limit`.
+    pub fn parse(
+        query: &HashMap<String, String>,
+        yt_channel_ids: Vec<String>,
+        tw_channel_logins: Vec<String>,
+        default_limit: usize,
+        max_limit: usize,
+    ) -> Result<Self, String> {
+        let parse_number = |name: &str, default: usize| match query.get(name) {
+            Some(v) => v.parse().map_err(|_| format!("Invalid {name}: {v}")),
+            None => Ok(default),
+        };
+        let limit = parse_number("limit", default_limit)?;
+        if limit > max_limit {
+            return Err(format!("Invalid limit: {limit} is more than {max_limit}"));
+        }
+        Ok(Self {
+            yt_channel_ids,
+            tw_channel_logins,
+            from: query.get("from").map(|t| parse_time(t)).transpose()?,
+            to: query.get("to").map(|t| parse_time(t)).transpose()?,
+            offset: parse_number("offset", 0)?,
+            limit,
+        })
+    }
+}
+
+/// Accept a rfc3339 time, or a date which means its midnight in utc
+fn parse_time(time: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(time) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(time, "%Y-%m-%d")
+        .map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("Invalid time: {time}"))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HistoryPage {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub records: Vec<HistoryRecord>,
+}
+
+fn csv_field(value: &str) -> String {
+    // a spreadsheet would run a cell starting like this as a formula
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{value}")
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+pub fn to_csv(records: &[HistoryRecord]) -> String {
+    let time = |t: Option<DateTime<Utc>>| t.map(|t| t.to_rfc3339()).unwrap_or_default();
+    let mut csv = String::from(
+        "uid,platform,channel,channel_title,title,url,scheduled_start,actual_start,end,duration_seconds,peak_viewers\r\n",
+    );
+    for r in records {
+        let fields = [
+            csv_field(&r.uid),
+            r.platform.as_str().to_string(),
+            csv_field(&r.channel),
+            csv_field(&r.channel_title),
+            csv_field(&r.title),
+            csv_field(&r.url),
+            time(r.scheduled_start),
+            r.actual_start.to_rfc3339(),
+            time(r.end),
+            r.duration_seconds
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+            r.peak_viewers.map(|v| v.to_string()).unwrap_or_default(),
+        ];
+        csv += &fields.join(",");
+        csv += "\r\n";
+    }
+    csv
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::TwChannelBrief;
+
+    fn live_event(start: DateTime<Utc>, viewers: u64) -> UpcomingEvent {
+        UpcomingEvent {
+            start_date_time: start,
+            start_timestamp_millis: start.timestamp_millis(),
+            thumbnail_url: None,
+            title: "Stream, \"title\"".to_string(),
+            description: "Just Chatting".to_string(),
+            target_url: "https://www.twitch.tv/history_test".to_string(),
+            ongoing: true,
+            source: EventSource::TwitchChannel(TwChannelBrief {
+                id: "history-test".to_string(),
+                thumbnail_url: String::new(),
+                title: "History Test".to_string(),
+                login: "history_test".to_string(),
+            }),
+            uid: "history_test@twitch@yt-watcher".to_string(),
+            end_date_time: None,
+            ended: false,
+            scheduled_start_date_time: None,
+            viewer_count: Some(viewers),
+            sequence: 0,
+            last_modified: None,
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let start = Utc::now() - chrono::Duration::hours(2);
+        let mut history = History::default();
+        history.record(&live_event(start, 10));
+        history.record(&live_event(start, 30));
+        history.record(&live_event(start, 20));
+        assert_eq!(history.take_changed().len(), 1);
+        assert!(history.take_changed().is_empty());
+        history.record(&live_event(start, 20).end_at(start + chrono::Duration::hours(1)));
+        assert_eq!(history.records.len(), 1);
+        assert_eq!(history.records[0].peak_viewers, Some(30));
+        assert_eq!(history.records[0].duration_seconds, Some(3600));
+
+        // twitch reuses the uid for the next stream of the channel
+        history.record(&live_event(start + chrono::Duration::hours(1), 5));
+        assert_eq!(history.records.len(), 2);
+        assert!(history.records[1].end.is_none());
+    }
+
+    #[test]
+    fn test_query() {
+        let start = Utc::now() - chrono::Duration::days(3);
+        let mut history = History::default();
+        for day in 0..3 {
+            history.record(&live_event(start + chrono::Duration::days(day), 1));
+        }
+        let query = |q: &[(&str, &str)], tw: Vec<String>| {
+            let q = q
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            HistoryQuery::parse(&q, vec![], tw, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE)
+        };
+        let page = history.query(&query(&[("offset", "1"), ("limit", "1")], vec![]).unwrap());
+        assert_eq!(page.total, 3);
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(
+            page.records[0].actual_start,
+            start + chrono::Duration::days(1)
+        );
+
+        let from = (start + chrono::Duration::hours(1)).to_rfc3339();
+        let page = history.query(&query(&[("from", &from)], vec![]).unwrap());
+        assert_eq!(page.total, 2);
+        let page = history.query(&query(&[], vec!["someone_else".to_string()]).unwrap());
+        assert_eq!(page.total, 0);
+        assert!(query(&[("to", "yesterday")], vec![]).is_err());
+        assert!(query(&[("limit", "1000")], vec![]).is_ok());
+        assert!(query(&[("limit", "1001")], vec![]).is_err());
+        assert_eq!(
+            query(&[("to", "2023-07-01")], vec![]).unwrap().to,
+            Some("2023-07-01T00:00:00Z".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let mut history = History::default();
+        history.record(&live_event(Utc::now(), 10));
+        let csv = to_csv(&history.records);
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().starts_with("uid,platform,channel,"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("history_test@twitch@yt-watcher,twitch,history_test,History Test,\"Stream, \"\"title\"\"\","));
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@user"), "'@user");
+        assert_eq!(csv_field("a-b"), "a-b");
+    }
+}
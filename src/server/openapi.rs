@@ -0,0 +1,691 @@
+//! The OpenAPI document of the json api, served at `/api/openapi.json`. The schemas are derived
+//! from the response types, and every path is documented both unversioned and under `/api/v1/`,
+//! where it can fail with an [`ApiErrorBody`].
+use once_cell::sync::Lazy;
+use utoipa::{
+    openapi::{path::Operation, ContentBuilder, OpenApi as Document, Ref, RefOr, ResponseBuilder},
+    OpenApi,
+};
+
+use super::api::{ApiErrorBody, ErrorCode};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Upcoming and live streams of youtube and twitch channels"),
+    paths(
+        paths::data,
+        paths::calendar,
+        paths::atom_feed,
+        paths::rss_feed,
+        paths::yt_channel_info,
+        paths::tw_channel_info,
+        paths::history,
+        paths::quota,
+        paths::notice_yt_video,
+        paths::sync_new,
+        paths::sync_push,
+        paths::sync_pull,
+        paths::webhook_add,
+        paths::webhook_remove,
+        paths::webhook_list,
+    ),
+    components(schemas(ApiErrorBody))
+)]
+struct ApiDoc;
+
+/// The errors each endpoint of the versioned api can fail with. The endpoints which aren't
+/// listed can't fail.
+const V1_ERRORS: &[(&str, &[ErrorCode])] = &[
+    (
+        "/yt-ch",
+        &[
+            ErrorCode::InvalidParameter,
+            ErrorCode::NotFound,
+            ErrorCode::QuotaExceeded,
+            ErrorCode::UpstreamFailed,
+            ErrorCode::Unavailable,
+        ],
+    ),
+    (
+        "/tw-ch",
+        &[
+            ErrorCode::InvalidParameter,
+            ErrorCode::NotFound,
+            ErrorCode::UpstreamFailed,
+            ErrorCode::Unavailable,
+        ],
+    ),
+    ("/history", &[ErrorCode::InvalidParameter]),
+    ("/notice-yt-video", &[ErrorCode::InvalidParameter]),
+    (
+        "/sync/push",
+        &[ErrorCode::InvalidParameter, ErrorCode::NotFound],
+    ),
+    (
+        "/sync/pull",
+        &[ErrorCode::InvalidParameter, ErrorCode::NotFound],
+    ),
+    (
+        "/sync/webhook/add",
+        &[ErrorCode::InvalidParameter, ErrorCode::NotFound],
+    ),
+    (
+        "/sync/webhook/remove",
+        &[ErrorCode::InvalidParameter, ErrorCode::NotFound],
+    ),
+    (
+        "/sync/webhook/list",
+        &[ErrorCode::InvalidParameter, ErrorCode::NotFound],
+    ),
+];
+
+static DOCUMENT_JSON: Lazy<String> = Lazy::new(|| document().to_pretty_json().unwrap_or_default());
+
+/// The document with the paths of the versioned api added
+pub fn document() -> Document {
+    let mut document = ApiDoc::openapi();
+    let unversioned = document.paths.paths.clone();
+    for (path, mut item) in unversioned {
+        if let Some(operation) = item.get.as_mut() {
+            add_v1_errors(&path, operation);
+        }
+        document.paths.paths.insert(format!("/api/v1{path}"), item);
+    }
+    document
+}
+
+pub fn document_json() -> &'static str {
+    &DOCUMENT_JSON
+}
+
+fn add_v1_errors(path: &str, operation: &mut Operation) {
+    operation.operation_id = operation.operation_id.as_ref().map(|id| format!("{id}_v1"));
+    operation.description = Some(
+        "Fails with an error status and an ApiErrorBody, unlike the unversioned endpoint"
+            .to_string(),
+    );
+    let errors = V1_ERRORS
+        .iter()
+        .find(|(p, _)| *p == path)
+        .map(|(_, errors)| *errors)
+        .unwrap_or_default();
+    for code in errors {
+        operation.responses.responses.insert(
+            code.status().as_u16().to_string(),
+            RefOr::T(
+                ResponseBuilder::new()
+                    .description(format!("{code:?}"))
+                    .content(
+                        "application/json",
+                        ContentBuilder::new()
+                            .schema(Some(Ref::from_schema_name("ApiErrorBody")))
+                            .build(),
+                    )
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// The endpoints, which are served by the closures of [`super::routes`]
+#[allow(dead_code)]
+mod paths {
+    use crate::{
+        server::{
+            api::{ChangeResult, LegacyError, NewSyncKey, SyncChannels},
+            history::HistoryPage,
+            UpcomingEvent, YtChannelInfoResponse,
+        },
+        sync::Webhook,
+        yt_api::quota::QuotaStatus,
+    };
+
+    /// The events of the requested channels, which are tracked if they are new
+    #[utoipa::path(
+        get,
+        path = "/data",
+        tag = "events",
+        params(
+            ("yt-ch" = Option<String>, Query, description = "Comma separated youtube channel ids, handles or urls"),
+            ("tw-ch" = Option<String>, Query, description = "Comma separated twitch logins"),
+            ("key" = Option<String>, Query, description = "A sync key, whose channels are added"),
+            ("ended" = Option<bool>, Query, description = "Include the ended streams"),
+        ),
+        responses((status = 200, description = "The events, by start", body = Vec<UpcomingEvent>)),
+    )]
+    fn data() {}
+
+    /// The events of the requested channels as an iCalendar
+    #[utoipa::path(
+        get,
+        path = "/cal",
+        tag = "events",
+        params(
+            ("yt-ch" = Option<String>, Query, description = "Comma separated youtube channel ids, handles or urls"),
+            ("tw-ch" = Option<String>, Query, description = "Comma separated twitch logins"),
+            ("key" = Option<String>, Query, description = "A sync key, whose channels are added"),
+            ("alarm" = Option<bool>, Query, description = "Add an alarm 5 minutes before each event"),
+            ("alarm-offset" = Option<u32>, Query, description = "Add an alarm this many minutes before each event"),
+            ("duration" = Option<u32>, Query, description = "The length in minutes of the events whose end isn't known, 60 by default"),
+            ("tz" = Option<String>, Query, description = "The timezone of the calendar, like `Asia/Tokyo`, UTC by default"),
+        ),
+        responses(
+            (status = 200, description = "The calendar", body = String, content_type = "text/calendar"),
+            (status = 400, description = "The timezone is unknown", body = String),
+        ),
+    )]
+    fn calendar() {}
+
+    /// The events of the requested channels as an Atom feed
+    #[utoipa::path(
+        get,
+        path = "/feed.xml",
+        tag = "events",
+        params(
+            ("yt-ch" = Option<String>, Query, description = "Comma separated youtube channel ids, handles or urls"),
+            ("tw-ch" = Option<String>, Query, description = "Comma separated twitch logins"),
+            ("key" = Option<String>, Query, description = "A sync key, whose channels are added"),
+            ("ended" = Option<bool>, Query, description = "Include the ended streams"),
+        ),
+        responses((status = 200, description = "The feed", body = String, content_type = "application/atom+xml")),
+    )]
+    fn atom_feed() {}
+
+    /// The events of the requested channels as an RSS feed
+    #[utoipa::path(
+        get,
+        path = "/feed.rss",
+        tag = "events",
+        params(
+            ("yt-ch" = Option<String>, Query, description = "Comma separated youtube channel ids, handles or urls"),
+            ("tw-ch" = Option<String>, Query, description = "Comma separated twitch logins"),
+            ("key" = Option<String>, Query, description = "A sync key, whose channels are added"),
+            ("ended" = Option<bool>, Query, description = "Include the ended streams"),
+        ),
+        responses((status = 200, description = "The feed", body = String, content_type = "application/rss+xml")),
+    )]
+    fn rss_feed() {}
+
+    /// Find a youtube channel, which is tracked if it is new
+    #[utoipa::path(
+        get,
+        path = "/yt-ch",
+        tag = "channels",
+        params(("q" = String, Query, description = "A channel id, handle or url")),
+        responses((status = 200, description = "The channel, or why it isn't found", body = YtChannelInfoResponse)),
+    )]
+    fn yt_channel_info() {}
+
+    /// Find a twitch channel, which is tracked if it is new
+    #[utoipa::path(
+        get,
+        path = "/tw-ch",
+        tag = "channels",
+        params(("q" = String, Query, description = "A login or a channel url")),
+        responses((status = 200, description = "The channel, or why it isn't found", body = YtChannelInfoResponse)),
+    )]
+    fn tw_channel_info() {}
+
+    /// The streams which went live, newest first
+    #[utoipa::path(
+        get,
+        path = "/history",
+        tag = "events",
+        params(
+            ("yt-ch" = Option<String>, Query, description = "Comma separated youtube channel ids, handles or urls"),
+            ("tw-ch" = Option<String>, Query, description = "Comma separated twitch logins"),
+            ("key" = Option<String>, Query, description = "A sync key, whose channels are added"),
+            ("from" = Option<String>, Query, description = "A rfc3339 time or a date"),
+            ("to" = Option<String>, Query, description = "A rfc3339 time or a date"),
+            ("offset" = Option<usize>, Query),
+            ("limit" = Option<usize>, Query, description = "Defaults to 100, at most 1000. Defaults to and at most 10000 for csv"),
+            ("format" = Option<String>, Query, description = "json or csv"),
+        ),
+        responses(
+            (status = 200, description = "A page of the history", content(
+                (HistoryPage = "application/json"),
+                (String = "text/csv"),
+            )),
+            (status = 400, description = "Invalid parameter", body = LegacyError),
+        ),
+    )]
+    fn history() {}
+
+    /// The youtube api quota used today
+    #[utoipa::path(
+        get,
+        path = "/quota",
+        tag = "server",
+        responses((status = 200, description = "The quota of every api key", body = QuotaStatus)),
+    )]
+    fn quota() {}
+
+    /// Mark youtube videos as checked
+    #[utoipa::path(
+        get,
+        path = "/notice-yt-video",
+        tag = "events",
+        params(("id" = String, Query, description = "Comma separated video ids")),
+        responses((status = 200, description = "Ok, or the error", body = ChangeResult)),
+    )]
+    fn notice_yt_video() {}
+
+    /// Create a sync key
+    #[utoipa::path(
+        get,
+        path = "/sync/new",
+        tag = "sync",
+        responses((status = 200, description = "The new key", body = NewSyncKey)),
+    )]
+    fn sync_new() {}
+
+    /// Replace the channels of a sync key
+    #[utoipa::path(
+        get,
+        path = "/sync/push",
+        tag = "sync",
+        params(
+            ("key" = String, Query),
+            ("yt-ch" = Option<String>, Query, description = "Comma separated youtube channel ids"),
+            ("tw-ch" = Option<String>, Query, description = "Comma separated twitch logins"),
+        ),
+        responses((status = 200, description = "Ok, failed, or the error", body = ChangeResult)),
+    )]
+    fn sync_push() {}
+
+    /// The channels of a sync key
+    #[utoipa::path(
+        get,
+        path = "/sync/pull",
+        tag = "sync",
+        params(("key" = String, Query)),
+        responses(
+            (status = 200, description = "The channels, none if the key is not found. The unversioned endpoint answers a LegacyError instead when the key is missing", body = SyncChannels),
+        ),
+    )]
+    fn sync_pull() {}
+
+    /// Add a webhook to a sync key
+    #[utoipa::path(
+        get,
+        path = "/sync/webhook/add",
+        tag = "sync",
+        params(
+            ("key" = String, Query),
+            ("url" = String, Query, description = "A https url with a public address, or any http(s) url of the webhook_allowed_hosts"),
+            ("kind" = Option<String>, Query, description = "json, discord or slack. Defaults to json"),
+        ),
+        responses((status = 200, description = "Ok with the webhook id, failed, or the error", body = ChangeResult)),
+    )]
+    fn webhook_add() {}
+
+    /// Remove a webhook of a sync key
+    #[utoipa::path(
+        get,
+        path = "/sync/webhook/remove",
+        tag = "sync",
+        params(("key" = String, Query), ("id" = String, Query, description = "The webhook id")),
+        responses((status = 200, description = "Ok, failed, or the error", body = ChangeResult)),
+    )]
+    fn webhook_remove() {}
+
+    /// The webhooks of a sync key with their latest deliveries
+    #[utoipa::path(
+        get,
+        path = "/sync/webhook/list",
+        tag = "sync",
+        params(("key" = String, Query)),
+        responses(
+            (status = 200, description = "The webhooks. The unversioned endpoint answers a LegacyError instead when the key is missing or not found", body = Vec<Webhook>),
+        ),
+    )]
+    fn webhook_list() {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet, VecDeque},
+        sync::Arc,
+    };
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::Value;
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::{
+        server::{
+            api::*,
+            history::{HistoryPage, HistoryRecord, Platform},
+            ChannelInfoData, EventSource, ServerData, TwChannelBrief, UpcomingEvent,
+            YtChannelBrief, YtChannelInfoResponse,
+        },
+        sync::{Delivery, Webhook, WebhookKind},
+        test::*,
+        yt_api::{quota, YtApiError},
+    };
+
+    /// Check `value` against `schema` like a json schema validator, for the keywords the
+    /// document uses. Properties which aren't documented are errors.
+    fn check(document: &Value, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            return check(
+                document,
+                &document["components"]["schemas"][name],
+                value,
+                at,
+            );
+        }
+        if let Some(schemas) = schema["oneOf"].as_array() {
+            let matched = schemas
+                .iter()
+                .filter(|s| check(document, s, value, at).is_ok())
+                .count();
+            return match matched {
+                1 => Ok(()),
+                n => Err(format!("{at}: {value} matches {n} schemas of oneOf")),
+            };
+        }
+        for schema in schema["allOf"].as_array().into_iter().flatten() {
+            check(document, schema, value, at)?;
+        }
+        if let Some(values) = schema["enum"].as_array() {
+            if !values.contains(value) {
+                return Err(format!("{at}: {value} is not one of {values:?}"));
+            }
+        }
+        let types = match &schema["type"] {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(types) => types.iter().filter_map(|t| t.as_str()).collect(),
+            _ => vec![],
+        };
+        let is_type = |t: &str| match t {
+            "null" => value.is_null(),
+            "boolean" => value.is_boolean(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "string" => value.is_string(),
+            "array" => value.is_array(),
+            "object" => value.is_object(),
+            _ => false,
+        };
+        if !types.is_empty() && !types.iter().any(|t| is_type(t)) {
+            return Err(format!("{at}: {value} is not {types:?}"));
+        }
+        match value {
+            Value::Object(fields) => {
+                for required in schema["required"].as_array().into_iter().flatten() {
+                    let required = required.as_str().unwrap_or_default();
+                    if !fields.contains_key(required) {
+                        return Err(format!("{at}: required {required} is missing"));
+                    }
+                }
+                for (name, field) in fields {
+                    let at = format!("{at}.{name}");
+                    match (&schema["properties"][name], &schema["additionalProperties"]) {
+                        (Value::Object(_), _) => {
+                            check(document, &schema["properties"][name], field, &at)?
+                        }
+                        (_, additional @ Value::Object(_)) => {
+                            check(document, additional, field, &at)?
+                        }
+                        (_, Value::Bool(true)) => {}
+                        _ => return Err(format!("{at} is not documented")),
+                    }
+                }
+            }
+            Value::Array(items) if schema["items"].is_object() => {
+                for (i, item) in items.iter().enumerate() {
+                    check(document, &schema["items"], item, &format!("{at}[{i}]"))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn sample<T: serde::Serialize>(name: &str, value: T) -> (String, Value) {
+        (name.to_string(), serde_json::to_value(value).unwrap())
+    }
+
+    /// A serialized value of every documented schema, of each variant of the enums
+    fn samples() -> Vec<(String, Value)> {
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let yt_event = UpcomingEvent {
+            start_date_time: time,
+            start_timestamp_millis: time.timestamp_millis(),
+            thumbnail_url: Some("https://i.ytimg.com/vi/v/hqdefault.jpg".to_string()),
+            title: "Stream".to_string(),
+            description: "Description".to_string(),
+            target_url: "https://www.youtube.com/watch?v=v".to_string(),
+            ongoing: false,
+            source: EventSource::YoutubeChannel(YtChannelBrief {
+                id: "UCid".to_string(),
+                thumbnail_url: "https://yt3.ggpht.com/t".to_string(),
+                title: "Channel".to_string(),
+                custom_url: "@channel".to_string(),
+            }),
+            uid: "v".to_string(),
+            end_date_time: Some(time),
+            ended: true,
+            scheduled_start_date_time: Some(time),
+            viewer_count: Some(10),
+            sequence: 2,
+            last_modified: Some(time),
+        };
+        let tw_event = UpcomingEvent {
+            thumbnail_url: None,
+            source: EventSource::TwitchChannel(TwChannelBrief {
+                id: "1".to_string(),
+                thumbnail_url: "https://static-cdn.jtvnw.net/t".to_string(),
+                title: "Channel".to_string(),
+                login: "channel".to_string(),
+            }),
+            end_date_time: None,
+            ended: false,
+            scheduled_start_date_time: None,
+            viewer_count: None,
+            last_modified: None,
+            ..yt_event.clone()
+        };
+        let record = |platform, end: Option<chrono::DateTime<Utc>>| HistoryRecord {
+            uid: "v".to_string(),
+            platform,
+            channel: "UCid".to_string(),
+            channel_title: "Channel".to_string(),
+            title: "Stream".to_string(),
+            url: "https://www.youtube.com/watch?v=v".to_string(),
+            scheduled_start: end,
+            actual_start: time,
+            end,
+            duration_seconds: end.map(|_| 3600),
+            peak_viewers: end.map(|_| 100),
+        };
+        let channel_info = || ChannelInfoData {
+            id: "UCid".to_string(),
+            title: "Channel".to_string(),
+            custom_url: "@channel".to_string(),
+            thumbnail: "https://yt3.ggpht.com/t".to_string(),
+        };
+        let delivery = |status, error| Delivery {
+            time,
+            event_uid: "v".to_string(),
+            change: "scheduled".to_string(),
+            attempts: 1,
+            status,
+            error,
+        };
+        let quota = quota::Quota::new(vec!["key".to_string()], 100);
+        TOKIO_RUNTIME
+            .block_on(quota.spend(quota::Endpoint::Videos))
+            .unwrap();
+        let status = quota.status();
+
+        vec![
+            sample("UpcomingEvent", &yt_event),
+            sample("UpcomingEvent", &tw_event),
+            sample("EventSource", &yt_event.source),
+            sample("EventSource", &tw_event.source),
+            match &yt_event.source {
+                EventSource::YoutubeChannel(brief) => sample("YtChannelBrief", brief),
+                EventSource::TwitchChannel(brief) => sample("TwChannelBrief", brief),
+            },
+            match &tw_event.source {
+                EventSource::YoutubeChannel(brief) => sample("YtChannelBrief", brief),
+                EventSource::TwitchChannel(brief) => sample("TwChannelBrief", brief),
+            },
+            sample("ChannelInfoData", channel_info()),
+            sample(
+                "YtChannelInfoResponse",
+                YtChannelInfoResponse::data(channel_info()),
+            ),
+            sample(
+                "YtChannelInfoResponse",
+                YtChannelInfoResponse::error("Not found".to_string()),
+            ),
+            sample(
+                "HistoryPage",
+                HistoryPage {
+                    total: 2,
+                    offset: 0,
+                    limit: 100,
+                    records: vec![
+                        record(Platform::Youtube, Some(time)),
+                        record(Platform::Twitch, None),
+                    ],
+                },
+            ),
+            sample("HistoryRecord", record(Platform::Twitch, None)),
+            sample("Platform", Platform::Youtube),
+            sample("Platform", Platform::Twitch),
+            sample("KeyStatus", &status.keys[0]),
+            sample("QuotaStatus", status),
+            sample(
+                "Webhook",
+                Webhook {
+                    id: uuid::Uuid::new_v4(),
+                    url: "https://example.com/hook".to_string(),
+                    kind: WebhookKind::Discord,
+                    deliveries: VecDeque::from([
+                        delivery(Some(200), None),
+                        delivery(None, Some("timeout".to_string())),
+                    ]),
+                },
+            ),
+            sample("WebhookKind", WebhookKind::Json),
+            sample("WebhookKind", WebhookKind::Slack),
+            sample("Delivery", delivery(Some(500), None)),
+            sample(
+                "NewSyncKey",
+                NewSyncKey {
+                    key: uuid::Uuid::new_v4(),
+                },
+            ),
+            sample("ChangeResult", ChangeResult::new("Ok")),
+            sample(
+                "ChangeResult",
+                ChangeResult {
+                    id: Some(uuid::Uuid::new_v4()),
+                    ..ChangeResult::new("Ok")
+                },
+            ),
+            sample(
+                "SyncChannels",
+                SyncChannels {
+                    yt_ch: Some(HashSet::from(["UCid".to_string()])),
+                    tw_ch: Some(HashSet::new()),
+                },
+            ),
+            sample("SyncChannels", SyncChannels::default()),
+            sample(
+                "LegacyError",
+                LegacyError {
+                    error: "No key specified".to_string(),
+                },
+            ),
+            sample(
+                "ApiErrorBody",
+                ApiErrorBody {
+                    error: ApiError::from(YtApiError::QuotaExceeded),
+                },
+            ),
+            sample(
+                "ApiErrorBody",
+                ApiErrorBody {
+                    error: ApiError::missing("q"),
+                },
+            ),
+            sample("ApiError", ApiError::not_found("Not found")),
+            sample(
+                "ErrorDetails",
+                ApiError::from(YtApiError::DeserializeFailed("eof".to_string())).details,
+            ),
+            sample("ErrorCode", ErrorCode::UpstreamFailed),
+        ]
+    }
+
+    #[test]
+    fn test_document_matches_serialized_structs() {
+        let document = serde_json::to_value(document()).unwrap();
+        let samples = samples();
+        for (name, value) in samples.iter() {
+            let schema = &document["components"]["schemas"][name];
+            assert!(schema.is_object(), "{name} is not documented");
+            if let Err(e) = check(&document, schema, value, name) {
+                panic!("The schema of {name} doesn't match its serialization: {e}");
+            }
+        }
+        for name in document["components"]["schemas"]
+            .as_object()
+            .unwrap()
+            .keys()
+        {
+            assert!(
+                samples.iter().any(|(n, _)| n == name),
+                "No sample of {name} is checked"
+            );
+        }
+    }
+
+    #[test]
+    fn test_documented_paths_are_served() {
+        TOKIO_RUNTIME.block_on(async {
+            let context = new_context();
+            let routes = crate::server::routes(
+                Arc::new(RwLock::new(
+                    ServerData::new(context.clone(), CONFIG.channel_expire_min, 0, &None)
+                        .await
+                        .unwrap(),
+                )),
+                context,
+                None,
+                None,
+                Default::default(),
+                None,
+            );
+            let response = warp::test::request()
+                .path("/api/openapi.json")
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 200);
+            let served = serde_json::from_slice::<Value>(response.body()).unwrap();
+            let paths = served["paths"].as_object().unwrap();
+            assert!(paths.contains_key("/data") && paths.contains_key("/api/v1/data"));
+            let mut statuses = HashMap::new();
+            for path in paths.keys() {
+                let response = warp::test::request().path(path).reply(&routes).await;
+                statuses.insert(path.clone(), response.status().as_u16());
+                // every documented path is served, though most need parameters
+                let not_served = response.status() == 404
+                    && serde_json::from_slice::<Value>(response.body())
+                        .map(|b| b["error"]["message"] == "Unknown endpoint")
+                        .unwrap_or(true);
+                assert!(!not_served, "{path} is documented but not served");
+            }
+            assert_eq!(statuses["/api/v1/yt-ch"], 400);
+            assert_eq!(statuses["/yt-ch"], 200);
+        });
+    }
+}
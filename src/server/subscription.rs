@@ -0,0 +1,273 @@
+//! The channels a request subscribes to, shared by the endpoints which serve events, like
+//! `/data`, `/cal` and `/events/stream`.
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use futures::{stream, StreamExt};
+use tokio::sync::RwLock;
+use warp::Filter;
+
+use super::{ServerData, UpcomingEvent};
+use crate::{context::AppContext, yt_api::try_youtube_id};
+
+const DEFAULT_ALARM_OFFSET_MIN: i64 = 5;
+/// The channels of each platform a request can ask for, the others are ignored
+const MAX_CHANNELS: usize = 200;
+/// How many youtube handles or urls are looked up at once
+const RESOLVE_CONCURRENCY: usize = 4;
+
+/// The `yt-ch`, `tw-ch` and `key` parameters of a request resolved to channels, with the
+/// options of the endpoints
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Subscription {
+    pub yt_channel_ids: Vec<String>,
+    pub tw_channel_logins: Vec<String>,
+    /// `ended`, include the ended streams
+    pub include_ended: bool,
+    /// How long before the start of each calendar event an alarm is set, by `alarm-offset` in
+    /// minutes, or `alarm` for the default
+    pub alarm: Option<chrono::Duration>,
+    /// `duration` in minutes, the length of the calendar events whose end isn't known
+    pub duration: Option<chrono::Duration>,
+    /// `tz`, the timezone of the calendar, like `Asia/Tokyo`
+    pub timezone: Option<chrono_tz::Tz>,
+}
+
+impl Subscription {
+    /// Resolve the channels asked by `query`, at most [`MAX_CHANNELS`] of each platform. The
+    /// youtube handles and urls are looked up a few at a time. An unknown `tz` is ignored, the
+    /// endpoints which use it check it with [`timezone`] first.
+    pub async fn resolve(ctx: &AppContext, query: &HashMap<String, String>) -> Self {
+        let mut yt_channels: Vec<String> = vec![];
+        let mut tw_channel_logins: Vec<String> = vec![];
+        if let Some(query_str) = query.get("yt-ch") {
+            yt_channels.extend(query_str.split(',').map(|s| s.to_string()));
+        }
+        if let Some(query_str) = query.get("tw-ch") {
+            tw_channel_logins.extend(query_str.split(',').map(|s| s.to_string()));
+        }
+        if let Some(sync_key) = query.get("key") {
+            let key = uuid::Uuid::from_str(sync_key).unwrap_or_default();
+            if let Some(ch) = ctx.sync_store.get_yt_channel(&key).await {
+                yt_channels.extend(ch);
+            }
+            if let Some(ch) = ctx.sync_store.get_tw_channel(&key).await {
+                tw_channel_logins.extend(ch);
+            }
+        }
+        if yt_channels.len() > MAX_CHANNELS || tw_channel_logins.len() > MAX_CHANNELS {
+            log::warn!(
+                "{} youtube and {} twitch channels are requested, only the first {MAX_CHANNELS} of each are used",
+                yt_channels.len(),
+                tw_channel_logins.len()
+            );
+            yt_channels.truncate(MAX_CHANNELS);
+            tw_channel_logins.truncate(MAX_CHANNELS);
+        }
+        Self {
+            yt_channel_ids: youtube_ids(ctx, &yt_channels).await,
+            tw_channel_logins,
+            include_ended: is_enabled(query, "ended"),
+            alarm: match minutes(query, "alarm-offset") {
+                Some(offset) => Some(offset),
+                None if is_enabled(query, "alarm") => {
+                    Some(chrono::Duration::minutes(DEFAULT_ALARM_OFFSET_MIN))
+                }
+                None => None,
+            },
+            duration: minutes(query, "duration").filter(|d| *d > chrono::Duration::zero()),
+            timezone: timezone(query).ok().flatten(),
+        }
+    }
+
+    /// Resolve the channels like [`Subscription::resolve`]. The channels are tracked if they are
+    /// new, and marked as used.
+    pub async fn track(
+        server_data: &RwLock<ServerData>,
+        ctx: &AppContext,
+        query: &HashMap<String, String>,
+    ) -> Self {
+        let subscription = Self::resolve(ctx, query).await;
+        let new_yt_channel_ids = {
+            server_data
+                .read()
+                .await
+                .filter_new_yt_channel_id(&subscription.yt_channel_ids)
+        };
+        if !new_yt_channel_ids.is_empty() {
+            if let Err(e) =
+                ServerData::track_new_yt_channels(server_data, &new_yt_channel_ids).await
+            {
+                log::error!("Track new youtube channel failed: {:?}", e);
+            };
+        }
+
+        let new_tw_channel_logins = {
+            server_data
+                .read()
+                .await
+                .filter_new_tw_channel_login(&subscription.tw_channel_logins)
+        };
+        if !new_tw_channel_logins.is_empty() {
+            ServerData::track_new_tw_channels(server_data, &new_tw_channel_logins).await;
+        }
+        {
+            let mut server_data = server_data.write().await;
+            for id in subscription.yt_channel_ids.iter() {
+                server_data.touch_yt_channel(id);
+            }
+            for login in subscription.tw_channel_logins.iter() {
+                server_data.touch_tw_channel(login);
+            }
+        }
+        subscription
+    }
+
+    /// Whether `event` is from a subscribed channel
+    pub fn includes(&self, event: &UpcomingEvent) -> bool {
+        event.is_from(&self.yt_channel_ids, &self.tw_channel_logins)
+    }
+
+    /// The events of the subscribed channels, sorted, without the ended ones unless asked
+    pub async fn events(&self, server_data: &RwLock<ServerData>) -> Vec<UpcomingEvent> {
+        let mut events = server_data
+            .read()
+            .await
+            .events
+            .iter()
+            .filter(|e| self.includes(e))
+            .filter(|e| self.include_ended || !e.ended)
+            .cloned()
+            .collect::<Vec<UpcomingEvent>>();
+        events.sort();
+        events
+    }
+
+    /// The cancelled events of the subscribed channels, which the calendars keep for a while
+    pub async fn cancelled_events(&self, server_data: &RwLock<ServerData>) -> Vec<UpcomingEvent> {
+        server_data
+            .read()
+            .await
+            .cancelled_events
+            .iter()
+            .filter(|e| self.includes(e))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The youtube channel ids of `channels`, which are ids, handles or urls, in the same order.
+/// At most [`RESOLVE_CONCURRENCY`] of them are looked up at once.
+pub async fn youtube_ids(ctx: &AppContext, channels: &[String]) -> Vec<String> {
+    let lookups = channels
+        .iter()
+        .map(|c| try_youtube_id(ctx, c))
+        .collect::<Vec<_>>();
+    stream::iter(lookups)
+        .buffered(RESOLVE_CONCURRENCY)
+        .collect()
+        .await
+}
+
+fn is_enabled(query: &HashMap<String, String>, name: &str) -> bool {
+    match query.get(name) {
+        Some(v) => v.to_lowercase() == "true" || v.to_lowercase() == "yes",
+        None => false,
+    }
+}
+
+/// The `tz` parameter of `query`, an error if it isn't a timezone
+pub fn timezone(query: &HashMap<String, String>) -> Result<Option<chrono_tz::Tz>, String> {
+    query
+        .get("tz")
+        .map(|tz| tz.parse().map_err(|_| format!("Unknown timezone: {tz}")))
+        .transpose()
+}
+
+/// The parameter `name` as a number of minutes. Invalid values are ignored like missing ones.
+fn minutes(query: &HashMap<String, String>, name: &str) -> Option<chrono::Duration> {
+    query
+        .get(name)
+        .and_then(|v| v.parse::<u32>().ok())
+        .map(|m| chrono::Duration::minutes(m as i64))
+}
+
+/// Resolve the [`Subscription`] of the request, tracking its new channels
+pub fn subscription(
+    server_data: Arc<RwLock<ServerData>>,
+    context: Arc<AppContext>,
+) -> impl Filter<Extract = (Subscription,), Error = warp::Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().then(move |query: HashMap<String, String>| {
+        let server_data = server_data.clone();
+        let context = context.clone();
+        async move { Subscription::track(&server_data, &context, &query).await }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_resolve() {
+        TOKIO_RUNTIME.block_on(async {
+            MOCK.add_yt_channel("UCsubscriptionA", "SubscriptionA", "Subscription A");
+            MOCK.add_yt_channel("UCsubscriptionB", "SubscriptionB", "Subscription B");
+            let context = new_context();
+            let key = context.sync_store.new_key().await;
+            context
+                .sync_store
+                .set_yt_channels(&key, ["@SubscriptionB"].into_iter())
+                .await
+                .unwrap();
+            context
+                .sync_store
+                .set_tw_channels(&key, ["synced"].into_iter())
+                .await
+                .unwrap();
+            let query = HashMap::from([
+                (
+                    "yt-ch".to_string(),
+                    "@SubscriptionA,UCplain,https://www.youtube.com/@SubscriptionB".to_string(),
+                ),
+                ("tw-ch".to_string(), "login".to_string()),
+                ("key".to_string(), key.to_string()),
+                ("ended".to_string(), "Yes".to_string()),
+                ("alarm-offset".to_string(), "15".to_string()),
+                ("duration".to_string(), "invalid".to_string()),
+                ("tz".to_string(), "Asia/Tokyo".to_string()),
+            ]);
+            assert_eq!(
+                Subscription::resolve(&context, &query).await,
+                Subscription {
+                    yt_channel_ids: vec![
+                        "UCsubscriptionA".to_string(),
+                        "UCplain".to_string(),
+                        "UCsubscriptionB".to_string(),
+                        "UCsubscriptionB".to_string(),
+                    ],
+                    tw_channel_logins: vec!["login".to_string(), "synced".to_string()],
+                    include_ended: true,
+                    alarm: Some(chrono::Duration::minutes(15)),
+                    duration: None,
+                    timezone: Some(chrono_tz::Asia::Tokyo),
+                }
+            );
+            assert_eq!(
+                Subscription::resolve(&context, &HashMap::new()).await,
+                Subscription::default()
+            );
+            let query = HashMap::from([("tz".to_string(), "Mars/Olympus".to_string())]);
+            assert!(timezone(&query).is_err());
+            assert_eq!(Subscription::resolve(&context, &query).await.timezone, None);
+            let many = (0..MAX_CHANNELS + 1)
+                .map(|n| format!("UCmany{n}"))
+                .collect::<Vec<String>>();
+            let query = HashMap::from([("yt-ch".to_string(), many.join(","))]);
+            assert_eq!(
+                Subscription::resolve(&context, &query).await.yt_channel_ids,
+                many[..MAX_CHANNELS]
+            );
+        });
+    }
+}
@@ -0,0 +1,524 @@
+//! Notify the webhooks registered on the sync keys when an event of their channels is
+//! scheduled, rescheduled, starts or ends.
+//!
+//! Anyone with a sync key can register a webhook, so they may only target public https urls,
+//! unless their host is allowed in the config.
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use super::{subscription::youtube_ids, EventChange, EventSource};
+use crate::{
+    context::AppContext,
+    sync::{Delivery, WebhookKind},
+};
+
+const MAX_ATTEMPTS: u32 = 4;
+#[cfg(not(test))]
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(10);
+/// How long one delivery attempt may take
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The urls the webhooks may target: public https urls, or any http(s) url of the allowed
+/// hosts
+#[derive(Debug, Clone, Default)]
+pub struct WebhookPolicy {
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl WebhookPolicy {
+    pub fn new(allowed_hosts: Vec<String>) -> Self {
+        Self {
+            allowed_hosts: Arc::new(allowed_hosts),
+        }
+    }
+
+    fn allows_host(&self, host: &str) -> bool {
+        self.allowed_hosts
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(host))
+    }
+
+    /// Check that a webhook can target `url`. Its host is resolved, and rejected if any of its
+    /// addresses is loopback, private or link-local.
+    pub async fn check_url(&self, url: &str) -> Result<(), String> {
+        let url = reqwest::Url::parse(url).map_err(|_| "Invalid url".to_string())?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("Invalid url".to_string());
+        }
+        let host = url.host_str().ok_or("Invalid url")?;
+        if self.allows_host(host) {
+            return Ok(());
+        }
+        if url.scheme() != "https" {
+            return Err("Only https urls are allowed".to_string());
+        }
+        let addresses = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => vec![ip],
+            Err(_) => tokio::net::lookup_host((host, url.port_or_known_default().unwrap_or(443)))
+                .await
+                .map_err(|_| format!("Resolve {host} failed"))?
+                .map(|a| a.ip())
+                .collect(),
+        };
+        if addresses.is_empty() || !addresses.into_iter().all(is_public) {
+            return Err(format!("{host} is not a public address"));
+        }
+        Ok(())
+    }
+
+    /// The client of the deliveries. It doesn't follow redirects, and resolves hosts only to
+    /// public addresses unless they are allowed, so a changed dns record can't point a webhook
+    /// at the local network.
+    fn client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .local_address(local_ip_address::local_ip().unwrap())
+            .timeout(DELIVERY_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver(self.clone())))
+            .build()
+            .unwrap()
+    }
+}
+
+struct PublicResolver(WebhookPolicy);
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: warp::hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        let policy = self.0.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let addresses = tokio::net::lookup_host((host, 0))
+                .await?
+                .collect::<Vec<_>>();
+            if !policy.allows_host(host) && !addresses.iter().all(|a| is_public(a.ip())) {
+                return Err(format!("{host} is not a public address").into());
+            }
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Whether `ip` is reachable on the internet. The ipv6 addresses which embed an ipv4 address
+/// are judged by the embedded address.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(a == 0
+                || ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // the shared address space of carrier-grade nat
+                || (a == 100 && b & 0xc0 == 64)
+                // benchmarking
+                || (a == 198 && b & 0xfe == 18)
+                // reserved
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_multicast()
+                    // unique local and link-local
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The ipv4 address of an ipv4-mapped or ipv4-compatible (`::a.b.c.d`, which includes `::`
+/// and `::1`), NAT64 (`64:ff9b::/96`) or 6to4 (`2002::/16`) address
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let [a, b, c, d, e, f, g, h] = ip.segments();
+    let ipv4 = |high: u16, low: u16| Ipv4Addr::from(((high as u32) << 16) | low as u32);
+    match (a, b, c, d, e, f) {
+        (0x64, 0xff9b, 0, 0, 0, 0) => Some(ipv4(g, h)),
+        (0x2002, ..) => Some(ipv4(b, c)),
+        _ => ip.to_ipv4(),
+    }
+}
+
+/// Deliver the changes received from `receiver` to the webhooks of the sync keys until the
+/// sender is dropped
+pub fn spawn_dispatcher(
+    mut receiver: broadcast::Receiver<EventChange>,
+    ctx: &Arc<AppContext>,
+    policy: WebhookPolicy,
+) {
+    let ctx_clone = ctx.clone();
+    let http = policy.client();
+    ctx.spawn("webhook dispatcher", async move {
+        loop {
+            match receiver.recv().await {
+                Ok(change) => {
+                    // the changes of one update arrive together, and are dispatched at once
+                    let mut changes = vec![change];
+                    loop {
+                        match receiver.try_recv() {
+                            Ok(change) => changes.push(change),
+                            Err(TryRecvError::Lagged(n)) => {
+                                log::warn!("Webhook dispatcher lagged, {n} changes dropped")
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    changes.retain(should_notify);
+                    if !changes.is_empty() {
+                        dispatch(&ctx_clone, &http, &policy, &changes).await;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log::warn!("Webhook dispatcher lagged, {n} changes dropped")
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Only new events, rescheduled events and events that start or end are worth a ping
+fn should_notify(change: &EventChange) -> bool {
+    match change {
+        EventChange::Added { .. } | EventChange::Started { .. } | EventChange::Ended { .. } => true,
+        EventChange::Updated { event, previous } => {
+            event.start_date_time != previous.start_date_time
+        }
+        EventChange::Removed { .. } => false,
+    }
+}
+
+/// Deliver `changes` to the webhooks of the sync keys with their channels. The channels of each
+/// key are resolved once for all the changes.
+async fn dispatch(
+    ctx: &Arc<AppContext>,
+    http: &reqwest::Client,
+    policy: &WebhookPolicy,
+    changes: &[EventChange],
+) {
+    for subscriber in ctx.sync_store.get_webhook_subscribers().await {
+        let yt_channel_ids = youtube_ids(
+            ctx,
+            &subscriber.yt_channels.into_iter().collect::<Vec<String>>(),
+        )
+        .await;
+        let tw_channel_logins = subscriber.tw_channels.into_iter().collect::<Vec<String>>();
+        for change in changes
+            .iter()
+            .filter(|c| c.event().is_from(&yt_channel_ids, &tw_channel_logins))
+        {
+            for webhook in subscriber.webhooks.iter() {
+                let payload = payload(webhook.kind, change);
+                let change_name = change.name();
+                let event_uid = change.event().uid.clone();
+                let key = subscriber.key;
+                let webhook_id = webhook.id;
+                let url = webhook.url.clone();
+                let http = http.clone();
+                let policy = policy.clone();
+                let ctx_clone = ctx.clone();
+                ctx.spawn("webhook delivery", async move {
+                    let delivery =
+                        deliver(&http, &policy, &url, &payload, event_uid, change_name).await;
+                    ctx_clone
+                        .sync_store
+                        .record_delivery(&key, &webhook_id, delivery)
+                        .await;
+                });
+            }
+        }
+    }
+}
+
+fn headline(change: &EventChange) -> String {
+    let channel = match &change.event().source {
+        EventSource::YoutubeChannel(c) => &c.title,
+        EventSource::TwitchChannel(c) => &c.title,
+    };
+    match change {
+        EventChange::Added { event } if event.ongoing => format!("🔴 {channel} is live"),
+        EventChange::Added { .. } => format!("📅 {channel} scheduled a stream"),
+        EventChange::Updated { .. } => format!("🕒 {channel} rescheduled a stream"),
+        EventChange::Started { .. } => format!("🔴 {channel} is live"),
+        EventChange::Ended { .. } => format!("{channel} ended the stream"),
+        EventChange::Removed { .. } => format!("{channel} cancelled a stream"),
+    }
+}
+
+fn payload(kind: WebhookKind, change: &EventChange) -> Value {
+    let event = change.event();
+    match kind {
+        WebhookKind::Discord => json!({
+            "content": headline(change),
+            "embeds": [{
+                "title": event.title,
+                "url": event.target_url,
+                "timestamp": event.start_date_time,
+                "image": event.thumbnail_url.as_ref().map(|url| json!({ "url": url })),
+            }],
+        }),
+        WebhookKind::Slack => json!({
+            "text": format!(
+                "{}\n<{}|{}>\nStarts at {}",
+                headline(change),
+                event.target_url,
+                event.title,
+                event.start_date_time.to_rfc3339()
+            ),
+        }),
+        WebhookKind::Json => serde_json::to_value(change).unwrap_or_default(),
+    }
+}
+
+/// POST the payload, retrying with exponential backoff on connection errors, 429 and 5xx.
+/// The url is checked again, since its host may resolve to other addresses by now.
+async fn deliver(
+    http: &reqwest::Client,
+    policy: &WebhookPolicy,
+    url: &str,
+    payload: &Value,
+    event_uid: String,
+    change: &str,
+) -> Delivery {
+    let mut delivery = Delivery {
+        time: Utc::now(),
+        event_uid,
+        change: change.to_string(),
+        attempts: 0,
+        status: None,
+        error: None,
+    };
+    if let Err(e) = policy.check_url(url).await {
+        log::warn!("Refused to deliver to webhook {url}: {e}");
+        delivery.error = Some(e);
+        return delivery;
+    }
+    while delivery.attempts < MAX_ATTEMPTS {
+        if delivery.attempts > 0 {
+            tokio::time::sleep(RETRY_BASE_DELAY * 2u32.pow(delivery.attempts - 1)).await;
+        }
+        delivery.attempts += 1;
+        match http.post(url).json(payload).send().await {
+            Ok(response) => {
+                let status = response.status();
+                delivery.status = Some(status.as_u16());
+                if status.is_success() {
+                    delivery.error = None;
+                    return delivery;
+                }
+                delivery.error = Some(format!("Http status {status}"));
+                if !(status.is_server_error() || status.as_u16() == 429) {
+                    break;
+                }
+            }
+            Err(e) => {
+                delivery.status = None;
+                delivery.error = Some(e.without_url().to_string());
+            }
+        }
+    }
+    log::warn!(
+        "Deliver {} of {} to webhook failed after {} attempts: {:?}",
+        delivery.change,
+        delivery.event_uid,
+        delivery.attempts,
+        delivery.error
+    );
+    delivery
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{TwChannelBrief, UpcomingEvent};
+    use crate::test::*;
+
+    fn live_event() -> UpcomingEvent {
+        let start = Utc::now();
+        UpcomingEvent {
+            start_date_time: start,
+            start_timestamp_millis: start.timestamp_millis(),
+            thumbnail_url: None,
+            title: "Stream title".to_string(),
+            description: "Just Chatting".to_string(),
+            target_url: "https://www.twitch.tv/webhook_test".to_string(),
+            ongoing: true,
+            source: EventSource::TwitchChannel(TwChannelBrief {
+                id: "webhook-test".to_string(),
+                thumbnail_url: String::new(),
+                title: "Webhook Test".to_string(),
+                login: "webhook_test".to_string(),
+            }),
+            uid: "webhook_test@twitch@yt-watcher".to_string(),
+            end_date_time: None,
+            ended: false,
+            scheduled_start_date_time: None,
+            viewer_count: None,
+            sequence: 0,
+            last_modified: None,
+        }
+    }
+
+    #[test]
+    fn test_should_notify() {
+        let event = live_event();
+        let mut previous = event.clone();
+        previous.title = "Old title".to_string();
+        assert!(!should_notify(&EventChange::Updated {
+            event: event.clone(),
+            previous: Box::new(previous.clone()),
+        }));
+        previous.start_date_time -= chrono::Duration::hours(1);
+        assert!(should_notify(&EventChange::Updated {
+            event: event.clone(),
+            previous: Box::new(previous),
+        }));
+        assert!(should_notify(&EventChange::Started {
+            event: event.clone()
+        }));
+        assert!(!should_notify(&EventChange::Removed { event }));
+    }
+
+    #[test]
+    fn test_payload() {
+        let change = EventChange::Started {
+            event: live_event(),
+        };
+        let discord = payload(WebhookKind::Discord, &change);
+        assert_eq!(discord["content"], "🔴 Webhook Test is live");
+        assert_eq!(
+            discord["embeds"][0]["url"],
+            "https://www.twitch.tv/webhook_test"
+        );
+        let slack = payload(WebhookKind::Slack, &change);
+        assert!(slack["text"]
+            .as_str()
+            .unwrap()
+            .contains("<https://www.twitch.tv/webhook_test|Stream title>"));
+        let generic = payload(WebhookKind::Json, &change);
+        assert_eq!(generic["type"], "started");
+        assert_eq!(generic["event"]["uid"], "webhook_test@twitch@yt-watcher");
+    }
+
+    #[test]
+    fn test_check_url() {
+        TOKIO_RUNTIME.block_on(async {
+            let policy = WebhookPolicy::default();
+            assert!(policy.check_url("https://1.1.1.1/hook").await.is_ok());
+            assert!(policy
+                .check_url("https://[2606:4700:4700::1111]/hook")
+                .await
+                .is_ok());
+            // nat64 and 6to4 of a public address
+            assert!(policy
+                .check_url("https://[64:ff9b::101:101]/hook")
+                .await
+                .is_ok());
+            assert!(policy
+                .check_url("https://[2002:101:101::1]/hook")
+                .await
+                .is_ok());
+            for url in [
+                "ftp://1.1.1.1/hook",
+                "http://1.1.1.1/hook",
+                "https://127.0.0.1/hook",
+                "https://169.254.169.254/latest/meta-data",
+                "https://10.0.0.1/hook",
+                "https://192.168.1.1/hook",
+                "https://100.64.0.1/hook",
+                "https://0.0.0.0/hook",
+                "https://[::1]/hook",
+                "https://[fd00::1]/hook",
+                "https://[::ffff:127.0.0.1]/hook",
+                "https://localhost/hook",
+                "https://198.18.0.1/hook",
+                "https://198.19.255.1/hook",
+                "https://240.0.0.1/hook",
+                "https://0.1.2.3/hook",
+                "https://[::]/hook",
+                "https://[::7f00:1]/hook",
+                "https://[::a00:1]/hook",
+                "https://[64:ff9b::7f00:1]/hook",
+                "https://[64:ff9b::a9fe:a9fe]/hook",
+                "https://[2002:a00:1::1]/hook",
+                "https://[2002:7f00:1::1]/hook",
+            ] {
+                assert!(policy.check_url(url).await.is_err(), "{url} is allowed");
+            }
+            let policy = WebhookPolicy::new(vec!["127.0.0.1".to_string()]);
+            assert!(policy.check_url("http://127.0.0.1:8080/hook").await.is_ok());
+            assert!(policy.check_url("https://10.0.0.1/hook").await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_deliver_retry() {
+        let url = format!("{}/webhook", MOCK.upstream().youtube);
+        let change = EventChange::Started {
+            event: live_event(),
+        };
+        let policy = WebhookPolicy::new(vec!["127.0.0.1".to_string()]);
+        let http = policy.client();
+        TOKIO_RUNTIME.block_on(async {
+            MOCK.fail_next_webhooks(2);
+            let delivery = deliver(
+                &http,
+                &policy,
+                &url,
+                &payload(WebhookKind::Json, &change),
+                "uid".to_string(),
+                "started",
+            )
+            .await;
+            assert_eq!(delivery.attempts, 3);
+            assert_eq!(delivery.status, Some(204));
+            assert!(delivery.error.is_none());
+
+            MOCK.fail_next_webhooks(MAX_ATTEMPTS as usize);
+            let delivery = deliver(
+                &http,
+                &policy,
+                &url,
+                &Value::Null,
+                "uid".to_string(),
+                "started",
+            )
+            .await;
+            assert_eq!(delivery.attempts, MAX_ATTEMPTS);
+            assert_eq!(delivery.status, Some(503));
+            assert!(delivery.error.is_some());
+
+            // the mock is on the local network
+            let policy = WebhookPolicy::default();
+            let delivery = deliver(
+                &policy.client(),
+                &policy,
+                &url,
+                &Value::Null,
+                "uid".to_string(),
+                "started",
+            )
+            .await;
+            assert_eq!(delivery.attempts, 0);
+            assert!(delivery.error.is_some());
+        });
+        assert!(MOCK
+            .webhook_requests()
+            .iter()
+            .any(|body| body["type"] == "started"));
+    }
+}
@@ -0,0 +1,722 @@
+//! Persistent state of the server in an embedded SQLite database.
+//!
+//! The schema is upgraded by [`MIGRATIONS`] when the database is opened. Files written by older
+//! versions are moved into the database once by [`import_legacy_files`]. A corrupted database is
+//! replaced by its newest valid backup.
+use std::{collections::HashSet, path::Path, sync::Mutex};
+
+use chrono::Utc;
+use rusqlite::{params, Connection, OpenFlags};
+
+use crate::{
+    backup,
+    server::{history::HistoryRecord, CalendarSave, ChannelSave},
+    sync::KeySave,
+};
+
+pub const DATABASE_FILE: &str = "yt-watcher.db";
+pub const LEGACY_CHANNELS_FILE: &str = "channels.json";
+pub const LEGACY_VIDEOS_FILE: &str = "videos.txt";
+pub const LEGACY_HISTORY_FILE: &str = "history.json";
+pub const LEGACY_SYNC_KEYS_FILE: &str = "sync_keys.json";
+pub const LEGACY_CHANNEL_CACHE_FILE: &str = "channel_cache";
+
+/// Each entry upgrades the schema by one version, which is kept in `PRAGMA user_version`
+const MIGRATIONS: &[&str] = &[
+    r#"
+CREATE TABLE yt_channels (
+    id TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE TABLE tw_channels (
+    login TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE TABLE yt_videos (
+    id TEXT PRIMARY KEY NOT NULL
+);
+CREATE TABLE history (
+    uid TEXT NOT NULL,
+    actual_start TEXT NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (uid, actual_start)
+);
+CREATE TABLE sync_keys (
+    key TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE TABLE channel_id_cache (
+    url TEXT PRIMARY KEY NOT NULL,
+    channel_id TEXT NOT NULL,
+    position INTEGER NOT NULL
+);
+"#,
+    r#"
+CREATE TABLE calendar_events (
+    uid TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE TABLE cancelled_events (
+    uid TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL
+);
+"#,
+];
+
+#[derive(Debug)]
+pub enum StorageError {
+    Sqlite(rusqlite::Error),
+    Serialize(serde_json::Error),
+    Io(std::io::Error),
+    /// The integrity check of the database failed
+    Corrupted(String),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Sqlite(e) => write!(f, "Sqlite error: {e}"),
+            StorageError::Serialize(e) => write!(f, "Serialize error: {e}"),
+            StorageError::Io(e) => write!(f, "Io error: {e}"),
+            StorageError::Corrupted(e) => write!(f, "Database corrupted: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(value: rusqlite::Error) -> Self {
+        StorageError::Sqlite(value)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(value: serde_json::Error) -> Self {
+        StorageError::Serialize(value)
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(value: std::io::Error) -> Self {
+        StorageError::Io(value)
+    }
+}
+
+/// Where the server keeps its state. Every save replaces the stored state atomically, except
+/// [`Storage::save_history`] which only adds or updates records.
+pub trait Storage: Send + Sync {
+    fn load_channels(&self) -> Result<ChannelSave, StorageError>;
+    fn save_channels(&self, channels: &ChannelSave) -> Result<(), StorageError>;
+    fn load_yt_videos(&self) -> Result<HashSet<String>, StorageError>;
+    fn save_yt_videos(&self, ids: &HashSet<String>) -> Result<(), StorageError>;
+    fn load_history(&self) -> Result<Vec<HistoryRecord>, StorageError>;
+    fn save_history(&self, records: &[HistoryRecord]) -> Result<(), StorageError>;
+    fn load_sync_keys(&self) -> Result<Vec<KeySave>, StorageError>;
+    fn save_sync_keys(&self, keys: &[KeySave]) -> Result<(), StorageError>;
+    /// The cached channel ids of urls, the least recently used first
+    fn load_channel_id_cache(&self) -> Result<Vec<(String, String)>, StorageError>;
+    fn save_channel_id_cache(&self, entries: &[(String, String)]) -> Result<(), StorageError>;
+    fn load_calendar(&self) -> Result<CalendarSave, StorageError>;
+    fn save_calendar(&self, calendar: &CalendarSave) -> Result<(), StorageError>;
+    /// Write a consistent copy of the whole storage to the new file `target`
+    fn backup(&self, target: &Path) -> Result<(), StorageError>;
+}
+
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+    /// A read only connection to the same file, so a backup doesn't block the saves
+    backup_connection: Option<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let connection = Connection::open(&path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        let check: String = connection.pragma_query_value(None, "quick_check", |row| row.get(0))?;
+        if check != "ok" {
+            return Err(StorageError::Corrupted(check));
+        }
+        let mut storage = Self::with_connection(connection)?;
+        let backup_connection = Connection::open_with_flags(
+            &path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        storage.backup_connection = Some(Mutex::new(backup_connection));
+        Ok(storage)
+    }
+
+    /// Open the database at `path`. If it is corrupted, it is moved aside and replaced by the
+    /// newest backup in `backup_dir` which opens.
+    pub fn open_with_fallback(path: &Path, backup_dir: &Path) -> Result<Self, StorageError> {
+        let error = match Self::open(path) {
+            Ok(storage) => return Ok(storage),
+            Err(e) => e,
+        };
+        log::error!("Open database {} failed: {error}", path.display());
+        let backups = backup::list(path, backup_dir);
+        if backups.is_empty() {
+            return Err(error);
+        }
+        let suffix = format!(".corrupted-{}", Utc::now().timestamp());
+        for extension in ["", "-wal", "-shm"] {
+            let mut file = path.as_os_str().to_owned();
+            file.push(extension);
+            let mut moved = file.clone();
+            moved.push(&suffix);
+            match std::fs::rename(&file, &moved) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        for b in backups {
+            std::fs::copy(&b, path)?;
+            match Self::open(path) {
+                Ok(storage) => {
+                    log::warn!("Restored database {} from {}", path.display(), b.display());
+                    return Ok(storage);
+                }
+                Err(e) => {
+                    log::error!("Open backup {} failed: {e}", b.display());
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, StorageError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<Self, StorageError> {
+        migrate(&mut connection)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            backup_connection: None,
+        })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // a panic while holding the lock can't leave a transaction open
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn backup_connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        match &self.backup_connection {
+            Some(connection) => connection
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            // an in memory database can't be opened twice
+            None => self.connection(),
+        }
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), StorageError> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (n, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::info!("Migrating database to version {}", n + 1);
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", n + 1)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+/// Read the json `data` column of every row of `table`
+fn load_data<T: serde::de::DeserializeOwned>(
+    connection: &Connection,
+    table: &str,
+) -> Result<Vec<T>, StorageError> {
+    let mut statement = connection.prepare(&format!("SELECT data FROM {table}"))?;
+    let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+    let mut values = vec![];
+    for row in rows {
+        values.push(serde_json::from_str(&row?)?);
+    }
+    Ok(values)
+}
+
+impl Storage for SqliteStorage {
+    fn load_channels(&self) -> Result<ChannelSave, StorageError> {
+        let connection = self.connection();
+        Ok(ChannelSave {
+            yt: load_data::<crate::server::YtChannelSave>(&connection, "yt_channels")?
+                .into_iter()
+                .map(|c| (c.id.clone(), c))
+                .collect(),
+            tw: load_data::<crate::server::TwChannelSave>(&connection, "tw_channels")?
+                .into_iter()
+                .map(|c| (c.login.clone(), c))
+                .collect(),
+        })
+    }
+
+    fn save_channels(&self, channels: &ChannelSave) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM yt_channels", [])?;
+        for (id, c) in channels.yt.iter() {
+            transaction.execute(
+                "INSERT INTO yt_channels (id, data) VALUES (?1, ?2)",
+                params![id, serde_json::to_string(c)?],
+            )?;
+        }
+        transaction.execute("DELETE FROM tw_channels", [])?;
+        for (login, c) in channels.tw.iter() {
+            transaction.execute(
+                "INSERT INTO tw_channels (login, data) VALUES (?1, ?2)",
+                params![login, serde_json::to_string(c)?],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn load_yt_videos(&self) -> Result<HashSet<String>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT id FROM yt_videos")?;
+        let ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+        Ok(ids)
+    }
+
+    fn save_yt_videos(&self, ids: &HashSet<String>) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM yt_videos", [])?;
+        for id in ids.iter() {
+            transaction.execute("INSERT INTO yt_videos (id) VALUES (?1)", params![id])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn load_history(&self) -> Result<Vec<HistoryRecord>, StorageError> {
+        let connection = self.connection();
+        let mut records: Vec<HistoryRecord> = load_data(&connection, "history")?;
+        records.sort_by_key(|r| r.actual_start);
+        Ok(records)
+    }
+
+    fn save_history(&self, records: &[HistoryRecord]) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        for r in records {
+            transaction.execute(
+                "INSERT OR REPLACE INTO history (uid, actual_start, data) VALUES (?1, ?2, ?3)",
+                params![
+                    r.uid,
+                    r.actual_start.to_rfc3339(),
+                    serde_json::to_string(r)?
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn load_sync_keys(&self) -> Result<Vec<KeySave>, StorageError> {
+        load_data(&self.connection(), "sync_keys")
+    }
+
+    fn save_sync_keys(&self, keys: &[KeySave]) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM sync_keys", [])?;
+        for k in keys {
+            transaction.execute(
+                "INSERT INTO sync_keys (key, data) VALUES (?1, ?2)",
+                params![k.key().to_string(), serde_json::to_string(k)?],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn load_channel_id_cache(&self) -> Result<Vec<(String, String)>, StorageError> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare("SELECT url, channel_id FROM channel_id_cache ORDER BY position")?;
+        let entries = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+        Ok(entries)
+    }
+
+    fn save_channel_id_cache(&self, entries: &[(String, String)]) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM channel_id_cache", [])?;
+        for (position, (url, id)) in entries.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO channel_id_cache (url, channel_id, position) VALUES (?1, ?2, ?3)",
+                params![url, id, position],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn load_calendar(&self) -> Result<CalendarSave, StorageError> {
+        let connection = self.connection();
+        Ok(CalendarSave {
+            events: load_data(&connection, "calendar_events")?,
+            cancelled: load_data(&connection, "cancelled_events")?,
+        })
+    }
+
+    fn save_calendar(&self, calendar: &CalendarSave) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        for (table, events) in [
+            ("calendar_events", &calendar.events),
+            ("cancelled_events", &calendar.cancelled),
+        ] {
+            transaction.execute(&format!("DELETE FROM {table}"), [])?;
+            for e in events {
+                transaction.execute(
+                    &format!("INSERT OR REPLACE INTO {table} (uid, data) VALUES (?1, ?2)"),
+                    params![e.uid(), serde_json::to_string(e)?],
+                )?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn backup(&self, target: &Path) -> Result<(), StorageError> {
+        self.backup_connection()
+            .execute("VACUUM INTO ?1", params![target.to_string_lossy()])?;
+        Ok(())
+    }
+}
+
+/// The state kept in memory only, for the tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+#[cfg(test)]
+#[derive(Default, serde::Serialize)]
+struct MemoryState {
+    channels: ChannelSave,
+    yt_videos: HashSet<String>,
+    history: Vec<HistoryRecord>,
+    sync_keys: Vec<KeySave>,
+    channel_id_cache: Vec<(String, String)>,
+    calendar: CalendarSave,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn load_channels(&self) -> Result<ChannelSave, StorageError> {
+        Ok(self.state().channels.clone())
+    }
+
+    fn save_channels(&self, channels: &ChannelSave) -> Result<(), StorageError> {
+        self.state().channels = channels.clone();
+        Ok(())
+    }
+
+    fn load_yt_videos(&self) -> Result<HashSet<String>, StorageError> {
+        Ok(self.state().yt_videos.clone())
+    }
+
+    fn save_yt_videos(&self, ids: &HashSet<String>) -> Result<(), StorageError> {
+        self.state().yt_videos = ids.clone();
+        Ok(())
+    }
+
+    fn load_history(&self) -> Result<Vec<HistoryRecord>, StorageError> {
+        Ok(self.state().history.clone())
+    }
+
+    fn save_history(&self, records: &[HistoryRecord]) -> Result<(), StorageError> {
+        let history = &mut self.state().history;
+        for r in records {
+            match history
+                .iter_mut()
+                .find(|old| old.uid == r.uid && old.actual_start == r.actual_start)
+            {
+                Some(old) => *old = r.clone(),
+                None => history.push(r.clone()),
+            }
+        }
+        history.sort_by_key(|r| r.actual_start);
+        Ok(())
+    }
+
+    fn load_sync_keys(&self) -> Result<Vec<KeySave>, StorageError> {
+        Ok(self.state().sync_keys.clone())
+    }
+
+    fn save_sync_keys(&self, keys: &[KeySave]) -> Result<(), StorageError> {
+        self.state().sync_keys = keys.to_vec();
+        Ok(())
+    }
+
+    fn load_channel_id_cache(&self) -> Result<Vec<(String, String)>, StorageError> {
+        Ok(self.state().channel_id_cache.clone())
+    }
+
+    fn save_channel_id_cache(&self, entries: &[(String, String)]) -> Result<(), StorageError> {
+        self.state().channel_id_cache = entries.to_vec();
+        Ok(())
+    }
+
+    fn load_calendar(&self) -> Result<CalendarSave, StorageError> {
+        Ok(self.state().calendar.clone())
+    }
+
+    fn save_calendar(&self, calendar: &CalendarSave) -> Result<(), StorageError> {
+        self.state().calendar = calendar.clone();
+        Ok(())
+    }
+
+    fn backup(&self, target: &Path) -> Result<(), StorageError> {
+        let dump = serde_json::to_vec(&*self.state())?;
+        std::fs::write(target, dump)?;
+        Ok(())
+    }
+}
+
+/// Move the save files of older versions in `dir` into the storage. Each imported file is
+/// renamed with an `.imported` suffix, so it is imported only once.
+pub fn import_legacy_files(storage: &dyn Storage, dir: &Path) {
+    let import = |name: &str, f: &dyn Fn(String) -> Result<(), StorageError>| {
+        let path = dir.join(name);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                log::error!("Read legacy save file {} failed: {e}", path.display());
+                return;
+            }
+        };
+        if let Err(e) = f(content) {
+            log::error!("Import legacy save file {} failed: {e}", path.display());
+            return;
+        }
+        let mut imported = path.clone().into_os_string();
+        imported.push(".imported");
+        match std::fs::rename(&path, &imported) {
+            Ok(()) => log::info!("Imported legacy save file {}", path.display()),
+            Err(e) => log::error!("Rename legacy save file {} failed: {e}", path.display()),
+        }
+    };
+    let lines = |s: &str| {
+        s.lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<String>>()
+    };
+
+    import(LEGACY_CHANNELS_FILE, &|s| {
+        storage.save_channels(&serde_json::from_str(&s)?)
+    });
+    import(LEGACY_VIDEOS_FILE, &|s| {
+        let mut ids = storage.load_yt_videos()?;
+        ids.extend(lines(&s));
+        storage.save_yt_videos(&ids)
+    });
+    import(LEGACY_HISTORY_FILE, &|s| {
+        storage.save_history(&serde_json::from_str::<Vec<HistoryRecord>>(&s)?)
+    });
+    import(LEGACY_SYNC_KEYS_FILE, &|s| {
+        storage.save_sync_keys(&serde_json::from_str::<Vec<KeySave>>(&s)?)
+    });
+    import(LEGACY_CHANNEL_CACHE_FILE, &|s| {
+        let entries = lines(&s)
+            .into_iter()
+            .filter_map(|pair| {
+                let (url, id) = pair.split_once(' ')?;
+                Some((url.to_string(), id.to_string()))
+            })
+            .collect::<Vec<(String, String)>>();
+        storage.save_channel_id_cache(&entries)
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rusqlite::OptionalExtension;
+
+    #[test]
+    fn test_migrate() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        // migrating again is a no-op
+        migrate(&mut connection).unwrap();
+        let version: usize = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        let table: Option<String> = connection
+            .query_row(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'history'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap();
+        assert!(table.is_some());
+    }
+
+    /// Both implementations must behave the same, so tests can use the in-memory one
+    #[test]
+    fn test_implementations_agree() {
+        let record = |title: &str| HistoryRecord {
+            uid: "agree@twitch@yt-watcher".to_string(),
+            platform: crate::server::history::Platform::Twitch,
+            channel: "agree".to_string(),
+            channel_title: "Agree".to_string(),
+            title: title.to_string(),
+            url: "https://www.twitch.tv/agree".to_string(),
+            scheduled_start: None,
+            actual_start: "2023-07-01T00:00:00Z".parse().unwrap(),
+            end: None,
+            duration_seconds: None,
+            peak_viewers: None,
+        };
+        let cache = vec![
+            ("https://www.youtube.com/@b".to_string(), "UCb".to_string()),
+            ("https://www.youtube.com/@a".to_string(), "UCa".to_string()),
+        ];
+        let storages: [Box<dyn Storage>; 2] = [
+            Box::new(SqliteStorage::open_in_memory().unwrap()),
+            Box::new(MemoryStorage::default()),
+        ];
+        for storage in storages {
+            assert!(storage.load_channels().unwrap().yt.is_empty());
+            storage.save_history(&[record("live")]).unwrap();
+            storage.save_history(&[record("ended")]).unwrap();
+            assert_eq!(storage.load_history().unwrap(), vec![record("ended")]);
+            storage.save_channel_id_cache(&cache).unwrap();
+            assert_eq!(storage.load_channel_id_cache().unwrap(), cache);
+            storage
+                .save_yt_videos(&HashSet::from(["v".to_string()]))
+                .unwrap();
+            storage.save_yt_videos(&HashSet::new()).unwrap();
+            assert!(storage.load_yt_videos().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_import_legacy_files() {
+        let dir = std::env::temp_dir().join(format!(
+            "yt-watcher-test-legacy-import-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(LEGACY_CHANNELS_FILE),
+            r#"{"yt": {"UClegacy": {"custom_url": "@legacy", "id": "UClegacy", "title": "Legacy",
+                "thumbnail": "", "upload_playlist": "UUlegacy", "last_time_used": "2023-07-01T00:00:00Z",
+                "first_video_after_all_stream": ""}},
+                "tw": {"legacy_user": {"id": "1", "login": "legacy_user", "profile_img": "",
+                "name": "Legacy User", "last_time_used": "2023-07-01T00:00:00Z"}}}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join(LEGACY_VIDEOS_FILE), "video-1\nvideo-2\r\n").unwrap();
+        std::fs::write(
+            dir.join(LEGACY_SYNC_KEYS_FILE),
+            r#"[{"key": "67e55044-10b1-426f-9247-bb680e5fe0c8", "last_used": "2023-07-01T00:00:00Z",
+                "yt_channels": ["UClegacy"]}]"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join(LEGACY_CHANNEL_CACHE_FILE),
+            "https://www.youtube.com/@old UCold\nhttps://www.youtube.com/@new UCnew\n",
+        )
+        .unwrap();
+
+        let storage = SqliteStorage::open(dir.join(DATABASE_FILE)).unwrap();
+        import_legacy_files(&storage, &dir);
+        let channels = storage.load_channels().unwrap();
+        assert!(channels.yt.contains_key("UClegacy"));
+        assert!(channels.tw.contains_key("legacy_user"));
+        assert_eq!(
+            storage.load_yt_videos().unwrap(),
+            HashSet::from(["video-1".to_string(), "video-2".to_string()])
+        );
+        let keys = storage.load_sync_keys().unwrap();
+        assert_eq!(
+            keys[0].key().to_string(),
+            "67e55044-10b1-426f-9247-bb680e5fe0c8"
+        );
+        assert_eq!(
+            storage.load_channel_id_cache().unwrap(),
+            vec![
+                (
+                    "https://www.youtube.com/@old".to_string(),
+                    "UCold".to_string()
+                ),
+                (
+                    "https://www.youtube.com/@new".to_string(),
+                    "UCnew".to_string()
+                ),
+            ]
+        );
+        assert!(!dir.join(LEGACY_CHANNELS_FILE).exists());
+        assert!(dir.join("channels.json.imported").exists());
+
+        // the state survives reopening the database
+        drop(storage);
+        let storage = SqliteStorage::open(dir.join(DATABASE_FILE)).unwrap();
+        assert_eq!(storage.load_yt_videos().unwrap().len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_with_fallback() {
+        let dir = std::env::temp_dir().join(format!(
+            "yt-watcher-test-database-fallback-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(DATABASE_FILE);
+        let backup_dir = dir.join(backup::BACKUP_DIR);
+        let storage = SqliteStorage::open(&path).unwrap();
+        storage
+            .save_yt_videos(&HashSet::from(["backed-up".to_string()]))
+            .unwrap();
+        backup::rotate(&path, &backup_dir, 2, |temp| {
+            // the backup uses its own connection, so it doesn't wait for the saves
+            let _saving = storage.connection();
+            storage.backup(temp).map_err(std::io::Error::other)
+        })
+        .unwrap();
+        drop(storage);
+
+        std::fs::write(&path, "not a database").unwrap();
+        let _ = std::fs::remove_file(dir.join(format!("{DATABASE_FILE}-wal")));
+        assert!(SqliteStorage::open(&path).is_err());
+        let storage = SqliteStorage::open_with_fallback(&path, &backup_dir).unwrap();
+        assert_eq!(
+            storage.load_yt_videos().unwrap(),
+            HashSet::from(["backed-up".to_string()])
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,24 +1,91 @@
+use crate::storage::Storage;
 use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
 use tokio::{sync::Mutex, task::JoinHandle};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 static MAX_KEEP_TIME: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::days(30));
 static SAVE_INTERVAL: Lazy<std::time::Duration> =
     Lazy::new(|| std::time::Duration::from_secs(10 * 60));
-static mut SAVER_HANDLE: Option<JoinHandle<()>> = None;
-const SAVE_NAME: &str = "sync_keys.json";
+const MAX_DELIVERY_LOG: usize = 20;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct KeySave {
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookKind {
+    Discord,
+    Slack,
+    Json,
+}
+
+impl FromStr for WebhookKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "discord" => Ok(WebhookKind::Discord),
+            "slack" => Ok(WebhookKind::Slack),
+            "json" => Ok(WebhookKind::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct Delivery {
+    pub time: DateTime<Utc>,
+    pub event_uid: String,
+    pub change: String,
+    pub attempts: u32,
+    /// Http status of the last attempt
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    pub kind: WebhookKind,
+    /// The latest deliveries, newest first
+    #[serde(default)]
+    #[schema(value_type = Vec<Delivery>)]
+    pub deliveries: VecDeque<Delivery>,
+}
+
+/// The channels and the webhooks of a sync key
+#[derive(Debug, Clone)]
+pub struct WebhookSubscriber {
+    pub key: Uuid,
+    pub yt_channels: HashSet<String>,
+    pub tw_channels: HashSet<String>,
+    pub webhooks: Vec<Webhook>,
+}
+
+/// What a sync key holds, without the channels and webhooks themselves
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KeySummary {
+    pub key: Uuid,
+    pub last_used: DateTime<Utc>,
+    pub yt_channels: usize,
+    pub tw_channels: usize,
+    pub webhooks: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KeySave {
     key: Uuid,
     last_used: DateTime<Utc>,
     #[serde(default)]
     yt_channels: HashSet<String>,
     #[serde(default)]
     tw_channels: HashSet<String>,
+    #[serde(default)]
+    webhooks: Vec<Webhook>,
 }
 
 impl Default for KeySave {
@@ -28,6 +95,7 @@ impl Default for KeySave {
             last_used: Utc::now(),
             yt_channels: HashSet::new(),
             tw_channels: HashSet::new(),
+            webhooks: vec![],
         }
     }
 }
@@ -47,132 +115,305 @@ impl KeySave {
         &mut self.tw_channels
     }
 
+    pub fn webhooks(&mut self) -> &mut Vec<Webhook> {
+        self.last_used = Utc::now();
+        &mut self.webhooks
+    }
+
     pub fn last_used(&self) -> &DateTime<Utc> {
         &self.last_used
     }
 }
 
-static SYNC_KEY_SAVES: Lazy<Mutex<Vec<KeySave>>> = Lazy::new(|| {
-    Mutex::new(
-        serde_json::from_str::<Vec<KeySave>>(
-            &std::fs::read_to_string(SAVE_NAME)
-                .map_err(|e| {
-                    log::error!("Open sync key save failed: {e}");
-                    e
-                })
-                .unwrap_or_default(),
-        )
-        .map_err(|e| {
-            log::error!("Deserialize sync key save failed: {e}");
-            e
-        })
-        .unwrap_or_default(),
-    )
-});
-
-pub async fn new_key() -> Uuid {
-    let new_key = KeySave::default();
-    let id = *new_key.key();
-    log::info!("Generated new sync key: {id}");
-    SYNC_KEY_SAVES.lock().await.push(new_key);
-    save().await;
-    id
+/// The sync keys, saved to the storage when they change and every [`SAVE_INTERVAL`]
+pub struct SyncStore {
+    keys: Mutex<Vec<KeySave>>,
+    storage: Arc<dyn Storage>,
 }
 
-pub async fn get_yt_channel(key: &Uuid) -> Option<HashSet<String>> {
-    init_saver();
-    SYNC_KEY_SAVES
-        .lock()
-        .await
-        .iter_mut()
-        .find(|s| s.key() == key)
-        .map(|s| s.yt_channels().clone())
-}
-
-pub async fn get_tw_channel(key: &Uuid) -> Option<HashSet<String>> {
-    init_saver();
-    SYNC_KEY_SAVES
-        .lock()
-        .await
-        .iter_mut()
-        .find(|s| s.key() == key)
-        .map(|s| s.tw_channels().clone())
-}
+impl SyncStore {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        let keys = storage
+            .load_sync_keys()
+            .map_err(|e| {
+                log::error!("Load sync keys failed: {e}");
+                e
+            })
+            .unwrap_or_default();
+        Self {
+            keys: Mutex::new(keys),
+            storage,
+        }
+    }
 
-pub async fn set_yt_channels(
-    key: &Uuid,
-    ch: impl Iterator<Item = impl ToString>,
-) -> Result<(), ()> {
-    init_saver();
-    let resp = SYNC_KEY_SAVES
-        .lock()
-        .await
-        .iter_mut()
-        .find(|s| s.key() == key)
-        .map(|s| {
-            *s.yt_channels() = ch.map(|s| s.to_string()).collect();
+    /// Save the keys periodically, which records when they were used, until the store is dropped
+    pub fn spawn_saver(self: &Arc<Self>) -> JoinHandle<()> {
+        let store = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(*SAVE_INTERVAL).await;
+                match store.upgrade() {
+                    Some(store) => store.save().await,
+                    None => break,
+                }
+            }
         })
-        .ok_or(());
-    save().await;
-    resp
-}
+    }
 
-pub async fn set_tw_channels(
-    key: &Uuid,
-    ch: impl Iterator<Item = impl ToString>,
-) -> Result<(), ()> {
-    init_saver();
-    let resp = SYNC_KEY_SAVES
-        .lock()
-        .await
-        .iter_mut()
-        .find(|s| s.key() == key)
-        .map(|s| {
-            *s.tw_channels() = ch.map(|s| s.to_string()).collect();
-        })
-        .ok_or(());
-    save().await;
-    resp
-}
+    pub async fn new_key(&self) -> Uuid {
+        let new_key = KeySave::default();
+        let id = *new_key.key();
+        log::info!("Generated new sync key: {id}");
+        self.keys.lock().await.push(new_key);
+        self.save().await;
+        id
+    }
 
-pub async fn save() {
-    trim().await;
-    match serde_json::to_string(&*SYNC_KEY_SAVES.lock().await) {
-        Ok(s) => {
-            if let Err(e) = tokio::fs::write(SAVE_NAME, s.as_bytes()).await {
-                log::error!("Save sync keys to file failed: {e}");
-            }
+    pub async fn get_yt_channel(&self, key: &Uuid) -> Option<HashSet<String>> {
+        self.keys
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.key() == key)
+            .map(|s| s.yt_channels().clone())
+    }
+
+    pub async fn get_tw_channel(&self, key: &Uuid) -> Option<HashSet<String>> {
+        self.keys
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.key() == key)
+            .map(|s| s.tw_channels().clone())
+    }
+
+    pub async fn set_yt_channels(
+        &self,
+        key: &Uuid,
+        ch: impl Iterator<Item = impl ToString>,
+    ) -> Result<(), ()> {
+        let resp = self
+            .keys
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.key() == key)
+            .map(|s| {
+                *s.yt_channels() = ch.map(|s| s.to_string()).collect();
+            })
+            .ok_or(());
+        self.save().await;
+        resp
+    }
+
+    pub async fn set_tw_channels(
+        &self,
+        key: &Uuid,
+        ch: impl Iterator<Item = impl ToString>,
+    ) -> Result<(), ()> {
+        let resp = self
+            .keys
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.key() == key)
+            .map(|s| {
+                *s.tw_channels() = ch.map(|s| s.to_string()).collect();
+            })
+            .ok_or(());
+        self.save().await;
+        resp
+    }
+
+    pub async fn add_webhook(&self, key: &Uuid, url: &str, kind: WebhookKind) -> Result<Uuid, ()> {
+        let resp = self
+            .keys
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.key() == key)
+            .map(|s| {
+                let id = Uuid::new_v4();
+                s.webhooks().push(Webhook {
+                    id,
+                    url: url.to_string(),
+                    kind,
+                    deliveries: VecDeque::new(),
+                });
+                id
+            })
+            .ok_or(());
+        self.save().await;
+        resp
+    }
+
+    pub async fn remove_webhook(&self, key: &Uuid, id: &Uuid) -> Result<(), ()> {
+        let resp = self
+            .keys
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.key() == key)
+            .and_then(|s| {
+                let webhooks = s.webhooks();
+                let n = webhooks.iter().position(|w| w.id == *id)?;
+                webhooks.remove(n);
+                Some(())
+            })
+            .ok_or(());
+        self.save().await;
+        resp
+    }
+
+    pub async fn get_webhooks(&self, key: &Uuid) -> Option<Vec<Webhook>> {
+        self.keys
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.key() == key)
+            .map(|s| s.webhooks().clone())
+    }
+
+    /// Every sync key with at least one webhook. Doesn't count as a use of the keys.
+    pub async fn get_webhook_subscribers(&self) -> Vec<WebhookSubscriber> {
+        self.keys
+            .lock()
+            .await
+            .iter()
+            .filter(|s| !s.webhooks.is_empty())
+            .map(|s| WebhookSubscriber {
+                key: s.key,
+                yt_channels: s.yt_channels.clone(),
+                tw_channels: s.tw_channels.clone(),
+                webhooks: s.webhooks.clone(),
+            })
+            .collect()
+    }
+
+    /// Every sync key, the most recently used first. Doesn't count as a use of the keys.
+    pub async fn summaries(&self) -> Vec<KeySummary> {
+        let mut summaries = self
+            .keys
+            .lock()
+            .await
+            .iter()
+            .map(|s| KeySummary {
+                key: s.key,
+                last_used: s.last_used,
+                yt_channels: s.yt_channels.len(),
+                tw_channels: s.tw_channels.len(),
+                webhooks: s.webhooks.len(),
+            })
+            .collect::<Vec<KeySummary>>();
+        summaries.sort_by_key(|s| std::cmp::Reverse(s.last_used));
+        summaries
+    }
+
+    /// A copy of every sync key. Doesn't count as a use of the keys.
+    pub async fn export(&self) -> Vec<KeySave> {
+        self.keys.lock().await.clone()
+    }
+
+    /// Delete `key` and its webhooks. Returns whether the key existed.
+    pub async fn revoke(&self, key: &Uuid) -> bool {
+        let removed = {
+            let mut lock = self.keys.lock().await;
+            let len = lock.len();
+            lock.retain(|s| s.key() != key);
+            lock.len() != len
+        };
+        if removed {
+            log::info!("Revoked sync key {key}");
+            self.save().await;
         }
-        Err(e) => {
-            log::error!("Serialize sync keys to file failed: {e}");
+        removed
+    }
+
+    pub async fn record_delivery(&self, key: &Uuid, webhook_id: &Uuid, delivery: Delivery) {
+        if let Some(webhook) = self
+            .keys
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.key() == key)
+            .and_then(|s| s.webhooks.iter_mut().find(|w| w.id == *webhook_id))
+        {
+            webhook.deliveries.push_front(delivery);
+            webhook.deliveries.truncate(MAX_DELIVERY_LOG);
         }
     }
-}
 
-pub async fn trim() {
-    let mut remove_idx = vec![];
-    let mut lock = SYNC_KEY_SAVES.lock().await;
-    let now = Utc::now();
-    for (idx, v) in lock.iter().enumerate().rev() {
-        if now - *v.last_used() > *MAX_KEEP_TIME {
-            log::info!("Remove sync key {} due to not used", v.key);
-            remove_idx.push(idx);
+    pub async fn save(&self) {
+        self.trim().await;
+        let storage = self.storage.clone();
+        let keys = self.keys.lock().await.clone();
+        let saved = tokio::task::spawn_blocking(move || storage.save_sync_keys(&keys)).await;
+        match saved {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("Save sync keys failed: {e}"),
+            Err(e) => log::error!("Save sync keys task failed: {e}"),
         }
     }
-    for idx in remove_idx.into_iter() {
-        lock.remove(idx);
+
+    pub async fn trim(&self) {
+        let mut remove_idx = vec![];
+        let mut lock = self.keys.lock().await;
+        let now = Utc::now();
+        for (idx, v) in lock.iter().enumerate().rev() {
+            if now - *v.last_used() > *MAX_KEEP_TIME {
+                log::info!("Remove sync key {} due to not used", v.key);
+                remove_idx.push(idx);
+            }
+        }
+        for idx in remove_idx.into_iter() {
+            lock.remove(idx);
+        }
     }
 }
 
-fn init_saver() {
-    unsafe {
-        if SAVER_HANDLE.is_none() {
-            SAVER_HANDLE = Some(tokio::spawn(async {
-                loop {
-                    tokio::time::sleep(*SAVE_INTERVAL).await;
-                    save().await;
-                }
-            }));
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::test::TOKIO_RUNTIME;
+
+    #[test]
+    fn test_sync_store() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        TOKIO_RUNTIME.block_on(async {
+            let store = SyncStore::new(storage.clone());
+            let key = store.new_key().await;
+            store
+                .set_yt_channels(&key, ["UCsync-test"].iter())
+                .await
+                .unwrap();
+            assert!(store
+                .set_tw_channels(&Uuid::new_v4(), ["unknown"].iter())
+                .await
+                .is_err());
+            let webhook = store
+                .add_webhook(&key, "https://example.com/hook", WebhookKind::Discord)
+                .await
+                .unwrap();
+            assert_eq!(store.get_webhook_subscribers().await.len(), 1);
+
+            // the keys are restored from the storage
+            let restored = SyncStore::new(storage.clone());
+            assert_eq!(
+                restored.get_yt_channel(&key).await,
+                Some(HashSet::from(["UCsync-test".to_string()]))
+            );
+            restored.remove_webhook(&key, &webhook).await.unwrap();
+            assert_eq!(restored.get_webhooks(&key).await.map(|w| w.len()), Some(0));
+
+            let other = restored.new_key().await;
+            let summaries = restored.summaries().await;
+            assert_eq!(summaries.len(), 2);
+            assert_eq!(summaries[0].key, other);
+            assert_eq!(summaries[1].yt_channels, 1);
+            assert!(restored.revoke(&key).await);
+            assert!(!restored.revoke(&key).await);
+            assert_eq!(SyncStore::new(storage).export().await.len(), 1);
+        });
     }
 }
@@ -0,0 +1,274 @@
+//! Messages twitch sends to the EventSub webhook callback.
+//!
+//! See https://dev.twitch.tv/docs/eventsub/handling-webhook-events
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use super::structs::EventSubSubscription;
+
+pub const MESSAGE_ID_HEADER: &str = "twitch-eventsub-message-id";
+pub const MESSAGE_TIMESTAMP_HEADER: &str = "twitch-eventsub-message-timestamp";
+pub const MESSAGE_SIGNATURE_HEADER: &str = "twitch-eventsub-message-signature";
+pub const MESSAGE_TYPE_HEADER: &str = "twitch-eventsub-message-type";
+/// Messages are a single small json object, larger bodies are refused
+pub const MAX_MESSAGE_BYTES: u64 = 64 * 1024;
+
+/// Messages older than this are rejected to prevent replay attacks
+const MAX_MESSAGE_AGE_MIN: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionType {
+    StreamOnline,
+    StreamOffline,
+    ChannelUpdate,
+}
+
+impl SubscriptionType {
+    pub const ALL: [SubscriptionType; 3] = [
+        SubscriptionType::StreamOnline,
+        SubscriptionType::StreamOffline,
+        SubscriptionType::ChannelUpdate,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SubscriptionType::StreamOnline => "stream.online",
+            SubscriptionType::StreamOffline => "stream.offline",
+            SubscriptionType::ChannelUpdate => "channel.update",
+        }
+    }
+
+    pub fn version(&self) -> &'static str {
+        match self {
+            SubscriptionType::StreamOnline | SubscriptionType::StreamOffline => "1",
+            SubscriptionType::ChannelUpdate => "2",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StreamOnlineEvent {
+    pub broadcaster_user_id: String,
+    pub broadcaster_user_login: String,
+    pub broadcaster_user_name: String,
+    pub started_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StreamOfflineEvent {
+    pub broadcaster_user_id: String,
+    pub broadcaster_user_login: String,
+    pub broadcaster_user_name: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChannelUpdateEvent {
+    pub broadcaster_user_id: String,
+    pub broadcaster_user_login: String,
+    pub broadcaster_user_name: String,
+    pub title: String,
+    pub category_name: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    StreamOnline(StreamOnlineEvent),
+    StreamOffline(StreamOfflineEvent),
+    ChannelUpdate(ChannelUpdateEvent),
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Verification(String),
+    Notification(Event),
+    Revocation(EventSubSubscription),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EventSubError {
+    MissingHeader(&'static str),
+    InvalidSignature,
+    Expired,
+    DeserializeFailed(String),
+    UnknownMessageType(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageBody {
+    subscription: EventSubSubscription,
+    challenge: Option<String>,
+    event: Option<serde_json::Value>,
+}
+
+fn signature(secret: &str, message_id: &str, timestamp: &str, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(message_id.as_bytes());
+    mac.update(timestamp.as_bytes());
+    mac.update(body);
+    mac
+}
+
+/// The value of the signature header twitch sends along with the message
+pub fn sign(secret: &str, message_id: &str, timestamp: &str, body: &[u8]) -> String {
+    format!(
+        "sha256={}",
+        hex::encode(
+            signature(secret, message_id, timestamp, body)
+                .finalize()
+                .into_bytes()
+        )
+    )
+}
+
+/// Verify and parse a message delivered to the callback. `header` looks up a request header
+/// by its lowercase name.
+pub fn parse_message<'a>(
+    secret: &str,
+    header: impl Fn(&str) -> Option<&'a str>,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Result<Message, EventSubError> {
+    let get = |name: &'static str| header(name).ok_or(EventSubError::MissingHeader(name));
+    let message_id = get(MESSAGE_ID_HEADER)?;
+    let timestamp = get(MESSAGE_TIMESTAMP_HEADER)?;
+    let expected = get(MESSAGE_SIGNATURE_HEADER)?
+        .strip_prefix("sha256=")
+        .and_then(|s| hex::decode(s).ok())
+        .ok_or(EventSubError::InvalidSignature)?;
+    signature(secret, message_id, timestamp, body)
+        .verify_slice(&expected)
+        .map_err(|_| EventSubError::InvalidSignature)?;
+    let sent_at = DateTime::parse_from_rfc3339(timestamp)
+        .map_err(|e| EventSubError::DeserializeFailed(e.to_string()))?;
+    if now - sent_at.with_timezone(&Utc) > chrono::Duration::minutes(MAX_MESSAGE_AGE_MIN) {
+        return Err(EventSubError::Expired);
+    }
+
+    let message: MessageBody = serde_json::from_slice(body)
+        .map_err(|e| EventSubError::DeserializeFailed(e.to_string()))?;
+    match get(MESSAGE_TYPE_HEADER)? {
+        "webhook_callback_verification" => message
+            .challenge
+            .map(Message::Verification)
+            .ok_or(EventSubError::DeserializeFailed("challenge".to_string())),
+        "revocation" => Ok(Message::Revocation(message.subscription)),
+        "notification" => {
+            let event = message
+                .event
+                .ok_or(EventSubError::DeserializeFailed("event".to_string()))?;
+            let de_err = |e: serde_json::Error| EventSubError::DeserializeFailed(e.to_string());
+            match SubscriptionType::parse(&message.subscription.subscription_type) {
+                Some(SubscriptionType::StreamOnline) => Ok(Event::StreamOnline(
+                    serde_json::from_value(event).map_err(de_err)?,
+                )),
+                Some(SubscriptionType::StreamOffline) => Ok(Event::StreamOffline(
+                    serde_json::from_value(event).map_err(de_err)?,
+                )),
+                Some(SubscriptionType::ChannelUpdate) => Ok(Event::ChannelUpdate(
+                    serde_json::from_value(event).map_err(de_err)?,
+                )),
+                None => Err(EventSubError::UnknownMessageType(
+                    message.subscription.subscription_type,
+                )),
+            }
+            .map(Message::Notification)
+        }
+        t => Err(EventSubError::UnknownMessageType(t.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    const SECRET: &str = "eventsub-test-secret";
+
+    fn headers(message_type: &str, timestamp: &str, body: &str) -> HashMap<&'static str, String> {
+        HashMap::from([
+            (MESSAGE_ID_HEADER, "message-id".to_string()),
+            (MESSAGE_TIMESTAMP_HEADER, timestamp.to_string()),
+            (
+                MESSAGE_SIGNATURE_HEADER,
+                sign(SECRET, "message-id", timestamp, body.as_bytes()),
+            ),
+            (MESSAGE_TYPE_HEADER, message_type.to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let now = Utc::now();
+        let timestamp = now.to_rfc3339();
+        let body = r#"{
+            "subscription": {"id": "sub-id", "status": "enabled", "type": "stream.offline", "version": "1",
+                "condition": {"broadcaster_user_id": "1337"}, "transport": {"method": "webhook"}},
+            "event": {"broadcaster_user_id": "1337", "broadcaster_user_login": "cool_user", "broadcaster_user_name": "Cool_User"}
+        }"#;
+        let h = headers("notification", &timestamp, body);
+        match parse_message(
+            SECRET,
+            |n| h.get(n).map(|s| s.as_str()),
+            body.as_bytes(),
+            now,
+        ) {
+            Ok(Message::Notification(Event::StreamOffline(e))) => {
+                assert_eq!(e.broadcaster_user_login, "cool_user")
+            }
+            m => panic!("Unexpected message: {m:?}"),
+        }
+
+        let tampered = body.replace("cool_user", "evil_user");
+        assert_eq!(
+            parse_message(
+                SECRET,
+                |n| h.get(n).map(|s| s.as_str()),
+                tampered.as_bytes(),
+                now
+            )
+            .unwrap_err(),
+            EventSubError::InvalidSignature
+        );
+        assert_eq!(
+            parse_message(
+                SECRET,
+                |n| h.get(n).map(|s| s.as_str()),
+                body.as_bytes(),
+                now + chrono::Duration::minutes(11)
+            )
+            .unwrap_err(),
+            EventSubError::Expired
+        );
+    }
+
+    #[test]
+    fn test_parse_verification() {
+        let now = Utc::now();
+        let timestamp = now.to_rfc3339();
+        let body = r#"{
+            "challenge": "pogchamp-kappa-360noscope-vohiyo",
+            "subscription": {"id": "sub-id", "status": "webhook_callback_verification_pending",
+                "type": "stream.online", "version": "1", "condition": {"broadcaster_user_id": "1337"},
+                "transport": {"method": "webhook", "callback": "https://example.com/webhooks/callback"}}
+        }"#;
+        let h = headers("webhook_callback_verification", &timestamp, body);
+        match parse_message(
+            SECRET,
+            |n| h.get(n).map(|s| s.as_str()),
+            body.as_bytes(),
+            now,
+        ) {
+            Ok(Message::Verification(challenge)) => {
+                assert_eq!(challenge, "pogchamp-kappa-360noscope-vohiyo")
+            }
+            m => panic!("Unexpected message: {m:?}"),
+        }
+    }
+}
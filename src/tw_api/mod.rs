@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
-use crate::REQWEST_CLIENT;
+use crate::Upstream;
+pub mod eventsub;
 pub mod structs;
 use once_cell::sync::Lazy;
 use regex::Regex;
@@ -14,7 +15,32 @@ pub fn validate_user_login(login: &str) -> bool {
     USER_LOGIN_PATTERN.is_match(login)
 }
 
+#[derive(Debug)]
+pub enum TwApiError {
+    RequestFailed(reqwest::Error),
+    /// Twitch answered without the item it should have created
+    EmptyResponse,
+}
+
+impl From<reqwest::Error> for TwApiError {
+    fn from(e: reqwest::Error) -> Self {
+        TwApiError::RequestFailed(e)
+    }
+}
+
+impl std::fmt::Display for TwApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TwApiError::RequestFailed(e) => e.fmt(f),
+            TwApiError::EmptyResponse => write!(f, "The response has no data"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct TwApiClient {
+    http: reqwest::Client,
+    upstream: Upstream,
     client_id: String,
     client_secret: String,
     access_token: String,
@@ -28,103 +54,201 @@ pub enum UserIdentity {
 
 impl TwApiClient {
     pub async fn new(
+        http: reqwest::Client,
+        upstream: Upstream,
         client_id: impl ToString,
         client_secret: impl ToString,
     ) -> Result<Self, reqwest::Error> {
         let client_id = client_id.to_string();
         let client_secret = client_secret.to_string();
-        let access_token = Self::require_access_token(&client_id, &client_secret).await?;
+        let access_token =
+            Self::require_access_token(&http, &upstream, &client_id, &client_secret).await?;
         Ok(Self {
+            http,
+            upstream,
             client_id,
             client_secret,
             access_token,
         })
     }
-    async fn require_access_token(id: &str, secret: &str) -> Result<String, reqwest::Error> {
-        unsafe {
-            let response: TokenResponse = REQWEST_CLIENT
-                .post("https://id.twitch.tv/oauth2/token")
-                .query(&[
-                    ("client_id", id),
-                    ("client_secret", secret),
-                    ("grant_type", "client_credentials"),
-                ])
-                .send()
-                .await?
-                .json()
-                .await?;
-            Ok(response.access_token)
-        }
+    async fn require_access_token(
+        http: &reqwest::Client,
+        upstream: &Upstream,
+        id: &str,
+        secret: &str,
+    ) -> Result<String, reqwest::Error> {
+        let response: TokenResponse = http
+            .post(format!("{}/token", upstream.twitch_oauth))
+            .query(&[
+                ("client_id", id),
+                ("client_secret", secret),
+                ("grant_type", "client_credentials"),
+            ])
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(response.access_token)
     }
 
     async fn post_request<T: Serialize + ?Sized>(
         &mut self,
-        url: &str,
+        path: &str,
         args: &T,
     ) -> Result<Response, reqwest::Error> {
-        unsafe {
-            let mut response = REQWEST_CLIENT
-                .post(url)
-                .query(args)
-                .header("Authorization", format!("Bearer {}", self.access_token))
-                .header("Client-Id", &self.client_id)
-                .send()
-                .await?
-                .error_for_status();
-            if let Err(e) = &response {
-                if let Some(code) = e.status() {
-                    if code.as_u16() == 401 {
-                        let new_access_token =
-                            Self::require_access_token(&self.client_id, &self.client_secret)
-                                .await?;
-                        self.access_token = new_access_token;
-                        response = REQWEST_CLIENT
-                            .post(url)
-                            .query(args)
-                            .header("Authorization", format!("Bearer {}", self.access_token))
-                            .header("Client-Id", &self.client_id)
-                            .send()
-                            .await;
-                    }
+        let url = format!("{}{}", self.upstream.twitch_helix, path);
+        let mut response = self
+            .http
+            .post(&url)
+            .query(args)
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .header("Client-Id", &self.client_id)
+            .send()
+            .await?
+            .error_for_status();
+        if let Err(e) = &response {
+            if let Some(code) = e.status() {
+                if code.as_u16() == 401 {
+                    let new_access_token = Self::require_access_token(
+                        &self.http,
+                        &self.upstream,
+                        &self.client_id,
+                        &self.client_secret,
+                    )
+                    .await?;
+                    self.access_token = new_access_token;
+                    response = self
+                        .http
+                        .post(&url)
+                        .query(args)
+                        .header("Authorization", format!("Bearer {}", self.access_token))
+                        .header("Client-Id", &self.client_id)
+                        .send()
+                        .await;
+                }
+            }
+        }
+        response
+    }
+
+    async fn post_json_request<T: Serialize + ?Sized>(
+        &mut self,
+        path: &str,
+        body: &T,
+    ) -> Result<Response, reqwest::Error> {
+        let url = format!("{}{}", self.upstream.twitch_helix, path);
+        let mut response = self
+            .http
+            .post(&url)
+            .json(body)
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .header("Client-Id", &self.client_id)
+            .send()
+            .await?
+            .error_for_status();
+        if let Err(e) = &response {
+            if let Some(code) = e.status() {
+                if code.as_u16() == 401 {
+                    let new_access_token = Self::require_access_token(
+                        &self.http,
+                        &self.upstream,
+                        &self.client_id,
+                        &self.client_secret,
+                    )
+                    .await?;
+                    self.access_token = new_access_token;
+                    response = self
+                        .http
+                        .post(&url)
+                        .json(body)
+                        .header("Authorization", format!("Bearer {}", self.access_token))
+                        .header("Client-Id", &self.client_id)
+                        .send()
+                        .await;
                 }
             }
-            response
         }
+        response
+    }
+
+    async fn delete_request<T: Serialize + ?Sized>(
+        &mut self,
+        path: &str,
+        args: &T,
+    ) -> Result<Response, reqwest::Error> {
+        let url = format!("{}{}", self.upstream.twitch_helix, path);
+        let mut response = self
+            .http
+            .delete(&url)
+            .query(args)
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .header("Client-Id", &self.client_id)
+            .send()
+            .await?
+            .error_for_status();
+        if let Err(e) = &response {
+            if let Some(code) = e.status() {
+                if code.as_u16() == 401 {
+                    let new_access_token = Self::require_access_token(
+                        &self.http,
+                        &self.upstream,
+                        &self.client_id,
+                        &self.client_secret,
+                    )
+                    .await?;
+                    self.access_token = new_access_token;
+                    response = self
+                        .http
+                        .delete(&url)
+                        .query(args)
+                        .header("Authorization", format!("Bearer {}", self.access_token))
+                        .header("Client-Id", &self.client_id)
+                        .send()
+                        .await;
+                }
+            }
+        }
+        response
     }
 
     async fn get_request<T: Serialize + ?Sized + std::fmt::Debug>(
         &mut self,
-        url: &str,
+        path: &str,
         args: &T,
     ) -> Result<Response, reqwest::Error> {
-        unsafe {
-            let mut response = REQWEST_CLIENT
-                .get(url)
-                .query(args)
-                .header("Authorization", format!("Bearer {}", self.access_token))
-                .header("Client-Id", &self.client_id)
-                .send()
-                .await?
-                .error_for_status();
-            if let Err(e) = &response {
-                if let Some(code) = e.status() {
-                    if code.as_u16() == 401 {
-                        let new_access_token =
-                            Self::require_access_token(&self.client_id, &self.client_secret)
-                                .await?;
-                        self.access_token = new_access_token;
-                        response = REQWEST_CLIENT
-                            .get(url)
-                            .query(args)
-                            .header("Authorization", format!("Bearer {}", self.access_token))
-                            .header("Client-Id", &self.client_id)
-                            .send()
-                            .await;
-                    }
+        let url = format!("{}{}", self.upstream.twitch_helix, path);
+        let mut response = self
+            .http
+            .get(&url)
+            .query(args)
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .header("Client-Id", &self.client_id)
+            .send()
+            .await?
+            .error_for_status();
+        if let Err(e) = &response {
+            if let Some(code) = e.status() {
+                if code.as_u16() == 401 {
+                    let new_access_token = Self::require_access_token(
+                        &self.http,
+                        &self.upstream,
+                        &self.client_id,
+                        &self.client_secret,
+                    )
+                    .await?;
+                    self.access_token = new_access_token;
+                    response = self
+                        .http
+                        .get(&url)
+                        .query(args)
+                        .header("Authorization", format!("Bearer {}", self.access_token))
+                        .header("Client-Id", &self.client_id)
+                        .send()
+                        .await;
                 }
             }
-            response
         }
+        response
     }
 
     pub async fn search_channel(
@@ -133,10 +257,7 @@ impl TwApiClient {
     ) -> Result<Vec<ChannelSearchResult>, reqwest::Error> {
         log::debug!("Search twitch channel: {query}");
         Ok(self
-            .get_request(
-                "https://api.twitch.tv/helix/search/channels",
-                &[("query", query)],
-            )
+            .get_request("/search/channels", &[("query", query)])
             .await?
             .json::<PagedResponses<ChannelSearchResult>>()
             .await?
@@ -152,7 +273,7 @@ impl TwApiClient {
         while idx * 100 < channel_ids.len() {
             result.extend(
                 self.get_request(
-                    "https://api.twitch.tv/helix/channels",
+                    "/channels",
                     &channel_ids[idx * 100..channel_ids.len().min(idx * 100 + 100)]
                         .iter()
                         .map(|id| ("broadcaster_id", id))
@@ -161,8 +282,7 @@ impl TwApiClient {
                 .await?
                 .json::<Responses<ChannelInformation>>()
                 .await?
-                .data
-                .into_iter(),
+                .data,
             );
             idx += 1;
         }
@@ -178,7 +298,7 @@ impl TwApiClient {
         while idx * 100 < identities.len() {
             result.extend(
                 self.get_request(
-                    "https://api.twitch.tv/helix/streams",
+                    "/streams",
                     &identities[idx * 100..identities.len().min(idx * 100 + 100)]
                         .iter()
                         .map(|id| match id {
@@ -190,8 +310,7 @@ impl TwApiClient {
                 .await?
                 .json::<PagedResponses<StreamInformation>>()
                 .await?
-                .data
-                .into_iter(),
+                .data,
             );
             idx += 1;
         }
@@ -207,7 +326,7 @@ impl TwApiClient {
         while idx * 100 < identities.len() {
             result.extend(
                 self.get_request(
-                    "https://api.twitch.tv/helix/users",
+                    "/users",
                     &identities[idx * 100..identities.len().min(idx * 100 + 100)]
                         .iter()
                         .map(|id| match id {
@@ -219,13 +338,79 @@ impl TwApiClient {
                 .await?
                 .json::<Responses<UserInformation>>()
                 .await?
-                .data
-                .into_iter(),
+                .data,
             );
             idx += 1;
         }
         Ok(result)
     }
+
+    /// Create an EventSub subscription delivered to the webhook `callback`
+    pub async fn create_eventsub_subscription(
+        &mut self,
+        subscription_type: eventsub::SubscriptionType,
+        broadcaster_id: &str,
+        callback: &str,
+        secret: &str,
+    ) -> Result<EventSubSubscription, TwApiError> {
+        log::debug!(
+            "Create {} subscription of {broadcaster_id}",
+            subscription_type.name()
+        );
+        let subscriptions = self
+            .post_json_request(
+                "/eventsub/subscriptions",
+                &serde_json::json!({
+                    "type": subscription_type.name(),
+                    "version": subscription_type.version(),
+                    "condition": { "broadcaster_user_id": broadcaster_id },
+                    "transport": {
+                        "method": "webhook",
+                        "callback": callback,
+                        "secret": secret,
+                    },
+                }),
+            )
+            .await?
+            .json::<Responses<EventSubSubscription>>()
+            .await?
+            .data;
+        subscriptions
+            .into_iter()
+            .next()
+            .ok_or(TwApiError::EmptyResponse)
+    }
+
+    pub async fn get_eventsub_subscriptions(
+        &mut self,
+    ) -> Result<Vec<EventSubSubscription>, reqwest::Error> {
+        let mut result = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let args = cursor
+                .iter()
+                .map(|c| ("after", c.as_str()))
+                .collect::<Vec<(&str, &str)>>();
+            let page = self
+                .get_request("/eventsub/subscriptions", &args)
+                .await?
+                .json::<PagedResponses<EventSubSubscription>>()
+                .await?;
+            result.extend(page.data);
+            cursor = page.pagination.and_then(|p| p.cursor);
+            if cursor.is_none() {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    pub async fn delete_eventsub_subscription(&mut self, id: &str) -> Result<(), reqwest::Error> {
+        log::debug!("Delete EventSub subscription {id}");
+        self.delete_request("/eventsub/subscriptions", &[("id", id)])
+            .await?;
+        Ok(())
+    }
 }
 
 pub fn process_thumbnail_url(url: &str, width: usize, height: usize) -> String {
@@ -236,21 +421,29 @@ pub fn process_thumbnail_url(url: &str, width: usize, height: usize) -> String {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::mock_upstream::MockStream;
     use crate::test::*;
 
     const TEST_ID: &str = "126595970";
+    const TEST_LOGIN: &str = "restiafps";
+    static FIXTURES: Lazy<()> = Lazy::new(|| {
+        MOCK.add_tw_user(TEST_ID, TEST_LOGIN, "Restia");
+    });
 
     #[test]
     fn test_get_tw_user_info() {
+        Lazy::force(&FIXTURES);
         TOKIO_RUNTIME.block_on(async {
             let mut client = TwApiClient::new(
+                crate::context::http_client(),
+                MOCK.upstream(),
                 &CONFIG.twitch_key.as_ref().unwrap().client_id,
                 &CONFIG.twitch_key.as_ref().unwrap().client_secret,
             )
             .await
             .unwrap();
             let users = client
-                .get_user_info(&[UserIdentity::Login("restiafps".to_string())])
+                .get_user_info(&[UserIdentity::Login(TEST_LOGIN.to_string())])
                 .await
                 .unwrap();
             assert!(!users.is_empty());
@@ -262,9 +455,14 @@ mod test {
                 .is_empty());
         });
     }
+
+    #[test]
     fn test_get_tw_channel_info() {
+        Lazy::force(&FIXTURES);
         TOKIO_RUNTIME.block_on(async {
             let mut client = TwApiClient::new(
+                crate::context::http_client(),
+                MOCK.upstream(),
                 &CONFIG.twitch_key.as_ref().unwrap().client_id,
                 &CONFIG.twitch_key.as_ref().unwrap().client_secret,
             )
@@ -277,4 +475,35 @@ mod test {
             assert!(!channels.is_empty());
         });
     }
+
+    #[test]
+    fn test_get_tw_stream_info() {
+        const LOGIN: &str = "tw_api_stream_test";
+        MOCK.add_tw_user("tw-api-stream-test", LOGIN, "Stream Test");
+        TOKIO_RUNTIME.block_on(async {
+            let mut client = TwApiClient::new(
+                crate::context::http_client(),
+                MOCK.upstream(),
+                &CONFIG.twitch_key.as_ref().unwrap().client_id,
+                &CONFIG.twitch_key.as_ref().unwrap().client_secret,
+            )
+            .await
+            .unwrap();
+            let identity = [UserIdentity::Login(LOGIN.to_string())];
+            assert!(client.get_stream_info(&identity).await.unwrap().is_empty());
+
+            MOCK.set_tw_stream(
+                LOGIN,
+                Some(MockStream {
+                    title: "Live".to_string(),
+                    game_name: "Just Chatting".to_string(),
+                    started_at: chrono::Utc::now(),
+                    viewer_count: 10,
+                }),
+            );
+            let streams = client.get_stream_info(&identity).await.unwrap();
+            assert_eq!(streams.len(), 1);
+            assert_eq!(streams[0].title, "Live");
+        });
+    }
 }
@@ -89,3 +89,25 @@ pub struct UserInformation {
     pub email: Option<String>,
     pub created_at: String,
 }
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EventSubCondition {
+    pub broadcaster_user_id: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EventSubTransport {
+    pub method: String,
+    pub callback: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EventSubSubscription {
+    pub id: String,
+    pub status: String,
+    #[serde(rename = "type")]
+    pub subscription_type: String,
+    pub version: String,
+    pub condition: EventSubCondition,
+    pub transport: EventSubTransport,
+}
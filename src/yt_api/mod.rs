@@ -1,16 +1,18 @@
 #![allow(dead_code)]
+pub mod quota;
 pub mod structs;
+pub mod websub;
 use std::num::NonZeroUsize;
 
-use crate::make_http_get;
-use chrono::{Datelike, Utc};
+use crate::{context::AppContext, make_http_get, storage::Storage};
 use lru::LruCache;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use reqwest::{self, StatusCode};
+use std::sync::Arc;
 use std::time::Duration;
 use structs::*;
-use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tokio::{sync::Mutex, task::JoinHandle};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum YtApiError {
@@ -18,28 +20,76 @@ pub enum YtApiError {
     DeserializeFailed(String),
     InvalidParameter,
     NotFound,
+    QuotaExceeded,
 }
-const CHANNEL_URL_SAVE: &str = "channel_cache";
-static mut CHANNEL_NAME_CACHE: Lazy<Mutex<LruCache<String, String>>> = Lazy::new(|| {
-    let mut cache = LruCache::new(NonZeroUsize::new(1000).unwrap());
-    let line_break_pattern = regex::Regex::new(r"\n|\r\n").unwrap();
-    match std::fs::read_to_string(CHANNEL_URL_SAVE) {
-        Err(e) => log::info!("Open channel url cache file failed: {e}"),
-        Ok(s) => {
-            for pair in line_break_pattern.split(&s) {
-                if pair.trim().is_empty() {
-                    continue;
+
+/// The reasons of a 403 for which another api key is tried
+const KEY_QUOTA_REASONS: &[&str] = &["quotaExceeded", "dailyLimitExceeded"];
+
+const CHANNEL_ID_CACHE_SIZE: usize = 1000;
+const CHANNEL_ID_CACHE_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 5);
+
+/// The channel ids of the channel urls, saved to the storage every
+/// [`CHANNEL_ID_CACHE_SAVE_INTERVAL`]
+pub struct ChannelIdCache {
+    cache: Mutex<LruCache<String, String>>,
+    storage: Arc<dyn Storage>,
+}
+
+impl ChannelIdCache {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        let mut cache = LruCache::new(NonZeroUsize::new(CHANNEL_ID_CACHE_SIZE).unwrap());
+        match storage.load_channel_id_cache() {
+            Err(e) => log::error!("Load channel url cache failed: {e}"),
+            Ok(entries) => {
+                // the least recently used entry comes first, and stays the least recently used
+                for (key, value) in entries {
+                    cache.put(key, value);
                 }
-                let mut splitter = pair.split(' ');
-                if let Some((key, value)) = splitter.next().zip(splitter.next()) {
-                    cache.put(key.to_string(), value.to_string());
+            }
+        }
+        Self {
+            cache: Mutex::new(cache),
+            storage,
+        }
+    }
+
+    /// Save the cache periodically until it is dropped
+    pub fn spawn_saver(self: &Arc<Self>) -> JoinHandle<()> {
+        let cache = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(CHANNEL_ID_CACHE_SAVE_INTERVAL).await;
+                match cache.upgrade() {
+                    Some(cache) => cache.save().await,
+                    None => break,
                 }
             }
+        })
+    }
+
+    pub async fn save(&self) {
+        log::info!("Saving channel url cache");
+        // reverse the iterator, so the least recently used entry is saved first
+        let entries = self
+            .cache
+            .lock()
+            .await
+            .iter()
+            .rev()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<(String, String)>>();
+        let storage = self.storage.clone();
+        let saved =
+            tokio::task::spawn_blocking(move || storage.save_channel_id_cache(&entries)).await;
+        match saved {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("Save channel url cache failed: {e}"),
+            Err(e) => log::error!("Save channel url cache task failed: {e}"),
         }
     }
-    Mutex::new(cache)
-});
-static mut CACHE_SAVE_INITIALIZED: bool = false;
+}
+
 static CHANNEL_ID_PATTERNS: [(Lazy<Regex>, usize); 4] = [
     (
         Lazy::new(|| {
@@ -69,51 +119,19 @@ static CHANNEL_ID_PATTERNS: [(Lazy<Regex>, usize); 4] = [
     ),
 ];
 static CUSTOM_URL_PATTERN: Lazy<Regex> = Lazy::new(|| regex::Regex::new(r"^[\w.-]+$").unwrap());
-static mut QUOTA_COUNTER: Lazy<(u32, u32)> = Lazy::new(|| (Utc::now().day(), 0));
 
 pub fn validate_custom_url(custom_url: &str) -> bool {
     CUSTOM_URL_PATTERN.is_match(custom_url)
 }
-pub async fn get_channel_id_by_url(url: &str) -> Result<String, YtApiError> {
+pub async fn get_channel_id_by_url(ctx: &AppContext, url: &str) -> Result<String, YtApiError> {
     let url = url.to_string();
-    unsafe {
-        if !CACHE_SAVE_INITIALIZED {
-            tokio::spawn(async {
-                loop {
-                    tokio::time::sleep(std::time::Duration::from_secs(60 * 5)).await;
-                    log::info!("Saving channel url cache");
-                    match tokio::fs::OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .open(CHANNEL_URL_SAVE)
-                        .await
-                    {
-                        Err(e) => log::error!("Open channel url cache file to save failed: {e}"),
-                        Ok(mut file) => {
-                            // reverse the iterator. So the last used entry is at the bottom of the file
-                            // when read the save back, it will remain as the last used entry
-                            for (key, value) in CHANNEL_NAME_CACHE.lock().await.iter().rev() {
-                                if let Err(e) =
-                                    file.write_all(format!("{key} {value}\n").as_bytes()).await
-                                {
-                                    log::error!("Write to channel url cache file failed: {e}");
-                                }
-                            }
-                        }
-                    }
-                }
-            });
-            CACHE_SAVE_INITIALIZED = true;
-        }
-
-        if let Some(id) = CHANNEL_NAME_CACHE.lock().await.get(&url) {
-            return Ok(id.clone());
-        }
+    if let Some(id) = ctx.channel_ids.cache.lock().await.get(&url) {
+        return Ok(id.clone());
     }
 
     let channel_page_src = {
         log::info!("Channel id cache miss. Making http request: {}", url);
-        make_http_get(&url)
+        make_http_get(&ctx.http, &url)
             .await
             .map_err(|e| YtApiError::RequestFailed(e.status()))?
             .error_for_status()
@@ -126,12 +144,11 @@ pub async fn get_channel_id_by_url(url: &str) -> Result<String, YtApiError> {
     for (pattern, grp) in CHANNEL_ID_PATTERNS.iter() {
         if let Some(cap) = pattern.captures(&channel_page_src) {
             if let Some(id) = cap.get(*grp) {
-                unsafe {
-                    CHANNEL_NAME_CACHE
-                        .lock()
-                        .await
-                        .put(url, id.as_str().to_string());
-                }
+                ctx.channel_ids
+                    .cache
+                    .lock()
+                    .await
+                    .put(url, id.as_str().to_string());
                 return Ok(id.as_str().to_string());
             }
         }
@@ -140,14 +157,18 @@ pub async fn get_channel_id_by_url(url: &str) -> Result<String, YtApiError> {
     Err(YtApiError::NotFound)
 }
 
-pub async fn try_youtube_id(query: &str) -> String {
-    match get_channel_id_by_url(&format!(
-        "https://www.youtube.com/@{}",
-        query
-            .trim_start_matches("https://www.youtube.com/@")
-            .trim_start_matches("https://youtube.com/@")
-            .trim_start_matches('@')
-    ))
+pub async fn try_youtube_id(ctx: &AppContext, query: &str) -> String {
+    match get_channel_id_by_url(
+        ctx,
+        &format!(
+            "{}/@{}",
+            ctx.upstream.youtube,
+            query
+                .trim_start_matches("https://www.youtube.com/@")
+                .trim_start_matches("https://youtube.com/@")
+                .trim_start_matches('@')
+        ),
+    )
     .await
     {
         Ok(id) => id,
@@ -271,49 +292,86 @@ impl GetChannelParts {
     }
 }
 
+/// Request `url` of the youtube api with the first api key which has quota left. When youtube
+/// refuses a key for exceeding its quota, the key is disabled and the next one is tried.
+async fn request_api<T: serde::de::DeserializeOwned>(
+    ctx: &AppContext,
+    endpoint: quota::Endpoint,
+    url: &str,
+) -> Result<T, YtApiError> {
+    log::debug!("Request url: {}", url);
+    loop {
+        let key = ctx.quota.spend(endpoint).await?;
+        let response = make_http_get(&ctx.http, format!("{url}&key={key}"))
+            .await
+            .map_err(|e| YtApiError::RequestFailed(e.status()))?;
+        let status = response.status();
+        if status == StatusCode::FORBIDDEN {
+            let body = response.text().await.unwrap_or_default();
+            if is_key_quota_error(&body) {
+                ctx.quota.disable(&key);
+                continue;
+            }
+            return Err(YtApiError::RequestFailed(Some(status)));
+        }
+        return response
+            .error_for_status()
+            .map_err(|e| YtApiError::RequestFailed(e.status()))?
+            .json::<T>()
+            .await
+            .map_err(|e| YtApiError::DeserializeFailed(format!("{}", e.without_url())));
+    }
+}
+
+/// Whether the body of a 403 says the quota of the api key is exceeded, rather than the request
+/// being forbidden
+fn is_key_quota_error(body: &str) -> bool {
+    let Ok(body) = serde_json::from_str::<serde_json::Value>(body) else {
+        return false;
+    };
+    body["error"]["errors"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e["reason"].as_str())
+        .any(|reason| KEY_QUOTA_REASONS.contains(&reason))
+}
+
 pub async fn get_all_channels(
+    ctx: &AppContext,
     ids: &[&str],
     parts: &GetChannelParts,
-    key: &str,
 ) -> Result<Vec<Channel::Resource>, YtApiError> {
     let mut result = vec![];
     let mut idx = 0;
     while idx * 50 < ids.len() {
         let get_channels = get_channels(
+            ctx,
             &ids[idx * 50..ids.len().min(idx * 50 + 50)],
             parts,
             None,
-            key,
         )
         .await?;
-        result.extend(get_channels.value.into_iter());
+        result.extend(get_channels.value);
         idx += 1;
     }
     Ok(result)
 }
 
 pub async fn get_channels(
+    ctx: &AppContext,
     ids: &[&str],
     parts: &GetChannelParts,
     page_token: Option<String>,
-    key: &str,
 ) -> Result<PagedResponse<Channel::Resource>, YtApiError> {
-    log::info!("Getting {} channels info, 1 quota used", ids.len());
-    unsafe {
-        let day = Utc::now().day();
-        if day != QUOTA_COUNTER.0 {
-            *QUOTA_COUNTER = (day, 0);
-        }
-        QUOTA_COUNTER.1 += 1;
-        log::info!("Quota used today (in utc): {}", QUOTA_COUNTER.1);
-    }
+    log::info!("Getting {} channels info", ids.len());
     log::debug!("Channel IDs: {:?}", ids);
     if ids.is_empty() {
         return Err(YtApiError::InvalidParameter);
     }
     let mut url = format!(
-        "https://www.googleapis.com/youtube/v3/channels?key={}&id={}&maxResults=50",
-        key,
+        "{}/channels?id={}&maxResults=50",
+        ctx.upstream.youtube_api,
         ids.join(","),
     );
     if !parts.is_none() {
@@ -324,15 +382,8 @@ pub async fn get_channels(
         url += "&pageToken=";
         url += &token;
     }
-    log::debug!("Request url: {}", url);
-    make_http_get(&url)
+    request_api::<MultipleItemsResponse<Channel::Resource>>(ctx, quota::Endpoint::Channels, &url)
         .await
-        .map_err(|e| YtApiError::RequestFailed(e.status()))?
-        .error_for_status()
-        .map_err(|e| YtApiError::RequestFailed(e.status()))?
-        .json::<MultipleItemsResponse<Channel::Resource>>()
-        .await
-        .map_err(|e| YtApiError::DeserializeFailed(format!("{}", e.without_url())))
         .map(|resp| PagedResponse {
             next_page_token: resp.nextPageToken,
             prev_page_token: resp.prevPageToken,
@@ -388,16 +439,16 @@ impl GetPlaylistItemParts {
 }
 
 pub async fn get_all_playlist_items(
+    ctx: &AppContext,
     playlist_item_id: &str,
     parts: &GetPlaylistItemParts,
-    key: &str,
 ) -> Result<Vec<PlayListItem::Resource>, YtApiError> {
     let mut result = vec![];
     let mut page_token = None;
     loop {
-        let current_page = get_playlist_items(playlist_item_id, parts, page_token, key).await?;
+        let current_page = get_playlist_items(ctx, playlist_item_id, parts, page_token).await?;
         page_token = current_page.next_page_token;
-        result.extend(current_page.value.into_iter());
+        result.extend(current_page.value);
         if page_token.is_none() {
             break;
         }
@@ -406,24 +457,16 @@ pub async fn get_all_playlist_items(
 }
 
 pub async fn get_playlist_items(
+    ctx: &AppContext,
     playlist_item_id: &str,
     parts: &GetPlaylistItemParts,
     page_token: Option<String>,
-    api_key: &str,
 ) -> Result<PagedResponse<PlayListItem::Resource>, YtApiError> {
-    log::info!("Getting playlist item, 1 quota used");
-    unsafe {
-        let day = Utc::now().day();
-        if day != QUOTA_COUNTER.0 {
-            *QUOTA_COUNTER = (day, 0);
-        }
-        QUOTA_COUNTER.1 += 1;
-        log::info!("Quota used today (in utc): {}", QUOTA_COUNTER.1);
-    }
+    log::info!("Getting playlist item");
     log::debug!("Playlist ID: {}", playlist_item_id);
     let mut url = format!(
-        "https://www.googleapis.com/youtube/v3/playlistItems?key={}&playlistId={}&maxResults=50",
-        api_key, playlist_item_id,
+        "{}/playlistItems?playlistId={}&maxResults=50",
+        ctx.upstream.youtube_api, playlist_item_id,
     );
     if !parts.is_none() {
         url += "&part=";
@@ -434,20 +477,17 @@ pub async fn get_playlist_items(
         url += &token;
     }
 
-    log::debug!("Request url: {}", url);
-    make_http_get(&url)
-        .await
-        .map_err(|e| YtApiError::RequestFailed(e.status()))?
-        .error_for_status()
-        .map_err(|e| YtApiError::RequestFailed(e.status()))?
-        .json::<MultipleItemsResponse<PlayListItem::Resource>>()
-        .await
-        .map_err(|e| YtApiError::DeserializeFailed(format!("{}", e.without_url())))
-        .map(|resp| PagedResponse {
-            next_page_token: resp.nextPageToken,
-            prev_page_token: resp.prevPageToken,
-            value: resp.items,
-        })
+    request_api::<MultipleItemsResponse<PlayListItem::Resource>>(
+        ctx,
+        quota::Endpoint::PlaylistItems,
+        &url,
+    )
+    .await
+    .map(|resp| PagedResponse {
+        next_page_token: resp.nextPageToken,
+        prev_page_token: resp.prevPageToken,
+        value: resp.items,
+    })
 }
 
 #[derive(Default)]
@@ -582,46 +622,38 @@ impl GetVideoParts {
 }
 
 pub async fn get_all_video_items(
+    ctx: &AppContext,
     video_ids: &[String],
     parts: &GetVideoParts,
-    key: &str,
 ) -> Result<Vec<Video::Resource>, YtApiError> {
     let mut result = vec![];
     let mut idx = 0;
     while idx * 50 < video_ids.len() {
         let current_page = get_video(
+            ctx,
             &video_ids[idx * 50..video_ids.len().min(idx * 50 + 50)],
             parts,
-            key,
         )
         .await?;
-        result.extend(current_page.value.into_iter());
+        result.extend(current_page.value);
         idx += 1;
     }
     Ok(result)
 }
 
 pub async fn get_video(
+    ctx: &AppContext,
     video_ids: &[String],
     parts: &GetVideoParts,
-    api_key: &str,
 ) -> Result<PagedResponse<Video::Resource>, YtApiError> {
     if video_ids.is_empty() {
         return Err(YtApiError::InvalidParameter);
     }
-    log::info!("Getting {} videos info, 1 quota used", video_ids.len());
-    unsafe {
-        let day = Utc::now().day();
-        if day != QUOTA_COUNTER.0 {
-            *QUOTA_COUNTER = (day, 0);
-        }
-        QUOTA_COUNTER.1 += 1;
-        log::info!("Quota used today (in utc): {}", QUOTA_COUNTER.1);
-    }
+    log::info!("Getting {} videos info", video_ids.len());
     log::debug!("Video IDs: {:?}", video_ids);
     let mut url = format!(
-        "https://www.googleapis.com/youtube/v3/videos?key={}&id={}&maxResults=50",
-        api_key,
+        "{}/videos?id={}&maxResults=50",
+        ctx.upstream.youtube_api,
         video_ids.join(","),
     );
     if !parts.is_none() {
@@ -629,15 +661,8 @@ pub async fn get_video(
         url += &parts.build();
     }
 
-    log::debug!("Request url: {}", url);
-    make_http_get(&url)
-        .await
-        .map_err(|e| YtApiError::RequestFailed(e.status()))?
-        .error_for_status()
-        .map_err(|e| YtApiError::RequestFailed(e.status()))?
-        .json::<MultipleItemsResponse<Video::Resource>>()
+    request_api::<MultipleItemsResponse<Video::Resource>>(ctx, quota::Endpoint::Videos, &url)
         .await
-        .map_err(|e| YtApiError::DeserializeFailed(format!("{}", e.without_url())))
         .map(|resp| {
             resp.items.iter().for_each(|r| {
                 log::debug!(
@@ -660,14 +685,18 @@ pub async fn get_video(
 static VIDEO_ID_PATTERN: Lazy<Regex> =
     Lazy::new(|| regex::Regex::new("<yt:videoId>(.+?)</yt:videoId>").unwrap());
 
-pub async fn get_video_list_through_rss(channel_id: &str) -> Result<Vec<String>, YtApiError> {
-    let feed_body = match make_http_get(&format!(
-        "https://www.youtube.com/feeds/videos.xml?channel_id={}",
-        channel_id
-    ))
-    .await
-    .map_err(|e| YtApiError::RequestFailed(e.status()))?
-    .error_for_status()
+pub async fn get_video_list_through_rss(
+    ctx: &AppContext,
+    channel_id: &str,
+) -> Result<Vec<String>, YtApiError> {
+    let feed_url = format!(
+        "{}/feeds/videos.xml?channel_id={}",
+        ctx.upstream.youtube, channel_id
+    );
+    let feed_body = match make_http_get(&ctx.http, &feed_url)
+        .await
+        .map_err(|e| YtApiError::RequestFailed(e.status()))?
+        .error_for_status()
     {
         Ok(r) => r
             .text()
@@ -678,17 +707,16 @@ pub async fn get_video_list_through_rss(channel_id: &str) -> Result<Vec<String>,
                 if status == 429 {
                     log::error!("Got http error 429: too many requests. Try again after 1 min...");
                     tokio::time::sleep(Duration::from_secs(60)).await;
-                    make_http_get(format!(
-                        "https://www.youtube.com/feeds/videos.xml?channel_id={}",
-                        channel_id
-                    ))
-                    .await
-                    .map_err(|e| YtApiError::RequestFailed(e.status()))?
-                    .error_for_status()
-                    .map_err(|e| YtApiError::RequestFailed(e.status()))?
-                    .text()
-                    .await
-                    .map_err(|e| YtApiError::DeserializeFailed(format!("{}", e.without_url())))?
+                    make_http_get(&ctx.http, &feed_url)
+                        .await
+                        .map_err(|e| YtApiError::RequestFailed(e.status()))?
+                        .error_for_status()
+                        .map_err(|e| YtApiError::RequestFailed(e.status()))?
+                        .text()
+                        .await
+                        .map_err(|e| {
+                            YtApiError::DeserializeFailed(format!("{}", e.without_url()))
+                        })?
                 } else {
                     return Err(YtApiError::RequestFailed(e.status()));
                 }
@@ -705,66 +733,97 @@ pub async fn get_video_list_through_rss(channel_id: &str) -> Result<Vec<String>,
 
 #[cfg(test)]
 mod test {
+    use crate::mock_upstream::VideoState;
     use crate::test::*;
 
     use super::*;
-    const CUSTOM_URL: &'static str = "GawrGura";
-    const CHANNEL_ID_TEST_URL: &'static str = "https://www.youtube.com/@GawrGura";
-    const CHANNEL_ID_TEST_ID: &'static str = "UCoSrY_IQQVpmIRZ9Xf-y93g";
+    const CUSTOM_URL: &str = "GawrGura";
+    const CHANNEL_ID_TEST_URL: &str = "https://www.youtube.com/@GawrGura";
+    const CHANNEL_ID_TEST_ID: &str = "UCoSrY_IQQVpmIRZ9Xf-y93g";
+    static FIXTURES: Lazy<()> = Lazy::new(|| {
+        MOCK.add_yt_channel(CHANNEL_ID_TEST_ID, CUSTOM_URL, "Gawr Gura Ch. hololive-EN");
+        MOCK.add_video(
+            CHANNEL_ID_TEST_ID,
+            "yt-api-test-upload",
+            "Upload",
+            vec![VideoState::Upload],
+        );
+        MOCK.add_video(
+            CHANNEL_ID_TEST_ID,
+            "yt-api-test-stream",
+            "Stream",
+            vec![VideoState::Upcoming(
+                chrono::Utc::now() + chrono::Duration::hours(1),
+            )],
+        );
+    });
+
     #[test]
     fn get_channel_id_test() {
+        Lazy::force(&FIXTURES);
         assert_eq!(
-            TOKIO_RUNTIME.block_on(get_channel_id_by_url(CHANNEL_ID_TEST_URL)),
+            TOKIO_RUNTIME.block_on(get_channel_id_by_url(
+                &new_context(),
+                &format!("{}/@{}", MOCK.upstream().youtube, CUSTOM_URL)
+            )),
             Ok(CHANNEL_ID_TEST_ID.to_string())
         )
     }
 
     #[test]
     fn test_try_youtube_id() {
+        Lazy::force(&FIXTURES);
         assert_eq!(
-            TOKIO_RUNTIME.block_on(try_youtube_id(CHANNEL_ID_TEST_URL)),
+            TOKIO_RUNTIME.block_on(try_youtube_id(&new_context(), CHANNEL_ID_TEST_URL)),
             CHANNEL_ID_TEST_ID.to_string()
         );
         assert_eq!(
-            TOKIO_RUNTIME.block_on(try_youtube_id(CUSTOM_URL)),
+            TOKIO_RUNTIME.block_on(try_youtube_id(&new_context(), CUSTOM_URL)),
             CHANNEL_ID_TEST_ID.to_string()
         );
         assert_eq!(
-            TOKIO_RUNTIME.block_on(try_youtube_id(&format!("@{}", CUSTOM_URL))),
+            TOKIO_RUNTIME.block_on(try_youtube_id(&new_context(), &format!("@{}", CUSTOM_URL))),
             CHANNEL_ID_TEST_ID.to_string()
         );
         assert_eq!(
-            TOKIO_RUNTIME.block_on(try_youtube_id(CHANNEL_ID_TEST_ID)),
+            TOKIO_RUNTIME.block_on(try_youtube_id(&new_context(), CHANNEL_ID_TEST_ID)),
             CHANNEL_ID_TEST_ID.to_string()
         );
         assert_eq!(
-            TOKIO_RUNTIME.block_on(try_youtube_id(&format!(
-                "https://www.youtube.com/channel/{}",
-                CHANNEL_ID_TEST_ID
-            ))),
+            TOKIO_RUNTIME.block_on(try_youtube_id(
+                &new_context(),
+                &format!("https://www.youtube.com/channel/{}", CHANNEL_ID_TEST_ID)
+            )),
             CHANNEL_ID_TEST_ID.to_string()
         );
     }
 
     #[test]
     fn test_all_channel_id_patterns() {
+        Lazy::force(&FIXTURES);
         TOKIO_RUNTIME.block_on(async {
-            let channel_page_src = make_http_get(CHANNEL_ID_TEST_URL)
-                .await
-                .expect("Get channel page source failed")
-                .error_for_status()
-                .expect("Get channel page source failed")
-                .text()
-                .await
-                .expect("Decode response body failed");
+            let channel_page_src = make_http_get(
+                &new_context().http,
+                format!("{}/@{}", MOCK.upstream().youtube, CUSTOM_URL),
+            )
+            .await
+            .expect("Get channel page source failed")
+            .error_for_status()
+            .expect("Get channel page source failed")
+            .text()
+            .await
+            .expect("Decode response body failed");
 
             for (pattern, grp) in CHANNEL_ID_PATTERNS.iter() {
                 assert_eq!(
                     pattern
                         .captures(&channel_page_src)
-                        .expect(&format!("Capture with pattern {} failed", pattern.as_str()))
+                        .unwrap_or_else(|| panic!(
+                            "Capture with pattern {} failed",
+                            pattern.as_str()
+                        ))
                         .get(*grp)
-                        .expect(&format!(
+                        .unwrap_or_else(|| panic!(
                             "Get group {} from capture result of pattern {} failed",
                             *grp,
                             pattern.as_str()
@@ -778,9 +837,12 @@ mod test {
 
     #[test]
     fn test_get_channel_info() {
+        Lazy::force(&FIXTURES);
         TOKIO_RUNTIME.block_on(async {
+            let ctx = new_context();
             let channel = get_all_channels(
-                &[&CHANNEL_ID_TEST_ID],
+                &ctx,
+                &[CHANNEL_ID_TEST_ID],
                 &GetChannelParts::default()
                     .content_details()
                     .id()
@@ -788,7 +850,6 @@ mod test {
                     .snippet()
                     .status()
                     .topic_details(),
-                &CONFIG.api_key,
             )
             .await
             .unwrap();
@@ -802,29 +863,75 @@ mod test {
                 .uploads
                 .clone();
             let playlist = get_all_playlist_items(
+                &ctx,
                 &upload_list,
                 &GetPlaylistItemParts::default()
                     .content_details()
                     .id()
                     .snippet()
                     .status(),
-                &CONFIG.api_key,
             )
             .await
             .unwrap();
             assert!(!playlist.is_empty());
             assert!(!get_all_video_items(
+                &ctx,
                 playlist
                     .iter()
                     .map(|r| r.contentDetails.as_ref().unwrap().videoId.clone())
                     .collect::<Vec<String>>()
                     .as_slice(),
                 &GetVideoParts::default().content_details().snippet(),
-                &CONFIG.api_key
             )
             .await
             .unwrap()
             .is_empty());
         });
     }
+
+    #[test]
+    fn test_rotate_exhausted_key() {
+        Lazy::force(&FIXTURES);
+        TOKIO_RUNTIME.block_on(async {
+            let ctx = Arc::new(AppContext::new(
+                crate::context::http_client(),
+                MOCK.upstream(),
+                Arc::new(crate::storage::MemoryStorage::default()),
+                quota::Quota::new(
+                    vec![
+                        crate::mock_upstream::EXHAUSTED_API_KEY.to_string(),
+                        crate::mock_upstream::API_KEY.to_string(),
+                    ],
+                    quota::DEFAULT_DAILY_BUDGET,
+                ),
+                crate::paths::DataPaths::in_dir(&std::env::temp_dir()),
+            ));
+            let parts = GetChannelParts::default().id();
+            let get = || get_all_channels(&ctx, &[CHANNEL_ID_TEST_ID], &parts);
+            assert_eq!(get().await.unwrap().len(), 1);
+            assert_eq!(get().await.unwrap().len(), 1);
+            let status = ctx.quota.status();
+            assert!(status.keys[0].exhausted);
+            assert_eq!(status.keys[0].used, 1);
+            assert!(!status.keys[1].exhausted);
+            assert_eq!(status.keys[1].used, 2);
+
+            ctx.quota.disable(crate::mock_upstream::API_KEY);
+            assert!(matches!(get().await, Err(YtApiError::QuotaExceeded)));
+        });
+    }
+
+    #[test]
+    fn test_get_video_list_through_rss() {
+        Lazy::force(&FIXTURES);
+        assert_eq!(
+            TOKIO_RUNTIME
+                .block_on(get_video_list_through_rss(
+                    &new_context(),
+                    CHANNEL_ID_TEST_ID
+                ))
+                .unwrap(),
+            vec!["yt-api-test-stream", "yt-api-test-upload"]
+        );
+    }
 }
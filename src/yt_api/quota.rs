@@ -0,0 +1,485 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::America::Los_Angeles;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+
+use super::YtApiError;
+
+pub const QUOTA_SAVE_FILE: &str = "quota.json";
+/// The default daily quota of a youtube data api project
+pub const DEFAULT_DAILY_BUDGET: u32 = 10000;
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Channels,
+    PlaylistItems,
+    Videos,
+    Search,
+}
+
+impl Endpoint {
+    /// Quota cost of a single request, see https://developers.google.com/youtube/v3/determine_quota_cost
+    pub fn cost(&self) -> u32 {
+        match self {
+            Endpoint::Channels | Endpoint::PlaylistItems | Endpoint::Videos => 1,
+            Endpoint::Search => 100,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Endpoint::Channels => "channels",
+            Endpoint::PlaylistItems => "playlistItems",
+            Endpoint::Videos => "videos",
+            Endpoint::Search => "search",
+        }
+    }
+}
+
+/// Youtube resets the quota at midnight of the pacific time
+pub fn quota_day(time: DateTime<Utc>) -> NaiveDate {
+    time.with_timezone(&Los_Angeles).date_naive()
+}
+
+/// Time left before the quota resets
+pub fn until_reset(time: DateTime<Utc>) -> chrono::Duration {
+    let next_day = quota_day(time).succ_opt().unwrap_or(NaiveDate::MAX);
+    match next_day
+        .and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_local_timezone(Los_Angeles).earliest())
+    {
+        Some(reset) => reset.with_timezone(&Utc) - time,
+        None => chrono::Duration::zero(),
+    }
+}
+
+/// The usage of an api key in the quota day
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+struct KeyUsage {
+    used: u32,
+    #[serde(default)]
+    endpoints: HashMap<String, u32>,
+    /// Youtube refused the key for exceeding its quota. It isn't used until the quota resets.
+    #[serde(default)]
+    exhausted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct QuotaLedger {
+    day: NaiveDate,
+    /// By the [`key_id`] of the api keys
+    #[serde(default)]
+    keys: HashMap<String, KeyUsage>,
+    /// The usage of the single key of older versions, only read
+    #[serde(default, skip_serializing)]
+    used: u32,
+    #[serde(default, skip_serializing)]
+    endpoints: HashMap<String, u32>,
+    /// The budget of each key
+    #[serde(skip)]
+    budget: u32,
+    /// In the order they are used
+    #[serde(skip)]
+    api_keys: Vec<String>,
+    #[serde(skip)]
+    save_path: Option<PathBuf>,
+    /// Counts the changes, so an older snapshot is never saved over a newer one
+    #[serde(skip)]
+    generation: u64,
+}
+
+impl Default for QuotaLedger {
+    fn default() -> Self {
+        Self {
+            day: quota_day(Utc::now()),
+            keys: HashMap::new(),
+            used: 0,
+            endpoints: HashMap::new(),
+            budget: DEFAULT_DAILY_BUDGET,
+            api_keys: vec![],
+            save_path: None,
+            generation: 0,
+        }
+    }
+}
+
+/// Identifies an api key in the saves and the status without revealing it
+pub fn key_id(key: &str) -> String {
+    hex::encode(&Sha256::digest(key.as_bytes())[..4])
+}
+
+impl QuotaLedger {
+    fn roll_over(&mut self, now: DateTime<Utc>) {
+        let today = quota_day(now);
+        if today != self.day {
+            log::info!(
+                "Quota day changed. {} quota was used on {}",
+                self.keys.values().map(|k| k.used).sum::<u32>(),
+                self.day
+            );
+            self.day = today;
+            self.keys.clear();
+        }
+    }
+
+    fn usage(&self, key: &str) -> KeyUsage {
+        self.keys.get(&key_id(key)).cloned().unwrap_or_default()
+    }
+
+    fn remaining(&self, key: &str) -> u32 {
+        let usage = self.usage(key);
+        if usage.exhausted {
+            0
+        } else {
+            self.budget.saturating_sub(usage.used)
+        }
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.save_path {
+            match serde_json::to_string(self) {
+                Ok(s) => {
+                    if let Err(e) = crate::backup::write_atomic(path, s.as_bytes()) {
+                        log::error!("Write quota save {} failed: {e}", path.display());
+                    }
+                }
+                Err(e) => log::error!("Serialize quota save failed: {e}"),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct KeyStatus {
+    /// The [`key_id`] of the key
+    pub key: String,
+    pub used: u32,
+    pub budget: u32,
+    pub remaining: u32,
+    pub exhausted: bool,
+    pub endpoints: HashMap<String, u32>,
+}
+
+/// The totals of every key, and the status of each key in the order they are used
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct QuotaStatus {
+    pub day: NaiveDate,
+    pub used: u32,
+    pub budget: u32,
+    pub remaining: u32,
+    pub endpoints: HashMap<String, u32>,
+    pub keys: Vec<KeyStatus>,
+}
+
+/// The quota used today by each api key, against the daily budget of each key. The changes are
+/// saved every [`SAVE_INTERVAL`] by the saver.
+#[derive(Debug)]
+pub struct Quota {
+    ledger: Mutex<QuotaLedger>,
+    /// The generation of the ledger in the save file
+    saved_generation: Mutex<u64>,
+}
+
+impl Default for Quota {
+    /// A ledger without keys and with the default budget, which isn't saved
+    fn default() -> Self {
+        Self::new(vec![], DEFAULT_DAILY_BUDGET)
+    }
+}
+
+impl Quota {
+    /// A ledger which isn't saved
+    pub fn new(api_keys: Vec<String>, budget: u32) -> Self {
+        Self {
+            ledger: Mutex::new(QuotaLedger {
+                budget,
+                api_keys,
+                ..Default::default()
+            }),
+            saved_generation: Mutex::new(0),
+        }
+    }
+
+    /// Set the keys and the daily budget of each, and restore the usage of the current quota day
+    /// from `save_path`, or from its newest valid backup in `backup_dir`
+    pub fn load(api_keys: Vec<String>, budget: u32, save_path: &Path, backup_dir: &Path) -> Self {
+        let mut ledger = match crate::backup::read_with_fallback(save_path, backup_dir, |s| {
+            serde_json::from_str::<QuotaLedger>(s).map_err(|e| e.to_string())
+        }) {
+            Ok(l) => l,
+            Err(e) => {
+                log::info!("Read quota save {} failed: {e}", save_path.display());
+                QuotaLedger::default()
+            }
+        };
+        if let (true, Some(first)) = (ledger.keys.is_empty(), api_keys.first()) {
+            if ledger.used > 0 {
+                ledger.keys.insert(
+                    key_id(first),
+                    KeyUsage {
+                        used: ledger.used,
+                        endpoints: std::mem::take(&mut ledger.endpoints),
+                        exhausted: false,
+                    },
+                );
+            }
+        }
+        ledger.budget = budget;
+        ledger.api_keys = api_keys;
+        ledger.save_path = Some(save_path.to_path_buf());
+        ledger.roll_over(Utc::now());
+        for key in ledger.api_keys.iter() {
+            let usage = ledger.usage(key);
+            log::info!(
+                "Quota of key {} used on {}: {}/{}{}",
+                key_id(key),
+                ledger.day,
+                usage.used,
+                ledger.budget,
+                if usage.exhausted { ", exhausted" } else { "" }
+            );
+        }
+        Self {
+            ledger: Mutex::new(ledger),
+            saved_generation: Mutex::new(0),
+        }
+    }
+
+    /// Record a request to `endpoint` and return the key to make it with, the first key which
+    /// has quota left. Fails without recording when no key has enough quota.
+    pub async fn spend(&self, endpoint: Endpoint) -> Result<String, YtApiError> {
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.roll_over(Utc::now());
+        let Some(key) = ledger
+            .api_keys
+            .iter()
+            .find(|k| ledger.remaining(k) >= endpoint.cost())
+            .cloned()
+        else {
+            log::warn!(
+                "Request to {} refused: the daily quota of every api key is used up",
+                endpoint.name(),
+            );
+            return Err(YtApiError::QuotaExceeded);
+        };
+        ledger.generation += 1;
+        let day = ledger.day;
+        let budget = ledger.budget;
+        let usage = ledger.keys.entry(key_id(&key)).or_default();
+        usage.used += endpoint.cost();
+        *usage
+            .endpoints
+            .entry(endpoint.name().to_string())
+            .or_default() += endpoint.cost();
+        log::info!(
+            "{} quota used by {}. Quota of key {} used on {}: {}/{}",
+            endpoint.cost(),
+            endpoint.name(),
+            key_id(&key),
+            day,
+            usage.used,
+            budget
+        );
+        Ok(key)
+    }
+
+    /// Stop using `key` until the quota resets, after youtube refused it for exceeding its quota
+    pub fn disable(&self, key: &str) {
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.roll_over(Utc::now());
+        log::warn!(
+            "Youtube refused api key {} for exceeding its quota. It is disabled until the quota resets",
+            key_id(key)
+        );
+        ledger.keys.entry(key_id(key)).or_default().exhausted = true;
+        ledger.generation += 1;
+    }
+
+    /// Whether `cost` quota can still be spent today
+    pub fn can_afford(&self, cost: u32) -> bool {
+        self.status().remaining >= cost
+    }
+
+    pub fn status(&self) -> QuotaStatus {
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.roll_over(Utc::now());
+        let keys = ledger
+            .api_keys
+            .iter()
+            .map(|key| {
+                let usage = ledger.usage(key);
+                KeyStatus {
+                    key: key_id(key),
+                    used: usage.used,
+                    budget: ledger.budget,
+                    remaining: ledger.remaining(key),
+                    exhausted: usage.exhausted,
+                    endpoints: usage.endpoints,
+                }
+            })
+            .collect::<Vec<KeyStatus>>();
+        let mut endpoints = HashMap::new();
+        for (name, used) in keys.iter().flat_map(|k| k.endpoints.iter()) {
+            *endpoints.entry(name.clone()).or_default() += used;
+        }
+        QuotaStatus {
+            day: ledger.day,
+            used: keys.iter().map(|k| k.used).sum(),
+            budget: keys.iter().map(|k| k.budget).sum(),
+            remaining: keys.iter().map(|k| k.remaining).sum(),
+            endpoints,
+            keys,
+        }
+    }
+
+    pub fn set_budget(&self, budget: u32) {
+        self.ledger.lock().unwrap().budget = budget;
+    }
+
+    /// Use `api_keys` from now on. The usage of the keys which are kept is kept.
+    pub fn set_keys(&self, api_keys: Vec<String>) {
+        self.ledger.lock().unwrap().api_keys = api_keys;
+    }
+
+    /// Save the ledger periodically until it is dropped. The file is written off the runtime
+    /// threads.
+    pub fn spawn_saver(self: &Arc<Self>) -> JoinHandle<()> {
+        let quota = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SAVE_INTERVAL).await;
+                match quota.upgrade() {
+                    Some(quota) => {
+                        if let Err(e) = tokio::task::spawn_blocking(move || quota.save()).await {
+                            log::error!("Save quota failed: {e}");
+                        }
+                    }
+                    None => break,
+                }
+            }
+        })
+    }
+
+    /// Write the ledger to its save file, if it has one and it changed since the last save.
+    /// Blocks on the file system.
+    pub fn save(&self) {
+        let snapshot = self.ledger.lock().unwrap().clone();
+        let mut saved_generation = self.saved_generation.lock().unwrap();
+        if snapshot.generation > *saved_generation {
+            snapshot.save();
+            *saved_generation = snapshot.generation;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_quota_day_is_pacific() {
+        // 06:59 UTC is still the previous day in california
+        assert_eq!(
+            quota_day(Utc.with_ymd_and_hms(2023, 7, 20, 6, 59, 0).unwrap()),
+            NaiveDate::from_ymd_opt(2023, 7, 19).unwrap()
+        );
+        assert_eq!(
+            quota_day(Utc.with_ymd_and_hms(2023, 7, 20, 7, 0, 0).unwrap()),
+            NaiveDate::from_ymd_opt(2023, 7, 20).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_until_reset() {
+        assert_eq!(
+            until_reset(Utc.with_ymd_and_hms(2023, 7, 20, 6, 0, 0).unwrap()),
+            chrono::Duration::hours(1)
+        );
+        assert_eq!(
+            until_reset(Utc.with_ymd_and_hms(2023, 7, 20, 7, 0, 0).unwrap()),
+            chrono::Duration::hours(24)
+        );
+    }
+
+    #[test]
+    fn test_ledger_roll_over() {
+        let mut ledger = QuotaLedger {
+            day: NaiveDate::from_ymd_opt(2023, 7, 19).unwrap(),
+            keys: HashMap::from([(
+                key_id("key"),
+                KeyUsage {
+                    used: 9000,
+                    endpoints: HashMap::from([("videos".to_string(), 9000)]),
+                    exhausted: false,
+                },
+            )]),
+            ..Default::default()
+        };
+        ledger.roll_over(Utc.with_ymd_and_hms(2023, 7, 20, 6, 0, 0).unwrap());
+        assert_eq!(ledger.usage("key").used, 9000);
+        assert_eq!(ledger.remaining("key"), DEFAULT_DAILY_BUDGET - 9000);
+        ledger.keys.get_mut(&key_id("key")).unwrap().exhausted = true;
+        assert_eq!(ledger.remaining("key"), 0);
+        ledger.roll_over(Utc.with_ymd_and_hms(2023, 7, 20, 8, 0, 0).unwrap());
+        assert_eq!(ledger.usage("key"), KeyUsage::default());
+        assert_eq!(ledger.remaining("key"), DEFAULT_DAILY_BUDGET);
+    }
+
+    #[test]
+    fn test_spend_rotates_keys() {
+        let quota = Quota::new(vec!["first".to_string(), "second".to_string()], 2);
+        let spend = |endpoint| crate::test::TOKIO_RUNTIME.block_on(quota.spend(endpoint));
+        assert_eq!(spend(Endpoint::Videos).unwrap(), "first");
+        quota.disable("first");
+        assert_eq!(spend(Endpoint::Videos).unwrap(), "second");
+        assert_eq!(spend(Endpoint::Videos).unwrap(), "second");
+        assert_eq!(spend(Endpoint::Videos), Err(YtApiError::QuotaExceeded));
+        let status = quota.status();
+        assert_eq!((status.used, status.budget, status.remaining), (3, 4, 0));
+        assert!(status.keys[0].exhausted);
+        assert_eq!(status.keys[1].key, key_id("second"));
+        assert_eq!(status.endpoints["videos"], 3);
+    }
+
+    #[test]
+    fn test_load_single_key_save() {
+        let dir =
+            std::env::temp_dir().join(format!("yt-watcher-test-quota-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(QUOTA_SAVE_FILE);
+        std::fs::write(
+            &path,
+            serde_json::json!({
+                "day": quota_day(Utc::now()),
+                "used": 7,
+                "endpoints": { "videos": 7 },
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let keys = vec!["old".to_string(), "new".to_string()];
+        let quota = Quota::load(keys.clone(), 10, &path, &dir);
+        assert_eq!(quota.status().keys[0].used, 7);
+        quota.disable("new");
+        quota.save();
+        // the keys themselves are never saved
+        let save = std::fs::read_to_string(&path).unwrap();
+        assert!(!save.contains("\"old\"") && !save.contains("\"new\""));
+        let status = Quota::load(keys, 10, &path, &dir).status();
+        assert_eq!(status.keys[0].used, 7);
+        assert!(status.keys[1].exhausted);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
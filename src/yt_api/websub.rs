@@ -0,0 +1,222 @@
+//! Push notifications of new uploads through the WebSub (PubSubHubbub) hub of youtube.
+//!
+//! The hub delivers the same atom entries as the rss feed of the channel to the callback url,
+//! after it verified the subscription with a challenge. Each subscription has its own secret,
+//! with which the hub signs the notifications, and its own callback url, which tells the channel
+//! of a notification before its body is read.
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::Sha256;
+
+use crate::context::AppContext;
+
+use super::{YtApiError, VIDEO_ID_PATTERN};
+
+/// The lease the hub grants when the subscriber doesn't ask for one
+pub const DEFAULT_LEASE_SECONDS: u32 = 432000;
+/// The header with the HMAC of the notification body
+pub const SIGNATURE_HEADER: &str = "x-hub-signature";
+/// Notifications are a single atom entry, larger bodies are refused
+pub const MAX_NOTIFICATION_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Subscribe,
+    Unsubscribe,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Subscribe => "subscribe",
+            Mode::Unsubscribe => "unsubscribe",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "subscribe" => Some(Mode::Subscribe),
+            "unsubscribe" => Some(Mode::Unsubscribe),
+            _ => None,
+        }
+    }
+}
+
+/// The feed of the channel, which is also the topic of the subscription
+pub fn topic_url(ctx: &AppContext, channel_id: &str) -> String {
+    format!(
+        "{}/feeds/videos.xml?channel_id={}",
+        ctx.upstream.youtube, channel_id
+    )
+}
+
+/// The channel id of a topic built by [`topic_url`]
+pub fn channel_id_of_topic<'a>(ctx: &AppContext, topic: &'a str) -> Option<&'a str> {
+    topic
+        .strip_prefix(&format!(
+            "{}/feeds/videos.xml?channel_id=",
+            ctx.upstream.youtube
+        ))
+        .filter(|id| !id.is_empty())
+}
+
+/// The callback url of the subscription of the channel, `callback` with the channel id added
+pub fn callback_url(callback: &str, channel_id: &str) -> String {
+    let separator = if callback.contains('?') { '&' } else { '?' };
+    format!("{callback}{separator}channel_id={channel_id}")
+}
+
+/// The secret of the subscription of the channel, derived from the configured `secret`
+fn channel_secret(secret: &str, channel_id: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(channel_id.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Ask the hub to (un)subscribe `callback` to the uploads of the channel. The hub verifies the
+/// request asynchronously by calling the callback.
+pub async fn request(
+    ctx: &AppContext,
+    mode: Mode,
+    channel_id: &str,
+    callback: &str,
+    secret: &str,
+    lease_seconds: u32,
+) -> Result<(), YtApiError> {
+    ctx.http
+        .post(&ctx.upstream.websub_hub)
+        .form(&[
+            ("hub.mode", mode.as_str()),
+            ("hub.topic", &topic_url(ctx, channel_id)),
+            ("hub.callback", &callback_url(callback, channel_id)),
+            ("hub.secret", &channel_secret(secret, channel_id)),
+            ("hub.lease_seconds", &lease_seconds.to_string()),
+            ("hub.verify", "async"),
+        ])
+        .send()
+        .await
+        .map_err(|e| YtApiError::RequestFailed(e.status()))?
+        .error_for_status()
+        .map_err(|e| YtApiError::RequestFailed(e.status()))?;
+    Ok(())
+}
+
+/// The value of the signature header the hub sends along with a notification of the channel
+pub fn sign(secret: &str, channel_id: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(channel_secret(secret, channel_id).as_bytes())
+        .expect("HMAC can take key of any size");
+    mac.update(body);
+    format!("sha1={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Whether `signature`, the signature header of a notification of the channel, matches `body`
+pub fn verify_signature(
+    secret: &str,
+    channel_id: &str,
+    signature: Option<&str>,
+    body: &[u8],
+) -> bool {
+    let Some((method, expected)) = signature.and_then(|s| s.split_once('=')) else {
+        return false;
+    };
+    let Ok(expected) = hex::decode(expected) else {
+        return false;
+    };
+    let key = channel_secret(secret, channel_id);
+    match method {
+        "sha1" => {
+            let mut mac = Hmac::<Sha1>::new_from_slice(key.as_bytes())
+                .expect("HMAC can take key of any size");
+            mac.update(body);
+            mac.verify_slice(&expected).is_ok()
+        }
+        "sha256" => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())
+                .expect("HMAC can take key of any size");
+            mac.update(body);
+            mac.verify_slice(&expected).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Video ids in an atom notification of the hub
+pub fn parse_notification(body: &str) -> Vec<String> {
+    VIDEO_ID_PATTERN
+        .captures_iter(body)
+        .map(|cap| cap.get(1).unwrap().as_str().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::new_context;
+
+    #[test]
+    fn test_topic_round_trip() {
+        let ctx = new_context();
+        let topic = topic_url(&ctx, "UCoSrY_IQQVpmIRZ9Xf-y93g");
+        assert_eq!(
+            channel_id_of_topic(&ctx, &topic),
+            Some("UCoSrY_IQQVpmIRZ9Xf-y93g")
+        );
+        assert_eq!(channel_id_of_topic(&ctx, "https://example.com/feed"), None);
+    }
+
+    #[test]
+    fn test_signature() {
+        let body = b"<feed></feed>";
+        let signature = sign("secret", "UCchannel", body);
+        assert!(verify_signature(
+            "secret",
+            "UCchannel",
+            Some(&signature),
+            body
+        ));
+        // the secret of another subscription
+        assert!(!verify_signature(
+            "secret",
+            "UCother",
+            Some(&signature),
+            body
+        ));
+        assert!(!verify_signature(
+            "secret",
+            "UCchannel",
+            Some(&signature),
+            b"<feed/>"
+        ));
+        assert!(!verify_signature(
+            "secret",
+            "UCchannel",
+            Some("sha1=zz"),
+            body
+        ));
+        assert!(!verify_signature("secret", "UCchannel", None, body));
+        assert_eq!(
+            callback_url("https://example.com/websub", "UCchannel"),
+            "https://example.com/websub?channel_id=UCchannel"
+        );
+        assert_eq!(
+            callback_url("https://example.com/hook?app=yt", "UCchannel"),
+            "https://example.com/hook?app=yt&channel_id=UCchannel"
+        );
+    }
+
+    #[test]
+    fn test_parse_notification() {
+        let body = r#"<feed xmlns:yt="http://www.youtube.com/xml/schemas/2015" xmlns="http://www.w3.org/2005/Atom">
+  <link rel="hub" href="https://pubsubhubbub.appspot.com"/>
+  <title>YouTube video feed</title>
+  <entry>
+    <id>yt:video:websub-video</id>
+    <yt:videoId>websub-video</yt:videoId>
+    <yt:channelId>UCoSrY_IQQVpmIRZ9Xf-y93g</yt:channelId>
+    <title>Video title</title>
+  </entry>
+</feed>"#;
+        assert_eq!(parse_notification(body), vec!["websub-video"]);
+    }
+}